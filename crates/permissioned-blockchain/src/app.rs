@@ -1,49 +1,429 @@
-use std::{future::Future, pin::Pin};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{Debug, Display},
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+};
 
-use rand::Rng;
-use tokio_util::sync::CancellationToken;
+use k256::sha2::{Digest, Sha256};
+use rand::{rngs::StdRng, RngCore};
 
-use crate::Client;
+use crate::{
+    client::CallOptions,
+    merkle::{self, SparseProof},
+    Client,
+};
 
+use self::shard::Partition;
+
+pub mod shard;
+pub mod smallbank;
 pub mod ycsb;
 
-#[derive(Debug, Clone)]
-pub enum App {
-    Null,
-    Ycsb(ycsb::App),
+// the replicated state machine
+pub trait Application: Debug + Send {
+    fn execute(&mut self, op: &[u8]) -> Vec<u8>;
+
+    fn execute_read_only(&self, op: &[u8]) -> Vec<u8>;
+
+    fn snapshot(&self) -> Vec<u8>;
+
+    fn restore(&mut self, snapshot: &[u8]);
+
+    // all zero if the state is not authenticated
+    fn state_digest(&self) -> merkle::Digest {
+        Default::default()
+    }
+
+    // the proof that the result of the read-only op is the value of
+    // `merkle::leaf(op)` in the state with `state_digest` as root, see
+    // `ReadOnlyReply::is_proved`
+    fn prove_read_only(&self, _op: &[u8]) -> Option<SparseProof> {
+        None
+    }
+
+    // an op that reverts the effect of the op on the current state, for
+    // aborting cross-shard transactions (see `shard`) and rolling back
+    // speculative execution (see `Speculative`)
+    fn undo(&self, op: &[u8]) -> Vec<u8>;
 }
 
+#[derive(Debug)]
+pub struct App(Box<dyn Application>);
+
 impl App {
+    pub fn new(app: impl Application + 'static) -> Self {
+        Self(Box::new(app))
+    }
+
     pub fn execute(&mut self, op: &[u8]) -> Vec<u8> {
-        match self {
-            Self::Null => Default::default(),
-            Self::Ycsb(app) => app.execute(op),
+        self.0.execute(op)
+    }
+
+    pub fn execute_read_only(&self, op: &[u8]) -> Vec<u8> {
+        self.0.execute_read_only(op)
+    }
+
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.0.snapshot()
+    }
+
+    pub fn restore(&mut self, snapshot: &[u8]) {
+        self.0.restore(snapshot)
+    }
+
+    pub fn state_digest(&self) -> merkle::Digest {
+        self.0.state_digest()
+    }
+
+    pub fn prove_read_only(&self, op: &[u8]) -> Option<SparseProof> {
+        self.0.prove_read_only(op)
+    }
+
+    pub fn undo(&self, op: &[u8]) -> Vec<u8> {
+        self.0.undo(op)
+    }
+
+    // for comparing states across replicas, the state digest if the state is
+    // authenticated, otherwise the digest of a snapshot
+    pub fn snapshot_digest(&self) -> [u8; 32] {
+        let digest = self.0.state_digest();
+        if digest != merkle::Digest::default() {
+            return digest;
         }
+        Sha256::digest(self.0.snapshot()).into()
     }
 }
 
+// versioned application state for speculative execution, which can be rolled
+// back to any height that is not committed yet
 #[derive(Debug)]
-pub enum Workload {
-    Null,
-    Ycsb(ycsb::Workload),
+pub struct Speculative {
+    app: App,
+    // the undo ops of the blocks above the committed height, in execution
+    // order
+    versions: VecDeque<(u32, Vec<Vec<u8>>)>,
+    committed_height: u32,
+    // whether the block being executed is above the committed height
+    speculating: bool,
 }
 
+impl Speculative {
+    pub fn new(app: App) -> Self {
+        Self {
+            app,
+            versions: Default::default(),
+            committed_height: 0,
+            speculating: false,
+        }
+    }
+
+    pub fn app(&self) -> &App {
+        &self.app
+    }
+
+    pub fn committed_height(&self) -> u32 {
+        self.committed_height
+    }
+
+    // start executing the block at the height. a block may be committed before
+    // getting executed, which needs no undo ops to roll back with
+    pub fn begin(&mut self, height: u32) {
+        self.speculating = height > self.committed_height;
+        if !self.speculating {
+            return;
+        }
+        if let Some((last_height, _)) = self.versions.back() {
+            assert!(height > *last_height)
+        }
+        self.versions.push_back((height, Default::default()))
+    }
+
+    pub fn execute(&mut self, op: &[u8]) -> Vec<u8> {
+        if self.speculating {
+            let undo_op = self.app.undo(op);
+            self.versions.back_mut().unwrap().1.push(undo_op)
+        }
+        self.app.execute(op)
+    }
+
+    // the blocks up to the height will never be rolled back
+    pub fn commit(&mut self, height: u32) {
+        while self
+            .versions
+            .front()
+            .is_some_and(|(version_height, _)| *version_height <= height)
+        {
+            self.versions.pop_front();
+        }
+        self.committed_height = self.committed_height.max(height)
+    }
+
+    // revert the blocks above the height
+    pub fn rollback(&mut self, height: u32) {
+        assert!(height >= self.committed_height);
+        while self
+            .versions
+            .back()
+            .is_some_and(|(version_height, _)| *version_height > height)
+        {
+            let (_, undo_ops) = self.versions.pop_back().unwrap();
+            for undo_op in undo_ops.iter().rev() {
+                self.app.execute(undo_op);
+            }
+        }
+    }
+}
+
+// an invocation of a transaction
+#[derive(Debug, Clone)]
+pub struct Invoke {
+    pub op: Vec<u8>,
+    // issue through the read-only fast path if the protocol supports
+    pub read_only: bool,
+}
+
+// generates transactions for the application, a transaction is invoked one op
+// after another
+pub trait Generate: Debug + Send + Sync {
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec<Invoke>;
+}
+
+#[derive(Debug)]
+pub struct Workload(Box<dyn Generate>);
+
 impl Workload {
+    pub fn new(workload: impl Generate + 'static) -> Self {
+        Self(Box::new(workload))
+    }
+
     pub fn generate(
         &self,
-        client: impl Client + Send + Sync + 'static,
-        rng: &mut impl Rng,
-    ) -> Pin<Box<dyn Future<Output = ()> + Send + Sync>> {
-        match self {
-            Self::Null => Box::pin(async move {
-                let finish = CancellationToken::new();
-                client.invoke(Default::default(), {
-                    let finish = finish.clone();
-                    move |_| finish.cancel()
-                });
-                finish.cancelled().await
-            }),
-            Self::Ycsb(workload) => workload.generate(client, rng),
-        }
+        client: impl Client + Clone + Send + Sync + 'static,
+        rng: &mut impl RngCore,
+    ) -> Pin<Box<dyn Future<Output = bool> + Send + Sync>> {
+        let txn = self.0.generate(rng);
+        Box::pin(async move {
+            for Invoke { op, read_only } in txn {
+                let options = CallOptions {
+                    read_only,
+                    ..Default::default()
+                };
+                // the rest of a failed transaction is given up, and the
+                // benchmark counts it as failed
+                if crate::client::call(&client, op, options).await.is_err() {
+                    return false;
+                }
+            }
+            true
+        })
+    }
+}
+
+impl<C> neat::benchmark::Workload<C> for Workload
+where
+    C: Client + Clone + Send + Sync + 'static,
+{
+    fn invoke(&self, session: &C) -> impl Future<Output = bool> + Send + 'static {
+        self.generate(session.clone(), &mut rand::thread_rng())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Null;
+
+impl Application for Null {
+    fn execute(&mut self, _: &[u8]) -> Vec<u8> {
+        Default::default()
+    }
+
+    fn execute_read_only(&self, _: &[u8]) -> Vec<u8> {
+        Default::default()
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        Default::default()
+    }
+
+    fn restore(&mut self, _: &[u8]) {}
+
+    fn undo(&self, _: &[u8]) -> Vec<u8> {
+        Default::default()
+    }
+}
+
+impl Partition for Null {
+    fn keys(&self, _: &[u8]) -> Vec<u64> {
+        Default::default()
+    }
+
+    // ops without keys go to any single shard and are never split
+    fn split(&self, _: &[u8], _: &[Vec<u8>]) -> Option<Vec<u8>> {
+        unreachable!()
+    }
+
+    fn merge(&self, _: &[u8], _: Vec<Vec<u8>>) -> (bool, Vec<u8>) {
+        unreachable!()
+    }
+}
+
+impl Generate for Null {
+    fn generate(&self, _: &mut dyn RngCore) -> Vec<Invoke> {
+        vec![Invoke {
+            op: Default::default(),
+            read_only: false,
+        }]
+    }
+}
+
+type NewApp = Box<dyn Fn(&[u8], &mut StdRng) -> App + Send + Sync>;
+type NewWorkload = Box<dyn Fn(&[u8], &mut StdRng) -> Workload + Send + Sync>;
+
+// applications that are plugged in from outside of this crate, constructed
+// from `control_messages::App::Custom` by name. the replicas and the clients
+// are seeded with the same rng so the two constructors can agree on e.g.
+// the initial keys
+#[derive(Default)]
+pub struct Registry {
+    apps: HashMap<String, (NewApp, NewWorkload)>,
+    // for sharding, optional
+    partitions: HashMap<String, Arc<dyn Partition>>,
+}
+
+impl Debug for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Registry")
+            .field("apps", &self.apps.keys())
+            .field("partitions", &self.partitions)
+            .finish()
+    }
+}
+
+// a custom application, or its partition, that is not registered by the name
+#[derive(Debug, Clone)]
+pub struct Unregistered(pub String);
+
+impl Display for Unregistered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no application registered for {}", self.0)
+    }
+}
+
+impl std::error::Error for Unregistered {}
+
+impl Registry {
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        new_app: impl Fn(&[u8], &mut StdRng) -> App + Send + Sync + 'static,
+        new_workload: impl Fn(&[u8], &mut StdRng) -> Workload + Send + Sync + 'static,
+    ) {
+        let evicted = self
+            .apps
+            .insert(name.into(), (Box::new(new_app), Box::new(new_workload)));
+        assert!(evicted.is_none())
+    }
+
+    pub fn register_partition(
+        &mut self,
+        name: impl Into<String>,
+        partition: impl Partition + 'static,
+    ) {
+        let evicted = self.partitions.insert(name.into(), Arc::new(partition));
+        assert!(evicted.is_none())
+    }
+
+    fn constructors(&self, name: &str) -> Result<&(NewApp, NewWorkload), Unregistered> {
+        self.apps.get(name).ok_or_else(|| Unregistered(name.into()))
+    }
+
+    pub fn app(
+        &self,
+        config: &control_messages::App,
+        rng: &mut StdRng,
+    ) -> Result<App, Unregistered> {
+        Ok(match config {
+            control_messages::App::Null => App::new(Null),
+            control_messages::App::Ycsb(config) => {
+                App::new(ycsb::Workload::app((*config).into(), rng))
+            }
+            control_messages::App::SmallBank(config) => {
+                App::new(smallbank::Workload::app((*config).into()))
+            }
+            control_messages::App::Custom { name, config } => {
+                (self.constructors(name)?.0)(config, rng)
+            }
+        })
+    }
+
+    pub fn workload(
+        &self,
+        config: &control_messages::App,
+        rng: &mut StdRng,
+    ) -> Result<Workload, Unregistered> {
+        Ok(match config {
+            control_messages::App::Null => Workload::new(Null),
+            control_messages::App::Ycsb(config) => {
+                Workload::new(ycsb::Workload::new((*config).into(), rng))
+            }
+            control_messages::App::SmallBank(config) => {
+                Workload::new(smallbank::Workload::new((*config).into()))
+            }
+            control_messages::App::Custom { name, config } => {
+                (self.constructors(name)?.1)(config, rng)
+            }
+        })
+    }
+
+    pub fn partition(
+        &self,
+        config: &control_messages::App,
+    ) -> Result<Arc<dyn Partition>, Unregistered> {
+        Ok(match config {
+            control_messages::App::Null => Arc::new(Null),
+            control_messages::App::Ycsb(_) => Arc::new(ycsb::Partition),
+            control_messages::App::SmallBank(_) => Arc::new(smallbank::Partition),
+            control_messages::App::Custom { name, .. } => self
+                .partitions
+                .get(name)
+                .ok_or_else(|| Unregistered(name.clone()))?
+                .clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::Options;
+
+    use super::*;
+
+    #[test]
+    fn rollback_speculative() {
+        let deposit = |customer| {
+            bincode::options()
+                .serialize(&smallbank::Op::DepositChecking(customer, 1))
+                .unwrap()
+        };
+        let mut app = Speculative::new(App::new(smallbank::App::new(2)));
+        app.begin(1);
+        app.execute(&deposit(0));
+        let digest = app.app().snapshot_digest();
+        app.begin(2);
+        app.execute(&deposit(1));
+        app.begin(3);
+        app.execute(&deposit(0));
+        app.rollback(1);
+        assert_eq!(app.app().snapshot_digest(), digest);
+
+        app.begin(2);
+        app.execute(&deposit(1));
+        let digest = app.app().snapshot_digest();
+        app.commit(2);
+        app.begin(3);
+        app.execute(&deposit(1));
+        app.rollback(2);
+        assert_eq!(app.app().snapshot_digest(), digest);
     }
 }
@@ -0,0 +1,588 @@
+// sharded replication, each shard is a replica group running any of the
+// protocols over a partition of the application keys
+//
+// an op that only touches the keys of one shard is sent to that shard. an op
+// that touches multiple shards is split into single key ops and committed
+// atomically with two-phase commit: the split ops are executed one after
+// another while locking their keys and saving their undo ops, then the
+// transaction is committed or aborted on every touched shard. the client drives
+// the commit, and the first touched shard acts as the coordinator: the decision
+// is logged there before any other shard learns it, so it is durable in a
+// replica group even if the client fails in the middle
+//
+// locks are not waited for. an op that touches a locked key is rejected, and
+// retried by the client after a random backoff. read-only ops that take the
+// fast path skip the locks, and may observe prepared transactions. a client
+// that keeps getting rejected by the same transaction takes its client as
+// failed: it asks the coordinator to abort the transaction, which returns the
+// logged decision instead if there is one, and finishes the transaction on the
+// rejecting shard with the decision
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    future::Future,
+    sync::{
+        atomic::{AtomicU32, Ordering::SeqCst},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bincode::Options;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::task::AbortHandle;
+
+use crate::{client::BoxedConsume, ClientIndex};
+
+use super::Application;
+
+// how the state of an application is partitioned by keys
+pub trait Partition: Debug + Send + Sync {
+    // the keys the op reads or writes
+    fn keys(&self, op: &[u8]) -> Vec<u64>;
+
+    // split an op that touches multiple keys into single key ops, which are
+    // executed one after another. return the next op given the results of the
+    // previous ones, none if there is no more
+    fn split(&self, op: &[u8], results: &[Vec<u8>]) -> Option<Vec<u8>>;
+
+    // whether to commit the split ops, and the result of the original op
+    fn merge(&self, op: &[u8], results: Vec<Vec<u8>>) -> (bool, Vec<u8>);
+}
+
+// for partitioning keys that are not integers
+pub fn key(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3)
+    }
+    hash
+}
+
+pub type TxnId = (ClientIndex, u32);
+
+// a lock held by the same transaction for this long is taken as left behind by
+// a failed client
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op {
+    Single(Vec<u8>),
+    // with the index of the coordinator shard
+    Prepare(TxnId, usize, Vec<u8>),
+    // commit or abort on the coordinator, which logs the decision. the result
+    // is the logged decision, which is the first one proposed
+    Decide(TxnId, bool),
+    // commit or abort on the other participants
+    Finish(TxnId, bool),
+    // revert an op for speculative execution, see `Application::undo`. the ops
+    // are executed in order, then the locks and prepared transactions are reset
+    // and the decision (if any) is forgotten
+    Undo(Vec<Vec<u8>>, Locks, Prepared, Option<TxnId>),
+}
+
+type Locks = BTreeMap<u64, TxnId>;
+// the coordinator of each prepared transaction, and its ops and their undo ops
+// in execution order
+type Prepared = BTreeMap<TxnId, (usize, Vec<(Vec<u8>, Vec<u8>)>)>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Result {
+    Done(Vec<u8>),
+    // some key is locked by another transaction, with the transaction and its
+    // coordinator
+    Conflict(TxnId, usize),
+}
+
+impl Result {
+    // of `Op::Decide`
+    fn decision(&self) -> bool {
+        let Self::Done(decision) = self else {
+            unreachable!()
+        };
+        bincode::options().deserialize(decision).unwrap()
+    }
+}
+
+#[derive(Debug)]
+pub struct App {
+    app: crate::App,
+    partition: Arc<dyn Partition>,
+    locks: Locks,
+    prepared: Prepared,
+    decisions: BTreeMap<TxnId, bool>,
+}
+
+impl App {
+    pub fn new(app: crate::App, partition: Arc<dyn Partition>) -> Self {
+        Self {
+            app,
+            partition,
+            locks: Default::default(),
+            prepared: Default::default(),
+            decisions: Default::default(),
+        }
+    }
+
+    fn finish(&mut self, txn_id: TxnId, commit: bool) {
+        if let Some((_, ops)) = self.prepared.remove(&txn_id) {
+            if !commit {
+                for (_, undo_op) in ops.iter().rev() {
+                    self.app.execute(undo_op);
+                }
+            }
+        }
+        self.locks.retain(|_, lock_txn_id| *lock_txn_id != txn_id)
+    }
+
+    // the ops that revert finishing the transaction: an aborted transaction is
+    // reverted by executing its ops again
+    fn redo_ops(&self, txn_id: TxnId, commit: bool) -> Vec<Vec<u8>> {
+        if commit {
+            return Default::default();
+        }
+        Vec::from_iter(
+            self.prepared
+                .get(&txn_id)
+                .into_iter()
+                .flat_map(|(_, ops)| ops)
+                .map(|(op, _)| op.clone()),
+        )
+    }
+
+    // the transaction other than the one (if any) that locks some key of the
+    // op, and its coordinator
+    fn conflict(&self, op: &[u8], txn_id: Option<TxnId>) -> Option<(TxnId, usize)> {
+        self.partition.keys(op).iter().find_map(|key| {
+            let lock_txn_id = *self.locks.get(key)?;
+            if Some(lock_txn_id) == txn_id {
+                return None;
+            }
+            Some((lock_txn_id, self.prepared[&lock_txn_id].0))
+        })
+    }
+}
+
+impl Application for App {
+    fn execute(&mut self, op: &[u8]) -> Vec<u8> {
+        let result = match bincode::options().deserialize(op).unwrap() {
+            Op::Single(op) => match self.conflict(&op, None) {
+                Some((txn_id, coordinator)) => Result::Conflict(txn_id, coordinator),
+                None => Result::Done(self.app.execute(&op)),
+            },
+            // a transaction that is aborted by another client before getting
+            // prepared on the coordinator is rejected
+            Op::Prepare(txn_id, coordinator, _) if self.decisions.contains_key(&txn_id) => {
+                Result::Conflict(txn_id, coordinator)
+            }
+            Op::Prepare(txn_id, coordinator, op) => match self.conflict(&op, Some(txn_id)) {
+                Some((txn_id, coordinator)) => Result::Conflict(txn_id, coordinator),
+                None => {
+                    for key in self.partition.keys(&op) {
+                        self.locks.insert(key, txn_id);
+                    }
+                    let undo_op = self.app.undo(&op);
+                    let result = self.app.execute(&op);
+                    self.prepared
+                        .entry(txn_id)
+                        .or_insert((coordinator, Default::default()))
+                        .1
+                        .push((op, undo_op));
+                    Result::Done(result)
+                }
+            },
+            Op::Decide(txn_id, commit) => {
+                let commit = *self.decisions.entry(txn_id).or_insert(commit);
+                self.finish(txn_id, commit);
+                Result::Done(bincode::options().serialize(&commit).unwrap())
+            }
+            Op::Finish(txn_id, commit) => {
+                self.finish(txn_id, commit);
+                Result::Done(Default::default())
+            }
+            Op::Undo(ops, locks, prepared, decided) => {
+                for op in ops {
+                    self.app.execute(&op);
+                }
+                (self.locks, self.prepared) = (locks, prepared);
+                if let Some(txn_id) = decided {
+                    self.decisions.remove(&txn_id);
+                }
+                Result::Done(Default::default())
+            }
+        };
+        bincode::options().serialize(&result).unwrap()
+    }
+
+    fn execute_read_only(&self, op: &[u8]) -> Vec<u8> {
+        let Op::Single(op) = bincode::options().deserialize(op).unwrap() else {
+            unimplemented!()
+        };
+        bincode::options()
+            .serialize(&Result::Done(self.app.execute_read_only(&op)))
+            .unwrap()
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        bincode::options()
+            .serialize(&(
+                self.app.snapshot(),
+                &self.locks,
+                &self.prepared,
+                &self.decisions,
+            ))
+            .unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        let app_snapshot: Vec<u8>;
+        (app_snapshot, self.locks, self.prepared, self.decisions) =
+            bincode::options().deserialize(snapshot).unwrap();
+        self.app.restore(&app_snapshot)
+    }
+
+    fn state_digest(&self) -> crate::merkle::Digest {
+        self.app.state_digest()
+    }
+
+    fn undo(&self, op: &[u8]) -> Vec<u8> {
+        let (ops, decided) = match bincode::options().deserialize(op).unwrap() {
+            Op::Single(op) if self.conflict(&op, None).is_none() => {
+                (vec![self.app.undo(&op)], None)
+            }
+            Op::Prepare(txn_id, _, op)
+                if !self.decisions.contains_key(&txn_id)
+                    && self.conflict(&op, Some(txn_id)).is_none() =>
+            {
+                (vec![self.app.undo(&op)], None)
+            }
+            Op::Single(_) | Op::Prepare(..) => Default::default(),
+            Op::Decide(txn_id, commit) => match self.decisions.get(&txn_id) {
+                Some(&commit) => (self.redo_ops(txn_id, commit), None),
+                None => (self.redo_ops(txn_id, commit), Some(txn_id)),
+            },
+            Op::Finish(txn_id, commit) => (self.redo_ops(txn_id, commit), None),
+            Op::Undo(..) => unimplemented!("undo an undo op"),
+        };
+        bincode::options()
+            .serialize(&Op::Undo(
+                ops,
+                self.locks.clone(),
+                self.prepared.clone(),
+                decided,
+            ))
+            .unwrap()
+    }
+}
+
+// a client over the clients of all shards, one per shard. with a single shard
+// the ops are passed through, so the replicas can run the application as is
+#[derive(Debug)]
+pub struct Client<C> {
+    shared: Arc<ClientShared<C>>,
+}
+
+#[derive(Debug)]
+struct ClientShared<C> {
+    index: ClientIndex,
+    shards: Vec<Arc<C>>,
+    partition: Arc<dyn Partition>,
+    txn_num: AtomicU32,
+    invoke_num: AtomicU32,
+    // the outstanding invocations that are not passed through
+    invokes: Mutex<HashMap<u32, (BoxedConsume, AbortHandle)>>,
+}
+
+impl<C> Client<C> {
+    pub fn new(index: ClientIndex, shards: Vec<Arc<C>>, partition: Arc<dyn Partition>) -> Self {
+        assert!(!shards.is_empty());
+        Self {
+            shared: Arc::new(ClientShared {
+                index,
+                shards,
+                partition,
+                txn_num: AtomicU32::new(0),
+                invoke_num: AtomicU32::new(0),
+                invokes: Default::default(),
+            }),
+        }
+    }
+}
+
+impl<C> crate::Client for Client<C>
+where
+    C: crate::Client + Send + Sync + 'static,
+{
+    type Message = C::Message;
+
+    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
+        if let [shard] = &self.shared.shards[..] {
+            return shard.invoke(op, consume);
+        }
+        self.shared.spawn(op, false, consume.into())
+    }
+
+    fn invoke_read_only(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
+        if let [shard] = &self.shared.shards[..] {
+            return shard.invoke_read_only(op, consume);
+        }
+        self.shared.spawn(op, true, consume.into())
+    }
+
+    // an aborted transaction may leave its locks on some shards, which are
+    // recovered by the clients that are blocked on them
+    fn abort(&self, invoke_num: u32) -> Option<BoxedConsume> {
+        if let [shard] = &self.shared.shards[..] {
+            return shard.abort(invoke_num);
+        }
+        let (consume, task) = self.shared.invokes.lock().unwrap().remove(&invoke_num)?;
+        task.abort();
+        Some(consume)
+    }
+
+    fn handle(&self, _: Self::Message) {
+        unreachable!("messages are dispatched to the client of each shard")
+    }
+}
+
+// a transaction that holds a lock on a shard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Lock {
+    txn_id: TxnId,
+    coordinator: usize,
+    shard_index: usize,
+}
+
+impl<C> ClientShared<C>
+where
+    C: crate::Client + Send + Sync + 'static,
+{
+    fn shard_index(&self, key: u64) -> usize {
+        (key % self.shards.len() as u64) as _
+    }
+
+    fn spawn(self: &Arc<Self>, op: Vec<u8>, read_only: bool, consume: BoxedConsume) -> u32 {
+        let invoke_num = self.invoke_num.fetch_add(1, SeqCst) + 1;
+        let shared = self.clone();
+        // the task removes the invocation after it is inserted
+        let mut invokes = self.invokes.lock().unwrap();
+        let task = tokio::spawn(async move {
+            let result = shared.invoke(op, read_only).await;
+            let invoke = shared.invokes.lock().unwrap().remove(&invoke_num);
+            if let Some((consume, _)) = invoke {
+                consume.apply(result)
+            }
+        });
+        invokes.insert(invoke_num, (consume, task.abort_handle()));
+        invoke_num
+    }
+
+    async fn invoke(&self, op: Vec<u8>, read_only: bool) -> Vec<u8> {
+        let mut shard_indexes = Vec::from_iter(
+            self.partition
+                .keys(&op)
+                .into_iter()
+                .map(|key| self.shard_index(key)),
+        );
+        shard_indexes.sort_unstable();
+        shard_indexes.dedup();
+        let mut blocked = None::<(Lock, Instant)>;
+        loop {
+            let result = match shard_indexes[..] {
+                // an op without keys can be executed by any shard
+                [] => {
+                    let shard_index = rand::thread_rng().gen_range(0..self.shards.len());
+                    self.invoke_single(shard_index, &op, read_only).await
+                }
+                [shard_index] => self.invoke_single(shard_index, &op, read_only).await,
+                _ => self.invoke_txn(&op).await,
+            };
+            match result {
+                Ok(result) => return result,
+                Err(Some(lock)) => match blocked {
+                    Some((blocked_lock, since)) if blocked_lock == lock => {
+                        if since.elapsed() >= LOCK_TIMEOUT {
+                            self.recover(lock).await;
+                            blocked = None
+                        }
+                    }
+                    _ => blocked = Some((lock, Instant::now())),
+                },
+                Err(None) => {}
+            }
+            let backoff = Duration::from_micros(rand::thread_rng().gen_range(0..1000));
+            tokio::time::sleep(backoff).await
+        }
+    }
+
+    async fn invoke_single(
+        &self,
+        shard_index: usize,
+        op: &[u8],
+        read_only: bool,
+    ) -> std::result::Result<Vec<u8>, Option<Lock>> {
+        let shard = &self.shards[shard_index];
+        match call(shard, &Op::Single(op.to_vec()), read_only).await {
+            Result::Done(result) => Ok(result),
+            Result::Conflict(txn_id, coordinator) => Err(Some(Lock {
+                txn_id,
+                coordinator,
+                shard_index,
+            })),
+        }
+    }
+
+    // the error is the conflicting lock if any, or none if the transaction is
+    // aborted by another client
+    async fn invoke_txn(&self, op: &[u8]) -> std::result::Result<Vec<u8>, Option<Lock>> {
+        let txn_id = (self.index, self.txn_num.fetch_add(1, SeqCst));
+        let mut results = Vec::new();
+        // the first one is the coordinator
+        let mut shard_indexes = Vec::new();
+        let mut conflict = None;
+        while let Some(split_op) = self.partition.split(op, &results) {
+            let shard_index = self.shard_index(self.partition.keys(&split_op)[0]);
+            let shard = &self.shards[shard_index];
+            let coordinator = *shard_indexes.first().unwrap_or(&shard_index);
+            match call(shard, &Op::Prepare(txn_id, coordinator, split_op), false).await {
+                Result::Done(result) => results.push(result),
+                Result::Conflict(txn_id, coordinator) => {
+                    conflict = Some(Lock {
+                        txn_id,
+                        coordinator,
+                        shard_index,
+                    })
+                }
+            }
+            // a conflicting shard may still hold the locks of previous ops
+            if !shard_indexes.contains(&shard_index) {
+                shard_indexes.push(shard_index)
+            }
+            if conflict.is_some() {
+                break;
+            }
+        }
+        let (commit, result) = match conflict {
+            Some(_) => (false, Default::default()),
+            None => self.partition.merge(op, results),
+        };
+        let mut decision = commit;
+        if let Some((&coordinator, participants)) = shard_indexes.split_first() {
+            let shard = &self.shards[coordinator];
+            decision = call(shard, &Op::Decide(txn_id, commit), false)
+                .await
+                .decision();
+            let finishes = Vec::from_iter(participants.iter().map(|&shard_index| {
+                call(
+                    &self.shards[shard_index],
+                    &Op::Finish(txn_id, decision),
+                    false,
+                )
+            }));
+            for finish in finishes {
+                finish.await;
+            }
+        }
+        if let Some(lock) = conflict {
+            return Err(Some(lock));
+        }
+        // aborted by a client that takes this one as failed
+        if decision != commit {
+            return Err(None);
+        }
+        Ok(result)
+    }
+
+    // resolve a lock that has been held for too long
+    async fn recover(&self, lock: Lock) {
+        let shard = &self.shards[lock.coordinator];
+        let commit = call(shard, &Op::Decide(lock.txn_id, false), false)
+            .await
+            .decision();
+        if lock.shard_index != lock.coordinator {
+            let shard = &self.shards[lock.shard_index];
+            call(shard, &Op::Finish(lock.txn_id, commit), false).await;
+        }
+    }
+}
+
+// the op is invoked before the first poll, so multiple calls to different
+// shards are concurrent
+fn call<C: crate::Client>(
+    client: &C,
+    op: &Op,
+    read_only: bool,
+) -> impl Future<Output = Result> + Send + 'static {
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    let op = bincode::options().serialize(op).unwrap();
+    let consume = move |result| {
+        let _ = sender.send(result);
+    };
+    if read_only {
+        client.invoke_read_only(op, consume);
+    } else {
+        client.invoke(op, consume);
+    }
+    async move {
+        bincode::options()
+            .deserialize(&receiver.await.unwrap())
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::smallbank;
+
+    use super::*;
+
+    fn execute(app: &mut App, op: Op) -> Result {
+        let result = app.execute(&bincode::options().serialize(&op).unwrap());
+        bincode::options().deserialize(&result).unwrap()
+    }
+
+    fn serialize(op: smallbank::Op) -> Vec<u8> {
+        bincode::options().serialize(&op).unwrap()
+    }
+
+    #[test]
+    fn abort_prepared() {
+        let mut app = App::new(
+            crate::App::new(smallbank::App::new(2)),
+            Arc::new(smallbank::Partition),
+        );
+        let balance = bincode::options()
+            .serialize(&smallbank::Result::BalanceOk(20000))
+            .unwrap();
+        let drain = Op::Prepare((0, 0), 0, serialize(smallbank::Op::Drain(0)));
+        assert_eq!(execute(&mut app, drain), Result::Done(balance.clone()));
+        let query = Op::Single(serialize(smallbank::Op::Balance(0)));
+        assert_eq!(
+            execute(&mut app, query.clone()),
+            Result::Conflict((0, 0), 0)
+        );
+        let other_query = Op::Single(serialize(smallbank::Op::Balance(1)));
+        assert!(matches!(execute(&mut app, other_query), Result::Done(_)));
+        execute(&mut app, Op::Finish((0, 0), false));
+        assert_eq!(execute(&mut app, query), Result::Done(balance));
+    }
+
+    #[test]
+    fn recover_failed_client() {
+        let mut app = App::new(
+            crate::App::new(smallbank::App::new(2)),
+            Arc::new(smallbank::Partition),
+        );
+        let drain = Op::Prepare((0, 0), 0, serialize(smallbank::Op::Drain(0)));
+        execute(&mut app, drain.clone());
+        // another client aborts the transaction, and the late commit of the
+        // failed client gets the abort decision
+        assert!(!execute(&mut app, Op::Decide((0, 0), false)).decision());
+        assert!(!execute(&mut app, Op::Decide((0, 0), true)).decision());
+        assert!(app.locks.is_empty());
+        assert_eq!(execute(&mut app, drain), Result::Conflict((0, 0), 0));
+    }
+}
@@ -0,0 +1,346 @@
+// SmallBank, customers with a checking and a saving account each
+// https://hstore.cs.brown.edu/wordpress/wp-content/uploads/2011/05/smallbank.pdf
+
+use bincode::Options;
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use super::{shard, Application, Generate, Invoke};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op {
+    Balance(u32),
+    DepositChecking(u32, i64),
+    TransactSavings(u32, i64),
+    // move all funds of the first customer into the checking of the second
+    Amalgamate(u32, u32),
+    WriteCheck(u32, i64),
+    // transfer between checking accounts
+    SendPayment(u32, u32, i64),
+    // the single customer steps of the above two across shards, see `Partition`
+    WithdrawChecking(u32, i64),
+    Drain(u32),
+    // for undoing, the checking and saving balances of the customers
+    Restore(Vec<(u32, i64, i64)>),
+}
+
+impl Op {
+    fn customers(&self) -> Vec<u32> {
+        match self {
+            Self::Balance(customer)
+            | Self::DepositChecking(customer, _)
+            | Self::TransactSavings(customer, _)
+            | Self::WriteCheck(customer, _)
+            | Self::WithdrawChecking(customer, _)
+            | Self::Drain(customer) => vec![*customer],
+            Self::Amalgamate(customer, other_customer)
+            | Self::SendPayment(customer, other_customer, _) => vec![*customer, *other_customer],
+            Self::Restore(balances) => balances.iter().map(|(customer, ..)| *customer).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Result {
+    BalanceOk(i64),
+    Ok,
+    InsufficientFunds,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Account {
+    checking: i64,
+    savings: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct App(Vec<Account>);
+
+impl App {
+    const INITIAL_BALANCE: i64 = 10000;
+    // charged when a check overdraws
+    const OVERDRAFT_PENALTY: i64 = 1;
+
+    pub fn new(num_account: usize) -> Self {
+        Self(vec![
+            Account {
+                checking: Self::INITIAL_BALANCE,
+                savings: Self::INITIAL_BALANCE,
+            };
+            num_account
+        ])
+    }
+
+    fn balance(accounts: &[Account], customer: u32) -> Result {
+        let account = &accounts[customer as usize];
+        Result::BalanceOk(account.checking + account.savings)
+    }
+}
+
+impl Application for App {
+    fn execute(&mut self, op: &[u8]) -> Vec<u8> {
+        let op = bincode::options()
+            .allow_trailing_bytes()
+            .deserialize(op)
+            .unwrap();
+        let Self(accounts) = self;
+        let result = match op {
+            Op::Balance(customer) => Self::balance(accounts, customer),
+            Op::DepositChecking(customer, amount) => {
+                accounts[customer as usize].checking += amount;
+                Result::Ok
+            }
+            Op::TransactSavings(customer, amount) => {
+                let account = &mut accounts[customer as usize];
+                if account.savings + amount < 0 {
+                    Result::InsufficientFunds
+                } else {
+                    account.savings += amount;
+                    Result::Ok
+                }
+            }
+            Op::Amalgamate(customer, other_customer) => {
+                let account = &mut accounts[customer as usize];
+                let amount = account.checking + account.savings;
+                *account = Account {
+                    checking: 0,
+                    savings: 0,
+                };
+                accounts[other_customer as usize].checking += amount;
+                Result::Ok
+            }
+            Op::WriteCheck(customer, amount) => {
+                let account = &mut accounts[customer as usize];
+                if account.checking + account.savings < amount {
+                    account.checking -= amount + Self::OVERDRAFT_PENALTY
+                } else {
+                    account.checking -= amount
+                }
+                Result::Ok
+            }
+            Op::SendPayment(customer, other_customer, amount) => {
+                if accounts[customer as usize].checking < amount {
+                    Result::InsufficientFunds
+                } else {
+                    accounts[customer as usize].checking -= amount;
+                    accounts[other_customer as usize].checking += amount;
+                    Result::Ok
+                }
+            }
+            Op::WithdrawChecking(customer, amount) => {
+                let account = &mut accounts[customer as usize];
+                if account.checking < amount {
+                    Result::InsufficientFunds
+                } else {
+                    account.checking -= amount;
+                    Result::Ok
+                }
+            }
+            Op::Drain(customer) => {
+                let result = Self::balance(accounts, customer);
+                accounts[customer as usize] = Account {
+                    checking: 0,
+                    savings: 0,
+                };
+                result
+            }
+            Op::Restore(balances) => {
+                for (customer, checking, savings) in balances {
+                    accounts[customer as usize] = Account { checking, savings }
+                }
+                Result::Ok
+            }
+        };
+        bincode::options().serialize(&result).unwrap()
+    }
+
+    fn execute_read_only(&self, op: &[u8]) -> Vec<u8> {
+        let op = bincode::options()
+            .allow_trailing_bytes()
+            .deserialize(op)
+            .unwrap();
+        let Op::Balance(customer) = op else {
+            unimplemented!("{op:?} is not read-only")
+        };
+        bincode::options()
+            .serialize(&Self::balance(&self.0, customer))
+            .unwrap()
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        bincode::options().serialize(self).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        *self = bincode::options().deserialize(snapshot).unwrap()
+    }
+
+    fn undo(&self, op: &[u8]) -> Vec<u8> {
+        let op = bincode::options()
+            .allow_trailing_bytes()
+            .deserialize::<Op>(op)
+            .unwrap();
+        let balances = op
+            .customers()
+            .into_iter()
+            .map(|customer| {
+                let account = &self.0[customer as usize];
+                (customer, account.checking, account.savings)
+            })
+            .collect();
+        bincode::options()
+            .serialize(&Op::Restore(balances))
+            .unwrap()
+    }
+}
+
+// customers are partitioned by their indexes. a payment or an amalgamation
+// across shards is split into a withdrawal from the first customer and a
+// deposit to the second one, and the payment aborts if the withdrawal fails
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Partition;
+
+impl shard::Partition for Partition {
+    fn keys(&self, op: &[u8]) -> Vec<u64> {
+        let op = bincode::options()
+            .allow_trailing_bytes()
+            .deserialize::<Op>(op)
+            .unwrap();
+        op.customers().into_iter().map(Into::into).collect()
+    }
+
+    fn split(&self, op: &[u8], results: &[Vec<u8>]) -> Option<Vec<u8>> {
+        let op = bincode::options()
+            .allow_trailing_bytes()
+            .deserialize::<Op>(op)
+            .unwrap();
+        let results = Vec::from_iter(
+            results
+                .iter()
+                .map(|result| bincode::options().deserialize::<Result>(result).unwrap()),
+        );
+        let split_op = match (op, &results[..]) {
+            (Op::SendPayment(customer, _, amount), []) => Op::WithdrawChecking(customer, amount),
+            (Op::SendPayment(_, other_customer, amount), [Result::Ok]) => {
+                Op::DepositChecking(other_customer, amount)
+            }
+            (Op::Amalgamate(customer, _), []) => Op::Drain(customer),
+            (Op::Amalgamate(_, other_customer), [Result::BalanceOk(amount)]) => {
+                Op::DepositChecking(other_customer, *amount)
+            }
+            _ => return None,
+        };
+        Some(bincode::options().serialize(&split_op).unwrap())
+    }
+
+    fn merge(&self, op: &[u8], mut results: Vec<Vec<u8>>) -> (bool, Vec<u8>) {
+        let op = bincode::options()
+            .allow_trailing_bytes()
+            .deserialize::<Op>(op)
+            .unwrap();
+        match op {
+            Op::SendPayment(..) => {
+                let result = results.swap_remove(0);
+                let commit =
+                    bincode::options().deserialize::<Result>(&result).unwrap() == Result::Ok;
+                (commit, result)
+            }
+            Op::Amalgamate(..) => (true, bincode::options().serialize(&Result::Ok).unwrap()),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WorkloadConfig {
+    pub num_account: usize,
+    // the first this number of accounts are hot
+    pub num_hot_account: usize,
+    // the portion of accounts that are picked from the hot ones, in percent
+    pub hot_portion: u32,
+    pub read_only: bool,
+}
+
+impl From<control_messages::SmallBankConfig> for WorkloadConfig {
+    fn from(value: control_messages::SmallBankConfig) -> Self {
+        let control_messages::SmallBankConfig {
+            num_account,
+            num_hot_account,
+            hot_portion,
+            read_only,
+        } = value;
+        Self {
+            num_account,
+            num_hot_account,
+            hot_portion,
+            read_only,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Workload {
+    config: WorkloadConfig,
+}
+
+impl Workload {
+    pub fn new(config: WorkloadConfig) -> Self {
+        assert!(config.num_account >= 2);
+        assert!(config.num_hot_account <= config.num_account);
+        assert!(config.hot_portion <= 100);
+        Self { config }
+    }
+
+    pub fn app(config: WorkloadConfig) -> App {
+        App::new(config.num_account)
+    }
+
+    fn customer(&self, rng: &mut dyn RngCore) -> u32 {
+        let customer =
+            if self.config.num_hot_account > 0 && rng.gen_range(0..100) < self.config.hot_portion {
+                rng.gen_range(0..self.config.num_hot_account)
+            } else {
+                rng.gen_range(0..self.config.num_account)
+            };
+        customer as _
+    }
+
+    fn two_customers(&self, rng: &mut dyn RngCore) -> (u32, u32) {
+        let customer = self.customer(rng);
+        let mut other_customer = self.customer(rng);
+        if other_customer == customer {
+            other_customer = (customer + rng.gen_range(1..self.config.num_account as u32))
+                % self.config.num_account as u32
+        }
+        (customer, other_customer)
+    }
+}
+
+impl Generate for Workload {
+    // the transaction mix of the original benchmark
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec<Invoke> {
+        let mut read_only = false;
+        let amount = rng.gen_range(1..=100);
+        let op = match rng.gen_range(0..100) {
+            0..=14 => {
+                read_only = self.config.read_only;
+                Op::Balance(self.customer(rng))
+            }
+            15..=29 => Op::DepositChecking(self.customer(rng), amount),
+            30..=44 => Op::TransactSavings(self.customer(rng), -amount),
+            45..=59 => {
+                let (customer, other_customer) = self.two_customers(rng);
+                Op::Amalgamate(customer, other_customer)
+            }
+            60..=74 => Op::WriteCheck(self.customer(rng), amount),
+            _ => {
+                let (customer, other_customer) = self.two_customers(rng);
+                Op::SendPayment(customer, other_customer, amount)
+            }
+        };
+        vec![Invoke {
+            op: bincode::options().serialize(&op).unwrap(),
+            read_only,
+        }]
+    }
+}
@@ -1,11 +1,16 @@
-use std::{collections::BTreeMap, future::Future, iter::repeat_with, pin::Pin};
+use std::{
+    collections::BTreeMap,
+    iter::repeat_with,
+    sync::atomic::{AtomicUsize, Ordering::SeqCst},
+};
 
 use bincode::Options;
-use rand::{distributions::Alphanumeric, seq::SliceRandom, Rng};
+use rand::{distributions::Alphanumeric, seq::SliceRandom, Rng, RngCore};
 use serde::{Deserialize, Serialize};
-use tokio_util::sync::CancellationToken;
 
-use crate::Client;
+use crate::merkle::{self, SparseProof, SparseTree};
+
+use super::{shard, Application, Generate, Invoke};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Op {
@@ -27,16 +32,49 @@ pub enum Result {
     // batched?
 }
 
+impl Op {
+    fn key(&self) -> &String {
+        let (Self::Read(key)
+        | Self::Scan(key, _)
+        | Self::Update(key, _)
+        | Self::Insert(key, _)
+        | Self::Delete(key)) = self;
+        key
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
-pub struct App(BTreeMap<String, String>);
+pub struct App {
+    table: BTreeMap<String, String>,
+    // authenticated state. every key is committed as the point read of it,
+    // mapping to the read result, so a read result can be proved against the
+    // state root without knowing the op and result encoding
+    state: SparseTree,
+}
+
 impl App {
-    pub fn execute(&mut self, op: &[u8]) -> Vec<u8> {
-        let Self(table) = self;
-        let result = match bincode::options()
-            .allow_trailing_bytes()
-            .deserialize(op)
-            .unwrap()
-        {
+    pub fn new(table: BTreeMap<String, String>) -> Self {
+        let mut state = SparseTree::default();
+        for (key, value) in &table {
+            state.insert(Self::state_key(key), Self::state_value(value))
+        }
+        Self { table, state }
+    }
+
+    fn state_key(key: &str) -> merkle::Digest {
+        merkle::leaf(&bincode::options().serialize(&Op::Read(key.into())).unwrap())
+    }
+
+    fn state_value(value: &str) -> merkle::Digest {
+        merkle::leaf(
+            &bincode::options()
+                .serialize(&Result::ReadOk(value.into()))
+                .unwrap(),
+        )
+    }
+
+    fn read(table: &BTreeMap<String, String>, op: Op) -> Result {
+        match op {
             Op::Read(key) => {
                 if let Some(value) = table.get(&key).cloned() {
                     Result::ReadOk(value)
@@ -52,8 +90,23 @@ impl App {
                     .collect();
                 Result::ScanOk(values)
             }
+            _ => unimplemented!("{op:?} is not read-only"),
+        }
+    }
+}
+
+impl Application for App {
+    fn execute(&mut self, op: &[u8]) -> Vec<u8> {
+        let op = bincode::options()
+            .allow_trailing_bytes()
+            .deserialize(op)
+            .unwrap();
+        let Self { table, state } = self;
+        let result = match op {
+            Op::Read(_) | Op::Scan(..) => Self::read(table, op),
             Op::Update(key, value) => {
                 if let Some(value_mut) = table.get_mut(&key) {
+                    state.insert(Self::state_key(&key), Self::state_value(&value));
                     *value_mut = value;
                     Result::UpdateOk
                 } else {
@@ -61,29 +114,106 @@ impl App {
                 }
             }
             Op::Insert(key, value) => {
+                state.insert(Self::state_key(&key), Self::state_value(&value));
                 table.insert(key, value); // check for override?
                 Result::InsertOk
             }
             Op::Delete(key) => {
                 if table.remove(&key).is_some() {
+                    state.remove(&Self::state_key(&key));
                     Result::DeleteOk
                 } else {
                     Result::NotFound
                 }
             }
         };
-        assert_ne!(result, Result::NotFound);
+        // not found is expected e.g. for reading a key of which the insertion is
+        // not committed yet
         bincode::options().serialize(&result).unwrap()
     }
+
+    fn execute_read_only(&self, op: &[u8]) -> Vec<u8> {
+        let result = Self::read(
+            &self.table,
+            bincode::options()
+                .allow_trailing_bytes()
+                .deserialize(op)
+                .unwrap(),
+        );
+        bincode::options().serialize(&result).unwrap()
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        bincode::options().serialize(&self.table).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        *self = Self::new(bincode::options().deserialize(snapshot).unwrap())
+    }
+
+    fn state_digest(&self) -> merkle::Digest {
+        self.state.root()
+    }
+
+    // only point reads can be proved, scans are not
+    fn prove_read_only(&self, op: &[u8]) -> Option<SparseProof> {
+        let Op::Read(key) = bincode::options()
+            .allow_trailing_bytes()
+            .deserialize(op)
+            .unwrap()
+        else {
+            return None;
+        };
+        Some(self.state.prove(&Self::state_key(&key)))
+    }
+
+    fn undo(&self, op: &[u8]) -> Vec<u8> {
+        let op = bincode::options()
+            .allow_trailing_bytes()
+            .deserialize::<Op>(op)
+            .unwrap();
+        let key = op.key().clone();
+        let undo_op = match (op, self.table.get(&key).cloned()) {
+            (Op::Update(..) | Op::Insert(..), Some(value)) => Op::Update(key, value),
+            (Op::Insert(..), None) => Op::Delete(key),
+            (Op::Delete(_), Some(value)) => Op::Insert(key, value),
+            // no effect
+            _ => Op::Read(key),
+        };
+        bincode::options().serialize(&undo_op).unwrap()
+    }
+}
+
+// keys are hashed into shards, so a scan only covers the shard of its start key
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Partition;
+
+impl shard::Partition for Partition {
+    fn keys(&self, op: &[u8]) -> Vec<u64> {
+        let op = bincode::options()
+            .allow_trailing_bytes()
+            .deserialize::<Op>(op)
+            .unwrap();
+        vec![shard::key(op.key().as_bytes())]
+    }
+
+    // every op touches a single key, so it is never split across shards
+    fn split(&self, op: &[u8], results: &[Vec<u8>]) -> Option<Vec<u8>> {
+        results.is_empty().then(|| op.to_vec())
+    }
+
+    fn merge(&self, _: &[u8], mut results: Vec<Vec<u8>>) -> (bool, Vec<u8>) {
+        (true, results.swap_remove(0))
+    }
 }
 
 #[derive(Debug)]
 pub struct Workload {
-    keys: Vec<String>,
+    config: WorkloadConfig,
     values: Vec<String>,
-    read_portion: u32,
-    update_portion: u32,
-    // rmw_portion: u32,
+    zipfian: Zipfian,
+    // the index of the next inserted key
+    num_inserted_key: AtomicUsize,
 }
 
 impl Workload {
@@ -96,13 +226,41 @@ impl Workload {
         })
     }
 
+    // the keys are hashed from their indexes so inserted keys do not cluster at
+    // the end of the key space, same as the original YCSB
+    fn key(index: usize, key_len: usize) -> String {
+        format!(
+            "user{:0>1$}",
+            fnv_hash(index as _),
+            key_len.saturating_sub(4)
+        )
+    }
+
     pub fn app(config: WorkloadConfig, rng: &mut impl Rng) -> App {
-        let keys = Vec::from_iter(Self::iter_strings(rng, config.key_len).take(config.num_key));
-        let entries = keys
-            .into_iter()
+        let entries = (0..config.num_key)
+            .map(|index| Self::key(index, config.key_len))
             .zip(Self::iter_strings(rng, config.value_len))
             .collect();
-        App(entries)
+        App::new(entries)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Distribution {
+    Uniform,
+    // scrambled zipfian, so the popular keys are spread over the key space
+    Zipfian,
+    // zipfian over the recency of insertion
+    Latest,
+}
+
+impl From<control_messages::Distribution> for Distribution {
+    fn from(value: control_messages::Distribution) -> Self {
+        match value {
+            control_messages::Distribution::Uniform => Self::Uniform,
+            control_messages::Distribution::Zipfian => Self::Zipfian,
+            control_messages::Distribution::Latest => Self::Latest,
+        }
     }
 }
 
@@ -115,6 +273,12 @@ pub struct WorkloadConfig {
     pub read_portion: u32,
     pub update_portion: u32,
     pub rmw_portion: u32,
+    pub scan_portion: u32,
+    pub insert_portion: u32,
+    pub delete_portion: u32,
+    pub max_scan_len: usize,
+    pub distribution: Distribution,
+    pub read_only: bool,
 }
 
 impl From<control_messages::YcsbConfig> for WorkloadConfig {
@@ -127,6 +291,12 @@ impl From<control_messages::YcsbConfig> for WorkloadConfig {
             read_portion,
             update_portion,
             rmw_portion,
+            scan_portion,
+            insert_portion,
+            delete_portion,
+            max_scan_len,
+            distribution,
+            read_only,
         } = value;
         Self {
             num_key,
@@ -136,66 +306,148 @@ impl From<control_messages::YcsbConfig> for WorkloadConfig {
             read_portion,
             update_portion,
             rmw_portion,
+            scan_portion,
+            insert_portion,
+            delete_portion,
+            max_scan_len,
+            distribution: distribution.into(),
+            read_only,
         }
     }
 }
 
 impl Workload {
     pub fn new(config: WorkloadConfig, rng: &mut impl Rng) -> Self {
-        let keys = Self::iter_strings(rng, config.key_len)
-            .take(config.num_key)
-            .collect();
         let values = Self::iter_strings(rng, config.value_len)
             .take(config.num_value)
             .collect();
         assert_eq!(
-            config.read_portion + config.update_portion + config.rmw_portion,
+            config.read_portion
+                + config.update_portion
+                + config.rmw_portion
+                + config.scan_portion
+                + config.insert_portion
+                + config.delete_portion,
             100
         );
         Self {
-            keys,
             values,
-            read_portion: config.read_portion,
-            update_portion: config.update_portion,
-            // rmw_portion,
+            zipfian: Zipfian::new(config.num_key, Zipfian::THETA),
+            num_inserted_key: AtomicUsize::new(config.num_key),
+            config,
         }
     }
 
-    pub fn generate(
-        &self,
-        client: impl Client + Send + Sync + 'static,
-        rng: &mut impl Rng,
-    ) -> Pin<Box<dyn Future<Output = ()> + Send + Sync>> {
-        async fn invoke(client: &(impl Client + Send + Sync), op: Vec<u8>) {
-            let finish = CancellationToken::new();
-            client.invoke(op, {
-                let finish = finish.clone();
-                move |_| finish.cancel()
-            });
-            finish.cancelled().await
-        }
+    fn choose_key(&self, rng: &mut dyn RngCore) -> String {
+        let index = match self.config.distribution {
+            Distribution::Uniform => rng.gen_range(0..self.num_inserted_key.load(SeqCst)),
+            Distribution::Zipfian => {
+                fnv_hash(self.zipfian.sample(rng) as _) as usize % self.config.num_key
+            }
+            Distribution::Latest => self
+                .num_inserted_key
+                .load(SeqCst)
+                .saturating_sub(1 + self.zipfian.sample(rng)),
+        };
+        Self::key(index, self.config.key_len)
+    }
+
+    fn choose_value(&self, rng: &mut dyn RngCore) -> String {
+        self.values.choose(rng).unwrap().clone()
+    }
+}
+
+impl Generate for Workload {
+    fn generate(&self, rng: &mut dyn RngCore) -> Vec<Invoke> {
         let serialize = |op| bincode::options().serialize(&op).unwrap();
+        let read_only = self.config.read_only;
+        let invoke = |op, read_only| Invoke {
+            op: serialize(op),
+            read_only,
+        };
+
+        let mut txn_type = rng.gen_range(0..100);
+        if txn_type < self.config.read_portion {
+            return vec![invoke(Op::Read(self.choose_key(rng)), read_only)];
+        }
+        txn_type -= self.config.read_portion;
+        if txn_type < self.config.update_portion {
+            let op = Op::Update(self.choose_key(rng), self.choose_value(rng));
+            return vec![invoke(op, false)];
+        }
+        txn_type -= self.config.update_portion;
+        if txn_type < self.config.rmw_portion {
+            let key = self.choose_key(rng);
+            let value = self.choose_value(rng);
+            return vec![
+                invoke(Op::Read(key.clone()), read_only),
+                invoke(Op::Update(key, value), false),
+            ];
+        }
+        txn_type -= self.config.rmw_portion;
+        if txn_type < self.config.scan_portion {
+            let len = rng.gen_range(1..=self.config.max_scan_len);
+            return vec![invoke(Op::Scan(self.choose_key(rng), len), read_only)];
+        }
+        txn_type -= self.config.scan_portion;
+        if txn_type < self.config.insert_portion {
+            // clients on different hosts may insert the same keys, which is
+            // executed as overwriting
+            let index = self.num_inserted_key.fetch_add(1, SeqCst);
+            let op = Op::Insert(
+                Self::key(index, self.config.key_len),
+                self.choose_value(rng),
+            );
+            return vec![invoke(op, false)];
+        }
+        vec![invoke(Op::Delete(self.choose_key(rng)), false)]
+    }
+}
+
+fn fnv_hash(value: u64) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in value.to_le_bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3)
+    }
+    hash
+}
+
+// Gray et al., Quickly Generating Billion-Record Synthetic Databases
+// samples in [0, n), smaller values are more popular
+#[derive(Debug)]
+struct Zipfian {
+    n: usize,
+    theta: f64,
+    alpha: f64,
+    zeta_n: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    const THETA: f64 = 0.99;
 
-        let txn_type = rng.gen_range(0..100);
-        if txn_type < self.read_portion {
-            // TODO zipf distribution
-            let op = serialize(Op::Read(self.keys.choose(rng).unwrap().clone()));
-            Box::pin(async move { invoke(&client, op).await })
-        } else if txn_type < self.read_portion + self.update_portion {
-            let op = serialize(Op::Update(
-                self.keys.choose(rng).unwrap().clone(),
-                self.values.choose(rng).unwrap().clone(),
-            ));
-            Box::pin(async move { invoke(&client, op).await })
-        } else {
-            let key = self.keys.choose(rng).unwrap();
-            let value = self.values.choose(rng).unwrap();
-            let op1 = serialize(Op::Read(key.clone()));
-            let op2 = serialize(Op::Update(key.clone(), value.clone()));
-            Box::pin(async move {
-                invoke(&client, op1).await;
-                invoke(&client, op2).await
-            })
+    fn new(n: usize, theta: f64) -> Self {
+        let zeta = |n: usize| (1..=n).map(|i| 1. / (i as f64).powf(theta)).sum::<f64>();
+        let zeta_n = zeta(n);
+        Self {
+            n,
+            theta,
+            alpha: 1. / (1. - theta),
+            zeta_n,
+            eta: (1. - (2. / n as f64).powf(1. - theta)) / (1. - zeta(2) / zeta_n),
+        }
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore) -> usize {
+        let u = rng.gen::<f64>();
+        let uz = u * self.zeta_n;
+        if uz < 1. {
+            return 0;
+        }
+        if uz < 1. + 0.5f64.powf(self.theta) {
+            return 1;
         }
+        ((self.n as f64 * (self.eta * u - self.eta + 1.).powf(self.alpha)) as usize).min(self.n - 1)
     }
 }
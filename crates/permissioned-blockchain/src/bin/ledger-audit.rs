@@ -0,0 +1,60 @@
+// usage: ledger-audit <num_replica> <num_faulty> <ledger>...
+// verify exported ledgers offline against the hardcoded replica keys, and
+// cross check that the ledgers agree on their common prefix
+
+use std::{env::args, process::exit};
+
+use permissioned_blockchain::{
+    context::ordered_multicast::Receiver,
+    crypto::{hardcoded_ed25519, Verifier},
+    ledger::{audit, compare, read},
+};
+
+fn main() {
+    let num_replica = args().nth(1).unwrap().parse::<usize>().unwrap();
+    let num_faulty = args().nth(2).unwrap().parse().unwrap();
+    let paths = args().skip(3).collect::<Vec<_>>();
+    assert!(!paths.is_empty());
+
+    let mut verifier = Verifier::new_standard(Receiver::Unreachable);
+    for index in 0..num_replica {
+        verifier.insert_verifying_key(index as _, hardcoded_ed25519(index).verifying_key())
+    }
+
+    let mut ledgers = Vec::new();
+    let mut valid = true;
+    for path in &paths {
+        let ledger = match read(path) {
+            Ok(ledger) => ledger,
+            // still compare the others, and fail in the end
+            Err(err) => {
+                println!("{path}: {err}");
+                valid = false;
+                continue;
+            }
+        };
+        match audit(&ledger, num_replica, num_faulty, &verifier) {
+            Ok(()) => println!("{path}: {} blocks verified", ledger.len()),
+            Err(err) => {
+                println!("{path}: {err}");
+                valid = false
+            }
+        }
+        ledgers.push((path, ledger))
+    }
+    // against the first ledger that loads
+    if let Some(((first_path, first), rest)) = ledgers.split_first() {
+        for (path, ledger) in rest {
+            match compare(first, ledger) {
+                Ok(len) => println!("{path}: agree with {first_path} on {len} blocks"),
+                Err(i) => {
+                    println!("{path}: diverge from {first_path} at block {i}");
+                    valid = false
+                }
+            }
+        }
+    }
+    if !valid {
+        exit(1)
+    }
+}
@@ -1,12 +1,13 @@
 use std::{
     env::args,
-    iter::repeat,
+    iter::{repeat, repeat_n},
     net::{Ipv4Addr, UdpSocket},
     sync::Arc,
-    thread::{available_parallelism, spawn},
+    thread::spawn,
 };
 
-use permissioned_blockchain::{common::set_affinity, context::ordered_multicast::Sequencer};
+use control_messages::{available_cores, parse_cores, set_affinity};
+use permissioned_blockchain::context::ordered_multicast::Sequencer;
 
 fn main() {
     // let ip = args().nth(1).unwrap().parse::<Ipv4Addr>().unwrap();
@@ -19,12 +20,22 @@ fn main() {
     };
     let multicast_ip = args().nth(3).unwrap().parse::<Ipv4Addr>().unwrap();
 
+    // receive on the first core and sign on each of the others, e.g. AFFINITY=0,2-7
+    let cores = std::env::var("AFFINITY")
+        .map(|spec| parse_cores(&spec))
+        .unwrap_or_else(|_| available_cores());
+    let available = available_cores();
+    assert!(
+        cores.len() >= 2 && cores.iter().all(|core| available.contains(core)),
+        "cores {cores:?} are not enough or not all available, available cores {available:?}"
+    );
+
     let socket = Arc::new(UdpSocket::bind(("0.0.0.0", 60004)).unwrap());
     let messages = flume::bounded(1024);
 
     // this has to go first or compiler cannot guess `messages` type
     let mut run = || {
-        set_affinity(0);
+        set_affinity(cores[0]);
         let mut buf = vec![0; 65536];
         loop {
             let (len, _) = socket.recv_from(&mut buf).unwrap();
@@ -33,13 +44,12 @@ fn main() {
         }
     };
 
-    for ((index, messages), socket) in repeat(messages.1)
-        .take(usize::from(available_parallelism().unwrap()) - 1)
-        .enumerate()
+    for ((messages, &core), socket) in repeat_n(messages.1, cores.len() - 1)
+        .zip(&cores[1..])
         .zip(repeat(socket.clone()))
     {
         spawn(move || {
-            set_affinity(index + 1);
+            set_affinity(core);
             loop {
                 let process = messages.recv().unwrap();
                 process.apply(|buf| {
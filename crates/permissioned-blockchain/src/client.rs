@@ -1,26 +1,23 @@
-use std::{
-    collections::HashMap,
-    iter::repeat,
-    sync::{Arc, Barrier},
-    thread::JoinHandle,
-    time::{Duration, Instant},
-};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
+use control_messages::Affinity;
+use neat::benchmark::{Phases, Ready, RunConfig, Thread};
+use rand::{Rng, RngCore};
 use serde::{de::DeserializeOwned, Serialize};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    app::Workload,
-    common::set_affinity,
-    context::{
-        ordered_multicast::Receiver,
-        tokio::{Multiplex, MultiplexHandle},
-        Addr,
+    app::{
+        shard::{self, Partition},
+        Workload,
     },
+    context::{Addr, TimerId},
     crypto::{Signer, Verifier, Verify},
     ClientIndex, ReplicaIndex,
 };
 
+pub use neat::benchmark::GroupResult;
+
 pub trait OnResult {
     fn apply(self: Box<Self>, result: Vec<u8>);
 }
@@ -48,227 +45,305 @@ impl std::fmt::Debug for BoxedConsume {
 pub trait Client {
     type Message;
 
-    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>);
+    // return the number of the invocation for `abort`. a client may have
+    // multiple outstanding invocations
+    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32;
+
+    // protocols without a read-only fast path simply order the op
+    fn invoke_read_only(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
+        self.invoke(op, consume)
+    }
 
-    fn abort(&self) -> Option<BoxedConsume> {
-        unimplemented!()
+    // none if the invocation is not outstanding, e.g. it is completed
+    fn abort(&self, invoke_num: u32) -> Option<BoxedConsume> {
+        unimplemented!("abort {invoke_num}")
     }
 
     fn handle(&self, message: Self::Message);
 
-    // on timer
+    fn on_timer(&self, id: TimerId) {
+        panic!("{id:?} timeout")
+    }
 }
 
 impl<T: Client> Client for Arc<T> {
     type Message = T::Message;
 
-    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) {
+    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
         T::invoke(self, op, consume)
     }
 
-    fn abort(&self) -> Option<BoxedConsume> {
-        T::abort(self)
+    fn invoke_read_only(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
+        T::invoke_read_only(self, op, consume)
+    }
+
+    fn abort(&self, invoke_num: u32) -> Option<BoxedConsume> {
+        T::abort(self, invoke_num)
     }
 
     fn handle(&self, message: Self::Message) {
         T::handle(self, message)
     }
-}
 
-#[derive(Debug)]
-pub struct Benchmark<C> {
-    clients: HashMap<Addr, Arc<C>>,
-    bootstrap: bool,
-    finish_sender: flume::Sender<(Addr, Duration)>,
-    finish_receiver: flume::Receiver<(Addr, Duration)>,
-    pub latencies: Vec<Duration>,
+    fn on_timer(&self, id: TimerId) {
+        T::on_timer(self, id)
+    }
 }
 
-impl<C> Default for Benchmark<C> {
-    fn default() -> Self {
-        Self::new()
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientError {
+    Timeout,
+    Cancelled,
 }
 
-impl<C> Benchmark<C> {
-    pub fn new() -> Self {
-        let (finish_sender, finish_receiver) = flume::unbounded();
-        Self {
-            clients: Default::default(),
-            bootstrap: true,
-            finish_sender,
-            finish_receiver,
-            latencies: Default::default(),
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "invocation timeout"),
+            Self::Cancelled => write!(f, "invocation cancelled"),
         }
     }
+}
 
-    pub fn insert_client(&mut self, addr: Addr, client: C) {
-        let evicted = self.clients.insert(addr, Arc::new(client));
-        assert!(evicted.is_none())
-    }
+impl std::error::Error for ClientError {}
 
-    pub fn close_loop(
-        &mut self,
-        duration: Duration,
-        workload: &Workload,
-        runtime: tokio::runtime::Handle,
-    ) where
-        C: Client + Send + Sync + 'static,
-    {
-        let invoke = |index, client: Arc<C>| {
-            let txn = workload.generate(client.clone(), &mut rand::thread_rng());
-            let finish_sender = self.finish_sender.clone();
-            async move {
-                let start = Instant::now();
-                txn.await;
-                finish_sender.send((index, start.elapsed())).unwrap()
-            }
-        };
+#[derive(Debug, Clone, Default)]
+pub struct CallOptions {
+    // through the read-only fast path if the protocol supports
+    pub read_only: bool,
+    pub timeout: Option<Duration>,
+    pub cancel: Option<CancellationToken>,
+}
 
-        if self.bootstrap {
-            for (i, (&index, client)) in self.clients.iter().enumerate() {
-                // synchronously finish the first invocation, to avoid first-packet reordering
-                if i == 0 {
-                    runtime.block_on(invoke(index, client.clone()))
-                } else {
-                    runtime.spawn(invoke(index, client.clone()));
-                }
-            }
-            self.bootstrap = false;
+// the async interface of a client, the invocation is aborted on timeout or on
+// cancellation. the future should be driven to finish instead of dropped, or
+// the invocation stays outstanding. multiple calls can be outstanding on a
+// client
+pub async fn call<C>(client: &C, op: Vec<u8>, options: CallOptions) -> Result<Vec<u8>, ClientError>
+where
+    C: Client + Clone + Send + Sync + 'static,
+{
+    let (sender, mut receiver) = tokio::sync::oneshot::channel();
+    let consume = move |result| {
+        let _ = sender.send(result);
+    };
+    let invoke_num = if options.read_only {
+        client.invoke_read_only(op, consume)
+    } else {
+        client.invoke(op, consume)
+    };
+    let timeout = async {
+        match options.timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
         }
-        let deadline = Instant::now() + duration;
-        while let Ok((index, latency)) = self.finish_receiver.recv_deadline(deadline) {
-            self.latencies.push(latency);
-            runtime.spawn(invoke(index, self.clients[&index].clone()));
+    };
+    let cancelled = async {
+        match &options.cancel {
+            Some(cancel) => cancel.cancelled().await,
+            None => std::future::pending().await,
         }
+    };
+    let error = tokio::select! {
+        result = &mut receiver => return Ok(result.unwrap()),
+        () = timeout => ClientError::Timeout,
+        () = cancelled => ClientError::Cancelled,
+    };
+    if client.abort(invoke_num).is_some() {
+        return Err(error);
     }
+    // the result is already on its way
+    Ok(receiver.await.unwrap())
+}
 
-    pub fn run_dispatch(&self) -> impl FnOnce(&mut crate::context::tokio::Multiplex) + Send
+// dispatch the messages of the clients of the shards, which are driven through
+// the `shard::Client` sessions of the benchmark
+pub fn run_dispatch<C>(
+    clients: HashMap<Addr, Arc<C>>,
+) -> impl FnOnce(&mut crate::context::tokio::Multiplex) + Send
+where
+    C: Client + Send + Sync + 'static,
+    C::Message: Serialize + DeserializeOwned + Verify<ReplicaIndex>,
+{
+    struct R<C>(HashMap<Addr, Arc<C>>);
+    impl<C> crate::context::MultiplexReceive for R<C>
     where
-        C: Client + Send + Sync + 'static,
-        C::Message: DeserializeOwned + Verify<ReplicaIndex>,
+        C: Client,
     {
-        struct R<C>(HashMap<Addr, Arc<C>>);
-        impl<C> crate::context::MultiplexReceive for R<C>
-        where
-            C: Client,
-        {
-            type Message = C::Message;
-
-            fn handle(&mut self, receiver: Addr, _: Addr, message: Self::Message) {
-                self.0[&receiver].handle(message)
-            }
+        type Message = C::Message;
+
+        fn handle(&mut self, receiver: Addr, _: Addr, message: Self::Message) {
+            self.0[&receiver].handle(message)
+        }
+
+        fn on_timer(&mut self, receiver: Addr, id: TimerId) {
+            self.0[&receiver].on_timer(id)
+        }
+    }
 
-            fn on_timer(&mut self, receiver: Addr, _: crate::context::TimerId) {
-                panic!("{receiver:?} timeout")
+    let mut receivers = R(clients);
+    move |runtime| runtime.run(&mut receivers, Verifier::<ReplicaIndex>::Nop)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Arrival {
+    Constant(f64),
+    Poisson(f64),
+    Bursty { rate: f64, burst_size: usize },
+}
+
+impl From<control_messages::Arrival> for Arrival {
+    fn from(value: control_messages::Arrival) -> Self {
+        match value {
+            control_messages::Arrival::Constant(rate) => Self::Constant(rate),
+            control_messages::Arrival::Poisson(rate) => Self::Poisson(rate),
+            control_messages::Arrival::Bursty { rate, burst_size } => {
+                Self::Bursty { rate, burst_size }
             }
         }
+    }
+}
+
+impl Arrival {
+    // the same arrival process with the rate split among `n` generators
+    pub fn split(self, n: usize) -> Self {
+        match self {
+            Self::Constant(rate) => Self::Constant(rate / n as f64),
+            Self::Poisson(rate) => Self::Poisson(rate / n as f64),
+            Self::Bursty { rate, burst_size } => Self::Bursty {
+                rate: rate / n as f64,
+                burst_size,
+            },
+        }
+    }
+}
 
-        let mut receivers = R(self.clients.clone());
-        move |runtime| runtime.run(&mut receivers, Verifier::<ReplicaIndex>::Nop)
+impl neat::benchmark::Arrival for Arrival {
+    fn interval(&self, rng: &mut dyn RngCore) -> Duration {
+        let exponential = |rng: &mut _, mean: f64| -(1. - Rng::gen::<f64>(rng)).ln() * mean;
+        Duration::from_secs_f64(match *self {
+            Self::Constant(rate) => 1. / rate,
+            Self::Poisson(rate) => exponential(rng, 1. / rate),
+            // a burst ends after each arrival with probability 1 / burst_size,
+            // so the bursts are on average burst_size long, and they start at
+            // a rate that keeps the overall rate
+            Self::Bursty { rate, burst_size } => {
+                if rng.gen_bool(1. / burst_size as f64) {
+                    exponential(rng, burst_size as f64 / rate)
+                } else {
+                    0.
+                }
+            }
+        })
     }
 }
 
 #[derive(Debug)]
 pub struct RunBenchmarkConfig {
+    // of all shards, see `crate::Config::shard`
     pub replication_config: crate::Config,
+    pub num_shard: usize,
+    pub partition: Arc<dyn Partition>,
     pub offset: usize,
     pub num_group: usize,
     pub num_client: usize,
-    pub duration: Duration,
+    pub phases: Phases,
     pub workload: Workload,
+    // of the whole client host, closed loop if none
+    pub arrival: Option<Arrival>,
+    // per client
+    pub num_outstanding: usize,
+    pub affinity: Affinity,
+    pub ready: Option<Ready>,
 }
 
-// a benchmark runner that is already almost decoupled with replication
-// just a few tweak away from lifting into context library
-// maybe do so when there's other uses than in this crate
-
 pub fn run_benchmark<C>(
     config: RunBenchmarkConfig,
     new_client: impl Fn(crate::Context<C::Message>, ClientIndex) -> C,
-) -> Vec<Duration>
+) -> Vec<GroupResult>
 where
     C: Client + Send + Sync + 'static,
     C::Message: Serialize + DeserializeOwned + Verify<ReplicaIndex>,
 {
-    struct Group<C> {
-        benchmark_thread: JoinHandle<Benchmark<C>>,
-        runtime_thread: JoinHandle<()>,
-        dispatch_thread: JoinHandle<()>,
-        dispatch_handle: MultiplexHandle,
-    }
-
     // println!("{config:?}");
-    let barrier = Arc::new(Barrier::new(config.num_group));
-    let replication_config = Arc::new(config.replication_config);
-    let groups = Vec::from_iter(
-        repeat((barrier, Arc::new(config.workload)))
-            .take(config.num_group)
-            .enumerate()
-            .map(|(group_index, (barrier, workload))| {
-                let runtime = tokio::runtime::Builder::new_current_thread()
-                    .enable_all()
-                    .build()
-                    .unwrap();
-                let handle = runtime.handle().clone();
-                let mut multiplex = Multiplex::new(handle.clone(), Receiver::Unreachable);
-
-                let mut benchmark = Benchmark::new();
-                for group_offset in 0..config.num_client {
-                    let index = config.offset + group_index * config.num_client + group_offset;
-                    let addr = replication_config.client_addrs[index];
-                    let client = new_client(
+    let replication_configs = Vec::from_iter((0..config.num_shard).map(|shard_index| {
+        Arc::new(
+            config
+                .replication_config
+                .shard(config.num_shard, shard_index),
+        )
+    }));
+    let run_config = RunConfig {
+        num_group: config.num_group,
+        phases: config.phases,
+        arrival: config
+            .arrival
+            .map(|arrival| Arc::new(arrival.split(config.num_group)) as _),
+        num_outstanding: config.num_outstanding,
+        ready: config.ready,
+    };
+    config.affinity.validate();
+    let affinity = config.affinity;
+    neat::benchmark::run(
+        run_config,
+        config.workload,
+        move |group_index, thread| match thread {
+            Thread::Runtime => affinity.set_runtime(group_index),
+            Thread::Dispatch => affinity.set_dispatch(group_index),
+            Thread::Benchmark => affinity.set_benchmark(group_index),
+        },
+        |group_index, multiplex| {
+            let mut sessions = Vec::new();
+            let mut shard_clients = HashMap::new();
+            for group_offset in 0..config.num_client {
+                let index = config.offset + group_index * config.num_client + group_offset;
+                let shards = Vec::from_iter(replication_configs.iter().map(|config| {
+                    let addr = config.client_addrs[index];
+                    let client = Arc::new(new_client(
                         multiplex
                             .register(addr, Signer::new_standard(None))
-                            .into_replication(replication_config.clone()),
+                            .into_replication(config.clone()),
                         index as _,
-                    );
-                    benchmark.insert_client(addr, client);
-                }
+                    ));
+                    shard_clients.insert(addr, client.clone());
+                    client
+                }));
+                sessions.push(Arc::new(shard::Client::new(
+                    index as _,
+                    shards,
+                    config.partition.clone(),
+                )))
+            }
+            (sessions, run_dispatch(shard_clients))
+        },
+    )
+}
 
-                let cancel = CancellationToken::new();
-                let runtime_thread = std::thread::spawn({
-                    set_affinity(group_index * 2);
-                    let cancel = cancel.clone();
-                    move || runtime.block_on(cancel.cancelled())
-                });
-
-                let dispatch_handle = multiplex.handle();
-                let run = benchmark.run_dispatch();
-                let dispatch_thread = std::thread::spawn(move || {
-                    set_affinity(group_index * 2 + 1);
-                    run(&mut multiplex);
-                    cancel.cancel()
-                });
-
-                let benchmark_thread = std::thread::spawn(move || {
-                    set_affinity(group_index * 2 + 1);
-                    if group_index == 0 {
-                        benchmark.close_loop(Duration::from_secs(1), &workload, handle.clone());
-                    }
-                    barrier.wait();
-                    benchmark.close_loop(Duration::from_secs(1), &workload, handle.clone());
-                    benchmark.latencies.clear();
-                    benchmark.close_loop(config.duration, &workload, handle);
-                    benchmark
-                });
-
-                Group {
-                    benchmark_thread,
-                    runtime_thread,
-                    dispatch_thread,
-                    dispatch_handle,
-                }
-            }),
-    );
-
-    let mut latencies = Vec::new();
-    for group in groups {
-        let benchmark = group.benchmark_thread.join().unwrap();
-        latencies.extend(benchmark.latencies);
-        group.dispatch_handle.stop();
-        group.dispatch_thread.join().unwrap();
-        group.runtime_thread.join().unwrap();
+#[cfg(test)]
+mod tests {
+    use neat::benchmark::Arrival as _;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn arrival_rate() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for arrival in [
+            Arrival::Constant(1000.),
+            Arrival::Poisson(1000.),
+            Arrival::Bursty {
+                rate: 1000.,
+                burst_size: 10,
+            },
+        ] {
+            let total = (0..100_000)
+                .map(|_| arrival.interval(&mut rng))
+                .sum::<Duration>();
+            assert!(
+                (total.as_secs_f64() - 100.).abs() < 5.,
+                "{arrival:?} {total:?}"
+            )
+        }
     }
-    latencies
 }
@@ -1,14 +1,22 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::Hash,
+    sync::OnceLock,
+    time::Duration,
+};
 
 use k256::sha2::Digest;
 use neat::crypto::Hasher;
-use nix::{
-    sched::{sched_setaffinity, CpuSet},
-    unistd::Pid,
-};
 use serde::{Deserialize, Serialize};
 
-use crate::context::{Context, TimerId};
+use crate::{
+    client::BoxedConsume,
+    context::{Context, TimerId},
+    crypto::Sign,
+    ledger,
+    merkle::{self, SparseProof, Tree},
+    App, ClientIndex, ReplicaIndex, To,
+};
 
 #[derive(Debug)]
 pub struct Timer {
@@ -36,19 +44,335 @@ impl Timer {
     }
 }
 
-pub fn set_affinity(index: usize) {
-    let mut cpu_set = CpuSet::new();
-    cpu_set.set(index).unwrap();
-    sched_setaffinity(Pid::from_raw(0), &cpu_set).unwrap()
-}
+// replica counters that are reported through `Context::count`
+pub const BLOCK_COMMITTED: &str = "block-committed";
+pub const VIEW_CHANGE: &str = "view-change";
+// the comparisons with other replicas that find a divergence, see `StateCheck`
+pub const STATE_DIVERGED: &str = "state-diverged";
+// the height of the first diverging block, counted once
+pub const FIRST_DIVERGED_BLOCK: &str = "first-diverged-block";
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Request {
-    pub client_index: crate::ClientIndex,
+    pub client_index: ClientIndex,
+    pub request_num: u32,
+    // the client has completed or aborted all of its requests numbered below
+    // this, so the replicas can forget their replies, see `ClientTable`
+    pub ack_num: u32,
+    pub op: Vec<u8>,
+}
+
+// read-only requests bypass ordering and are executed against the committed
+// state of each replica, the client collects n - f matching replies
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ReadOnlyRequest {
+    pub client_index: ClientIndex,
     pub request_num: u32,
     pub op: Vec<u8>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ReadOnlyReply {
+    pub request_num: u32,
+    pub result: Vec<u8>,
+    pub state_root: merkle::Digest,
+    pub proof: Option<SparseProof>,
+    pub replica_index: ReplicaIndex,
+}
+
+// collected by client, with the state root if the result is proved
+type ReadOnlyResults = HashMap<ReplicaIndex, (Vec<u8>, Option<merkle::Digest>)>;
+
+impl ReadOnlyReply {
+    pub fn new(app: &App, request: &ReadOnlyRequest, replica_index: ReplicaIndex) -> Self {
+        Self {
+            request_num: request.request_num,
+            result: app.execute_read_only(&request.op),
+            state_root: app.state_digest(),
+            proof: app.prove_read_only(&request.op),
+            replica_index,
+        }
+    }
+
+    // a proved result is accepted once n - f replies agree on the state root
+    // as well as on the result, i.e., the root is certified by a read quorum
+    pub fn is_proved(&self, op: &[u8]) -> bool {
+        self.proof.as_ref().is_some_and(|proof| {
+            proof.verify(
+                &merkle::leaf(op),
+                Some(&merkle::leaf(&self.result)),
+                &self.state_root,
+            )
+        })
+    }
+}
+
+// the client state that is common to the protocols with the read-only fast
+// path. the protocol decides where the ordered requests go and when their
+// replies match, by acting on the returned `ClientStep`s
+#[derive(Debug)]
+pub struct ClientCore<M, R> {
+    pub context: crate::Context<M>,
+    pub index: ClientIndex,
+    pub request_num: u32,
+    // the outstanding invocations by their request numbers
+    pub invokes: BTreeMap<u32, ClientInvoke<R>>,
+    // set while there is any outstanding invocation, and reset whenever one of
+    // them completes
+    pub resend_timer: Timer,
+}
+
+#[derive(Debug)]
+pub struct ClientInvoke<R> {
+    pub op: Vec<u8>,
+    ack_num: u32,
+    pub replies: HashMap<ReplicaIndex, R>,
+    pub consume: BoxedConsume,
+    // none after falling back to ordered execution
+    read_only_results: Option<ReadOnlyResults>,
+}
+
+#[derive(Debug)]
+pub enum ClientStep<R> {
+    Pending,
+    // the ordered request is not replied in time
+    Resend(Request),
+    // the read-only path fails, the request should be ordered instead
+    FallBack(Request),
+    Complete(ClientInvoke<R>, Vec<u8>),
+}
+
+impl<M, R> ClientCore<M, R> {
+    pub fn new(context: crate::Context<M>, index: ClientIndex) -> Self {
+        Self {
+            context,
+            index,
+            request_num: 0,
+            invokes: Default::default(),
+            resend_timer: Timer::new(Duration::from_millis(100)),
+        }
+    }
+
+    // the request of an outstanding invocation, the same one on every resend
+    pub fn request(&self, request_num: u32) -> Request {
+        let invoke = &self.invokes[&request_num];
+        Request {
+            client_index: self.index,
+            request_num,
+            ack_num: invoke.ack_num,
+            op: invoke.op.clone(),
+        }
+    }
+
+    // the returned request is for the protocol to send
+    pub fn invoke(&mut self, op: Vec<u8>, consume: BoxedConsume) -> Request {
+        self.begin(op, consume, None);
+        self.request(self.request_num)
+    }
+
+    fn begin(&mut self, op: Vec<u8>, consume: BoxedConsume, results: Option<ReadOnlyResults>) {
+        self.request_num += 1;
+        let ack_num = self
+            .invokes
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or(self.request_num);
+        self.invokes.insert(
+            self.request_num,
+            ClientInvoke {
+                op,
+                ack_num,
+                replies: Default::default(),
+                consume,
+                read_only_results: results,
+            },
+        );
+        if self.resend_timer.id.is_none() {
+            self.resend_timer.set(&mut self.context)
+        }
+    }
+
+    pub fn abort(&mut self, request_num: u32) -> Option<BoxedConsume> {
+        let invoke = self.invokes.remove(&request_num)?;
+        if self.invokes.is_empty() {
+            self.resend_timer.unset(&mut self.context)
+        }
+        Some(invoke.consume)
+    }
+
+    // the outstanding invocation that `reply_num` is replied for, if any
+    pub fn invoke_mut(&mut self, reply_num: u32) -> Option<&mut ClientInvoke<R>> {
+        self.invokes.get_mut(&reply_num)
+    }
+
+    pub fn complete(&mut self, request_num: u32) -> ClientInvoke<R> {
+        let invoke = self.invokes.remove(&request_num).unwrap();
+        if self.invokes.is_empty() {
+            self.resend_timer.unset(&mut self.context)
+        } else {
+            self.resend_timer.reset(&mut self.context)
+        }
+        invoke
+    }
+
+    fn fall_back(&mut self, request_num: u32) -> ClientStep<R> {
+        self.invokes
+            .get_mut(&request_num)
+            .unwrap()
+            .read_only_results = None;
+        ClientStep::FallBack(self.request(request_num))
+    }
+
+    pub fn on_timer(&mut self, id: TimerId) -> Vec<ClientStep<R>> {
+        if self.resend_timer.id != Some(id) {
+            return Default::default();
+        }
+        let request_nums = Vec::from_iter(self.invokes.keys().copied());
+        Vec::from_iter(request_nums.into_iter().map(|request_num| {
+            if self.invokes[&request_num].read_only_results.is_none() {
+                ClientStep::Resend(self.request(request_num))
+            } else {
+                // not enough read-only replies, possibly from an unavailable
+                // replica
+                self.fall_back(request_num)
+            }
+        }))
+    }
+}
+
+impl<M: Sign<ReadOnlyRequest> + Serialize + Clone, R> ClientCore<M, R> {
+    pub fn invoke_read_only(&mut self, op: Vec<u8>, consume: BoxedConsume) -> u32 {
+        self.begin(op.clone(), consume, Some(Default::default()));
+        let request = ReadOnlyRequest {
+            client_index: self.index,
+            request_num: self.request_num,
+            op,
+        };
+        self.context.send(To::AllReplica, request);
+        self.request_num
+    }
+
+    pub fn handle_read_only_reply(&mut self, reply: ReadOnlyReply) -> ClientStep<R> {
+        // also the number of replicas that commit a block, so that a read
+        // quorum includes a correct replica that observes the latest commit
+        let quorum = self.context.num_replica() - self.context.num_faulty();
+        let Some(invoke) = self.invoke_mut(reply.request_num) else {
+            return ClientStep::Pending;
+        };
+        let Some(results) = &mut invoke.read_only_results else {
+            return ClientStep::Pending;
+        };
+        if reply.proof.is_some() && !reply.is_proved(&invoke.op) {
+            return ClientStep::Pending; // from a faulty replica
+        }
+        let proved_root = reply.proof.as_ref().map(|_| reply.state_root);
+        if results
+            .values()
+            .any(|(result, root)| (result, *root) != (&reply.result, proved_root))
+        {
+            // replicas disagree on committed state, e.g. some of them are lagging
+            self.resend_timer.reset(&mut self.context);
+            return self.fall_back(reply.request_num);
+        }
+        results.insert(reply.replica_index, (reply.result.clone(), proved_root));
+        if results.len() == quorum {
+            ClientStep::Complete(self.complete(reply.request_num), reply.result)
+        } else {
+            ClientStep::Pending
+        }
+    }
+}
+
+// the replies to the requests of each client, for deduplicating requests and
+// replying the resent ones. the replies of the requests that the client has
+// acknowledged are forgotten, and those requests are taken as executed. the
+// acknowledgements only apply on execution, so every replica takes the same
+// requests as executed at the same point of the chain
+#[derive(Debug)]
+pub struct ClientTable<R> {
+    clients: HashMap<ClientIndex, ClientEntry<R>>,
+}
+
+#[derive(Debug)]
+struct ClientEntry<R> {
+    ack_num: u32,
+    // none if the request is not executed yet
+    replies: BTreeMap<u32, Option<R>>,
+}
+
+impl<R> Default for ClientTable<R> {
+    fn default() -> Self {
+        Self {
+            clients: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestStatus<'a, R> {
+    New,
+    // received before and not executed yet, or acknowledged
+    Pending,
+    Replied(&'a R),
+}
+
+impl<R> ClientTable<R> {
+    fn entry(&mut self, request: &Request) -> &mut ClientEntry<R> {
+        self.clients
+            .entry(request.client_index)
+            .or_insert_with(|| ClientEntry {
+                ack_num: 0,
+                replies: Default::default(),
+            })
+    }
+
+    // a new request is recorded as pending
+    pub fn insert_request(&mut self, request: &Request) -> RequestStatus<'_, R> {
+        let entry = self.entry(request);
+        if request.request_num < entry.ack_num {
+            return RequestStatus::Pending;
+        }
+        match entry.replies.entry(request.request_num) {
+            std::collections::btree_map::Entry::Vacant(vacant) => {
+                vacant.insert(None);
+                RequestStatus::New
+            }
+            std::collections::btree_map::Entry::Occupied(occupied) => match occupied.into_mut() {
+                Some(reply) => RequestStatus::Replied(reply),
+                None => RequestStatus::Pending,
+            },
+        }
+    }
+
+    pub fn is_executed(&self, request: &Request) -> bool {
+        self.clients
+            .get(&request.client_index)
+            .is_some_and(|entry| {
+                request.request_num < entry.ack_num
+                    || matches!(entry.replies.get(&request.request_num), Some(Some(_)))
+            })
+    }
+
+    pub fn insert_reply(&mut self, request: &Request, reply: R) {
+        let entry = self.entry(request);
+        if request.ack_num > entry.ack_num {
+            entry.ack_num = request.ack_num;
+            entry.replies = entry.replies.split_off(&request.ack_num)
+        }
+        if request.request_num >= entry.ack_num {
+            entry.replies.insert(request.request_num, Some(reply));
+        }
+    }
+
+    // whether any received request is not executed yet
+    pub fn has_pending(&self) -> bool {
+        self.clients
+            .values()
+            .any(|entry| entry.replies.values().any(Option::is_none))
+    }
+}
+
 pub type BlockDigest = [u8; 32];
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
@@ -56,20 +380,82 @@ pub struct Block {
     pub requests: Vec<Request>,
     pub parent_digest: BlockDigest,
     pub height: u32,
+    // the other fields are not modified after the block is constructed
+    #[serde(skip)]
+    digest: DigestCache,
 }
 
-impl Block {
+// the digest rebuilds the requests tree, so it is computed once on demand
+#[derive(Debug, Clone, Default)]
+struct DigestCache(OnceLock<BlockDigest>);
+
+impl PartialEq for DigestCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for DigestCache {}
+
+impl Hash for DigestCache {
+    fn hash<H: std::hash::Hasher>(&self, _: &mut H) {}
+}
+
+// the block digest commits to the requests through a merkle root, so a header
+// and a proof are enough to show a request is in the block
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub parent_digest: BlockDigest,
+    pub height: u32,
+    pub requests_root: merkle::Digest,
+}
+
+impl BlockHeader {
     pub fn digest(&self) -> BlockDigest {
         Hasher::sha256(self).finalize().into()
     }
 }
 
+impl Block {
+    pub fn new(requests: Vec<Request>, parent_digest: BlockDigest, height: u32) -> Self {
+        Self {
+            requests,
+            parent_digest,
+            height,
+            digest: Default::default(),
+        }
+    }
+
+    pub fn digest(&self) -> BlockDigest {
+        *self.digest.0.get_or_init(|| self.header().digest())
+    }
+
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            parent_digest: self.parent_digest,
+            height: self.height,
+            requests_root: self.requests_tree().root(),
+        }
+    }
+
+    pub fn requests_tree(&self) -> Tree {
+        Tree::new(self.requests.iter().map(Request::leaf).collect())
+    }
+}
+
+impl Request {
+    pub fn leaf(&self) -> merkle::Digest {
+        merkle::leaf(&Hasher::sha256(self).finalize())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Chain {
     pub digest_parent: BlockDigest,
     pub digest_execute: BlockDigest,
     height: u32,
-    pending_execute: HashMap<BlockDigest, BlockDigest>,
+    height_execute: u32,
+    pending_execute: HashMap<BlockDigest, (BlockDigest, u32)>,
 }
 
 impl Chain {
@@ -78,6 +464,7 @@ impl Chain {
             digest_parent: Self::genesis().digest(),
             height: 0,
             digest_execute: Self::genesis().digest(),
+            height_execute: 0,
             pending_execute: Default::default(),
         }
     }
@@ -94,29 +481,24 @@ impl Default for Chain {
 }
 
 impl Chain {
-    pub const MAX_BATCH_SIZE: usize = 100;
-
-    pub fn propose(&mut self, requests: &mut Vec<Request>) -> Block {
+    pub fn propose_batch(&mut self, requests: Vec<Request>) -> Block {
         assert!(!requests.is_empty());
         self.height += 1;
-        let block = Block {
-            requests: requests
-                .drain(..requests.len().min(Self::MAX_BATCH_SIZE))
-                .collect(),
-            parent_digest: self.digest_parent,
-            height: self.height,
-        };
+        let block = Block::new(requests, self.digest_parent, self.height);
         self.digest_parent = block.digest();
         block
     }
 
+    // for protocols that may propose on top of a block proposed by others, e.g.
+    // after a leader change
+    pub fn rebase(&mut self, digest: BlockDigest, height: u32) {
+        self.digest_parent = digest;
+        self.height = height
+    }
+
     pub fn propose_empty(&mut self) -> Block {
         self.height += 1;
-        let block = Block {
-            requests: Default::default(),
-            parent_digest: self.digest_parent,
-            height: self.height,
-        };
+        let block = Block::new(Default::default(), self.digest_parent, self.height);
         self.digest_parent = block.digest();
         block
     }
@@ -124,22 +506,411 @@ impl Chain {
     pub fn commit(&mut self, block: &Block) -> bool {
         if block.parent_digest == self.digest_execute {
             self.digest_execute = block.digest();
+            self.height_execute = block.height;
             true
         } else {
             let evicted = self
                 .pending_execute
-                .insert(block.parent_digest, block.digest());
+                .insert(block.parent_digest, (block.digest(), block.height));
             assert!(evicted.is_none(), "commit conflicting blocks");
             false
         }
     }
 
+    // for protocols that execute speculatively, execute again from the block
+    pub fn rollback(&mut self, digest: BlockDigest, height: u32) {
+        self.digest_execute = digest;
+        self.height_execute = height
+    }
+
     pub fn next_execute(&mut self) -> Option<BlockDigest> {
-        if let Some(block_digest) = self.pending_execute.remove(&self.digest_execute) {
+        if let Some((block_digest, height)) = self.pending_execute.remove(&self.digest_execute) {
             self.digest_execute = block_digest;
+            self.height_execute = height;
             Some(block_digest)
         } else {
             None
         }
     }
 }
+
+impl Chain {
+    // blocks proposed locally that are not executed yet
+    pub fn num_in_flight(&self) -> u32 {
+        self.height.saturating_sub(self.height_execute)
+    }
+
+    pub fn executed_height(&self) -> u32 {
+        self.height_execute
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    pub max_size: usize,
+    pub max_bytes: usize,
+    pub max_delay: Duration,
+    pub adaptive: bool,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        control_messages::Batching::default().into()
+    }
+}
+
+impl From<control_messages::Batching> for BatchConfig {
+    fn from(value: control_messages::Batching) -> Self {
+        let control_messages::Batching {
+            max_size,
+            max_bytes,
+            max_delay,
+            adaptive,
+        } = value;
+        Self {
+            max_size,
+            max_bytes,
+            max_delay,
+            adaptive,
+        }
+    }
+}
+
+// decides when the primary should propose and how many requests go into the
+// block. without a delay nor adaptive policy this is the original behavior
+// i.e. propose on every pace with up to `max_size` requests
+//
+// also works as the flow control of pipelined protocols: no proposal is ready
+// while the window of in flight blocks is full, and pending requests keep
+// accumulating into a larger batch meanwhile
+#[derive(Debug)]
+pub struct Batcher {
+    config: BatchConfig,
+    target_size: usize,
+    delay_timer: Timer,
+    delay_expired: bool,
+}
+
+impl Batcher {
+    pub fn new(config: BatchConfig) -> Self {
+        assert_ne!(config.max_size, 0);
+        Self {
+            config,
+            target_size: if config.adaptive { 1 } else { config.max_size },
+            delay_timer: Timer::new(config.max_delay),
+            delay_expired: false,
+        }
+    }
+
+    pub fn ready<M>(
+        &mut self,
+        requests: &[Request],
+        num_in_flight: u32,
+        context: &mut Context<M>,
+    ) -> bool {
+        if requests.is_empty() {
+            return false;
+        }
+        if self.delay_expired
+            || requests.len() >= self.target_size
+            || self.batch_len(requests) < requests.len().min(self.config.max_size)
+            // nothing to wait for, latency goes first
+            || (self.config.adaptive && num_in_flight == 0)
+            || (!self.config.adaptive && self.config.max_delay.is_zero())
+        {
+            return true;
+        }
+        if !self.config.max_delay.is_zero() && self.delay_timer.id.is_none() {
+            self.delay_timer.set(context)
+        }
+        false
+    }
+
+    // number of requests that fit into one block
+    fn batch_len(&self, requests: &[Request]) -> usize {
+        let mut num_bytes = 0;
+        let len = requests
+            .iter()
+            .take(self.config.max_size)
+            .take_while(|request| {
+                num_bytes += request.op.len();
+                num_bytes <= self.config.max_bytes
+            })
+            .count();
+        len.max(1)
+    }
+
+    pub fn take<M>(
+        &mut self,
+        requests: &mut Vec<Request>,
+        num_in_flight: u32,
+        context: &mut Context<M>,
+    ) -> Vec<Request> {
+        if self.config.adaptive {
+            self.target_size = if num_in_flight == 0 {
+                (self.target_size / 2).max(1)
+            } else {
+                (self.target_size * 2).min(self.config.max_size)
+            }
+        }
+        if self.delay_timer.id.is_some() {
+            self.delay_timer.unset(context)
+        }
+        self.delay_expired = false;
+        requests.drain(..self.batch_len(requests)).collect()
+    }
+
+    // return true if the timer belongs to batcher, and the caller should check
+    // for proposing again
+    pub fn on_timer<M>(&mut self, id: TimerId, context: &mut Context<M>) -> bool {
+        if self.delay_timer.id != Some(id) {
+            return false;
+        }
+        self.delay_timer.unset(context);
+        self.delay_expired = true;
+        true
+    }
+}
+
+// periodically compare the application state with the other replicas, so a
+// nondeterministic execution gets noticed
+//
+// the state is compared every `interval` blocks, and a checkpoint also carries
+// a digest of every block since the previous one, over the block's results and
+// the authenticated state root. so the first diverging block is exact if the
+// results diverge or the application is authenticated, otherwise it is the
+// checkpoint height. divergences are reported through `STATE_DIVERGED` and
+// `FIRST_DIVERGED_BLOCK`
+//
+// done by PBFT, HotStuff and MinBFT. Zyzzyva executes speculatively and may
+// roll back, Neo, Jolteon and HoneyBadgerBFT have no checkpoint message yet,
+// and the unreplicated mode has nothing to compare against
+#[derive(Debug)]
+pub struct StateCheck {
+    // zero to disable
+    pub interval: u32,
+    // of the blocks executed since the latest checkpoint
+    block_digests: Vec<[u8; 32]>,
+    // taken by this replica, with the other replicas compared with it
+    checkpoints: BTreeMap<u32, (StateCheckpoint, HashSet<ReplicaIndex>)>,
+    // received before executing to the height
+    pending: BTreeMap<u32, Vec<(ReplicaIndex, StateCheckpoint)>>,
+    // the heights up to this one are dropped
+    checked_height: u32,
+    pub diverged_height: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StateCheckpoint {
+    pub state_digest: [u8; 32],
+    // of the blocks since the previous checkpoint, in order
+    pub block_digests: Vec<[u8; 32]>,
+}
+
+impl StateCheck {
+    pub fn new(interval: u32) -> Self {
+        Self {
+            interval,
+            block_digests: Default::default(),
+            checkpoints: Default::default(),
+            pending: Default::default(),
+            checked_height: 0,
+            diverged_height: None,
+        }
+    }
+
+    // called after executing every block. return the checkpoint to be sent to
+    // the other replicas, if the height is checked
+    pub fn on_execute<M>(
+        &mut self,
+        height: u32,
+        results: &[Vec<u8>],
+        app: &App,
+        context: &crate::Context<M>,
+    ) -> Option<StateCheckpoint> {
+        if self.interval == 0 {
+            return None;
+        }
+        let block_digest = Hasher::sha256(&(ledger::result_digest(results), app.state_digest()));
+        self.block_digests.push(block_digest.finalize().into());
+        if !height.is_multiple_of(self.interval) {
+            return None;
+        }
+        let checkpoint = StateCheckpoint {
+            state_digest: app.snapshot_digest(),
+            block_digests: std::mem::take(&mut self.block_digests),
+        };
+        self.checkpoints
+            .insert(height, (checkpoint.clone(), Default::default()));
+        for (replica_index, other) in self.pending.remove(&height).unwrap_or_default() {
+            self.compare(height, replica_index, &other, context)
+        }
+        Some(checkpoint)
+    }
+
+    pub fn insert<M>(
+        &mut self,
+        height: u32,
+        replica_index: ReplicaIndex,
+        checkpoint: StateCheckpoint,
+        context: &crate::Context<M>,
+    ) {
+        if height <= self.checked_height {
+            return;
+        }
+        if self.checkpoints.contains_key(&height) {
+            self.compare(height, replica_index, &checkpoint, context)
+        } else {
+            self.pending
+                .entry(height)
+                .or_default()
+                .push((replica_index, checkpoint))
+        }
+    }
+
+    fn compare<M>(
+        &mut self,
+        height: u32,
+        replica_index: ReplicaIndex,
+        other: &StateCheckpoint,
+        context: &crate::Context<M>,
+    ) {
+        let (checkpoint, compared) = self.checkpoints.get_mut(&height).unwrap();
+        if !compared.insert(replica_index) {
+            return;
+        }
+        // a replica that recovers in the middle has fewer block digests, so they
+        // are aligned by the end
+        let num_block = checkpoint
+            .block_digests
+            .len()
+            .min(other.block_digests.len());
+        let diverged_block = checkpoint.block_digests[checkpoint.block_digests.len() - num_block..]
+            .iter()
+            .zip(&other.block_digests[other.block_digests.len() - num_block..])
+            .position(|(digest, other_digest)| digest != other_digest)
+            .map(|i| height - (num_block - 1 - i) as u32);
+        let diverged_height = diverged_block
+            .or(Some(height).filter(|_| checkpoint.state_digest != other.state_digest));
+        // n - f replicas including this one have compared, the remaining ones
+        // are too late
+        if compared.len() + 1 >= context.num_replica() - context.num_faulty() {
+            self.checked_height = height;
+            self.checkpoints
+                .retain(|&other_height, _| other_height > height);
+            self.pending
+                .retain(|&other_height, _| other_height > height);
+        }
+        let Some(diverged_height) = diverged_height else {
+            return;
+        };
+        context.count(STATE_DIVERGED, 1);
+        // only the first detected block is reported, the states are not expected
+        // to converge again
+        if self.diverged_height.is_none() {
+            context.count(FIRST_DIVERGED_BLOCK, diverged_height as _);
+            self.diverged_height = Some(diverged_height)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::Options;
+
+    use crate::{
+        app::ycsb::{self, Op},
+        context::{
+            simulated::{self, Dispatch},
+            Addr,
+        },
+        pbft::Message,
+        Config,
+    };
+
+    use super::*;
+
+    #[test]
+    fn acknowledge_on_execution() {
+        let request = |request_num, ack_num| Request {
+            client_index: 0,
+            request_num,
+            ack_num,
+            op: Default::default(),
+        };
+        let mut table = ClientTable::default();
+        assert!(matches!(
+            table.insert_request(&request(1, 1)),
+            RequestStatus::New
+        ));
+        assert!(matches!(
+            table.insert_request(&request(2, 1)),
+            RequestStatus::New
+        ));
+        assert!(matches!(
+            table.insert_request(&request(3, 2)),
+            RequestStatus::New
+        ));
+        assert!(table.has_pending());
+        table.insert_reply(&request(2, 1), 2);
+        assert!(matches!(
+            table.insert_request(&request(2, 1)),
+            RequestStatus::Replied(2)
+        ));
+        // the acknowledgement of 3 is not executed yet
+        assert!(!table.is_executed(&request(1, 1)));
+        table.insert_reply(&request(3, 2), 3);
+        assert!(table.is_executed(&request(1, 1)));
+        assert!(matches!(
+            table.insert_request(&request(1, 1)),
+            RequestStatus::Pending
+        ));
+        assert!(!table.has_pending())
+    }
+
+    #[test]
+    fn proved_read_quorum() {
+        let dispatch = Dispatch::<Message>::new();
+        let config = std::sync::Arc::new(Config {
+            num_faulty: 1,
+            client_addrs: vec![Addr::Simulated(simulated::Addr::Client(0))],
+            replica_addrs: Vec::from_iter(
+                (0..4).map(|index| Addr::Simulated(simulated::Addr::Replica(index))),
+            ),
+            multicast_addr: None,
+        });
+        let mut core = ClientCore::<Message, ()>::new(
+            dispatch
+                .register(simulated::Addr::Client(0))
+                .into_replication(config),
+            0,
+        );
+        let op = bincode::options()
+            .serialize(&Op::Read(String::from("key")))
+            .unwrap();
+        let request_num = core.invoke_read_only(op.clone(), BoxedConsume::from(|_| {}));
+        let app = App::new(ycsb::App::new(
+            [(String::from("key"), String::from("value"))].into(),
+        ));
+        let request = ReadOnlyRequest {
+            client_index: 0,
+            request_num,
+            op,
+        };
+        let reply = ReadOnlyReply::new(&app, &request, 0);
+        assert!(reply.is_proved(&request.op));
+        // f + 1 proved replies are not a quorum
+        for replica_index in 0..2 {
+            let step = core.handle_read_only_reply(ReadOnlyReply {
+                replica_index,
+                ..reply.clone()
+            });
+            assert!(matches!(step, ClientStep::Pending))
+        }
+        let step = core.handle_read_only_reply(ReadOnlyReply {
+            replica_index: 2,
+            ..reply
+        });
+        assert!(matches!(step, ClientStep::Complete(..)))
+    }
+}
@@ -0,0 +1,906 @@
+//! HoneyBadgerBFT, an asynchronous BFT protocol.
+//!
+//! Every epoch each replica proposes a random sample of its pending requests
+//! through `neat::broadcast::reliable`, and a binary agreement per proposer
+//! decides which proposals are included, i.e., asynchronous common subset. The
+//! included proposals are concatenated in proposer order, deduplicated and cut
+//! into `common::Block`s. The binary agreement is the signature-free one from
+//! Mostéfaoui et al. with an additional `Conf` phase, `Term` messages for
+//! termination and a threshold common coin.
+//!
+//! The threshold encryption of proposals (censorship resilience) and erasure
+//! coded broadcast of the original protocol are omitted.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use neat::broadcast::reliable::{self, ReliableBroadcast};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::BoxedConsume,
+    common::{
+        BatchConfig, Chain, ClientCore, ClientStep, ClientTable, Request, RequestStatus, Timer,
+        BLOCK_COMMITTED,
+    },
+    context::{Addr, MultiplexReceive, TimerId},
+    crypto::{CoinShare, Sign, Signed, ThresholdCoin, Verify},
+    App, ClientIndex, Context, ReplicaIndex, To,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Message {
+    Request(Signed<Request>),
+    Reply(Signed<Reply>),
+    Broadcast(reliable::Message<Vec<Request>>),
+    Agreement(Signed<Agreement>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Reply {
+    request_num: u32,
+    result: Vec<u8>,
+    replica_index: ReplicaIndex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Agreement {
+    epoch: u32,
+    proposer: ReplicaIndex,
+    step: AgreementStep,
+    replica_index: ReplicaIndex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AgreementStep {
+    BVal(u32, bool),
+    Aux(u32, bool),
+    Conf(u32, BinValues),
+    Coin(u32, CoinShare),
+    Term(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct BinValues {
+    zero: bool,
+    one: bool,
+}
+
+impl BinValues {
+    fn insert(&mut self, value: bool) {
+        if value {
+            self.one = true
+        } else {
+            self.zero = true
+        }
+    }
+
+    fn contains(&self, value: bool) -> bool {
+        if value {
+            self.one
+        } else {
+            self.zero
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.zero && !self.one
+    }
+
+    fn is_subset(&self, other: &Self) -> bool {
+        (!self.zero || other.zero) && (!self.one || other.one)
+    }
+
+    fn union(&self, other: &Self) -> Self {
+        Self {
+            zero: self.zero || other.zero,
+            one: self.one || other.one,
+        }
+    }
+
+    fn single(value: bool) -> Self {
+        let mut values = Self::default();
+        values.insert(value);
+        values
+    }
+
+    fn definite(&self) -> Option<bool> {
+        match (self.zero, self.one) {
+            (true, false) => Some(false),
+            (false, true) => Some(true),
+            _ => None,
+        }
+    }
+}
+
+// binary agreement as a plain state machine that pushes the steps it wants to
+// broadcast (to all replicas including itself) into `sends`
+// a `Term(b)` from a replica is counted as its `BVal(b)`, `Aux(b)` and
+// `Conf({b})` in every round, so decided replicas can stop working on rounds,
+// except for answering coin shares
+#[derive(Debug)]
+pub struct BinaryAgreement {
+    session: (u32, ReplicaIndex),
+    num_replica: usize,
+    num_faulty: usize,
+    round: u32,
+    input: bool,
+    decision: Option<bool>,
+    bvals: HashMap<(u32, bool), HashSet<ReplicaIndex>>,
+    bvals_sent: HashSet<(u32, bool)>,
+    bin_values: HashMap<u32, BinValues>,
+    auxes: HashMap<u32, HashMap<ReplicaIndex, bool>>,
+    confs: HashMap<u32, HashMap<ReplicaIndex, BinValues>>,
+    conf_sent: HashSet<u32>,
+    coin_shares: HashMap<u32, HashMap<ReplicaIndex, CoinShare>>,
+    coin_sent: HashSet<u32>,
+    coins: HashMap<u32, bool>,
+    terms: HashMap<ReplicaIndex, bool>,
+}
+
+impl BinaryAgreement {
+    pub fn new(session: (u32, ReplicaIndex), num_replica: usize, num_faulty: usize) -> Self {
+        Self {
+            session,
+            num_replica,
+            num_faulty,
+            round: 0,
+            input: false,
+            decision: None,
+            bvals: Default::default(),
+            bvals_sent: Default::default(),
+            bin_values: Default::default(),
+            auxes: Default::default(),
+            confs: Default::default(),
+            conf_sent: Default::default(),
+            coin_shares: Default::default(),
+            coin_sent: Default::default(),
+            coins: Default::default(),
+            terms: Default::default(),
+        }
+    }
+
+    pub fn has_input(&self) -> bool {
+        self.input
+    }
+
+    pub fn decision(&self) -> Option<bool> {
+        self.decision
+    }
+
+    pub fn input(&mut self, value: bool, coin: &ThresholdCoin, sends: &mut Vec<AgreementStep>) {
+        assert!(!self.input);
+        self.input = true;
+        if self.round == 0 && self.bvals_sent.insert((0, value)) {
+            sends.push(AgreementStep::BVal(0, value))
+        }
+        self.progress(coin, sends)
+    }
+
+    pub fn handle(
+        &mut self,
+        remote: ReplicaIndex,
+        step: AgreementStep,
+        coin: &ThresholdCoin,
+        sends: &mut Vec<AgreementStep>,
+    ) {
+        match step {
+            AgreementStep::BVal(round, value) => {
+                self.bvals.entry((round, value)).or_default().insert(remote);
+            }
+            AgreementStep::Aux(round, value) => {
+                self.auxes
+                    .entry(round)
+                    .or_default()
+                    .entry(remote)
+                    .or_insert(value);
+            }
+            AgreementStep::Conf(round, values) => {
+                self.confs
+                    .entry(round)
+                    .or_default()
+                    .entry(remote)
+                    .or_insert(values);
+            }
+            AgreementStep::Coin(round, share) => {
+                // a malformed share from a faulty replica would poison the
+                // combined coin
+                if coin
+                    .verify_share(remote as _, &(self.session, round), &share)
+                    .is_err()
+                {
+                    return;
+                }
+                self.coin_shares
+                    .entry(round)
+                    .or_default()
+                    .entry(remote)
+                    .or_insert(share);
+                // help lagging replicas to toss the coin
+                if self.decision.is_some() && self.coin_sent.insert(round) {
+                    sends.push(AgreementStep::Coin(
+                        round,
+                        coin.share(&(self.session, round)),
+                    ))
+                }
+            }
+            AgreementStep::Term(value) => {
+                self.terms.entry(remote).or_insert(value);
+                if self.decision.is_none()
+                    && self.terms.values().filter(|&&term| term == value).count() > self.num_faulty
+                {
+                    self.decision = Some(value);
+                    sends.push(AgreementStep::Term(value))
+                }
+            }
+        }
+        self.progress(coin, sends)
+    }
+
+    fn progress(&mut self, coin: &ThresholdCoin, sends: &mut Vec<AgreementStep>) {
+        while self.decision.is_none() {
+            let round = self.round;
+            for value in [false, true] {
+                let num_bval = self
+                    .bvals
+                    .get(&(round, value))
+                    .map(HashSet::len)
+                    .unwrap_or_default()
+                    + self.terms.values().filter(|&&term| term == value).count();
+                if num_bval > self.num_faulty && self.bvals_sent.insert((round, value)) {
+                    sends.push(AgreementStep::BVal(round, value))
+                }
+                if num_bval >= self.num_replica - self.num_faulty {
+                    let bin_values = self.bin_values.entry(round).or_default();
+                    if bin_values.is_empty() {
+                        sends.push(AgreementStep::Aux(round, value))
+                    }
+                    bin_values.insert(value)
+                }
+            }
+            let bin_values = self.bin_values.get(&round).copied().unwrap_or_default();
+            if bin_values.is_empty() {
+                break;
+            }
+
+            let num_aux = self
+                .auxes
+                .get(&round)
+                .into_iter()
+                .flat_map(HashMap::values)
+                .chain(self.terms.values())
+                .filter(|&&value| bin_values.contains(value))
+                .count();
+            if num_aux < self.num_replica - self.num_faulty {
+                break;
+            }
+            if self.conf_sent.insert(round) {
+                sends.push(AgreementStep::Conf(round, bin_values))
+            }
+
+            let confs = Vec::from_iter(
+                self.confs
+                    .get(&round)
+                    .into_iter()
+                    .flat_map(HashMap::values)
+                    .copied()
+                    .chain(self.terms.values().map(|&value| BinValues::single(value)))
+                    .filter(|values| values.is_subset(&bin_values)),
+            );
+            if confs.len() < self.num_replica - self.num_faulty {
+                break;
+            }
+            let values = confs
+                .into_iter()
+                .fold(BinValues::default(), |values, other| values.union(&other));
+
+            if self.coin_sent.insert(round) {
+                sends.push(AgreementStep::Coin(
+                    round,
+                    coin.share(&(self.session, round)),
+                ))
+            }
+            let Some(coin_value) = self.toss(round) else {
+                break;
+            };
+            if let Some(value) = values.definite() {
+                if value == coin_value {
+                    self.decision = Some(value);
+                    sends.push(AgreementStep::Term(value));
+                    break;
+                }
+            }
+            let estimate = values.definite().unwrap_or(coin_value);
+            self.round += 1;
+            if self.bvals_sent.insert((self.round, estimate)) {
+                sends.push(AgreementStep::BVal(self.round, estimate))
+            }
+        }
+    }
+
+    fn toss(&mut self, round: u32) -> Option<bool> {
+        if let Some(coin) = self.coins.get(&round) {
+            return Some(*coin);
+        }
+        let shares = Vec::from_iter(
+            self.coin_shares
+                .get(&round)?
+                .iter()
+                .take(self.num_faulty + 1)
+                .map(|(&index, share)| (index as usize, share.clone())),
+        );
+        if shares.len() <= self.num_faulty {
+            return None;
+        }
+        // shares are verified on receipt
+        let coin = ThresholdCoin::combine(&shares).ok()?;
+        self.coins.insert(round, coin);
+        Some(coin)
+    }
+}
+
+#[derive(Debug)]
+pub struct Client {
+    shared: Arc<Mutex<ClientCore<Message, Reply>>>,
+}
+
+impl Client {
+    pub fn new(context: Context<Message>, index: ClientIndex) -> Self {
+        let mut core = ClientCore::new(context, index);
+        // an epoch takes several rounds of all-to-all communication
+        core.resend_timer = Timer::new(Duration::from_millis(1000));
+        Self {
+            shared: Arc::new(Mutex::new(core)),
+        }
+    }
+}
+
+impl crate::Client for Client {
+    type Message = Message;
+
+    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
+        let core = &mut *self.shared.lock().unwrap();
+        let request = core.invoke(op, consume.into());
+        let request_num = request.request_num;
+        core.context.send(To::AllReplica, request);
+        request_num
+    }
+
+    fn abort(&self, invoke_num: u32) -> Option<BoxedConsume> {
+        self.shared.lock().unwrap().abort(invoke_num)
+    }
+
+    fn handle(&self, message: Self::Message) {
+        let Message::Reply(message) = message else {
+            unimplemented!()
+        };
+        let mut core = self.shared.lock().unwrap();
+        let num_faulty = core.context.num_faulty();
+        let Some(invoke) = core.invoke_mut(message.request_num) else {
+            return;
+        };
+        invoke
+            .replies
+            .insert(message.replica_index, Reply::clone(&message));
+        let num_match = invoke
+            .replies
+            .values()
+            .filter(|reply| reply.result == message.result)
+            .count();
+        assert!(num_match <= num_faulty + 1);
+        if num_match == num_faulty + 1 {
+            let invoke = core.complete(message.request_num);
+            drop(core);
+            invoke.consume.apply(message.inner.result)
+        }
+    }
+
+    fn on_timer(&self, id: TimerId) {
+        let core = &mut *self.shared.lock().unwrap();
+        for step in core.on_timer(id) {
+            // every replica queues the request until it is executed, so the
+            // resend only covers the lost ones and the lost replies
+            let ClientStep::Resend(request) = step else {
+                unreachable!()
+            };
+            core.context.send(To::AllReplica, request)
+        }
+    }
+}
+
+pub struct Replica {
+    context: Context<Message>,
+    index: ReplicaIndex,
+    coin: ThresholdCoin,
+    broadcast: ReliableBroadcast<Vec<Request>>,
+
+    epoch: u32,
+    proposed: bool,
+    requests: Vec<Request>,
+    replies: ClientTable<Reply>,
+    // current and future epochs
+    epochs: BTreeMap<u32, Epoch>,
+    chain: Chain,
+    app: App,
+    // the B parameter, total size of the proposals in an epoch
+    max_batch_size: usize,
+}
+
+#[derive(Debug)]
+struct Epoch {
+    batches: Vec<Option<Vec<Request>>>,
+    agreements: Vec<BinaryAgreement>,
+}
+
+impl Replica {
+    pub fn new(
+        context: Context<Message>,
+        broadcast_context: Context<reliable::Message<Vec<Request>>>,
+        index: ReplicaIndex,
+        app: App,
+        coin: ThresholdCoin,
+        batching: BatchConfig,
+    ) -> Self {
+        assert_eq!(coin.index(), index as usize);
+        Self {
+            context,
+            index,
+            coin,
+            broadcast: ReliableBroadcast::new(broadcast_context, index),
+            epoch: 0,
+            proposed: false,
+            requests: Default::default(),
+            replies: Default::default(),
+            epochs: Default::default(),
+            chain: Default::default(),
+            app,
+            max_batch_size: batching.max_size,
+        }
+    }
+}
+
+impl MultiplexReceive for Replica {
+    type Message = Message;
+
+    fn handle(&mut self, receiver: Addr, remote: Addr, message: Self::Message) {
+        assert_eq!(receiver, self.context.addr());
+        match message {
+            Message::Request(message) => self.handle_request(remote, message),
+            Message::Broadcast(message) => self.broadcast.handle(receiver, remote, message),
+            Message::Agreement(message) => self.handle_agreement(message),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
+        assert_eq!(receiver, self.context.addr());
+        match message {
+            Message::Broadcast(reliable::Message::Deliver(deliver)) => self.handle_deliver(deliver),
+            Message::Broadcast(message) => self.broadcast.handle_loopback(receiver, message),
+            Message::Agreement(message) => self.handle_agreement(message),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn on_timer(&mut self, receiver: Addr, _: TimerId) {
+        assert_eq!(receiver, self.context.addr());
+        unreachable!()
+    }
+
+    fn on_pace(&mut self) {
+        // join an epoch either with pending requests or when others started it
+        if !self.proposed && (!self.requests.is_empty() || self.epochs.contains_key(&self.epoch)) {
+            self.do_propose()
+        }
+    }
+}
+
+impl Replica {
+    fn handle_request(&mut self, remote: Addr, message: Signed<Request>) {
+        match self.replies.insert_request(&message) {
+            RequestStatus::New => {}
+            RequestStatus::Pending => return,
+            RequestStatus::Replied(reply) => {
+                self.context.send(To::Addr(remote), reply.clone());
+                return;
+            }
+        }
+        self.requests.push(message.inner)
+    }
+
+    fn handle_deliver(&mut self, deliver: reliable::Deliver<Vec<Request>>) {
+        let (proposer, epoch) = deliver.session;
+        if epoch < self.epoch {
+            return;
+        }
+        self.epoch_entry(epoch).batches[proposer as usize] = Some(deliver.value);
+        self.do_input(epoch, proposer, true);
+        self.do_output()
+    }
+
+    fn handle_agreement(&mut self, message: Signed<Agreement>) {
+        let Agreement {
+            epoch,
+            proposer,
+            step,
+            replica_index,
+        } = message.inner;
+        if epoch < self.epoch {
+            return;
+        }
+        let mut sends = Vec::new();
+        let coin = self.coin.clone();
+        self.epoch_entry(epoch).agreements[proposer as usize].handle(
+            replica_index,
+            step,
+            &coin,
+            &mut sends,
+        );
+        self.do_send_agreement(epoch, proposer, sends);
+        self.do_input_rest(epoch);
+        self.do_output()
+    }
+
+    fn epoch_entry(&mut self, epoch: u32) -> &mut Epoch {
+        let (num_replica, num_faulty) = (self.context.num_replica(), self.context.num_faulty());
+        self.epochs.entry(epoch).or_insert_with(|| Epoch {
+            batches: vec![None; num_replica],
+            agreements: Vec::from_iter(
+                (0..num_replica).map(|index| {
+                    BinaryAgreement::new((epoch, index as _), num_replica, num_faulty)
+                }),
+            ),
+        })
+    }
+
+    fn do_propose(&mut self) {
+        self.proposed = true;
+        // sample from the head of the queue as in the original protocol, so
+        // concurrent proposers are likely to include different requests
+        let num_candidate = self.requests.len().min(self.max_batch_size);
+        let amount = (self.max_batch_size / self.context.num_replica())
+            .max(1)
+            .min(num_candidate);
+        let batch = Vec::from_iter(
+            rand::seq::index::sample(&mut rand::thread_rng(), num_candidate, amount)
+                .into_iter()
+                .map(|index| self.requests[index].clone()),
+        );
+        let broadcast = reliable::Broadcast {
+            sequence: self.epoch,
+            value: batch,
+        };
+        self.broadcast.handle(
+            self.context.addr(),
+            Addr::Upcall,
+            reliable::Message::Broadcast(broadcast),
+        )
+    }
+
+    fn do_input(&mut self, epoch: u32, proposer: ReplicaIndex, value: bool) {
+        let coin = self.coin.clone();
+        let agreement = &mut self.epoch_entry(epoch).agreements[proposer as usize];
+        if agreement.has_input() {
+            return;
+        }
+        let mut sends = Vec::new();
+        agreement.input(value, &coin, &mut sends);
+        self.do_send_agreement(epoch, proposer, sends);
+        self.do_input_rest(epoch)
+    }
+
+    // once n - f proposals are accepted, vote to reject all the others that
+    // have not been delivered yet
+    fn do_input_rest(&mut self, epoch: u32) {
+        let num_accepted = self.epochs[&epoch]
+            .agreements
+            .iter()
+            .filter(|agreement| agreement.decision() == Some(true))
+            .count();
+        if num_accepted < self.context.num_replica() - self.context.num_faulty() {
+            return;
+        }
+        for proposer in 0..self.context.num_replica() {
+            if !self.epochs[&epoch].agreements[proposer].has_input() {
+                self.do_input(epoch, proposer as _, false)
+            }
+        }
+    }
+
+    fn do_send_agreement(&mut self, epoch: u32, proposer: ReplicaIndex, sends: Vec<AgreementStep>) {
+        for step in sends {
+            self.context.send(
+                To::AllReplicaWithLoopback,
+                Agreement {
+                    epoch,
+                    proposer,
+                    step,
+                    replica_index: self.index,
+                },
+            )
+        }
+    }
+
+    fn do_output(&mut self) {
+        while let Some(epoch) = self.epochs.get(&self.epoch) {
+            let mut requests = Vec::new();
+            for (agreement, batch) in epoch.agreements.iter().zip(&epoch.batches) {
+                match agreement.decision() {
+                    None => return,
+                    Some(false) => {}
+                    Some(true) => {
+                        let Some(batch) = batch else {
+                            return;
+                        };
+                        requests.extend(batch.iter().cloned())
+                    }
+                }
+            }
+            self.epochs.remove(&self.epoch);
+            self.epoch += 1;
+            self.proposed = false;
+            for proposer in 0..self.context.num_replica() {
+                self.broadcast.truncate(proposer as _, self.epoch)
+            }
+
+            // different proposers may include the same request
+            let mut included = HashSet::new();
+            requests.retain(|request| {
+                !self.replies.is_executed(request)
+                    && included.insert((request.client_index, request.request_num))
+            });
+            while !requests.is_empty() {
+                let block = self.chain.propose_batch(
+                    requests
+                        .drain(..requests.len().min(self.max_batch_size))
+                        .collect(),
+                );
+                let execute = self.chain.commit(&block);
+                assert!(execute);
+                self.context.count(BLOCK_COMMITTED, 1);
+                for request in block.requests {
+                    let reply = Reply {
+                        request_num: request.request_num,
+                        result: self.app.execute(&request.op),
+                        replica_index: self.index,
+                    };
+                    self.replies.insert_reply(&request, reply.clone());
+                    self.context.send(To::Client(request.client_index), reply)
+                }
+            }
+            self.requests
+                .retain(|request| !self.replies.is_executed(request))
+        }
+    }
+}
+
+impl From<reliable::Message<Vec<Request>>> for Message {
+    fn from(value: reliable::Message<Vec<Request>>) -> Self {
+        Self::Broadcast(value)
+    }
+}
+
+impl Sign<Request> for Message {
+    fn sign(message: Request, signer: &crate::crypto::Signer) -> Self {
+        Self::Request(signer.sign_private(message))
+    }
+}
+
+impl Sign<Reply> for Message {
+    fn sign(message: Reply, signer: &crate::crypto::Signer) -> Self {
+        Self::Reply(signer.sign_private(message))
+    }
+}
+
+impl Sign<Agreement> for Message {
+    fn sign(message: Agreement, signer: &crate::crypto::Signer) -> Self {
+        Self::Agreement(signer.sign_public(message))
+    }
+}
+
+impl Verify<ReplicaIndex> for Message {
+    fn verify(
+        &self,
+        verifier: &crate::crypto::Verifier<ReplicaIndex>,
+    ) -> Result<(), crate::crypto::Invalid> {
+        match self {
+            Self::Request(message) => verifier.verify(message, None),
+            Self::Reply(message) => verifier.verify(message, message.replica_index),
+            Self::Broadcast(message) => message.verify(verifier),
+            Self::Agreement(message) => verifier.verify(message, message.replica_index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use crate::{
+        app::Null,
+        context::simulated::{self, Dispatch},
+        crypto::hardcoded_threshold_coin,
+        Client as _, Config,
+    };
+
+    use super::*;
+
+    // run the agreements of `inputs` with the messages delivered in random
+    // order. the agreements without input stay silent, as crashed replicas
+    fn agree(inputs: [Option<bool>; 4]) -> Vec<Option<bool>> {
+        let coins = Vec::from_iter((0..4).map(|index| hardcoded_threshold_coin(index, 2)));
+        let mut agreements = Vec::from_iter((0..4).map(|_| BinaryAgreement::new((0, 0), 4, 1)));
+        let mut messages = Vec::new();
+        for (index, input) in inputs.iter().enumerate() {
+            if let Some(input) = input {
+                let mut sends = Vec::new();
+                agreements[index].input(*input, &coins[index], &mut sends);
+                messages.extend(sends.into_iter().map(|step| (index, step)))
+            }
+        }
+        while !messages.is_empty() {
+            let (remote, step) =
+                messages.swap_remove(rand::thread_rng().gen_range(0..messages.len()));
+            for index in 0..4 {
+                if inputs[index].is_none() {
+                    continue;
+                }
+                let mut sends = Vec::new();
+                agreements[index].handle(remote as _, step.clone(), &coins[index], &mut sends);
+                messages.extend(sends.into_iter().map(|step| (index, step)))
+            }
+        }
+        Vec::from_iter(agreements.iter().map(BinaryAgreement::decision))
+    }
+
+    #[test]
+    fn agreement_terminate() {
+        for _ in 0..10 {
+            let decisions = agree([Some(true), Some(false), Some(true), Some(false)]);
+            assert!(decisions[0].is_some());
+            assert!(decisions.iter().all(|decision| *decision == decisions[0]));
+        }
+    }
+
+    #[test]
+    fn agreement_terminate_crashed() {
+        for _ in 0..10 {
+            let decisions = agree([Some(true), Some(true), Some(true), None]);
+            assert_eq!(decisions[..3], [Some(true); 3]);
+            let decisions = agree([Some(false), Some(true), Some(false), None]);
+            assert!(decisions[0].is_some());
+            assert!(decisions[..3]
+                .iter()
+                .all(|decision| *decision == decisions[0]));
+        }
+    }
+
+    struct System {
+        replicas: Vec<Replica>,
+        client: Client,
+        crashed: Option<ReplicaIndex>,
+    }
+
+    impl System {
+        fn replica(&mut self, addr: Addr) -> Option<&mut Replica> {
+            let Addr::Simulated(simulated::Addr::Replica(index)) = addr else {
+                return None;
+            };
+            Some(&mut self.replicas[index as usize]).filter(|_| self.crashed != Some(index))
+        }
+    }
+
+    impl MultiplexReceive for System {
+        type Message = Message;
+
+        fn handle(&mut self, receiver: Addr, remote: Addr, message: Self::Message) {
+            if let Addr::Simulated(simulated::Addr::Client(_)) = receiver {
+                self.client.handle(message)
+            } else if let Some(replica) = self.replica(receiver) {
+                replica.handle(receiver, remote, message)
+            }
+        }
+
+        fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
+            if let Some(replica) = self.replica(receiver) {
+                replica.handle_loopback(receiver, message)
+            }
+        }
+
+        fn on_timer(&mut self, receiver: Addr, id: TimerId) {
+            if let Addr::Simulated(simulated::Addr::Client(_)) = receiver {
+                self.client.on_timer(id)
+            } else if let Some(replica) = self.replica(receiver) {
+                replica.on_timer(receiver, id)
+            }
+        }
+
+        fn on_pace(&mut self) {
+            for (index, replica) in self.replicas.iter_mut().enumerate() {
+                if self.crashed != Some(index as _) {
+                    replica.on_pace()
+                }
+            }
+        }
+    }
+
+    fn system(dispatch: &Dispatch<Message>, crashed: Option<ReplicaIndex>) -> System {
+        let config = Arc::new(Config {
+            num_faulty: 1,
+            client_addrs: vec![Addr::Simulated(simulated::Addr::Client(0))],
+            replica_addrs: Vec::from_iter(
+                (0..4).map(|index| Addr::Simulated(simulated::Addr::Replica(index))),
+            ),
+            multicast_addr: None,
+        });
+        System {
+            replicas: Vec::from_iter((0..4).map(|index| {
+                let context = dispatch
+                    .register(simulated::Addr::Replica(index))
+                    .into_replication(config.clone());
+                let broadcast_context = dispatch
+                    .register_subnode(&context)
+                    .into_replication(config.clone());
+                Replica::new(
+                    context,
+                    broadcast_context,
+                    index,
+                    App::new(Null),
+                    hardcoded_threshold_coin(index as _, 2),
+                    BatchConfig::default(),
+                )
+            })),
+            client: Client::new(
+                dispatch
+                    .register(simulated::Addr::Client(0))
+                    .into_replication(config),
+                0,
+            ),
+            crashed,
+        }
+    }
+
+    // invoke `num_op` ops at once and run until all of them complete or the
+    // virtual time is up
+    fn run(dispatch: &Dispatch<Message>, system: &mut System, num_op: usize) -> usize {
+        let results = Arc::new(Mutex::new(0));
+        for _ in 0..num_op {
+            let results = results.clone();
+            system
+                .client
+                .invoke(Default::default(), move |_| *results.lock().unwrap() += 1);
+        }
+        let deadline = Duration::from_secs(60);
+        while *results.lock().unwrap() < num_op
+            && dispatch.deliver_event_before(deadline, &mut *system)
+        {
+            system.on_pace()
+        }
+        let num_result = *results.lock().unwrap();
+        num_result
+    }
+
+    #[test]
+    fn commit() {
+        let dispatch = Dispatch::new();
+        let mut system = system(&dispatch, None);
+        assert_eq!(run(&dispatch, &mut system, 3), 3);
+        assert!(system.replicas.iter().all(|replica| replica.epoch >= 1));
+    }
+
+    // the epoch outlasts the resend timer of the client
+    #[test]
+    fn commit_delayed() {
+        let dispatch = Dispatch::new();
+        dispatch.set_max_delay(Duration::from_millis(500));
+        let mut system = system(&dispatch, Some(3));
+        assert_eq!(run(&dispatch, &mut system, 3), 3);
+        assert!(dispatch.now() > Duration::from_millis(1000));
+        assert!(system.replicas[..3]
+            .iter()
+            .all(|replica| replica.epoch >= 1));
+    }
+}
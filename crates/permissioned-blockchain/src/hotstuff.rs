@@ -1,16 +1,21 @@
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
-    time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
     client::BoxedConsume,
-    common::{Block, BlockDigest, Chain, Request, Timer},
-    context::{Addr, MultiplexReceive},
+    common::{
+        BatchConfig, Batcher, Block, BlockDigest, Chain, ClientCore, ClientStep, ClientTable,
+        ReadOnlyReply, ReadOnlyRequest, Request, RequestStatus, StateCheck, StateCheckpoint,
+        BLOCK_COMMITTED,
+    },
+    context::{Addr, MultiplexReceive, TimerId},
     crypto::{Sign, Signed, Verify},
+    ledger::{self, Certificate, Ledger},
+    storage::{deserialize_state, Entry, Storage},
     App, ClientIndex, Context, ReplicaIndex, To,
 };
 
@@ -18,8 +23,11 @@ use crate::{
 pub enum Message {
     Request(Signed<Request>),
     Reply(Signed<Reply>),
+    ReadOnlyRequest(Signed<ReadOnlyRequest>),
+    ReadOnlyReply(Signed<ReadOnlyReply>),
     Generic(Signed<Generic>),
     Vote(Signed<Vote>),
+    Checkpoint(Signed<Checkpoint>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -43,71 +51,90 @@ pub struct Vote {
     replica_index: ReplicaIndex,
 }
 
-#[derive(Debug)]
-pub struct Client {
-    index: ClientIndex,
-    shared: Arc<Mutex<ClientShared>>,
+// only for comparing application states, see `StateCheck`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Checkpoint {
+    height: u32,
+    state: StateCheckpoint,
+    replica_index: ReplicaIndex,
 }
 
-#[derive(Debug)]
-struct ClientShared {
-    context: Context<Message>,
-    request_num: u32,
-    invoke: Option<ClientInvoke>,
-    resend_timer: Timer,
+// safety-critical state that is written to storage before sending the
+// corresponding messages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum State {
+    Generic(Signed<Generic>),
+    // vheight
+    Vote(u32),
+    Lock(BlockDigest),
 }
 
 #[derive(Debug)]
-struct ClientInvoke {
-    op: Vec<u8>,
-    replies: HashMap<ReplicaIndex, Reply>,
-    consume: BoxedConsume,
+pub struct Client {
+    shared: Arc<Mutex<ClientCore<Message, Reply>>>,
 }
 
 impl Client {
     pub fn new(context: Context<Message>, index: ClientIndex) -> Self {
         Self {
-            index,
-            shared: Arc::new(Mutex::new(ClientShared {
-                context,
-                request_num: 0,
-                invoke: None,
-                resend_timer: Timer::new(Duration::from_millis(100)),
-            })),
+            shared: Arc::new(Mutex::new(ClientCore::new(context, index))),
         }
     }
 }
 
+fn client_step(
+    core: &mut ClientCore<Message, Reply>,
+    step: ClientStep<Reply>,
+) -> Option<(BoxedConsume, Vec<u8>)> {
+    match step {
+        ClientStep::Pending => None,
+        // the requests are sent to all replicas as the leader may change,
+        // so just in case the request is lost
+        ClientStep::Resend(request) | ClientStep::FallBack(request) => {
+            core.context.send(To::AllReplica, request);
+            None
+        }
+        ClientStep::Complete(invoke, result) => Some((invoke.consume, result)),
+    }
+}
+
 impl crate::Client for Client {
     type Message = Message;
 
-    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) {
-        let shared = &mut *self.shared.lock().unwrap();
-        assert!(shared.invoke.is_none());
-        shared.request_num += 1;
-        shared.invoke = Some(ClientInvoke {
-            op: op.clone(),
-            replies: Default::default(),
-            consume: consume.into(),
-        });
-        let request = Request {
-            client_index: self.index,
-            request_num: shared.request_num,
-            op,
-        };
-        shared.context.send(To::AllReplica, request);
-        shared.resend_timer.set(&mut shared.context)
+    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
+        let core = &mut *self.shared.lock().unwrap();
+        let request = core.invoke(op, consume.into());
+        let request_num = request.request_num;
+        core.context.send(To::AllReplica, request);
+        request_num
+    }
+
+    fn invoke_read_only(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
+        let core = &mut *self.shared.lock().unwrap();
+        core.invoke_read_only(op, consume.into())
+    }
+
+    fn abort(&self, invoke_num: u32) -> Option<BoxedConsume> {
+        self.shared.lock().unwrap().abort(invoke_num)
     }
 
     fn handle(&self, message: Self::Message) {
+        if let Message::ReadOnlyReply(message) = message {
+            let mut core = self.shared.lock().unwrap();
+            let step = core.handle_read_only_reply(message.inner);
+            let complete = client_step(&mut core, step);
+            drop(core);
+            if let Some((consume, result)) = complete {
+                consume.apply(result)
+            }
+            return;
+        }
         let Message::Reply(message) = message else {
             unimplemented!()
         };
-        let mut shared = self.shared.lock().unwrap();
-        if message.request_num != shared.request_num {
-            return;
-        }
-        let Some(invoke) = &mut shared.invoke else {
+        let mut core = self.shared.lock().unwrap();
+        let num_faulty = core.context.num_faulty();
+        let Some(invoke) = core.invoke_mut(message.request_num) else {
             return;
         };
         invoke
@@ -118,19 +145,20 @@ impl crate::Client for Client {
             .values()
             .filter(|reply| reply.result == message.result)
             .count();
-        assert!(num_match <= shared.context.num_faulty() + 1);
-        if num_match == shared.context.num_faulty() + 1 {
-            {
-                let shared = &mut *shared;
-                shared.resend_timer.unset(&mut shared.context);
-            }
-            let invoke = shared.invoke.take().unwrap();
-            drop(shared);
-
-            let _op = invoke.op;
+        assert!(num_match <= num_faulty + 1);
+        if num_match == num_faulty + 1 {
+            let invoke = core.complete(message.request_num);
+            drop(core);
             invoke.consume.apply(message.inner.result)
         }
     }
+
+    fn on_timer(&self, id: TimerId) {
+        let core = &mut *self.shared.lock().unwrap();
+        for step in core.on_timer(id) {
+            assert!(client_step(core, step).is_none())
+        }
+    }
 }
 
 pub struct Replica {
@@ -143,21 +171,30 @@ pub struct Replica {
     digest_lock: BlockDigest,
 
     requests: Vec<Request>,
-    replies: HashMap<ClientIndex, (u32, Option<Reply>)>,
+    replies: ClientTable<Reply>,
     generics: HashMap<BlockDigest, Signed<Generic>>,
     votes: HashMap<BlockDigest, HashMap<ReplicaIndex, Signed<Vote>>>,
     reordering_generics: HashMap<BlockDigest, Vec<Signed<Generic>>>,
     chain: Chain,
     app: App,
+    // proposals are paced by certificates, so only max size and bytes apply
+    batcher: Batcher,
+    storage: Storage,
+    pub ledger: Ledger,
+    pub state_check: StateCheck,
 }
 
 impl Replica {
-    pub fn new(context: Context<Message>, index: ReplicaIndex, app: App) -> Self {
+    pub fn new(
+        context: Context<Message>,
+        index: ReplicaIndex,
+        app: App,
+        batching: BatchConfig,
+    ) -> Self {
         let mut votes = HashMap::new();
         votes.insert(Chain::genesis().digest(), Default::default());
         let mut generics = HashMap::new();
-        let mut genesis_block = Chain::genesis();
-        genesis_block.parent_digest = genesis_block.digest();
+        let genesis_block = Block::new(Default::default(), Chain::genesis().digest(), 0);
         generics.insert(
             Chain::genesis().digest(),
             Signed {
@@ -184,7 +221,67 @@ impl Replica {
             reordering_generics: Default::default(),
             chain: Default::default(),
             app,
+            batcher: Batcher::new(batching),
+            storage: Storage::Null,
+            ledger: Ledger::Null,
+            state_check: StateCheck::new(0),
+        }
+    }
+
+    // rebuild the replica from the entries of a log it has previously written
+    // the certificates collected by primary but not yet carried by a proposal
+    // are lost, so it may resume with a lower qc_{high}
+    pub fn recover(
+        context: Context<Message>,
+        index: ReplicaIndex,
+        app: App,
+        batching: BatchConfig,
+        storage: Storage,
+        entries: Vec<Entry>,
+    ) -> Self {
+        let mut replica = Self::new(context, index, app, batching);
+        replica.storage = storage;
+        for entry in entries {
+            match entry {
+                Entry::Block(block) => {
+                    assert!(replica.chain.commit(&block));
+                    for request in &block.requests {
+                        let reply = Reply {
+                            request_num: request.request_num,
+                            result: replica.app.execute(&request.op),
+                            replica_index: replica.index,
+                        };
+                        replica.replies.insert_reply(request, reply);
+                    }
+                }
+                Entry::State(state) => match deserialize_state(&state) {
+                    State::Generic(generic) => {
+                        replica
+                            .votes
+                            .entry(generic.certified_digest)
+                            .or_insert_with(|| {
+                                generic
+                                    .certificate
+                                    .iter()
+                                    .map(|vote| (vote.replica_index, vote.clone()))
+                                    .collect()
+                            });
+                        replica.do_update_certified(&generic.certified_digest);
+                        if generic.replica_index == replica.index {
+                            replica.propose_height =
+                                replica.propose_height.max(generic.block.height)
+                        }
+                        replica.generics.insert(generic.block.digest(), generic);
+                    }
+                    State::Vote(view_height) => replica.view_height = view_height,
+                    State::Lock(digest_lock) => replica.digest_lock = digest_lock,
+                },
+            }
         }
+        replica
+            .chain
+            .rebase(replica.digest_certified, replica.propose_height);
+        replica
     }
 }
 
@@ -196,8 +293,10 @@ impl MultiplexReceive for Replica {
         assert_eq!(receiver, self.context.addr());
         match message {
             Message::Request(message) => self.handle_request(remote, message),
+            Message::ReadOnlyRequest(message) => self.handle_read_only_request(remote, message),
             Message::Generic(message) => self.handle_generic(remote, message),
             Message::Vote(message) => self.handle_vote(remote, message),
+            Message::Checkpoint(message) => self.handle_checkpoint(remote, message),
             _ => unimplemented!(),
         }
     }
@@ -219,7 +318,7 @@ impl MultiplexReceive for Replica {
 
     fn on_pace(&mut self) {
         if self.index == self.primary_index()
-            && self.replies.values().any(|(_, reply)| reply.is_none())
+            && self.replies.has_pending()
             && self.generics[&self.digest_certified].block.height >= self.propose_height
         {
             self.do_propose()
@@ -233,18 +332,14 @@ impl Replica {
     }
 
     fn handle_request(&mut self, remote: Addr, message: Signed<Request>) {
-        match self.replies.get(&message.client_index) {
-            Some((request_num, _)) if request_num > &message.request_num => return,
-            Some((request_num, reply)) if request_num == &message.request_num => {
-                if let Some(reply) = reply {
-                    self.context.send(To::Addr(remote), reply.clone())
-                }
+        match self.replies.insert_request(&message) {
+            RequestStatus::New => {}
+            RequestStatus::Pending => return,
+            RequestStatus::Replied(reply) => {
+                self.context.send(To::Addr(remote), reply.clone());
                 return;
             }
-            _ => {}
         }
-        self.replies
-            .insert(message.client_index, (message.request_num, None));
 
         if self.index != self.primary_index() {
             return;
@@ -253,10 +348,25 @@ impl Replica {
         self.requests.push(message.inner)
     }
 
+    fn handle_read_only_request(&mut self, _remote: Addr, message: Signed<ReadOnlyRequest>) {
+        let reply = ReadOnlyReply::new(&self.app, &message, self.index);
+        self.context.send(To::Client(message.client_index), reply)
+    }
+
     fn handle_generic(&mut self, _remote: Addr, message: Signed<Generic>) {
         self.do_reorder_generic(message)
     }
 
+    fn handle_checkpoint(&mut self, _remote: Addr, message: Signed<Checkpoint>) {
+        let Checkpoint {
+            height,
+            state,
+            replica_index,
+        } = message.inner;
+        self.state_check
+            .insert(height, replica_index, state, &self.context)
+    }
+
     fn handle_vote(&mut self, _remote: Addr, message: Signed<Vote>) {
         let block_digest = message.block_digest;
         assert!(self.generics.contains_key(&block_digest)); // TODO
@@ -273,7 +383,12 @@ impl Replica {
     fn do_propose(&mut self) {
         self.chain.digest_parent = self.digest_certified; // careful
         let block = if !self.requests.is_empty() {
-            self.chain.propose(&mut self.requests)
+            let requests = self.batcher.take(
+                &mut self.requests,
+                self.chain.num_in_flight(),
+                &mut self.context,
+            );
+            self.chain.propose_batch(requests)
         } else {
             self.chain.propose_empty()
         };
@@ -318,6 +433,7 @@ impl Replica {
 
     fn insert_generic(&mut self, generic: Signed<Generic>) {
         // println!("> insert {:02x?}", generic.inner);
+        self.storage.append_state(&State::Generic(generic.clone()));
         self.generics
             .insert(generic.block.digest(), generic.clone());
 
@@ -328,6 +444,7 @@ impl Replica {
         {
             // println!("> vote   {:02x?}", generic.inner);
             self.view_height = generic.block.height;
+            self.storage.append_state(&State::Vote(self.view_height));
             let vote = Vote {
                 block_digest: generic.block.digest(),
                 replica_index: self.index,
@@ -350,7 +467,8 @@ impl Replica {
         let block_digest0 = self.generics[&block_digest1].certified_digest;
         self.do_update_certified(&block_digest2);
         if self.block_height(&block_digest1) > self.block_height(&self.digest_lock) {
-            self.digest_lock = block_digest1
+            self.digest_lock = block_digest1;
+            self.storage.append_state(&State::Lock(self.digest_lock))
         }
         if self.generics[&block_digest2].block.parent_digest == block_digest1
             && self.generics[&block_digest1].block.parent_digest == block_digest0
@@ -360,18 +478,38 @@ impl Replica {
             let block = &self.generics[&block_digest0].block;
             let execute = self.chain.commit(block);
             assert!(execute);
+            self.context.count(BLOCK_COMMITTED, 1);
+            self.storage.append(Entry::Block(block.clone()));
+            let mut results = Vec::new();
             for request in &block.requests {
                 let reply = Reply {
                     request_num: request.request_num,
                     result: self.app.execute(&request.op),
                     replica_index: self.index,
                 };
-                self.replies.insert(
-                    request.client_index,
-                    (request.request_num, Some(reply.clone())),
-                );
+                results.push(reply.result.clone());
+                self.replies.insert_reply(request, reply.clone());
                 self.context.send(To::Client(request.client_index), reply)
             }
+            // block1 carries the qc of block0
+            let certificate = &self.generics[&block_digest1].certificate;
+            self.ledger.append(
+                block,
+                || Certificate::HotStuff(certificate.clone()),
+                ledger::result_digest(&results),
+                self.app.state_digest(),
+            );
+            if let Some(state) =
+                self.state_check
+                    .on_execute(block.height, &results, &self.app, &self.context)
+            {
+                let checkpoint = Checkpoint {
+                    height: block.height,
+                    state,
+                    replica_index: self.index,
+                };
+                self.context.send(To::AllReplica, checkpoint)
+            }
             assert!(self.chain.next_execute().is_none())
         }
     }
@@ -397,6 +535,16 @@ impl Replica {
     }
 }
 
+impl ledger::Vote for Vote {
+    fn block_digest(&self) -> &BlockDigest {
+        &self.block_digest
+    }
+
+    fn replica_index(&self) -> ReplicaIndex {
+        self.replica_index
+    }
+}
+
 impl Sign<Request> for Message {
     fn sign(message: Request, signer: &crate::crypto::Signer) -> Self {
         Self::Request(signer.sign_private(message))
@@ -409,6 +557,18 @@ impl Sign<Reply> for Message {
     }
 }
 
+impl Sign<ReadOnlyRequest> for Message {
+    fn sign(message: ReadOnlyRequest, signer: &crate::crypto::Signer) -> Self {
+        Self::ReadOnlyRequest(signer.sign_private(message))
+    }
+}
+
+impl Sign<ReadOnlyReply> for Message {
+    fn sign(message: ReadOnlyReply, signer: &crate::crypto::Signer) -> Self {
+        Self::ReadOnlyReply(signer.sign_private(message))
+    }
+}
+
 impl Sign<Generic> for Message {
     fn sign(message: Generic, signer: &crate::crypto::Signer) -> Self {
         Self::Generic(signer.sign_public(message))
@@ -421,6 +581,12 @@ impl Sign<Vote> for Message {
     }
 }
 
+impl Sign<Checkpoint> for Message {
+    fn sign(message: Checkpoint, signer: &crate::crypto::Signer) -> Self {
+        Self::Checkpoint(signer.sign_public(message))
+    }
+}
+
 impl Verify<ReplicaIndex> for Message {
     fn verify(
         &self,
@@ -429,6 +595,8 @@ impl Verify<ReplicaIndex> for Message {
         match self {
             Self::Request(message) => verifier.verify(message, None),
             Self::Reply(message) => verifier.verify(message, message.replica_index),
+            Self::ReadOnlyRequest(message) => verifier.verify(message, None),
+            Self::ReadOnlyReply(message) => verifier.verify(message, message.replica_index),
             Self::Generic(message) => {
                 verifier.verify(message, message.replica_index)?;
                 if message.certified_digest == Chain::genesis().digest() {
@@ -445,6 +613,7 @@ impl Verify<ReplicaIndex> for Message {
                 )
             }
             Self::Vote(message) => verifier.verify(message, message.replica_index),
+            Self::Checkpoint(message) => verifier.verify(message, message.replica_index),
         }
     }
 }
@@ -0,0 +1,838 @@
+//! Two-chain HotStuff, i.e., Jolteon/Fast-HotStuff.
+//!
+//! Shares the chained structure of `hotstuff`, but commits a block as soon as
+//! it is followed by a certified direct child, and replaces the (missing)
+//! linear view change with a quadratic one: replicas broadcast `Timeout`s that
+//! carry their highest certificate, and 2f + 1 of them form a timeout
+//! certificate justifying the first proposal of the next view.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::BoxedConsume,
+    common::{
+        BatchConfig, Batcher, Block, BlockDigest, Chain, ClientCore, ClientStep, ClientTable,
+        Request, RequestStatus, Timer, BLOCK_COMMITTED, VIEW_CHANGE,
+    },
+    context::{Addr, MultiplexReceive, TimerId},
+    crypto::{Sign, Signed, Verify},
+    App, ClientIndex, Context, ReplicaIndex, To,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Message {
+    Request(Signed<Request>),
+    Reply(Signed<Reply>),
+    Generic(Signed<Generic>),
+    Vote(Signed<Vote>),
+    Timeout(Signed<Timeout>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Reply {
+    request_num: u32,
+    result: Vec<u8>,
+    replica_index: ReplicaIndex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Generic {
+    view_num: u32,
+    block: Block,
+    certified_digest: BlockDigest,
+    certificate: Vec<Signed<Vote>>,
+    // only present in the first proposal of a view
+    timeout_certificate: Vec<Signed<Timeout>>,
+    replica_index: ReplicaIndex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Vote {
+    block_digest: BlockDigest,
+    replica_index: ReplicaIndex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Timeout {
+    view_num: u32,
+    certified_digest: BlockDigest,
+    certificate: Vec<Signed<Vote>>,
+    replica_index: ReplicaIndex,
+}
+
+#[derive(Debug)]
+pub struct Client {
+    shared: Arc<Mutex<ClientCore<Message, Reply>>>,
+}
+
+impl Client {
+    pub fn new(context: Context<Message>, index: ClientIndex) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(ClientCore::new(context, index))),
+        }
+    }
+}
+
+impl crate::Client for Client {
+    type Message = Message;
+
+    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
+        let core = &mut *self.shared.lock().unwrap();
+        let request = core.invoke(op, consume.into());
+        let request_num = request.request_num;
+        core.context.send(To::AllReplica, request);
+        request_num
+    }
+
+    fn abort(&self, invoke_num: u32) -> Option<BoxedConsume> {
+        self.shared.lock().unwrap().abort(invoke_num)
+    }
+
+    fn handle(&self, message: Self::Message) {
+        let Message::Reply(message) = message else {
+            unimplemented!()
+        };
+        let mut core = self.shared.lock().unwrap();
+        let num_faulty = core.context.num_faulty();
+        let Some(invoke) = core.invoke_mut(message.request_num) else {
+            return;
+        };
+        invoke
+            .replies
+            .insert(message.replica_index, Reply::clone(&message));
+        let num_match = invoke
+            .replies
+            .values()
+            .filter(|reply| reply.result == message.result)
+            .count();
+        assert!(num_match <= num_faulty + 1);
+        if num_match == num_faulty + 1 {
+            let invoke = core.complete(message.request_num);
+            drop(core);
+            invoke.consume.apply(message.inner.result)
+        }
+    }
+
+    fn on_timer(&self, id: TimerId) {
+        let core = &mut *self.shared.lock().unwrap();
+        for step in core.on_timer(id) {
+            // every replica keeps the pending requests for the leader of the
+            // next view, so the resend only covers the lost ones
+            let ClientStep::Resend(request) = step else {
+                unreachable!()
+            };
+            core.context.send(To::AllReplica, request)
+        }
+    }
+}
+
+pub struct Replica {
+    context: Context<Message>,
+    index: ReplicaIndex,
+
+    view_num: u32,
+    // (view, height) of the last vote, a replica votes at most once per round
+    voted_round: (u32, u32),
+    propose_height: u32,
+    digest_certified: BlockDigest, // qc_{high}, also the lock in two-chain rule
+    timed_out: bool,
+    view_timer: Timer,
+
+    requests: Vec<Request>,
+    // requests that are not executed yet, kept by every replica so a new
+    // leader can propose them after view change
+    pending_requests: HashMap<ClientIndex, BTreeMap<u32, Request>>,
+    replies: ClientTable<Reply>,
+    generics: HashMap<BlockDigest, Signed<Generic>>,
+    votes: HashMap<BlockDigest, HashMap<ReplicaIndex, Signed<Vote>>>,
+    timeouts: HashMap<u32, HashMap<ReplicaIndex, Signed<Timeout>>>,
+    reordering_generics: HashMap<BlockDigest, Vec<Signed<Generic>>>,
+    chain: Chain,
+    app: App,
+    // proposals are paced by certificates, so only max size and bytes apply
+    batcher: Batcher,
+}
+
+impl Replica {
+    pub fn new(
+        context: Context<Message>,
+        index: ReplicaIndex,
+        app: App,
+        batching: BatchConfig,
+    ) -> Self {
+        let mut votes = HashMap::new();
+        votes.insert(Chain::genesis().digest(), Default::default());
+        let mut generics = HashMap::new();
+        let genesis_block = Block::new(Default::default(), Chain::genesis().digest(), 0);
+        generics.insert(
+            Chain::genesis().digest(),
+            Signed {
+                inner: Generic {
+                    view_num: 0,
+                    block: genesis_block,
+                    certified_digest: Chain::genesis().digest(),
+                    certificate: Default::default(),
+                    timeout_certificate: Default::default(),
+                    replica_index: u8::MAX,
+                },
+                signature: crate::crypto::Signature::Plain,
+            },
+        );
+        Self {
+            context,
+            index,
+            view_num: 0,
+            voted_round: (0, 0),
+            propose_height: 0,
+            digest_certified: Chain::genesis().digest(),
+            timed_out: false,
+            view_timer: Timer::new(Self::VIEW_CHANGE_TIMEOUT),
+            requests: Default::default(),
+            pending_requests: Default::default(),
+            replies: Default::default(),
+            generics,
+            votes,
+            timeouts: Default::default(),
+            reordering_generics: Default::default(),
+            chain: Default::default(),
+            app,
+            batcher: Batcher::new(batching),
+        }
+    }
+}
+
+impl MultiplexReceive for Replica {
+    type Message = Message;
+
+    fn handle(&mut self, receiver: Addr, remote: Addr, message: Self::Message) {
+        assert_eq!(receiver, self.context.addr());
+        match message {
+            Message::Request(message) => self.handle_request(remote, message),
+            Message::Generic(message) => self.handle_generic(remote, message),
+            Message::Vote(message) => self.handle_vote(remote, message),
+            Message::Timeout(message) => self.handle_timeout(remote, message),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
+        assert_eq!(receiver, self.context.addr());
+        match message {
+            Message::Generic(message) => self.insert_generic(message),
+            Message::Vote(message) => self.handle_vote(receiver, message),
+            Message::Timeout(message) => self.handle_timeout(receiver, message),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn on_timer(&mut self, receiver: Addr, id: TimerId) {
+        assert_eq!(receiver, self.context.addr());
+        // the alarm may be delivered after the timer get unset
+        if self.view_timer.id == Some(id) {
+            self.do_timeout()
+        }
+    }
+
+    fn on_pace(&mut self) {
+        let pending = self.replies.has_pending();
+        if pending && self.view_timer.id.is_none() {
+            self.view_timer.set(&mut self.context)
+        }
+        if self.index == self.primary_index()
+            && !self.timed_out
+            && pending
+            && self.block_height(&self.digest_certified) >= self.propose_height
+        {
+            self.do_propose(Default::default())
+        }
+    }
+}
+
+impl Replica {
+    pub const VIEW_CHANGE_TIMEOUT: Duration = Duration::from_millis(1000);
+
+    fn primary_index(&self) -> ReplicaIndex {
+        (self.view_num as usize % self.context.num_replica()) as _
+    }
+
+    fn handle_request(&mut self, remote: Addr, message: Signed<Request>) {
+        match self.replies.insert_request(&message) {
+            RequestStatus::New => {}
+            RequestStatus::Pending => return,
+            RequestStatus::Replied(reply) => {
+                self.context.send(To::Addr(remote), reply.clone());
+                return;
+            }
+        }
+        let pending = self
+            .pending_requests
+            .entry(message.client_index)
+            .or_default();
+        // the acknowledged requests are either executed or aborted
+        *pending = pending.split_off(&message.ack_num);
+        pending.insert(message.request_num, message.inner.clone());
+
+        if self.index != self.primary_index() {
+            return;
+        }
+
+        self.requests.push(message.inner)
+    }
+
+    fn handle_generic(&mut self, _remote: Addr, message: Signed<Generic>) {
+        if !self.is_certified(&message.certified_digest, &message.certificate) {
+            return;
+        }
+        if !message.timeout_certificate.is_empty()
+            && (!self.is_quorum(
+                message
+                    .timeout_certificate
+                    .iter()
+                    .map(|timeout| timeout.replica_index),
+            ) || !message
+                .timeout_certificate
+                .iter()
+                .all(|timeout| self.is_certified(&timeout.certified_digest, &timeout.certificate)))
+        {
+            return;
+        }
+        self.do_reorder_generic(message)
+    }
+
+    // the signatures are checked in `Verify`, which does not know the quorum
+    // size, so a certificate with too few distinct replicas is rejected here
+    fn is_quorum(&self, replica_indexes: impl Iterator<Item = ReplicaIndex>) -> bool {
+        replica_indexes.collect::<HashSet<_>>().len()
+            >= self.context.num_replica() - self.context.num_faulty()
+    }
+
+    fn is_certified(&self, certified_digest: &BlockDigest, certificate: &[Signed<Vote>]) -> bool {
+        *certified_digest == Chain::genesis().digest()
+            || self.is_quorum(certificate.iter().map(|vote| vote.replica_index))
+    }
+
+    fn handle_vote(&mut self, _remote: Addr, message: Signed<Vote>) {
+        let block_digest = message.block_digest;
+        if !self.generics.contains_key(&block_digest) {
+            // TODO
+            return;
+        }
+        let votes = self.votes.entry(block_digest).or_default();
+        if votes.len() == self.context.num_replica() - self.context.num_faulty() {
+            return;
+        }
+        votes.insert(message.replica_index, message);
+        if votes.len() == self.context.num_replica() - self.context.num_faulty() {
+            self.do_update_certified(&block_digest)
+        }
+    }
+
+    fn handle_timeout(&mut self, _remote: Addr, message: Signed<Timeout>) {
+        if message.view_num < self.view_num
+            || !self.is_certified(&message.certified_digest, &message.certificate)
+        {
+            return;
+        }
+        let view_num = message.view_num;
+        let timeouts = self.timeouts.entry(view_num).or_default();
+        if timeouts.len() == self.context.num_replica() - self.context.num_faulty() {
+            return;
+        }
+        timeouts.insert(message.replica_index, message);
+        let num_timeout = timeouts.len();
+        // join the view change as soon as one correct replica has timed out
+        if view_num == self.view_num
+            && !self.timed_out
+            && num_timeout == self.context.num_faulty() + 1
+        {
+            self.do_timeout()
+        }
+        if num_timeout == self.context.num_replica() - self.context.num_faulty() {
+            let timeout_certificate = self.timeouts[&view_num].values().cloned().collect();
+            self.do_enter_view(view_num + 1, timeout_certificate)
+        }
+    }
+
+    fn do_timeout(&mut self) {
+        // println!("! timeout view {}", self.view_num);
+        self.timed_out = true;
+        let timeout = Timeout {
+            view_num: self.view_num,
+            certified_digest: self.digest_certified,
+            certificate: self.votes[&self.digest_certified]
+                .values()
+                .cloned()
+                .collect(),
+            replica_index: self.index,
+        };
+        self.context.send(To::AllReplicaWithLoopback, timeout)
+    }
+
+    fn do_enter_view(&mut self, view_num: u32, timeout_certificate: Vec<Signed<Timeout>>) {
+        if view_num <= self.view_num {
+            return;
+        }
+        // println!("* enter view {view_num}");
+        if !timeout_certificate.is_empty() {
+            self.context.count(VIEW_CHANGE, 1)
+        }
+        self.view_num = view_num;
+        self.timed_out = false;
+        if self.view_timer.id.is_some() {
+            self.view_timer.unset(&mut self.context)
+        }
+        self.timeouts
+            .retain(|&timeout_view, _| timeout_view >= view_num);
+        for timeout in &timeout_certificate {
+            // TODO fetch missing blocks
+            if !self.generics.contains_key(&timeout.certified_digest) {
+                continue;
+            }
+            self.votes
+                .entry(timeout.certified_digest)
+                .or_insert_with(|| {
+                    timeout
+                        .certificate
+                        .iter()
+                        .map(|vote| (vote.replica_index, vote.clone()))
+                        .collect()
+                });
+            self.do_update_certified(&timeout.certified_digest)
+        }
+
+        if self.index == self.primary_index() {
+            let mut requests = Vec::from_iter(
+                self.pending_requests
+                    .values()
+                    .flat_map(|pending| pending.values().cloned()),
+            );
+            requests.sort_unstable_by_key(|request| (request.client_index, request.request_num));
+            self.requests = requests;
+            self.do_propose(timeout_certificate)
+        }
+    }
+
+    fn do_propose(&mut self, timeout_certificate: Vec<Signed<Timeout>>) {
+        self.chain.rebase(
+            self.digest_certified,
+            self.block_height(&self.digest_certified),
+        );
+        let block = if !self.requests.is_empty() {
+            let requests = self.batcher.take(
+                &mut self.requests,
+                self.chain.num_in_flight(),
+                &mut self.context,
+            );
+            self.chain.propose_batch(requests)
+        } else {
+            self.chain.propose_empty()
+        };
+        let generic = Generic {
+            view_num: self.view_num,
+            block,
+            certified_digest: self.digest_certified,
+            certificate: self.votes[&self.digest_certified]
+                .values()
+                .cloned()
+                .collect(),
+            timeout_certificate,
+            replica_index: self.index,
+        };
+        self.propose_height = generic.block.height;
+        self.context.send(To::AllReplicaWithLoopback, generic)
+    }
+
+    fn do_reorder_generic(&mut self, generic: Signed<Generic>) {
+        if !self.generics.contains_key(&generic.block.parent_digest) {
+            self.reordering_generics
+                .entry(generic.block.parent_digest)
+                .or_default()
+                .push(generic);
+            return;
+        }
+
+        if !self.generics.contains_key(&generic.certified_digest) {
+            self.reordering_generics
+                .entry(generic.certified_digest)
+                .or_default()
+                .push(generic);
+            return;
+        }
+
+        let block_digest = generic.block.digest();
+        self.insert_generic(generic);
+        if let Some(generics) = self.reordering_generics.remove(&block_digest) {
+            for generic in generics {
+                self.do_reorder_generic(generic)
+            }
+        }
+    }
+
+    fn insert_generic(&mut self, generic: Signed<Generic>) {
+        let block_digest = generic.block.digest();
+        self.generics.insert(block_digest, generic.clone());
+        self.votes
+            .entry(generic.certified_digest)
+            .or_insert_with(|| {
+                generic
+                    .certificate
+                    .iter()
+                    .map(|vote| (vote.replica_index, vote.clone()))
+                    .collect()
+            });
+
+        if generic.view_num > self.view_num
+            && self.is_quorum(
+                generic
+                    .timeout_certificate
+                    .iter()
+                    .map(|timeout| timeout.replica_index),
+            )
+            && generic
+                .timeout_certificate
+                .iter()
+                .all(|timeout| timeout.view_num + 1 == generic.view_num)
+        {
+            self.do_enter_view(generic.view_num, generic.timeout_certificate.clone())
+        }
+
+        // Fast-HotStuff voting rule: the proposal must directly extend its
+        // certificate, and the certificate must be at least as high as the
+        // highest one locally known, i.e. the lock
+        // for the first proposal of a view this also guarantees that it extends
+        // the highest certificate in the timeout certificate that justifies it.
+        // the rounds are ordered by view first, so the new leader may propose
+        // again at a height whose block is voted but not certified
+        let round = (generic.view_num, generic.block.height);
+        if generic.view_num == self.view_num
+            && !self.timed_out
+            && round > self.voted_round
+            && generic.block.parent_digest == generic.certified_digest
+            && self.block_height(&generic.certified_digest)
+                >= self.block_height(&self.digest_certified)
+        {
+            self.voted_round = round;
+            let vote = Vote {
+                block_digest,
+                replica_index: self.index,
+            };
+            let to = if self.index == self.primary_index() {
+                To::Loopback
+            } else {
+                To::Replica(self.primary_index())
+            };
+            self.context.send(to, vote)
+        }
+        self.do_update(&block_digest)
+    }
+
+    fn do_update(&mut self, block_digest: &BlockDigest) {
+        let block_digest2 = *block_digest;
+        let block_digest1 = self.generics[&block_digest2].certified_digest;
+        let block_digest0 = self.generics[&block_digest1].certified_digest;
+        self.do_update_certified(&block_digest1);
+        // two-chain commit rule: a certified block with a certified direct child
+        if self.generics[&block_digest1].block.parent_digest == block_digest0
+            && block_digest0 != Chain::genesis().digest()
+        {
+            self.do_commit(block_digest0)
+        }
+    }
+
+    fn do_update_certified(&mut self, digest_certified: &BlockDigest) {
+        if self.block_height(digest_certified) > self.block_height(&self.digest_certified) {
+            self.digest_certified = *digest_certified
+        }
+    }
+
+    fn do_commit(&mut self, block_digest: BlockDigest) {
+        // a commit may cover ancestors that are proposed in previous views and
+        // never get committed on their own
+        let mut block_digests = Vec::new();
+        let mut digest = block_digest;
+        while self.block_height(&digest) > self.block_height(&self.chain.digest_execute) {
+            block_digests.push(digest);
+            digest = self.generics[&digest].block.parent_digest;
+        }
+        if block_digests.is_empty() {
+            return;
+        }
+        assert_eq!(
+            digest, self.chain.digest_execute,
+            "commit conflicting blocks"
+        );
+
+        for block_digest in block_digests.into_iter().rev() {
+            let block = &self.generics[&block_digest].block;
+            let execute = self.chain.commit(block);
+            assert!(execute);
+            self.context.count(BLOCK_COMMITTED, 1);
+            for request in &block.requests {
+                if let Some(pending) = self.pending_requests.get_mut(&request.client_index) {
+                    pending.remove(&request.request_num);
+                }
+                // a request may get proposed again by the leader of a later view
+                if self.replies.is_executed(request) {
+                    continue;
+                }
+                let reply = Reply {
+                    request_num: request.request_num,
+                    result: self.app.execute(&request.op),
+                    replica_index: self.index,
+                };
+                self.replies.insert_reply(request, reply.clone());
+                self.context.send(To::Client(request.client_index), reply)
+            }
+            assert!(self.chain.next_execute().is_none())
+        }
+        if self.view_timer.id.is_some() {
+            self.view_timer.unset(&mut self.context)
+        }
+    }
+
+    fn block_height(&self, block_digest: &BlockDigest) -> u32 {
+        self.generics[block_digest].block.height
+    }
+}
+
+impl Sign<Request> for Message {
+    fn sign(message: Request, signer: &crate::crypto::Signer) -> Self {
+        Self::Request(signer.sign_private(message))
+    }
+}
+
+impl Sign<Reply> for Message {
+    fn sign(message: Reply, signer: &crate::crypto::Signer) -> Self {
+        Self::Reply(signer.sign_private(message))
+    }
+}
+
+impl Sign<Generic> for Message {
+    fn sign(message: Generic, signer: &crate::crypto::Signer) -> Self {
+        Self::Generic(signer.sign_public(message))
+    }
+}
+
+impl Sign<Vote> for Message {
+    fn sign(message: Vote, signer: &crate::crypto::Signer) -> Self {
+        Self::Vote(signer.sign_public_for_batch(message))
+    }
+}
+
+impl Sign<Timeout> for Message {
+    fn sign(message: Timeout, signer: &crate::crypto::Signer) -> Self {
+        Self::Timeout(signer.sign_public_for_batch(message))
+    }
+}
+
+fn verify_certificate(
+    verifier: &crate::crypto::Verifier<ReplicaIndex>,
+    certified_digest: &BlockDigest,
+    certificate: &[Signed<Vote>],
+) -> Result<(), crate::crypto::Invalid> {
+    if *certified_digest == Chain::genesis().digest() {
+        return Ok(());
+    }
+    // the size of the certificate is checked by the replica
+    if certificate
+        .iter()
+        .any(|vote| vote.block_digest != *certified_digest)
+    {
+        return Err(crate::crypto::Invalid::Public);
+    }
+    verifier.verify_batch(
+        certificate,
+        &certificate
+            .iter()
+            .map(|vote| vote.replica_index)
+            .collect::<Vec<_>>(),
+    )
+}
+
+impl Verify<ReplicaIndex> for Message {
+    fn verify(
+        &self,
+        verifier: &crate::crypto::Verifier<ReplicaIndex>,
+    ) -> Result<(), crate::crypto::Invalid> {
+        match self {
+            Self::Request(message) => verifier.verify(message, None),
+            Self::Reply(message) => verifier.verify(message, message.replica_index),
+            Self::Generic(message) => {
+                verifier.verify(message, message.replica_index)?;
+                verify_certificate(verifier, &message.certified_digest, &message.certificate)?;
+                // the replica adopts the highest certificate carried by the
+                // timeouts, so they are checked as well
+                for timeout in &message.timeout_certificate {
+                    verify_certificate(verifier, &timeout.certified_digest, &timeout.certificate)?
+                }
+                verifier.verify_batch(
+                    &message.timeout_certificate,
+                    &message
+                        .timeout_certificate
+                        .iter()
+                        .map(|timeout| timeout.replica_index)
+                        .collect::<Vec<_>>(),
+                )
+            }
+            Self::Vote(message) => verifier.verify(message, message.replica_index),
+            Self::Timeout(message) => {
+                verifier.verify(message, message.replica_index)?;
+                verify_certificate(verifier, &message.certified_digest, &message.certificate)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        app::Null,
+        context::simulated::{self, Dispatch},
+        Client as _, Config,
+    };
+
+    use super::*;
+
+    struct System {
+        replicas: Vec<Replica>,
+        client: Client,
+        crashed: Option<ReplicaIndex>,
+        // the leader crashes after its first proposal is voted but before it
+        // gets certified
+        crash_on_vote: bool,
+    }
+
+    impl System {
+        fn replica(&mut self, addr: Addr) -> Option<&mut Replica> {
+            let Addr::Simulated(simulated::Addr::Replica(index)) = addr else {
+                return None;
+            };
+            Some(&mut self.replicas[index as usize]).filter(|_| self.crashed != Some(index))
+        }
+    }
+
+    impl MultiplexReceive for System {
+        type Message = Message;
+
+        fn handle(&mut self, receiver: Addr, remote: Addr, message: Self::Message) {
+            if self.crash_on_vote
+                && matches!(message, Message::Vote(_))
+                && receiver == Addr::Simulated(simulated::Addr::Replica(0))
+            {
+                self.crashed = Some(0)
+            }
+            if let Addr::Simulated(simulated::Addr::Client(_)) = receiver {
+                self.client.handle(message)
+            } else if let Some(replica) = self.replica(receiver) {
+                replica.handle(receiver, remote, message)
+            }
+        }
+
+        fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
+            if let Some(replica) = self.replica(receiver) {
+                replica.handle_loopback(receiver, message)
+            }
+        }
+
+        fn on_timer(&mut self, receiver: Addr, id: TimerId) {
+            if let Addr::Simulated(simulated::Addr::Client(_)) = receiver {
+                self.client.on_timer(id)
+            } else if let Some(replica) = self.replica(receiver) {
+                replica.on_timer(receiver, id)
+            }
+        }
+
+        fn on_pace(&mut self) {
+            for (index, replica) in self.replicas.iter_mut().enumerate() {
+                if self.crashed != Some(index as _) {
+                    replica.on_pace()
+                }
+            }
+        }
+    }
+
+    fn system(dispatch: &Dispatch<Message>, crashed: Option<ReplicaIndex>) -> System {
+        let config = Arc::new(Config {
+            num_faulty: 1,
+            client_addrs: vec![Addr::Simulated(simulated::Addr::Client(0))],
+            replica_addrs: Vec::from_iter(
+                (0..4).map(|index| Addr::Simulated(simulated::Addr::Replica(index))),
+            ),
+            multicast_addr: None,
+        });
+        System {
+            replicas: Vec::from_iter((0..4).map(|index| {
+                let context = dispatch
+                    .register(simulated::Addr::Replica(index))
+                    .into_replication(config.clone());
+                Replica::new(context, index, App::new(Null), BatchConfig::default())
+            })),
+            client: Client::new(
+                dispatch
+                    .register(simulated::Addr::Client(0))
+                    .into_replication(config),
+                0,
+            ),
+            crashed,
+            crash_on_vote: false,
+        }
+    }
+
+    // invoke `num_op` ops at once and run until all of them complete or the
+    // virtual time is up
+    fn run(dispatch: &Dispatch<Message>, system: &mut System, num_op: usize) -> usize {
+        let results = Arc::new(Mutex::new(0));
+        for _ in 0..num_op {
+            let results = results.clone();
+            system
+                .client
+                .invoke(Default::default(), move |_| *results.lock().unwrap() += 1);
+        }
+        let deadline = Duration::from_secs(10);
+        while *results.lock().unwrap() < num_op
+            && dispatch.deliver_event_before(deadline, &mut *system)
+        {
+            system.on_pace()
+        }
+        let num_result = *results.lock().unwrap();
+        num_result
+    }
+
+    #[test]
+    fn commit() {
+        let dispatch = Dispatch::new();
+        let mut system = system(&dispatch, None);
+        assert_eq!(run(&dispatch, &mut system, 3), 3);
+        assert!(dispatch.now() < Replica::VIEW_CHANGE_TIMEOUT);
+        assert!(system.replicas.iter().all(|replica| replica.view_num == 0));
+    }
+
+    #[test]
+    fn leader_crash() {
+        let dispatch = Dispatch::new();
+        let mut system = system(&dispatch, Some(0));
+        assert_eq!(run(&dispatch, &mut system, 3), 3);
+        assert!(dispatch.now() >= Replica::VIEW_CHANGE_TIMEOUT);
+        assert!(system.replicas[1..]
+            .iter()
+            .all(|replica| replica.view_num >= 1));
+    }
+
+    #[test]
+    fn leader_crash_after_vote() {
+        let dispatch = Dispatch::new();
+        let mut system = system(&dispatch, None);
+        system.crash_on_vote = true;
+        assert_eq!(run(&dispatch, &mut system, 3), 3);
+        assert_eq!(system.crashed, Some(0));
+        assert!(system.replicas[1..]
+            .iter()
+            .all(|replica| replica.voted_round.0 >= 1));
+    }
+}
@@ -0,0 +1,214 @@
+// exported ledger of committed blocks, for auditing offline
+//
+// the file uses the same record framing as the write-ahead log (see
+// `storage`), and each record is a bincode encoded `LedgerBlock`, in
+// execution order
+// * `block` is the committed block. the first block extends
+//   `Chain::genesis()`, and every following block extends the previous one
+// * `certificate` is the quorum of signed votes that committed the block,
+//   i.e. PBFT commits or the HotStuff QC that certifies the block
+// * `result_digest` is the merkle root of the results of the block's requests
+//   in order, so ledgers exported by different replicas can be cross checked
+//   without replaying the application, and a result with its proof in a reply
+//   can be checked against the ledger
+// * `state_root` is the root of the authenticated application state after
+//   executing the block, all zero if the application is not authenticated
+//
+// the ledger is not fsync-ed. it is an export for auditing, the write-ahead log
+// is what replicas recover from
+
+use std::{collections::HashSet, fmt::Display, fs::File, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common::{Block, BlockDigest, Chain},
+    crypto::{self, Signed, Verifier},
+    hotstuff,
+    merkle::{self, Tree},
+    pbft,
+    storage::{append_record, read_records},
+    ReplicaIndex,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerBlock {
+    pub block: Block,
+    pub certificate: Certificate,
+    pub result_digest: [u8; 32],
+    pub state_root: merkle::Digest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Certificate {
+    Pbft(Vec<Signed<pbft::Commit>>),
+    HotStuff(Vec<Signed<hotstuff::Vote>>),
+}
+
+// a replica's signed vote that is collected into a certificate
+pub trait Vote {
+    fn block_digest(&self) -> &BlockDigest;
+    fn replica_index(&self) -> ReplicaIndex;
+}
+
+#[derive(Debug)]
+pub enum Ledger {
+    Null,
+    File(File),
+}
+
+impl Ledger {
+    // open the ledger for appending after the `num_recovered` blocks that the
+    // replica has replayed from its write-ahead log. the blocks after them are
+    // dropped, as the replica is going to execute and append them again, so a
+    // replica without a log starts with an empty ledger. a ledger that misses
+    // some of the recovered blocks, e.g. the replica crashed between logging
+    // and exporting a block, is refused as their certificates are gone
+    pub fn open(path: impl AsRef<Path>, num_recovered: usize) -> io::Result<Self> {
+        let mut file = File::options()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let records = read_records::<LedgerBlock>(&mut file)?;
+        if records.len() < num_recovered {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} blocks in ledger, {num_recovered} recovered from log",
+                    records.len()
+                ),
+            ));
+        }
+        file.set_len(match num_recovered {
+            0 => 0,
+            n => records[n - 1].1,
+        })?;
+        Ok(Self::File(file))
+    }
+
+    // the certificate is only collected when the ledger is exported
+    pub fn append(
+        &mut self,
+        block: &Block,
+        certificate: impl FnOnce() -> Certificate,
+        result_digest: [u8; 32],
+        state_root: merkle::Digest,
+    ) {
+        let Self::File(file) = self else {
+            return;
+        };
+        let ledger_block = LedgerBlock {
+            block: block.clone(),
+            certificate: certificate(),
+            result_digest,
+            state_root,
+        };
+        append_record(file, &ledger_block)
+    }
+}
+
+pub fn result_digest(results: &[Vec<u8>]) -> [u8; 32] {
+    Tree::new(results.iter().map(|result| merkle::leaf(result)).collect()).root()
+}
+
+pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<LedgerBlock>> {
+    let records = read_records(&mut File::open(path)?)?;
+    Ok(records.into_iter().map(|(block, _)| block).collect())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Invalid {
+    // the block at the position does not extend its predecessor
+    Parent(usize),
+    Height(usize),
+    // less than n - f distinct voters
+    Quorum(usize),
+    // some vote is for another block
+    Vote(usize),
+    Signature(usize, crypto::Invalid),
+}
+
+impl Display for Invalid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parent(i) => write!(f, "block {i} does not extend its parent"),
+            Self::Height(i) => write!(f, "block {i} has unexpected height"),
+            Self::Quorum(i) => write!(f, "block {i} certificate is not a quorum"),
+            Self::Vote(i) => write!(f, "block {i} certificate has vote for another block"),
+            Self::Signature(i, invalid) => write!(f, "block {i} certificate {invalid}"),
+        }
+    }
+}
+
+impl std::error::Error for Invalid {}
+
+// check chaining from genesis and the certificate of every block
+pub fn audit(
+    blocks: &[LedgerBlock],
+    num_replica: usize,
+    num_faulty: usize,
+    verifier: &Verifier<ReplicaIndex>,
+) -> Result<(), Invalid> {
+    let mut parent = Chain::genesis();
+    for (i, ledger_block) in blocks.iter().enumerate() {
+        let block = &ledger_block.block;
+        if block.parent_digest != parent.digest() {
+            return Err(Invalid::Parent(i));
+        }
+        if block.height != parent.height + 1 {
+            return Err(Invalid::Height(i));
+        }
+        let quorum = num_replica - num_faulty;
+        let digest = block.digest();
+        match &ledger_block.certificate {
+            Certificate::Pbft(certificate) => {
+                verify_certificate(i, certificate, &digest, quorum, verifier)?
+            }
+            Certificate::HotStuff(certificate) => {
+                verify_certificate(i, certificate, &digest, quorum, verifier)?
+            }
+        }
+        parent = block.clone()
+    }
+    Ok(())
+}
+
+fn verify_certificate<V: Vote + std::hash::Hash>(
+    i: usize,
+    certificate: &[Signed<V>],
+    block_digest: &BlockDigest,
+    quorum: usize,
+    verifier: &Verifier<ReplicaIndex>,
+) -> Result<(), Invalid> {
+    if certificate
+        .iter()
+        .any(|vote| vote.block_digest() != block_digest)
+    {
+        return Err(Invalid::Vote(i));
+    }
+    let replica_indexes = certificate
+        .iter()
+        .map(|vote| vote.replica_index())
+        .collect::<Vec<_>>();
+    if replica_indexes.iter().collect::<HashSet<_>>().len() < quorum {
+        return Err(Invalid::Quorum(i));
+    }
+    verifier
+        .verify_batch(certificate, &replica_indexes)
+        .map_err(|invalid| Invalid::Signature(i, invalid))
+}
+
+// the length of the common prefix, or the first position that two ledgers
+// disagree on
+pub fn compare(ledger: &[LedgerBlock], other_ledger: &[LedgerBlock]) -> Result<usize, usize> {
+    for (i, (ledger_block, other_block)) in ledger.iter().zip(other_ledger).enumerate() {
+        if ledger_block.block != other_block.block
+            || ledger_block.result_digest != other_block.result_digest
+            || ledger_block.state_root != other_block.state_root
+        {
+            return Err(i);
+        }
+    }
+    Ok(ledger.len().min(other_ledger.len()))
+}
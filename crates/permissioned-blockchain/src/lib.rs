@@ -1,10 +1,16 @@
 pub mod app;
 pub mod client;
 pub mod common;
+pub mod honey_badger;
 pub mod hotstuff;
+pub mod jolteon;
+pub mod ledger;
+pub mod merkle;
 pub mod minbft;
 pub mod neo;
 pub mod pbft;
+pub mod server;
+pub mod storage;
 pub mod unreplicated;
 pub mod zyzzyva;
 
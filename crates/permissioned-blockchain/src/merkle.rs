@@ -0,0 +1,322 @@
+// binary merkle tree over 32 bytes leaves, for proving a request or a result is
+// part of a block without shipping the whole block
+//
+// leaves and inner nodes are hashed with different prefixes so an inner node
+// cannot be passed as a leaf. the last node of a level with odd width is
+// carried up to the next level as is, instead of being paired with itself
+// (which allows two different lists of leaves to share a root)
+
+use std::collections::{BTreeMap, HashMap};
+
+use k256::sha2::{Digest as _, Sha256};
+use serde::{Deserialize, Serialize};
+
+pub type Digest = [u8; 32];
+
+pub fn leaf(data: &[u8]) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update([0]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+fn node(left: &Digest, right: &Digest) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update([1]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+#[derive(Debug, Clone)]
+pub struct Tree {
+    // leaves at level 0, root at the last level
+    levels: Vec<Vec<Digest>>,
+}
+
+impl Tree {
+    pub fn new(leaves: Vec<Digest>) -> Self {
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node(left, right),
+                    [last] => *last,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(level)
+        }
+        Self { levels }
+    }
+
+    // the root of an empty tree is all zero
+    pub fn root(&self) -> Digest {
+        self.levels
+            .last()
+            .unwrap()
+            .first()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn proof(&self, index: usize) -> Proof {
+        assert!(index < self.levels[0].len());
+        let mut siblings = Vec::new();
+        let mut i = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(i ^ 1) {
+                siblings.push(*sibling)
+            }
+            i /= 2
+        }
+        Proof {
+            index: index as _,
+            num_leaf: self.levels[0].len() as _,
+            siblings,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Proof {
+    index: u32,
+    num_leaf: u32,
+    siblings: Vec<Digest>,
+}
+
+impl Proof {
+    pub fn verify(&self, leaf: &Digest, root: &Digest) -> bool {
+        if self.index >= self.num_leaf {
+            return false;
+        }
+        let (mut i, mut width) = (self.index, self.num_leaf);
+        let mut digest = *leaf;
+        let mut siblings = self.siblings.iter();
+        while width > 1 {
+            if (i ^ 1) < width {
+                let Some(sibling) = siblings.next() else {
+                    return false;
+                };
+                digest = if i % 2 == 0 {
+                    node(&digest, sibling)
+                } else {
+                    node(sibling, &digest)
+                }
+            }
+            i /= 2;
+            width = width.div_ceil(2)
+        }
+        siblings.next().is_none() && &digest == root
+    }
+}
+
+// sparse merkle tree over 256 bits keys, for committing to a key-value state
+//
+// a subtree without leaf hashes to all zero, a subtree with a single leaf
+// hashes to the leaf, so the tree is only as deep as necessary to separate the
+// keys, and the root only depends on the content but not the insertion order
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct SparseTree {
+    leaves: BTreeMap<Digest, Digest>,
+    // subtrees that have at least two leaves, by depth and key prefix
+    nodes: HashMap<(u16, Digest), Digest>,
+}
+
+fn sparse_leaf(key: &Digest, value: &Digest) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update([0]);
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize().into()
+}
+
+fn bit(key: &Digest, i: u16) -> bool {
+    key[i as usize / 8] & (0x80 >> (i % 8)) != 0
+}
+
+// keep the first `depth` bits of the key, clear or set the rest
+fn prefix(key: &Digest, depth: u16, set: bool) -> Digest {
+    let mut prefix = *key;
+    for i in depth..256 {
+        if set {
+            prefix[i as usize / 8] |= 0x80 >> (i % 8)
+        } else {
+            prefix[i as usize / 8] &= !(0x80 >> (i % 8))
+        }
+    }
+    prefix
+}
+
+// the prefix of the sibling subtree at `depth + 1`
+fn sibling_prefix(key: &Digest, depth: u16) -> Digest {
+    let mut prefix = prefix(key, depth + 1, false);
+    prefix[depth as usize / 8] ^= 0x80 >> (depth % 8);
+    prefix
+}
+
+impl SparseTree {
+    pub fn insert(&mut self, key: Digest, value: Digest) {
+        self.leaves.insert(key, value);
+        self.update(&key)
+    }
+
+    pub fn remove(&mut self, key: &Digest) {
+        self.leaves.remove(key);
+        self.update(key)
+    }
+
+    pub fn root(&self) -> Digest {
+        self.subtree(0, &[0; 32])
+    }
+
+    pub fn prove(&self, key: &Digest) -> SparseProof {
+        let mut siblings = Vec::new();
+        let mut depth = 0;
+        loop {
+            let mut leaves = self.leaves(depth, key);
+            match (leaves.next(), leaves.next()) {
+                (Some(_), Some(_)) => {}
+                (leaf, _) => {
+                    return SparseProof {
+                        siblings,
+                        leaf: leaf.map(|(key, value)| (*key, *value)),
+                    }
+                }
+            }
+            siblings.push(self.subtree(depth + 1, &sibling_prefix(key, depth)));
+            depth += 1
+        }
+    }
+
+    fn leaves(&self, depth: u16, key: &Digest) -> impl Iterator<Item = (&Digest, &Digest)> {
+        self.leaves
+            .range(prefix(key, depth, false)..=prefix(key, depth, true))
+    }
+
+    fn subtree(&self, depth: u16, key: &Digest) -> Digest {
+        let mut leaves = self.leaves(depth, key);
+        match (leaves.next(), leaves.next()) {
+            (None, _) => Default::default(),
+            (Some((key, value)), None) => sparse_leaf(key, value),
+            (Some(_), Some(_)) => self.nodes[&(depth, prefix(key, depth, false))],
+        }
+    }
+
+    fn update(&mut self, key: &Digest) {
+        let mut depth = 0;
+        while {
+            let mut leaves = self.leaves(depth, key);
+            leaves.next().is_some() && leaves.next().is_some()
+        } {
+            depth += 1
+        }
+        // the subtrees below are left with at most one leaf
+        for stale_depth in depth.. {
+            if self
+                .nodes
+                .remove(&(stale_depth, prefix(key, stale_depth, false)))
+                .is_none()
+            {
+                break;
+            }
+        }
+        for depth in (0..depth).rev() {
+            let child = self.subtree(depth + 1, &prefix(key, depth + 1, false));
+            let sibling = self.subtree(depth + 1, &sibling_prefix(key, depth));
+            let digest = if bit(key, depth) {
+                node(&sibling, &child)
+            } else {
+                node(&child, &sibling)
+            };
+            self.nodes
+                .insert((depth, prefix(key, depth, false)), digest);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SparseProof {
+    // from the root down
+    siblings: Vec<Digest>,
+    // the only leaf of the subtree the key falls into, if any
+    leaf: Option<(Digest, Digest)>,
+}
+
+impl SparseProof {
+    // prove the key maps to the value, or is absent if the value is none
+    pub fn verify(&self, key: &Digest, value: Option<&Digest>, root: &Digest) -> bool {
+        let depth = self.siblings.len() as u16;
+        let valid_leaf = match (&self.leaf, value) {
+            (Some((leaf_key, leaf_value)), Some(value)) => leaf_key == key && leaf_value == value,
+            (Some((leaf_key, _)), None) => {
+                leaf_key != key && prefix(leaf_key, depth, false) == prefix(key, depth, false)
+            }
+            (None, value) => value.is_none(),
+        };
+        if !valid_leaf {
+            return false;
+        }
+        let mut digest = self
+            .leaf
+            .map(|(key, value)| sparse_leaf(&key, &value))
+            .unwrap_or_default();
+        for (depth, sibling) in self.siblings.iter().enumerate().rev() {
+            digest = if bit(key, depth as _) {
+                node(sibling, &digest)
+            } else {
+                node(&digest, sibling)
+            }
+        }
+        &digest == root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prove_every_leaf() {
+        for num_leaf in 1..20 {
+            let leaves = (0..num_leaf).map(|i| leaf(&[i])).collect::<Vec<_>>();
+            let tree = Tree::new(leaves.clone());
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = tree.proof(index);
+                assert!(proof.verify(leaf, &tree.root()));
+                assert!(!proof.verify(&super::leaf(&[u8::MAX]), &tree.root()));
+            }
+        }
+    }
+
+    #[test]
+    fn sparse_order_independent() {
+        let keys = (0..100).map(|i| leaf(&[i])).collect::<Vec<_>>();
+        let mut tree = SparseTree::default();
+        for key in &keys {
+            tree.insert(*key, *key)
+        }
+        let mut other_tree = SparseTree::default();
+        for key in keys.iter().rev() {
+            other_tree.insert(*key, leaf(&[]));
+            other_tree.insert(*key, *key)
+        }
+        other_tree.insert(leaf(&[u8::MAX]), leaf(&[]));
+        other_tree.remove(&leaf(&[u8::MAX]));
+        assert_eq!(tree.root(), other_tree.root());
+        assert_eq!(tree.nodes, other_tree.nodes);
+
+        for key in &keys {
+            assert!(tree.prove(key).verify(key, Some(key), &tree.root()));
+            assert!(!tree.prove(key).verify(key, None, &tree.root()));
+        }
+        let absent = leaf(&[u8::MAX]);
+        assert!(tree.prove(&absent).verify(&absent, None, &tree.root()));
+        assert!(!tree
+            .prove(&absent)
+            .verify(&absent, Some(&absent), &tree.root()));
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
@@ -8,8 +8,12 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     client::BoxedConsume,
-    common::{Block, BlockDigest, Chain, Request, Timer},
-    context::{Addr, MultiplexReceive},
+    common::{
+        BatchConfig, Batcher, Block, BlockDigest, Chain, ClientCore, ClientStep, ClientTable,
+        ReadOnlyReply, ReadOnlyRequest, Request, RequestStatus, StateCheck, StateCheckpoint,
+        BLOCK_COMMITTED,
+    },
+    context::{Addr, MultiplexReceive, TimerId},
     crypto::{Sign, Signature, Signed, Verify},
     App, ClientIndex, Context, ReplicaIndex, To,
 };
@@ -18,14 +22,19 @@ use crate::{
 pub enum Message {
     Request(Signed<Request>),
     Reply(Signed<Reply>),
+    ReadOnlyRequest(Signed<ReadOnlyRequest>),
+    ReadOnlyReply(Signed<ReadOnlyReply>),
     Prepare(Signed<Prepare>),
     Commit(Signed<Commit>),
+    Checkpoint(Signed<Checkpoint>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Reply {
     request_num: u32,
     result: Vec<u8>,
+    // for clients to learn the current primary
+    view_num: u32,
     block_digest: BlockDigest,
     replica_index: ReplicaIndex,
 }
@@ -43,71 +52,102 @@ pub struct Commit {
     replica_index: ReplicaIndex,
 }
 
+// only for comparing application states, see `StateCheck`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Checkpoint {
+    height: u32,
+    state: StateCheckpoint,
+    replica_index: ReplicaIndex,
+}
+
 #[derive(Debug)]
 pub struct Client {
-    index: ClientIndex,
     shared: Arc<Mutex<ClientShared>>,
 }
 
 #[derive(Debug)]
 struct ClientShared {
-    context: Context<Message>,
-    request_num: u32,
-    invoke: Option<ClientInvoke>,
-    resend_timer: Timer,
-}
-
-#[derive(Debug)]
-struct ClientInvoke {
-    op: Vec<u8>,
-    replies: HashMap<ReplicaIndex, Reply>,
-    consume: BoxedConsume,
+    core: ClientCore<Message, Reply>,
+    // the latest view learned from replies
+    view_num: u32,
 }
 
 impl Client {
     pub fn new(context: Context<Message>, index: ClientIndex) -> Self {
         Self {
-            index,
             shared: Arc::new(Mutex::new(ClientShared {
-                context,
-                request_num: 0,
-                invoke: None,
-                resend_timer: Timer::new(Duration::from_millis(100)),
+                core: ClientCore::new(context, index),
+                view_num: 0,
             })),
         }
     }
 }
 
+impl ClientShared {
+    fn primary_index(&self) -> ReplicaIndex {
+        (self.view_num as usize % self.core.context.num_replica()) as _
+    }
+
+    fn step(&mut self, step: ClientStep<Reply>) -> Option<(BoxedConsume, Vec<u8>)> {
+        match step {
+            ClientStep::Pending => None,
+            // the primary may be faulty or replaced, the backups relay the
+            // request to the one they know
+            ClientStep::Resend(request) => {
+                self.core.context.send(To::AllReplica, request);
+                None
+            }
+            ClientStep::FallBack(request) => {
+                let primary_index = self.primary_index();
+                self.core.context.send(To::Replica(primary_index), request);
+                None
+            }
+            ClientStep::Complete(invoke, result) => Some((invoke.consume, result)),
+        }
+    }
+}
+
 impl crate::Client for Client {
     type Message = Message;
 
-    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) {
+    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
         let shared = &mut *self.shared.lock().unwrap();
-        assert!(shared.invoke.is_none());
-        shared.request_num += 1;
-        shared.invoke = Some(ClientInvoke {
-            op: op.clone(),
-            replies: Default::default(),
-            consume: consume.into(),
-        });
-        let request = Request {
-            client_index: self.index,
-            request_num: shared.request_num,
-            op,
-        };
-        shared.context.send(To::AllReplica, request);
-        shared.resend_timer.set(&mut shared.context)
+        let request = shared.core.invoke(op, consume.into());
+        let request_num = request.request_num;
+        let primary_index = shared.primary_index();
+        shared
+            .core
+            .context
+            .send(To::Replica(primary_index), request);
+        request_num
+    }
+
+    fn invoke_read_only(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
+        let shared = &mut *self.shared.lock().unwrap();
+        shared.core.invoke_read_only(op, consume.into())
+    }
+
+    fn abort(&self, invoke_num: u32) -> Option<BoxedConsume> {
+        self.shared.lock().unwrap().core.abort(invoke_num)
     }
 
     fn handle(&self, message: Self::Message) {
+        if let Message::ReadOnlyReply(message) = message {
+            let mut shared = self.shared.lock().unwrap();
+            let step = shared.core.handle_read_only_reply(message.inner);
+            let complete = shared.step(step);
+            drop(shared);
+            if let Some((consume, result)) = complete {
+                consume.apply(result)
+            }
+            return;
+        }
         let Message::Reply(message) = message else {
             unimplemented!()
         };
         let mut shared = self.shared.lock().unwrap();
-        if message.request_num != shared.request_num {
-            return;
-        }
-        let Some(invoke) = &mut shared.invoke else {
+        let num_faulty = shared.core.context.num_faulty();
+        let Some(invoke) = shared.core.invoke_mut(message.request_num) else {
             return;
         };
         invoke
@@ -117,22 +157,25 @@ impl crate::Client for Client {
             .replies
             .values()
             .filter(|reply| {
-                (reply.block_digest, &reply.result) == (message.block_digest, &message.result)
+                (reply.view_num, reply.block_digest, &reply.result)
+                    == (message.view_num, message.block_digest, &message.result)
             })
             .count();
-        assert!(num_match <= shared.context.num_faulty() + 1);
-        if num_match == shared.context.num_faulty() + 1 {
-            {
-                let shared = &mut *shared;
-                shared.resend_timer.unset(&mut shared.context);
-            }
-            let invoke = shared.invoke.take().unwrap();
+        assert!(num_match <= num_faulty + 1);
+        if num_match == num_faulty + 1 {
+            let invoke = shared.core.complete(message.request_num);
+            shared.view_num = shared.view_num.max(message.view_num);
             drop(shared);
-
-            let _op = invoke.op;
             invoke.consume.apply(message.inner.result)
         }
     }
+
+    fn on_timer(&self, id: TimerId) {
+        let shared = &mut *self.shared.lock().unwrap();
+        for step in shared.core.on_timer(id) {
+            assert!(shared.step(step).is_none())
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -141,23 +184,40 @@ pub struct Replica {
     index: ReplicaIndex,
     view_num: u32,
     requests: Vec<Request>,
+    replies: ClientTable<Reply>,
     prepares: HashMap<BlockDigest, Signed<Prepare>>,
     commit_certificates: HashMap<BlockDigest, HashMap<ReplicaIndex, Signed<Commit>>>,
     chain: Chain,
     app: App,
+    batcher: Batcher,
+    pub state_check: StateCheck,
+    // zero for unbounded
+    pub pipeline_window: u32,
+    // received beyond the window, by block height
+    pending_prepares: BTreeMap<u32, Signed<Prepare>>,
 }
 
 impl Replica {
-    pub fn new(context: Context<Message>, index: ReplicaIndex, app: App) -> Self {
+    pub fn new(
+        context: Context<Message>,
+        index: ReplicaIndex,
+        app: App,
+        batching: BatchConfig,
+    ) -> Self {
         Self {
             context,
             index,
             view_num: 0,
             requests: Default::default(),
+            replies: Default::default(),
             prepares: Default::default(),
             commit_certificates: Default::default(),
             chain: Default::default(),
             app,
+            batcher: Batcher::new(batching),
+            state_check: StateCheck::new(0),
+            pipeline_window: 0,
+            pending_prepares: Default::default(),
         }
     }
 }
@@ -170,15 +230,20 @@ impl MultiplexReceive for Replica {
         // println!("{message:?}");
         match message {
             Message::Request(message) => self.handle_request(remote, message),
+            Message::ReadOnlyRequest(message) => self.handle_read_only_request(remote, message),
             Message::Prepare(message) => self.handle_prepare(remote, message),
             Message::Commit(message) => self.handle_commit(remote, message),
+            Message::Checkpoint(message) => self.handle_checkpoint(remote, message),
             _ => unimplemented!(),
         }
     }
 
-    fn on_timer(&mut self, receiver: Addr, _: crate::context::TimerId) {
+    fn on_timer(&mut self, receiver: Addr, id: crate::context::TimerId) {
         assert_eq!(receiver, self.context.addr());
-        todo!()
+        // the alarm may be delivered after the timer get unset
+        if self.batcher.on_timer(id, &mut self.context) {
+            self.on_pace()
+        }
     }
 
     fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
@@ -192,7 +257,14 @@ impl MultiplexReceive for Replica {
     }
 
     fn on_pace(&mut self) {
-        if self.index == self.primary_index() && !self.requests.is_empty() {
+        if self.index == self.primary_index()
+            && !self.window_full()
+            && self.batcher.ready(
+                &self.requests,
+                self.chain.num_in_flight(),
+                &mut self.context,
+            )
+        {
             self.do_propose()
         }
     }
@@ -203,17 +275,40 @@ impl Replica {
         (self.view_num as usize % self.context.num_replica()) as _
     }
 
+    fn window_full(&self) -> bool {
+        self.pipeline_window != 0 && self.chain.num_in_flight() >= self.pipeline_window
+    }
+
+    // the high water mark, above which proposals wait for execution to catch up
+    fn in_window(&self, height: u32) -> bool {
+        self.pipeline_window == 0 || height <= self.chain.executed_height() + self.pipeline_window
+    }
+
     fn handle_request(&mut self, _remote: Addr, message: Signed<Request>) {
+        match self.replies.insert_request(&message) {
+            RequestStatus::New => {}
+            RequestStatus::Pending => return,
+            RequestStatus::Replied(reply) => {
+                self.context
+                    .send(To::Client(message.client_index), reply.clone());
+                return;
+            }
+        }
+
         if self.index != self.primary_index() {
-            // TODO
+            // the client has timed out on the primary
+            self.context
+                .send(To::Replica(self.primary_index()), message.inner);
             return;
         }
-
-        // TODO check resend
-
         self.requests.push(message.inner);
     }
 
+    fn handle_read_only_request(&mut self, _remote: Addr, message: Signed<ReadOnlyRequest>) {
+        let reply = ReadOnlyReply::new(&self.app, &message, self.index);
+        self.context.send(To::Client(message.client_index), reply)
+    }
+
     fn handle_prepare(&mut self, _remote: Addr, message: Signed<Prepare>) {
         if message.view_num < self.view_num {
             return;
@@ -224,15 +319,28 @@ impl Replica {
             return;
         }
 
+        if !self.in_window(message.block.height) {
+            self.pending_prepares.insert(message.block.height, message);
+            return;
+        }
+        self.do_commit(message)
+    }
+
+    fn do_commit(&mut self, message: Signed<Prepare>) {
         let block_digest = message.block.digest();
-        self.prepares.insert(block_digest, message);
+        let is_new = self.prepares.insert(block_digest, message).is_none();
         // assert_ne!(self.index, self.primary_index());
         let commit = Commit {
             view_num: self.view_num,
             block_digest,
             replica_index: self.index,
         };
-        self.context.send(To::AllReplicaWithLoopback, commit)
+        self.context.send(To::AllReplicaWithLoopback, commit);
+        // the commits may be collected before the prepare is accepted, e.g. by
+        // a backup that lags beyond the window
+        if is_new && self.is_committed(&block_digest) {
+            self.do_execute(block_digest)
+        }
     }
 
     fn handle_commit(&mut self, _remote: Addr, message: Signed<Commit>) {
@@ -248,11 +356,25 @@ impl Replica {
         self.insert_commit(message);
     }
 
+    fn handle_checkpoint(&mut self, _remote: Addr, message: Signed<Checkpoint>) {
+        let Checkpoint {
+            height,
+            state,
+            replica_index,
+        } = message.inner;
+        self.state_check
+            .insert(height, replica_index, state, &self.context)
+    }
+
     fn do_propose(&mut self) {
         assert_eq!(self.index, self.primary_index());
         let prepare = Prepare {
             view_num: self.view_num,
-            block: self.chain.propose(&mut self.requests),
+            block: self.chain.propose_batch(self.batcher.take(
+                &mut self.requests,
+                self.chain.num_in_flight(),
+                &mut self.context,
+            )),
         };
         self.context.send(To::AllReplicaWithLoopback, prepare)
     }
@@ -261,40 +383,70 @@ impl Replica {
         let block_digest = commit.block_digest;
         let commit_certificate = self.commit_certificates.entry(block_digest).or_default();
         if commit_certificate.len() == self.context.num_faulty() + 1 {
-            if commit.replica_index != self.index {
-                return;
-            }
-        } else {
-            commit_certificate.insert(commit.replica_index, commit);
+            return;
         }
-        if self.prepares.contains_key(&block_digest)
-            && commit_certificate.len() == self.context.num_faulty() + 1
+        commit_certificate.insert(commit.replica_index, commit);
+        if commit_certificate.len() == self.context.num_faulty() + 1
+            && self.prepares.contains_key(&block_digest)
         {
             self.do_execute(block_digest);
         }
     }
 
+    fn is_committed(&self, block_digest: &BlockDigest) -> bool {
+        self.commit_certificates
+            .get(block_digest)
+            .is_some_and(|certificate| certificate.len() == self.context.num_faulty() + 1)
+    }
+
     fn do_execute(&mut self, block_digest: BlockDigest) {
+        let mut block_digest = block_digest;
         let mut block = &self.prepares[&block_digest].block;
         if !self.chain.commit(block) {
             return;
         }
         loop {
+            self.context.count(BLOCK_COMMITTED, 1);
+            let mut results = Vec::new();
             for request in &block.requests {
                 let reply = Reply {
                     request_num: request.request_num,
                     result: self.app.execute(&request.op),
+                    view_num: self.view_num,
                     block_digest,
                     replica_index: self.index,
                 };
+                results.push(reply.result.clone());
+                self.replies.insert_reply(request, reply.clone());
                 self.context.send(To::Client(request.client_index), reply)
             }
-            if let Some(block_digest) = self.chain.next_execute() {
+            if let Some(state) =
+                self.state_check
+                    .on_execute(block.height, &results, &self.app, &self.context)
+            {
+                let checkpoint = Checkpoint {
+                    height: block.height,
+                    state,
+                    replica_index: self.index,
+                };
+                self.context.send(To::AllReplica, checkpoint)
+            }
+            if let Some(next_digest) = self.chain.next_execute() {
+                block_digest = next_digest;
                 block = &self.prepares[&block_digest].block;
             } else {
                 break;
             }
         }
+        while let Some((&height, _)) = self.pending_prepares.first_key_value() {
+            if !self.in_window(height) {
+                break;
+            }
+            let prepare = self.pending_prepares.remove(&height).unwrap();
+            if prepare.view_num == self.view_num {
+                self.do_commit(prepare)
+            }
+        }
     }
 }
 
@@ -310,6 +462,18 @@ impl Sign<Reply> for Message {
     }
 }
 
+impl Sign<ReadOnlyRequest> for Message {
+    fn sign(message: ReadOnlyRequest, signer: &crate::crypto::Signer) -> Self {
+        Self::ReadOnlyRequest(signer.sign_private(message))
+    }
+}
+
+impl Sign<ReadOnlyReply> for Message {
+    fn sign(message: ReadOnlyReply, signer: &crate::crypto::Signer) -> Self {
+        Self::ReadOnlyReply(signer.sign_private(message))
+    }
+}
+
 fn simulate_sgx() {
     let start = Instant::now();
     while start.elapsed() < Duration::from_micros(16) {}
@@ -335,6 +499,12 @@ impl Sign<Commit> for Message {
     }
 }
 
+impl Sign<Checkpoint> for Message {
+    fn sign(message: Checkpoint, signer: &crate::crypto::Signer) -> Self {
+        Self::Checkpoint(signer.sign_public(message))
+    }
+}
+
 impl Verify<ReplicaIndex> for Message {
     fn verify(
         &self,
@@ -343,10 +513,108 @@ impl Verify<ReplicaIndex> for Message {
         match self {
             Self::Request(message) => verifier.verify(message, None),
             Self::Reply(message) => verifier.verify(message, message.replica_index),
+            Self::ReadOnlyRequest(message) => verifier.verify(message, None),
+            Self::ReadOnlyReply(message) => verifier.verify(message, message.replica_index),
             Self::Prepare(_) | Self::Commit(_) => {
                 simulate_sgx();
                 Ok(())
             }
+            Self::Checkpoint(message) => verifier.verify(message, message.replica_index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        app::Null,
+        context::{
+            simulated::{self, Dispatch},
+            MultiplexReceive,
+        },
+        crypto::Signer,
+        Config,
+    };
+
+    use super::*;
+
+    // only the messages to the replica itself are delivered
+    struct Isolated(Replica);
+
+    impl MultiplexReceive for Isolated {
+        type Message = Message;
+
+        fn handle(&mut self, receiver: Addr, remote: Addr, message: Self::Message) {
+            if receiver == self.0.context.addr() {
+                self.0.handle(receiver, remote, message)
+            }
+        }
+
+        fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
+            self.0.handle_loopback(receiver, message)
+        }
+
+        fn on_timer(&mut self, receiver: Addr, id: TimerId) {
+            self.0.on_timer(receiver, id)
+        }
+    }
+
+    #[test]
+    fn lagging_backup_beyond_window() {
+        let dispatch = Dispatch::new();
+        let addr = |index| Addr::Simulated(simulated::Addr::Replica(index));
+        let config = Arc::new(Config {
+            num_faulty: 1,
+            client_addrs: vec![Addr::Simulated(simulated::Addr::Client(0))],
+            replica_addrs: Vec::from_iter((0..3).map(addr)),
+            multicast_addr: None,
+        });
+        let context = dispatch
+            .register(simulated::Addr::Replica(2))
+            .into_replication(config);
+        let mut replica = Isolated(Replica::new(
+            context,
+            2,
+            App::new(Null),
+            BatchConfig::default(),
+        ));
+        replica.0.pipeline_window = 2;
+
+        let mut chain = Chain::new();
+        let blocks = Vec::from_iter((1..=6).map(|request_num| {
+            chain.propose_batch(vec![Request {
+                client_index: 0,
+                request_num,
+                ack_num: 0,
+                op: Default::default(),
+            }])
+        }));
+        for block in &blocks {
+            let prepare = Prepare {
+                view_num: 0,
+                block: block.clone(),
+            };
+            replica.handle(addr(2), addr(0), Message::sign(prepare, &Signer::Simulated))
+        }
+        // the others have committed all blocks before this backup catches up,
+        // so the commits of the blocks beyond its window arrive first
+        for block in blocks.iter().rev() {
+            for index in 0..2 {
+                let commit = Commit {
+                    view_num: 0,
+                    block_digest: block.digest(),
+                    replica_index: index,
+                };
+                replica.handle(
+                    addr(2),
+                    addr(index),
+                    Message::sign(commit, &Signer::Simulated),
+                )
+            }
         }
+        while dispatch.deliver_event(&mut replica) {}
+        assert_eq!(replica.0.chain.executed_height(), 6);
     }
 }
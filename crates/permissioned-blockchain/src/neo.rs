@@ -2,7 +2,6 @@ use std::{
     collections::HashMap,
     ops::RangeInclusive,
     sync::{Arc, Mutex},
-    time::Duration,
 };
 
 use k256::sha2::{Digest, Sha256};
@@ -10,10 +9,13 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     client::BoxedConsume,
-    common::{Request, Timer},
+    common::{
+        ClientCore, ClientStep, ClientTable, ReadOnlyReply, ReadOnlyRequest, Request,
+        RequestStatus, BLOCK_COMMITTED,
+    },
     context::{
         ordered_multicast::Signature::{K256Unverified, K256},
-        Addr, MultiplexReceive, OrderedMulticast, OrderedMulticastReceive,
+        Addr, MultiplexReceive, OrderedMulticast, OrderedMulticastReceive, TimerId,
     },
     crypto::{Hasher, Sign, Signed, Verify},
     App, ClientIndex, Context, ReplicaIndex, To,
@@ -23,6 +25,8 @@ use crate::{
 pub enum Message {
     Request(OrderedMulticast<Request>),
     Reply(Signed<Reply>),
+    ReadOnlyRequest(Signed<ReadOnlyRequest>),
+    ReadOnlyReply(Signed<ReadOnlyReply>),
     Confirm(Signed<Confirm>),
     Query(Signed<Query>),
     QueryOk(QueryOk),
@@ -58,70 +62,69 @@ pub struct QueryOk {
 
 #[derive(Debug)]
 pub struct Client {
-    index: ClientIndex,
-    shared: Arc<Mutex<ClientShared>>,
-}
-
-#[derive(Debug)]
-struct ClientShared {
-    context: Context<Message>,
-    request_num: u32,
-    invoke: Option<ClientInvoke>,
-    resend_timer: Timer,
-}
-
-#[derive(Debug)]
-struct ClientInvoke {
-    op: Vec<u8>,
-    replies: HashMap<ReplicaIndex, Reply>,
-    consume: BoxedConsume,
+    shared: Arc<Mutex<ClientCore<Message, Reply>>>,
 }
 
 impl Client {
     pub fn new(context: Context<Message>, index: ClientIndex) -> Self {
         Self {
-            index,
-            shared: Arc::new(Mutex::new(ClientShared {
-                context,
-                request_num: 0,
-                invoke: None,
-                resend_timer: Timer::new(Duration::from_millis(100)),
-            })),
+            shared: Arc::new(Mutex::new(ClientCore::new(context, index))),
         }
     }
 }
 
+fn client_step(
+    core: &mut ClientCore<Message, Reply>,
+    step: ClientStep<Reply>,
+) -> Option<(BoxedConsume, Vec<u8>)> {
+    match step {
+        ClientStep::Pending => None,
+        ClientStep::Resend(request) => panic!("request {} timeout", request.request_num),
+        ClientStep::FallBack(request) => {
+            core.context.send_ordered_multicast(request);
+            None
+        }
+        ClientStep::Complete(invoke, result) => Some((invoke.consume, result)),
+    }
+}
+
 impl crate::Client for Client {
     type Message = Message;
 
-    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) {
-        let shared = &mut *self.shared.lock().unwrap();
-        assert!(shared.invoke.is_none());
-        shared.request_num += 1;
-        shared.invoke = Some(ClientInvoke {
-            op: op.clone(),
-            replies: Default::default(),
-            consume: consume.into(),
-        });
-        let request = Request {
-            client_index: self.index,
-            request_num: shared.request_num,
-            op,
-        };
-        shared.context.send_ordered_multicast(request);
-        shared.resend_timer.set(&mut shared.context)
+    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
+        let core = &mut *self.shared.lock().unwrap();
+        let request = core.invoke(op, consume.into());
+        let request_num = request.request_num;
+        core.context.send_ordered_multicast(request);
+        request_num
+    }
+
+    fn invoke_read_only(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
+        let core = &mut *self.shared.lock().unwrap();
+        core.invoke_read_only(op, consume.into())
+    }
+
+    fn abort(&self, invoke_num: u32) -> Option<BoxedConsume> {
+        self.shared.lock().unwrap().abort(invoke_num)
     }
 
     fn handle(&self, message: Self::Message) {
+        if let Message::ReadOnlyReply(message) = message {
+            let mut core = self.shared.lock().unwrap();
+            let step = core.handle_read_only_reply(message.inner);
+            let complete = client_step(&mut core, step);
+            drop(core);
+            if let Some((consume, result)) = complete {
+                consume.apply(result)
+            }
+            return;
+        }
         let Message::Reply(message) = message else {
             unimplemented!()
         };
-        // println!("{message:?}");
-        let mut shared = self.shared.lock().unwrap();
-        if message.request_num != shared.request_num {
-            return;
-        }
-        let Some(invoke) = &mut shared.invoke else {
+        let mut core = self.shared.lock().unwrap();
+        let quorum = core.context.num_replica() - core.context.num_faulty();
+        let Some(invoke) = core.invoke_mut(message.request_num) else {
             return;
         };
         invoke
@@ -135,19 +138,20 @@ impl crate::Client for Client {
                     == (message.epoch_num, message.seq_num, &message.result)
             })
             .count()
-            >= shared.context.num_replica() - shared.context.num_faulty()
+            >= quorum
         {
-            {
-                let shared = &mut *shared;
-                shared.resend_timer.unset(&mut shared.context);
-            }
-            let invoke = shared.invoke.take().unwrap();
-            drop(shared);
-
-            let _op = invoke.op;
+            let invoke = core.complete(message.request_num);
+            drop(core);
             invoke.consume.apply(message.inner.result)
         }
     }
+
+    fn on_timer(&self, id: TimerId) {
+        let core = &mut *self.shared.lock().unwrap();
+        for step in core.on_timer(id) {
+            assert!(client_step(core, step).is_none())
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -160,7 +164,7 @@ pub struct Replica {
     requests: Vec<OrderedMulticast<Request>>,
     ordered_num: u32,
     verified_num: u32,
-    replies: HashMap<ClientIndex, Reply>,
+    replies: ClientTable<Reply>,
     app: App,
 
     confirm: bool,
@@ -229,6 +233,7 @@ impl MultiplexReceive for Replica {
         }
         match message {
             Message::Request(message) => self.handle_request(remote, message),
+            Message::ReadOnlyRequest(message) => self.handle_read_only_request(remote, message),
             Message::Confirm(message) => self.handle_confirm(remote, message),
             Message::Query(message) => self.handle_query(remote, message),
             Message::QueryOk(message) => self.handle_query_ok(remote, message),
@@ -325,6 +330,11 @@ impl Replica {
         self.verified_num = verified_num
     }
 
+    fn handle_read_only_request(&mut self, _remote: Addr, message: Signed<ReadOnlyRequest>) {
+        let reply = ReadOnlyReply::new(&self.app, &message, self.index);
+        self.context.send(To::Client(message.client_index), reply)
+    }
+
     fn handle_confirm(&mut self, _remote: Addr, message: Signed<Confirm>) {
         assert!(self.confirm);
         // println!("> confirm #{} {:?}", message.replica_index, message.op_nums);
@@ -377,15 +387,17 @@ impl Replica {
     }
 
     fn do_commit(&mut self, op_num: u32) {
+        // the sequencer orders requests one by one, so each op counts as a block
+        self.context.count(BLOCK_COMMITTED, 1);
         let request = &I(&self.requests)[op_num];
-        match self.replies.get(&request.client_index) {
-            Some(reply) if reply.request_num > request.request_num => return,
-            Some(reply) if reply.request_num == request.request_num => {
+        match self.replies.insert_request(request) {
+            RequestStatus::New => {}
+            RequestStatus::Pending => return,
+            RequestStatus::Replied(reply) => {
                 self.context
                     .send(To::Client(request.client_index), reply.clone());
                 return;
             }
-            _ => {}
         }
         let reply = Reply {
             epoch_num: 0,
@@ -394,6 +406,7 @@ impl Replica {
             seq_num: request.seq_num,
             replica_index: self.index,
         };
+        self.replies.insert_reply(request, reply.clone());
         self.context.send(To::Client(request.client_index), reply)
     }
 
@@ -483,6 +496,18 @@ impl Sign<Reply> for Message {
     }
 }
 
+impl Sign<ReadOnlyRequest> for Message {
+    fn sign(message: ReadOnlyRequest, signer: &crate::crypto::Signer) -> Self {
+        Self::ReadOnlyRequest(signer.sign_private(message))
+    }
+}
+
+impl Sign<ReadOnlyReply> for Message {
+    fn sign(message: ReadOnlyReply, signer: &crate::crypto::Signer) -> Self {
+        Self::ReadOnlyReply(signer.sign_private(message))
+    }
+}
+
 impl Sign<Confirm> for Message {
     fn sign(message: Confirm, signer: &crate::crypto::Signer) -> Self {
         Message::Confirm(signer.sign_public(message))
@@ -509,6 +534,8 @@ impl Verify<ReplicaIndex> for Message {
         match self {
             Self::Request(message) => verifier.verify_ordered_multicast(message),
             Self::Reply(message) => verifier.verify(message, message.replica_index),
+            Self::ReadOnlyRequest(message) => verifier.verify(message, None),
+            Self::ReadOnlyReply(message) => verifier.verify(message, message.replica_index),
             Self::Confirm(message) => verifier.verify(message, message.replica_index),
             Self::Query(message) => verifier.verify(message, message.replica_index),
             Self::QueryOk(message) => verifier.verify_ordered_multicast(&message.request),
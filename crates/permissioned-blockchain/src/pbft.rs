@@ -1,16 +1,22 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     sync::{Arc, Mutex},
-    time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
     client::BoxedConsume,
-    common::{Block, BlockDigest, Chain, Request, Timer},
-    context::{Addr, MultiplexReceive},
-    crypto::{Sign, Signed, Verify},
+    common::{
+        BatchConfig, Batcher, Block, BlockDigest, BlockHeader, Chain, ClientCore, ClientStep,
+        ClientTable, ReadOnlyReply, ReadOnlyRequest, Request, RequestStatus, StateCheck,
+        StateCheckpoint, BLOCK_COMMITTED,
+    },
+    context::{Addr, MultiplexReceive, TimerId},
+    crypto::{Sign, Signature, Signed, Verify},
+    ledger::{self, Certificate, Ledger},
+    merkle::{self, Proof, Tree},
+    storage::{deserialize_state, Entry, Storage},
     App, ClientIndex, Context, ReplicaIndex, To,
 };
 
@@ -18,16 +24,32 @@ use crate::{
 pub enum Message {
     Request(Signed<Request>),
     Reply(Signed<Reply>),
+    ReadOnlyRequest(Signed<ReadOnlyRequest>),
+    ReadOnlyReply(Signed<ReadOnlyReply>),
     PrePrepare(Signed<PrePrepare>),
     Prepare(Signed<Prepare>),
     Commit(Signed<Commit>),
+    Checkpoint(Signed<Checkpoint>),
+    Execution(Signed<Execution>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Reply {
     request_num: u32,
     result: Vec<u8>,
-    block_digest: BlockDigest,
+    // for clients to learn the current primary
+    view_num: u32,
+    // the request is in the block with the header, and the result is in the
+    // results of the block
+    header: BlockHeader,
+    request_proof: Proof,
+    results_root: merkle::Digest,
+    result_proof: Proof,
+    // after executing the whole block
+    state_root: merkle::Digest,
+    // f + 1 replicas agree on the roots, so the client accepts a single reply.
+    // empty unless `Replica::certify_execution`
+    certificate: Vec<Signed<Execution>>,
     replica_index: ReplicaIndex,
 }
 
@@ -51,95 +73,188 @@ pub struct Commit {
     replica_index: ReplicaIndex,
 }
 
+// the roots are only known after executing the block, so they are agreed on
+// in a round after the commit rather than in the block header
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Execution {
+    block_digest: BlockDigest,
+    height: u32,
+    results_root: merkle::Digest,
+    state_root: merkle::Digest,
+    replica_index: ReplicaIndex,
+}
+
+// only for comparing application states, see `StateCheck`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Checkpoint {
+    height: u32,
+    state: StateCheckpoint,
+    replica_index: ReplicaIndex,
+}
+
+// safety-critical state that is written to storage before sending the
+// corresponding messages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum State {
+    // proposed by primary, or prepared by backup
+    PrePrepare(PrePrepare),
+    Commit(Commit),
+}
+
 #[derive(Debug)]
 pub struct Client {
-    index: ClientIndex,
     shared: Arc<Mutex<ClientShared>>,
 }
 
 #[derive(Debug)]
 struct ClientShared {
-    context: Context<Message>,
-    request_num: u32,
-    invoke: Option<ClientInvoke>,
-    resend_timer: Timer,
-}
-
-#[derive(Debug)]
-struct ClientInvoke {
-    op: Vec<u8>,
-    replies: HashMap<ReplicaIndex, Reply>,
-    consume: BoxedConsume,
+    core: ClientCore<Message, Reply>,
+    // the latest view learned from replies
+    view_num: u32,
 }
 
 impl Client {
     pub fn new(context: Context<Message>, index: ClientIndex) -> Self {
         Self {
-            index,
             shared: Arc::new(Mutex::new(ClientShared {
-                context,
-                request_num: 0,
-                invoke: None,
-                resend_timer: Timer::new(Duration::from_millis(100)),
+                core: ClientCore::new(context, index),
+                view_num: 0,
             })),
         }
     }
 }
 
+impl ClientShared {
+    fn primary_index(&self) -> ReplicaIndex {
+        (self.view_num as usize % self.core.context.num_replica()) as _
+    }
+
+    fn step(&mut self, step: ClientStep<Reply>) -> Option<(BoxedConsume, Vec<u8>)> {
+        match step {
+            ClientStep::Pending => None,
+            // the primary may be faulty or replaced, the backups relay the
+            // request to the one they know
+            ClientStep::Resend(request) => {
+                self.core.context.send(To::AllReplica, request);
+                None
+            }
+            ClientStep::FallBack(request) => {
+                let primary_index = self.primary_index();
+                self.core.context.send(To::Replica(primary_index), request);
+                None
+            }
+            ClientStep::Complete(invoke, result) => Some((invoke.consume, result)),
+        }
+    }
+}
+
 impl crate::Client for Client {
     type Message = Message;
 
-    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) {
+    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
         let shared = &mut *self.shared.lock().unwrap();
-        assert!(shared.invoke.is_none());
-        shared.request_num += 1;
-        shared.invoke = Some(ClientInvoke {
-            op: op.clone(),
-            replies: Default::default(),
-            consume: consume.into(),
-        });
-        let request = Request {
-            client_index: self.index,
-            request_num: shared.request_num,
-            op,
-        };
-        // TODO
-        shared.context.send(To::Replica(0), request);
-        shared.resend_timer.set(&mut shared.context)
+        let request = shared.core.invoke(op, consume.into());
+        let request_num = request.request_num;
+        let primary_index = shared.primary_index();
+        shared
+            .core
+            .context
+            .send(To::Replica(primary_index), request);
+        request_num
+    }
+
+    fn invoke_read_only(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
+        let shared = &mut *self.shared.lock().unwrap();
+        shared.core.invoke_read_only(op, consume.into())
+    }
+
+    fn abort(&self, invoke_num: u32) -> Option<BoxedConsume> {
+        self.shared.lock().unwrap().core.abort(invoke_num)
     }
 
     fn handle(&self, message: Self::Message) {
+        if let Message::ReadOnlyReply(message) = message {
+            let mut shared = self.shared.lock().unwrap();
+            let step = shared.core.handle_read_only_reply(message.inner);
+            let complete = shared.step(step);
+            drop(shared);
+            if let Some((consume, result)) = complete {
+                consume.apply(result)
+            }
+            return;
+        }
         let Message::Reply(message) = message else {
             unimplemented!()
         };
         let mut shared = self.shared.lock().unwrap();
-        if message.request_num != shared.request_num {
+        let num_faulty = shared.core.context.num_faulty();
+        if shared.core.invoke_mut(message.request_num).is_none() {
             return;
         }
-        let Some(invoke) = &mut shared.invoke else {
+        let request = shared.core.request(message.request_num);
+        if !message
+            .request_proof
+            .verify(&request.leaf(), &message.header.requests_root)
+            || !message
+                .result_proof
+                .verify(&merkle::leaf(&message.result), &message.results_root)
+        {
             return;
-        };
-        invoke
-            .replies
-            .insert(message.replica_index, Reply::clone(&message));
-        let num_match = invoke
-            .replies
-            .values()
-            .filter(|reply| {
-                (reply.block_digest, &reply.result) == (message.block_digest, &message.result)
+        }
+        // the signatures are checked along with the reply's
+        let block_digest = message.header.digest();
+        let certifiers = message
+            .certificate
+            .iter()
+            .filter(|execution| {
+                (
+                    execution.block_digest,
+                    execution.results_root,
+                    execution.state_root,
+                ) == (block_digest, message.results_root, message.state_root)
             })
-            .count();
-        assert!(num_match <= shared.context.num_faulty() + 1);
-        if num_match == shared.context.num_faulty() + 1 {
-            {
-                let shared = &mut *shared;
-                shared.resend_timer.unset(&mut shared.context);
+            .map(|execution| execution.replica_index)
+            .collect::<HashSet<_>>();
+        // otherwise f + 1 matching replies
+        if certifiers.len() < num_faulty + 1 {
+            let invoke = shared.core.invoke_mut(message.request_num).unwrap();
+            invoke
+                .replies
+                .insert(message.replica_index, Reply::clone(&message));
+            let num_match = invoke
+                .replies
+                .values()
+                .filter(|reply| {
+                    (
+                        reply.view_num,
+                        &reply.header,
+                        &reply.results_root,
+                        &reply.state_root,
+                        &reply.result,
+                    ) == (
+                        message.view_num,
+                        &message.header,
+                        &message.results_root,
+                        &message.state_root,
+                        &message.result,
+                    )
+                })
+                .count();
+            assert!(num_match <= num_faulty + 1);
+            if num_match < num_faulty + 1 {
+                return;
             }
-            let invoke = shared.invoke.take().unwrap();
-            drop(shared);
+        }
+        let invoke = shared.core.complete(message.request_num);
+        shared.view_num = shared.view_num.max(message.view_num);
+        drop(shared);
+        invoke.consume.apply(message.inner.result)
+    }
 
-            let _op = invoke.op;
-            invoke.consume.apply(message.inner.result)
+    fn on_timer(&self, id: TimerId) {
+        let shared = &mut *self.shared.lock().unwrap();
+        for step in shared.core.on_timer(id) {
+            assert!(shared.step(step).is_none())
         }
     }
 }
@@ -150,26 +265,155 @@ pub struct Replica {
     index: ReplicaIndex,
     view_num: u32,
     requests: Vec<Request>,
+    replies: ClientTable<Reply>,
     pre_prepares: HashMap<BlockDigest, Signed<PrePrepare>>,
     prepare_certificates: HashMap<BlockDigest, HashMap<ReplicaIndex, Signed<Prepare>>>,
     commit_certificates: HashMap<BlockDigest, HashMap<ReplicaIndex, Signed<Commit>>>,
+    // by block height, the ones at and below the executed height are removed
+    // once the replies of the block are sent
+    executions: BTreeMap<u32, HashMap<ReplicaIndex, Signed<Execution>>>,
+    // the replies of executed blocks, sent once their execution is certified
+    pending_replies: HashMap<BlockDigest, Vec<(Request, Reply)>>,
     chain: Chain,
     app: App,
+    batcher: Batcher,
+    storage: Storage,
+    pub ledger: Ledger,
+    pub state_check: StateCheck,
+    // recovered proposals that were not executed before restart
+    resuming_pre_prepares: Vec<PrePrepare>,
+    // the replies of the recovered blocks, sent on the first pace after the
+    // settings are in place
+    resuming_replies: Vec<(Execution, Vec<(Request, Reply)>)>,
+    // sign the roots after executing a block and hold its replies until f + 1
+    // replicas agree on them, so a client accepts a single reply. this costs an
+    // all-to-all round per block, and clients collect f + 1 replies otherwise
+    pub certify_execution: bool,
+    // zero for unbounded
+    pub pipeline_window: u32,
+    // received beyond the window, by block height
+    pending_pre_prepares: BTreeMap<u32, Signed<PrePrepare>>,
 }
 
 impl Replica {
-    pub fn new(context: Context<Message>, index: ReplicaIndex, app: App) -> Self {
+    pub fn new(
+        context: Context<Message>,
+        index: ReplicaIndex,
+        app: App,
+        batching: BatchConfig,
+    ) -> Self {
         Self {
             context,
             index,
             view_num: 0,
             requests: Default::default(),
+            replies: Default::default(),
             pre_prepares: Default::default(),
             prepare_certificates: Default::default(),
             commit_certificates: Default::default(),
+            executions: Default::default(),
+            pending_replies: Default::default(),
             chain: Default::default(),
             app,
+            batcher: Batcher::new(batching),
+            storage: Storage::Null,
+            ledger: Ledger::Null,
+            state_check: StateCheck::new(0),
+            resuming_pre_prepares: Default::default(),
+            resuming_replies: Default::default(),
+            certify_execution: false,
+            pipeline_window: 0,
+            pending_pre_prepares: Default::default(),
+        }
+    }
+
+    // rebuild the replica from the entries of a log it has previously written
+    pub fn recover(
+        context: Context<Message>,
+        index: ReplicaIndex,
+        app: App,
+        batching: BatchConfig,
+        storage: Storage,
+        entries: Vec<Entry>,
+    ) -> Self {
+        let mut replica = Self::new(context, index, app, batching);
+        replica.storage = storage;
+        let mut executed = HashSet::new();
+        for entry in entries {
+            match entry {
+                Entry::Block(block) => {
+                    assert!(replica.chain.commit(&block));
+                    executed.insert(block.digest());
+                    let results = Vec::from_iter(
+                        block
+                            .requests
+                            .iter()
+                            .map(|request| replica.app.execute(&request.op)),
+                    );
+                    // the execution certificates are not persisted, so the
+                    // replies may be pending until the block is certified
+                    // again e.g. by the replicas that are lagging behind, and
+                    // the requests are not proposed twice meanwhile
+                    let state_root = replica.app.state_digest();
+                    let (replies, results_root) = block_replies(
+                        &block,
+                        &results,
+                        replica.view_num,
+                        state_root,
+                        replica.index,
+                    );
+                    for (request, _) in &replies {
+                        replica.replies.insert_request(request);
+                    }
+                    let execution = Execution {
+                        block_digest: block.digest(),
+                        height: block.height,
+                        results_root,
+                        state_root,
+                        replica_index: replica.index,
+                    };
+                    replica.resuming_replies.push((execution, replies))
+                }
+                Entry::State(state) => match deserialize_state(&state) {
+                    State::PrePrepare(pre_prepare) => {
+                        replica.view_num = replica.view_num.max(pre_prepare.view_num);
+                        replica.pre_prepares.insert(
+                            pre_prepare.block.digest(),
+                            Signed {
+                                inner: pre_prepare,
+                                signature: Signature::Plain,
+                            },
+                        );
+                    }
+                    State::Commit(commit) => {
+                        replica.view_num = replica.view_num.max(commit.view_num)
+                    }
+                },
+            }
+        }
+        // the latest proposal may be still in flight, keep proposing on top of it
+        if let Some(pre_prepare) = replica
+            .pre_prepares
+            .values()
+            .max_by_key(|pre_prepare| pre_prepare.block.height)
+        {
+            replica
+                .chain
+                .rebase(pre_prepare.block.digest(), pre_prepare.block.height)
+        }
+        if replica.index == replica.primary_index() {
+            replica.resuming_pre_prepares = Vec::from_iter(
+                replica
+                    .pre_prepares
+                    .iter()
+                    .filter(|(block_digest, _)| !executed.contains(*block_digest))
+                    .map(|(_, pre_prepare)| pre_prepare.inner.clone()),
+            );
+            replica
+                .resuming_pre_prepares
+                .sort_unstable_by_key(|pre_prepare| pre_prepare.block.height)
         }
+        replica
     }
 }
 
@@ -181,16 +425,22 @@ impl MultiplexReceive for Replica {
         assert_eq!(receiver, self.context.addr());
         match message {
             Message::Request(message) => self.handle_request(remote, message),
+            Message::ReadOnlyRequest(message) => self.handle_read_only_request(remote, message),
             Message::PrePrepare(message) => self.handle_pre_prepare(remote, message),
             Message::Prepare(message) => self.handle_prepare(remote, message),
             Message::Commit(message) => self.handle_commit(remote, message),
+            Message::Checkpoint(message) => self.handle_checkpoint(remote, message),
+            Message::Execution(message) => self.insert_execution(message),
             _ => unimplemented!(),
         }
     }
 
-    fn on_timer(&mut self, receiver: Addr, _: crate::context::TimerId) {
+    fn on_timer(&mut self, receiver: Addr, id: crate::context::TimerId) {
         assert_eq!(receiver, self.context.addr());
-        todo!()
+        // the alarm may be delivered after the timer get unset
+        if self.batcher.on_timer(id, &mut self.context) {
+            self.on_pace()
+        }
     }
 
     fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
@@ -201,12 +451,28 @@ impl MultiplexReceive for Replica {
             }
             Message::Prepare(message) => self.insert_prepare(message),
             Message::Commit(message) => self.insert_commit(message),
+            Message::Execution(message) => self.insert_execution(message),
             _ => unimplemented!(),
         }
     }
 
     fn on_pace(&mut self) {
-        if self.index == self.primary_index() && !self.requests.is_empty() {
+        // the first pace happens after receiving something, so the others are
+        // probably up as well
+        for pre_prepare in self.resuming_pre_prepares.drain(..) {
+            self.context.send(To::AllReplica, pre_prepare)
+        }
+        for (execution, replies) in std::mem::take(&mut self.resuming_replies) {
+            self.do_reply(execution, replies)
+        }
+        if self.index == self.primary_index()
+            && !self.window_full()
+            && self.batcher.ready(
+                &self.requests,
+                self.chain.num_in_flight(),
+                &mut self.context,
+            )
+        {
             self.do_propose()
         }
     }
@@ -217,17 +483,44 @@ impl Replica {
         (self.view_num as usize % self.context.num_replica()) as _
     }
 
+    fn window_full(&self) -> bool {
+        self.pipeline_window != 0 && self.chain.num_in_flight() >= self.pipeline_window
+    }
+
+    // the high water mark, above which proposals wait for execution to catch up
+    fn in_window(&self, height: u32) -> bool {
+        self.pipeline_window == 0 || height <= self.chain.executed_height() + self.pipeline_window
+    }
+
     fn handle_request(&mut self, _remote: Addr, message: Signed<Request>) {
-        if self.index != self.primary_index() {
-            // TODO
-            return;
+        let is_primary = self.index == self.primary_index();
+        match self.replies.insert_request(&message) {
+            RequestStatus::New => {}
+            // a resent request is relayed again, the primary may have missed
+            // it or be replaced
+            RequestStatus::Pending if !is_primary => {}
+            RequestStatus::Pending => return,
+            RequestStatus::Replied(reply) => {
+                self.context
+                    .send(To::Client(message.client_index), reply.clone());
+                return;
+            }
         }
 
-        // TODO check resend
-
+        if !is_primary {
+            // the client has timed out on the primary
+            self.context
+                .send(To::Replica(self.primary_index()), message.inner);
+            return;
+        }
         self.requests.push(message.inner);
     }
 
+    fn handle_read_only_request(&mut self, _remote: Addr, message: Signed<ReadOnlyRequest>) {
+        let reply = ReadOnlyReply::new(&self.app, &message, self.index);
+        self.context.send(To::Client(message.client_index), reply)
+    }
+
     fn handle_pre_prepare(&mut self, _remote: Addr, message: Signed<PrePrepare>) {
         if message.view_num < self.view_num {
             return;
@@ -238,7 +531,18 @@ impl Replica {
             return;
         }
 
+        if !self.in_window(message.block.height) {
+            self.pending_pre_prepares
+                .insert(message.block.height, message);
+            return;
+        }
+        self.do_prepare(message)
+    }
+
+    fn do_prepare(&mut self, message: Signed<PrePrepare>) {
         let block_digest = message.block.digest();
+        self.storage
+            .append_state(&State::PrePrepare(PrePrepare::clone(&message)));
         self.pre_prepares.insert(block_digest, message);
         assert_ne!(self.index, self.primary_index());
         let prepare = Prepare {
@@ -275,12 +579,28 @@ impl Replica {
         self.insert_commit(message);
     }
 
+    fn handle_checkpoint(&mut self, _remote: Addr, message: Signed<Checkpoint>) {
+        let Checkpoint {
+            height,
+            state,
+            replica_index,
+        } = message.inner;
+        self.state_check
+            .insert(height, replica_index, state, &self.context)
+    }
+
     fn do_propose(&mut self) {
         assert_eq!(self.index, self.primary_index());
         let pre_prepare = PrePrepare {
             view_num: self.view_num,
-            block: self.chain.propose(&mut self.requests),
+            block: self.chain.propose_batch(self.batcher.take(
+                &mut self.requests,
+                self.chain.num_in_flight(),
+                &mut self.context,
+            )),
         };
+        self.storage
+            .append_state(&State::PrePrepare(pre_prepare.clone()));
         self.context.send(To::AllReplicaWithLoopback, pre_prepare)
     }
 
@@ -311,6 +631,7 @@ impl Replica {
                 block_digest,
                 replica_index: self.index,
             };
+            self.storage.append_state(&State::Commit(commit.clone()));
             self.context
                 .send(To::AllReplicaWithLoopback, commit.clone())
         }
@@ -324,32 +645,195 @@ impl Replica {
             return;
         }
         commit_certificate.insert(commit.replica_index, commit);
-        if commit_certificate.len() >= self.context.num_replica() - self.context.num_faulty() {
+        // a backup that lags beyond the window may collect the commits before
+        // it accepts the proposal, then the block is executed once it does
+        if commit_certificate.len() >= self.context.num_replica() - self.context.num_faulty()
+            && self.pre_prepares.contains_key(&block_digest)
+        {
             self.do_execute(block_digest);
         }
     }
 
+    fn is_committed(&self, block_digest: &BlockDigest) -> bool {
+        self.commit_certificates
+            .get(block_digest)
+            .is_some_and(|certificate| {
+                certificate.len() >= self.context.num_replica() - self.context.num_faulty()
+            })
+    }
+
     fn do_execute(&mut self, block_digest: BlockDigest) {
+        let mut block_digest = block_digest;
         let mut block = &self.pre_prepares[&block_digest].block;
         if !self.chain.commit(block) {
             return;
         }
         loop {
-            for request in &block.requests {
-                let reply = Reply {
-                    request_num: request.request_num,
-                    result: self.app.execute(&request.op),
-                    block_digest,
+            self.context.count(BLOCK_COMMITTED, 1);
+            self.storage.append(Entry::Block(block.clone()));
+            let results = block
+                .requests
+                .iter()
+                .map(|request| self.app.execute(&request.op))
+                .collect::<Vec<_>>();
+            let state_root = self.app.state_digest();
+            let (replies, results_root) =
+                block_replies(block, &results, self.view_num, state_root, self.index);
+            let execution = Execution {
+                block_digest,
+                height: block.height,
+                results_root,
+                state_root,
+                replica_index: self.index,
+            };
+            let commit_certificate = &self.commit_certificates[&block_digest];
+            self.ledger.append(
+                block,
+                || {
+                    let mut certificate = commit_certificate.values().cloned().collect::<Vec<_>>();
+                    certificate.sort_unstable_by_key(|commit| commit.replica_index);
+                    Certificate::Pbft(certificate)
+                },
+                results_root,
+                state_root,
+            );
+            if let Some(state) =
+                self.state_check
+                    .on_execute(block.height, &results, &self.app, &self.context)
+            {
+                let checkpoint = Checkpoint {
+                    height: block.height,
+                    state,
                     replica_index: self.index,
                 };
-                self.context.send(To::Client(request.client_index), reply)
+                self.context.send(To::AllReplica, checkpoint)
             }
-            if let Some(block_digest) = self.chain.next_execute() {
+            self.do_reply(execution, replies);
+            if let Some(next_digest) = self.chain.next_execute() {
+                block_digest = next_digest;
                 block = &self.pre_prepares[&block_digest].block;
             } else {
                 break;
             }
         }
+        let mut committed = Vec::new();
+        while let Some((&height, _)) = self.pending_pre_prepares.first_key_value() {
+            if !self.in_window(height) {
+                break;
+            }
+            let pre_prepare = self.pending_pre_prepares.remove(&height).unwrap();
+            if pre_prepare.view_num == self.view_num {
+                let block_digest = pre_prepare.block.digest();
+                let is_new = !self.pre_prepares.contains_key(&block_digest);
+                self.do_prepare(pre_prepare);
+                if is_new && self.is_committed(&block_digest) {
+                    committed.push(block_digest)
+                }
+            }
+        }
+        for block_digest in committed {
+            self.do_execute(block_digest)
+        }
+    }
+
+    fn do_reply(&mut self, execution: Execution, replies: Vec<(Request, Reply)>) {
+        if self.certify_execution {
+            self.pending_replies.insert(execution.block_digest, replies);
+            self.context.send(To::AllReplicaWithLoopback, execution);
+            return;
+        }
+        for (request, reply) in replies {
+            self.replies.insert_reply(&request, reply.clone());
+            self.context.send(To::Client(request.client_index), reply)
+        }
+    }
+
+    fn insert_execution(&mut self, execution: Signed<Execution>) {
+        let height = execution.height;
+        // the replies of the block are already sent
+        if height <= self.chain.executed_height()
+            && !self.pending_replies.contains_key(&execution.block_digest)
+        {
+            return;
+        }
+        let executions = self.executions.entry(height).or_default();
+        executions.insert(execution.replica_index, execution);
+        // the roots of this replica are the ones it can certify
+        let Some(own) = executions.get(&self.index) else {
+            return;
+        };
+        let block_digest = own.block_digest;
+        let certificate = Vec::from_iter(
+            executions
+                .values()
+                .filter(|execution| {
+                    (
+                        execution.block_digest,
+                        execution.results_root,
+                        execution.state_root,
+                    ) == (own.block_digest, own.results_root, own.state_root)
+                })
+                .take(self.context.num_faulty() + 1)
+                .cloned(),
+        );
+        if certificate.len() < self.context.num_faulty() + 1 {
+            return;
+        }
+        let Some(replies) = self.pending_replies.remove(&block_digest) else {
+            return;
+        };
+        self.executions.remove(&height);
+        for (request, mut reply) in replies {
+            reply.certificate.clone_from(&certificate);
+            self.replies.insert_reply(&request, reply.clone());
+            self.context.send(To::Client(request.client_index), reply)
+        }
+    }
+}
+
+// the replies of an executed block without the execution certificate, and the
+// results root
+fn block_replies(
+    block: &Block,
+    results: &[Vec<u8>],
+    view_num: u32,
+    state_root: merkle::Digest,
+    replica_index: ReplicaIndex,
+) -> (Vec<(Request, Reply)>, merkle::Digest) {
+    let requests_tree = block.requests_tree();
+    let header = BlockHeader {
+        parent_digest: block.parent_digest,
+        height: block.height,
+        requests_root: requests_tree.root(),
+    };
+    let results_tree = Tree::new(results.iter().map(|result| merkle::leaf(result)).collect());
+    let replies = Vec::from_iter(block.requests.iter().zip(results).enumerate().map(
+        |(i, (request, result))| {
+            let reply = Reply {
+                request_num: request.request_num,
+                result: result.clone(),
+                view_num,
+                header: header.clone(),
+                request_proof: requests_tree.proof(i),
+                results_root: results_tree.root(),
+                result_proof: results_tree.proof(i),
+                state_root,
+                certificate: Default::default(),
+                replica_index,
+            };
+            (request.clone(), reply)
+        },
+    ));
+    (replies, results_tree.root())
+}
+
+impl ledger::Vote for Commit {
+    fn block_digest(&self) -> &BlockDigest {
+        &self.block_digest
+    }
+
+    fn replica_index(&self) -> ReplicaIndex {
+        self.replica_index
     }
 }
 
@@ -365,6 +849,18 @@ impl Sign<Reply> for Message {
     }
 }
 
+impl Sign<ReadOnlyRequest> for Message {
+    fn sign(message: ReadOnlyRequest, signer: &crate::crypto::Signer) -> Self {
+        Self::ReadOnlyRequest(signer.sign_private(message))
+    }
+}
+
+impl Sign<ReadOnlyReply> for Message {
+    fn sign(message: ReadOnlyReply, signer: &crate::crypto::Signer) -> Self {
+        Self::ReadOnlyReply(signer.sign_private(message))
+    }
+}
+
 impl Sign<PrePrepare> for Message {
     fn sign(message: PrePrepare, signer: &crate::crypto::Signer) -> Self {
         Self::PrePrepare(signer.sign_public(message))
@@ -377,6 +873,18 @@ impl Sign<Prepare> for Message {
     }
 }
 
+impl Sign<Checkpoint> for Message {
+    fn sign(message: Checkpoint, signer: &crate::crypto::Signer) -> Self {
+        Self::Checkpoint(signer.sign_public(message))
+    }
+}
+
+impl Sign<Execution> for Message {
+    fn sign(message: Execution, signer: &crate::crypto::Signer) -> Self {
+        Self::Execution(signer.sign_public(message))
+    }
+}
+
 impl Sign<Commit> for Message {
     fn sign(message: Commit, signer: &crate::crypto::Signer) -> Self {
         Self::Commit(signer.sign_public(message))
@@ -390,10 +898,222 @@ impl Verify<ReplicaIndex> for Message {
     ) -> Result<(), crate::crypto::Invalid> {
         match self {
             Self::Request(message) => verifier.verify(message, None),
-            Self::Reply(message) => verifier.verify(message, message.replica_index),
+            Self::Reply(message) => {
+                verifier.verify(message, message.replica_index)?;
+                for execution in &message.certificate {
+                    verifier.verify(execution, execution.replica_index)?
+                }
+                Ok(())
+            }
+            Self::ReadOnlyRequest(message) => verifier.verify(message, None),
+            Self::ReadOnlyReply(message) => verifier.verify(message, message.replica_index),
             Self::PrePrepare(message) => verifier.verify(message, 0), // TODO
             Self::Prepare(message) => verifier.verify(message, message.replica_index),
             Self::Commit(message) => verifier.verify(message, message.replica_index),
+            Self::Checkpoint(message) => verifier.verify(message, message.replica_index),
+            Self::Execution(message) => verifier.verify(message, message.replica_index),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        app::Null,
+        context::{
+            simulated::{self, Dispatch},
+            MultiplexReceive,
+        },
+        crypto::Signer,
+        Client as _, Config,
+    };
+
+    use super::*;
+
+    // only the messages to the replica itself are delivered
+    struct Isolated(Replica);
+
+    impl MultiplexReceive for Isolated {
+        type Message = Message;
+
+        fn handle(&mut self, receiver: Addr, remote: Addr, message: Self::Message) {
+            if receiver == self.0.context.addr() {
+                self.0.handle(receiver, remote, message)
+            }
+        }
+
+        fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
+            self.0.handle_loopback(receiver, message)
+        }
+
+        fn on_timer(&mut self, receiver: Addr, id: TimerId) {
+            self.0.on_timer(receiver, id)
+        }
+    }
+
+    #[test]
+    fn lagging_backup_beyond_window() {
+        let dispatch = Dispatch::new();
+        let addr = |index| Addr::Simulated(simulated::Addr::Replica(index));
+        let config = Arc::new(Config {
+            num_faulty: 1,
+            client_addrs: vec![Addr::Simulated(simulated::Addr::Client(0))],
+            replica_addrs: Vec::from_iter((0..4).map(addr)),
+            multicast_addr: None,
+        });
+        let context = dispatch
+            .register(simulated::Addr::Replica(3))
+            .into_replication(config);
+        let mut replica = Isolated(Replica::new(
+            context,
+            3,
+            App::new(Null),
+            BatchConfig::default(),
+        ));
+        replica.0.pipeline_window = 2;
+
+        let mut chain = Chain::new();
+        let blocks = Vec::from_iter((1..=6).map(|request_num| {
+            chain.propose_batch(vec![Request {
+                client_index: 0,
+                request_num,
+                ack_num: 0,
+                op: Default::default(),
+            }])
+        }));
+        for block in &blocks {
+            let pre_prepare = PrePrepare {
+                view_num: 0,
+                block: block.clone(),
+            };
+            let pre_prepare = Message::PrePrepare(Signer::Simulated.sign_public(pre_prepare));
+            replica.handle(addr(3), addr(0), pre_prepare)
+        }
+        // the others have committed all blocks before this backup catches up,
+        // so the commits of the blocks beyond its window arrive first
+        for block in blocks.iter().rev() {
+            for index in 0..3 {
+                let commit = Commit {
+                    view_num: 0,
+                    block_digest: block.digest(),
+                    replica_index: index,
+                };
+                let commit = Message::Commit(Signer::Simulated.sign_public(commit));
+                replica.handle(addr(3), addr(index), commit)
+            }
+        }
+        while dispatch.deliver_event(&mut replica) {}
+        assert_eq!(replica.0.chain.executed_height(), 6);
+    }
+
+    struct System {
+        replicas: Vec<Replica>,
+        client: Client,
+    }
+
+    impl MultiplexReceive for System {
+        type Message = Message;
+
+        fn handle(&mut self, receiver: Addr, remote: Addr, message: Self::Message) {
+            match receiver {
+                Addr::Simulated(simulated::Addr::Replica(index)) => {
+                    self.replicas[index as usize].handle(receiver, remote, message)
+                }
+                _ => self.client.handle(message),
+            }
+        }
+
+        fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
+            let Addr::Simulated(simulated::Addr::Replica(index)) = receiver else {
+                unreachable!()
+            };
+            self.replicas[index as usize].handle_loopback(receiver, message)
+        }
+
+        fn on_timer(&mut self, receiver: Addr, id: TimerId) {
+            match receiver {
+                Addr::Simulated(simulated::Addr::Replica(index)) => {
+                    self.replicas[index as usize].on_timer(receiver, id)
+                }
+                _ => self.client.on_timer(id),
+            }
+        }
+
+        fn on_pace(&mut self) {
+            for replica in &mut self.replicas {
+                replica.on_pace()
+            }
+        }
+    }
+
+    // invoke `num_op` ops one after another, and return the number of them
+    // that complete
+    fn commit(certify_execution: bool, num_op: usize) -> (System, usize) {
+        let dispatch = Dispatch::new();
+        let config = Arc::new(Config {
+            num_faulty: 1,
+            client_addrs: vec![Addr::Simulated(simulated::Addr::Client(0))],
+            replica_addrs: Vec::from_iter(
+                (0..4).map(|index| Addr::Simulated(simulated::Addr::Replica(index))),
+            ),
+            multicast_addr: None,
+        });
+        let mut system = System {
+            replicas: Vec::from_iter((0..4).map(|index| {
+                let context = dispatch
+                    .register(simulated::Addr::Replica(index))
+                    .into_replication(config.clone());
+                let mut replica =
+                    Replica::new(context, index, App::new(Null), BatchConfig::default());
+                replica.certify_execution = certify_execution;
+                replica
+            })),
+            client: Client::new(
+                dispatch
+                    .register(simulated::Addr::Client(0))
+                    .into_replication(config),
+                0,
+            ),
+        };
+        let results = Arc::new(Mutex::new(0));
+        for num_result in 0..num_op {
+            let shared_results = results.clone();
+            system.client.invoke(Default::default(), move |_| {
+                *shared_results.lock().unwrap() += 1
+            });
+            while *results.lock().unwrap() == num_result && dispatch.deliver_event(&mut system) {
+                system.on_pace()
+            }
+        }
+        // and the messages that are still in flight
+        let deadline = dispatch.now() + Duration::from_secs(1);
+        while dispatch.deliver_event_before(deadline, &mut system) {
+            system.on_pace()
+        }
+        let num_result = *results.lock().unwrap();
+        (system, num_result)
+    }
+
+    #[test]
+    fn commit_uncertified() {
+        let (system, num_result) = commit(false, 5);
+        assert_eq!(num_result, 5);
+        assert!(system
+            .replicas
+            .iter()
+            .all(|replica| replica.executions.is_empty()));
+    }
+
+    #[test]
+    fn commit_certified() {
+        let (system, num_result) = commit(true, 5);
+        assert_eq!(num_result, 5);
+        // including the late executions of the certified blocks
+        assert!(system
+            .replicas
+            .iter()
+            .all(|replica| replica.executions.is_empty()));
+    }
+}
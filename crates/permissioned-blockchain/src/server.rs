@@ -0,0 +1,579 @@
+// the HTTP server that the control script drives, either as a benchmark client
+// or as a replica
+//
+// the applications of `control_messages::App::Custom` are constructed from the
+// registry that is passed to `serve`. a binary that plugs in its own
+// applications registers them and serves in place of the default one
+
+use std::{
+    mem::replace,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    app::{shard, Registry},
+    client::{run_benchmark, GroupResult, RunBenchmarkConfig},
+    common::{StateCheck, BLOCK_COMMITTED, FIRST_DIVERGED_BLOCK, STATE_DIVERGED, VIEW_CHANGE},
+    context::{
+        ordered_multicast::Receiver,
+        tokio::{Multiplex, MultiplexHandle, Stats},
+        Addr,
+    },
+    crypto::{self, Signer, Verifier},
+    honey_badger, hotstuff, jolteon,
+    ledger::Ledger,
+    minbft, neo, pbft,
+    storage::{Entry, Storage},
+    unreplicated, zyzzyva, App, Config,
+};
+use axum::{
+    extract::{FromRef, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router, Server,
+};
+use control_messages::{
+    BenchmarkStats, GroupStats, Histogram, MessageStats, ReplicaStats, Role, Task,
+};
+use neat::benchmark::{Phases, Ready};
+use rand::{rngs::StdRng, SeedableRng};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug)]
+enum AppState {
+    Idle, // TODO exit on timeout
+    Panicked,
+
+    BenchmarkClientRunning,
+    // set up and waiting for `/start`, to start together with other client hosts
+    BenchmarkClientReady {
+        start: flume::Sender<()>,
+    },
+    BenchmarkClientFinish {
+        stats: BenchmarkStats,
+    },
+    ReplicaRunning {
+        cancel: CancellationToken,
+        task: JoinHandle<()>,
+        multiplex: MultiplexHandle,
+        // the signature counters are process-wide, so they are reported
+        // relative to the start of the replica
+        num_signed: u64,
+        num_verified: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct ServerState {
+    app: Arc<Mutex<AppState>>,
+    registry: Arc<Registry>,
+}
+
+impl FromRef<ServerState> for Arc<Mutex<AppState>> {
+    fn from_ref(input: &ServerState) -> Self {
+        input.app.clone()
+    }
+}
+
+impl FromRef<ServerState> for Arc<Registry> {
+    fn from_ref(input: &ServerState) -> Self {
+        input.registry.clone()
+    }
+}
+
+async fn set_task(
+    State(state): State<Arc<Mutex<AppState>>>,
+    State(registry): State<Arc<Registry>>,
+    Json(task): Json<Task>,
+) -> Result<(), (StatusCode, String)> {
+    assert!(matches!(*state.lock().unwrap(), AppState::Idle));
+
+    let mut replication_config =
+        Config::new_socket(task.client_addrs, task.replica_addrs, task.num_faulty);
+    replication_config.multicast_addr = Some(Addr::Socket(task.multicast_addr));
+
+    let mut rng = StdRng::seed_from_u64(task.seed);
+    match task.role {
+        Role::BenchmarkClient(config) => {
+            let workload = registry
+                .workload(&task.app, &mut rng)
+                .map_err(bad_request)?;
+            let partition = registry.partition(&task.app).map_err(bad_request)?;
+            *state.lock().unwrap() = AppState::BenchmarkClientRunning;
+
+            let benchmark_config = RunBenchmarkConfig {
+                replication_config,
+                num_shard: task.num_shard,
+                partition,
+                offset: config.offset,
+                num_group: config.num_group,
+                num_client: config.num_client,
+                phases: Phases {
+                    warmup: config.warmup,
+                    measure: config.duration,
+                    cooldown: config.cooldown,
+                },
+                workload,
+                arrival: config.arrival.map(Into::into),
+                num_outstanding: config.num_outstanding,
+                affinity: task.affinity,
+                ready: Some(Ready(Arc::new({
+                    let state = state.clone();
+                    move || {
+                        let (start, wait_start) = flume::bounded(1);
+                        *state.lock().unwrap() = AppState::BenchmarkClientReady { start };
+                        wait_start.recv().unwrap()
+                    }
+                }))),
+            };
+            // println!("{benchmark_config:?}");
+            let state = state.clone();
+            tokio::task::spawn_blocking(move || {
+                let results = match &*task.mode {
+                    "unreplicated" => run_benchmark(benchmark_config, unreplicated::Client::new),
+                    "neo-hm" | "neo-pk" | "neo-bn" => {
+                        run_benchmark(benchmark_config, neo::Client::new)
+                    }
+                    "pbft" => run_benchmark(benchmark_config, pbft::Client::new),
+                    "zyzzyva" | "zyzzyva-f" => run_benchmark(benchmark_config, |context, index| {
+                        zyzzyva::Client::new(context, index, task.mode == "zyzzyva-f")
+                    }),
+                    "hotstuff" => run_benchmark(benchmark_config, hotstuff::Client::new),
+                    "jolteon" => run_benchmark(benchmark_config, jolteon::Client::new),
+                    "minbft" => run_benchmark(benchmark_config, minbft::Client::new),
+                    "honey-badger" => run_benchmark(benchmark_config, honey_badger::Client::new),
+                    _ => unimplemented!(),
+                };
+                *state.lock().unwrap() = AppState::BenchmarkClientFinish {
+                    stats: benchmark_stats(results, config.duration, config.arrival.is_some()),
+                };
+            });
+        }
+        Role::Replica(replica) => {
+            let mut app = registry.app(&task.app, &mut rng).map_err(bad_request)?;
+            // the index of the replica is local to its shard from here on, and
+            // the global one is only used for naming files
+            let global_index = replica.index;
+            let num_replica = replication_config.replica_addrs.len() / task.num_shard;
+            let shard_index = replica.index as usize / num_replica;
+            let replica = control_messages::Replica {
+                index: (replica.index as usize % num_replica) as _,
+            };
+            let replication_config = replication_config.shard(task.num_shard, shard_index);
+            if task.num_shard > 1 {
+                let partition = registry.partition(&task.app).map_err(bad_request)?;
+                app = App::new(shard::App::new(app, partition))
+            }
+
+            let cancel = CancellationToken::new();
+            let (multiplex_sender, multiplex_receiver) = flume::bounded(1);
+            let (num_signed, num_verified) = (crypto::num_signed(), crypto::num_verified());
+            let task = tokio::task::spawn_blocking({
+                let cancel = cancel.clone();
+                move || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .unwrap();
+                    let variant = Arc::new(match &*task.mode {
+                        "neo-hm" => Receiver::new_half_sip_hash(replica.index),
+                        "neo-pk" | "neo-bn" => Receiver::new_k256(),
+                        _ => Receiver::Unreachable,
+                    });
+                    let mut multiplex = Multiplex::new(runtime.handle().clone(), variant.clone());
+                    multiplex.max_delay = task.network_max_delay;
+                    multiplex.collect_stats = task.collect_stats;
+                    multiplex_sender.send(multiplex.handle()).unwrap();
+
+                    let handle = multiplex.handle();
+                    let affinity = task.affinity.clone();
+                    affinity.validate();
+                    std::thread::spawn({
+                        let affinity = affinity.clone();
+                        move || {
+                            affinity.set_runtime(0);
+                            runtime.block_on(async move {
+                                cancel.cancelled().await;
+                                handle.stop_async().await
+                            });
+                            runtime.shutdown_background()
+                        }
+                    });
+
+                    affinity.set_dispatch(0);
+                    let addr = replication_config.replica_addrs[replica.index as usize];
+                    let signer = Signer::new_standard(
+                        crate::crypto::hardcoded_ed25519(replica.index as _),
+                        // crate::context::crypto::hardcoded_k256(replica.index),
+                    );
+                    let mut verifier = Verifier::new_standard(variant);
+                    for index in 0..replication_config.replica_addrs.len() {
+                        verifier.insert_verifying_key(
+                            index as _,
+                            crate::crypto::hardcoded_ed25519(index)
+                                // crate::context::crypto::hardcoded_k256(index)
+                                .verifying_key(),
+                        )
+                    }
+                    let storage = task.storage.as_ref().map(|storage| {
+                        let path = format!("{}/{}-{global_index}.wal", storage.dir, task.mode);
+                        Storage::open(&path, storage.sync.into())
+                            .unwrap_or_else(|err| panic!("{err} opening {path}"))
+                    });
+                    let num_recovered = storage.as_ref().map_or(0, |(_, entries)| {
+                        entries
+                            .iter()
+                            .filter(|entry| matches!(entry, Entry::Block(_)))
+                            .count()
+                    });
+                    let ledger = task.ledger_dir.as_ref().map(|dir| {
+                        let path = format!("{dir}/{}-{global_index}.ledger", task.mode);
+                        Ledger::open(&path, num_recovered)
+                            .unwrap_or_else(|err| panic!("{err} opening {path}"))
+                    });
+                    match &*task.mode {
+                        "unreplicated" => {
+                            assert_eq!(replica.index, 0);
+                            let mut replica = unreplicated::Replica::new(
+                                multiplex
+                                    .register(addr, signer)
+                                    .into_replication(replication_config),
+                                app,
+                                task.batching.into(),
+                            );
+                            // replica.make_blocks = true;
+                            multiplex.run(&mut replica, verifier)
+                        }
+                        "neo-hm" | "neo-pk" | "neo-bn" => {
+                            // there is only one sequencer
+                            assert_eq!(task.num_shard, 1);
+                            let mut replica = neo::Replica::new(
+                                multiplex
+                                    .register(addr, signer)
+                                    .into_replication(replication_config.clone()),
+                                replica.index,
+                                app,
+                                task.mode == "neo-bn",
+                            );
+                            multiplex.drop_rate = task.drop_rate;
+                            multiplex
+                                .enable_ordered_multicast(
+                                    replication_config.multicast_addr.unwrap(),
+                                )
+                                .run(&mut replica, verifier)
+                        }
+                        "pbft" => {
+                            let context = multiplex
+                                .register(addr, signer)
+                                .into_replication(replication_config.clone());
+                            let mut replica = if let Some((storage, entries)) = storage {
+                                pbft::Replica::recover(
+                                    context,
+                                    replica.index,
+                                    app,
+                                    task.batching.into(),
+                                    storage,
+                                    entries,
+                                )
+                            } else {
+                                pbft::Replica::new(
+                                    context,
+                                    replica.index,
+                                    app,
+                                    task.batching.into(),
+                                )
+                            };
+                            if let Some(ledger) = ledger {
+                                replica.ledger = ledger
+                            }
+                            replica.state_check = StateCheck::new(task.checkpoint_interval);
+                            replica.pipeline_window = task.pipeline_window;
+                            replica.certify_execution = task.certify_execution;
+                            multiplex.run(&mut replica, verifier)
+                        }
+                        "zyzzyva" | "zyzzyva-f" => {
+                            let mut replica = zyzzyva::Replica::new(
+                                multiplex
+                                    .register(addr, signer)
+                                    .into_replication(replication_config.clone()),
+                                replica.index,
+                                app,
+                                task.batching.into(),
+                            );
+                            multiplex.run(&mut replica, verifier)
+                        }
+                        "hotstuff" => {
+                            let context = multiplex
+                                .register(addr, signer)
+                                .into_replication(replication_config.clone());
+                            let mut replica = if let Some((storage, entries)) = storage {
+                                hotstuff::Replica::recover(
+                                    context,
+                                    replica.index,
+                                    app,
+                                    task.batching.into(),
+                                    storage,
+                                    entries,
+                                )
+                            } else {
+                                hotstuff::Replica::new(
+                                    context,
+                                    replica.index,
+                                    app,
+                                    task.batching.into(),
+                                )
+                            };
+                            if let Some(ledger) = ledger {
+                                replica.ledger = ledger
+                            }
+                            replica.state_check = StateCheck::new(task.checkpoint_interval);
+                            multiplex.run(&mut replica, verifier)
+                        }
+                        "jolteon" => {
+                            let mut replica = jolteon::Replica::new(
+                                multiplex
+                                    .register(addr, signer)
+                                    .into_replication(replication_config.clone()),
+                                replica.index,
+                                app,
+                                task.batching.into(),
+                            );
+                            multiplex.run(&mut replica, verifier)
+                        }
+                        "minbft" => {
+                            let mut replica = minbft::Replica::new(
+                                multiplex
+                                    .register(addr, signer)
+                                    .into_replication(replication_config.clone()),
+                                replica.index,
+                                app,
+                                task.batching.into(),
+                            );
+                            replica.state_check = StateCheck::new(task.checkpoint_interval);
+                            replica.pipeline_window = task.pipeline_window;
+                            multiplex.run(&mut replica, verifier)
+                        }
+                        "honey-badger" => {
+                            let context = multiplex
+                                .register(addr, signer)
+                                .into_replication(replication_config.clone());
+                            let broadcast_context = multiplex
+                                .register_subnode(&context)
+                                .into_replication(replication_config.clone());
+                            let mut replica = honey_badger::Replica::new(
+                                context,
+                                broadcast_context,
+                                replica.index,
+                                app,
+                                crate::crypto::hardcoded_threshold_coin(
+                                    replica.index as _,
+                                    replication_config.num_faulty + 1,
+                                ),
+                                task.batching.into(),
+                            );
+                            multiplex.run(&mut replica, verifier)
+                        }
+                        _ => unimplemented!(),
+                    }
+                }
+            });
+            let multiplex = multiplex_receiver.recv_async().await.unwrap();
+            *state.lock().unwrap() = AppState::ReplicaRunning {
+                cancel,
+                task,
+                multiplex,
+                num_signed,
+                num_verified,
+            };
+        }
+    }
+    Ok(())
+}
+
+// e.g. the task refers to an application that is not registered
+fn bad_request(err: impl std::fmt::Display) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, err.to_string())
+}
+
+fn benchmark_stats(
+    results: Vec<GroupResult>,
+    duration: Duration,
+    open_loop: bool,
+) -> BenchmarkStats {
+    let mut latency = Histogram::default();
+    let mut throughput_series = vec![0; duration.as_secs().max(1) as _];
+    let mut groups = Vec::new();
+    for result in &results {
+        let mut group_latency = Histogram::default();
+        for &value in &result.latencies {
+            group_latency.record(value)
+        }
+        for finish_time in &result.finish_times {
+            let second = (finish_time.as_secs() as usize).min(throughput_series.len() - 1);
+            throughput_series[second] += 1
+        }
+        latency.merge(&group_latency);
+        groups.push(GroupStats {
+            throughput: result.latencies.len() as f32 / duration.as_secs_f32(),
+            latency: group_latency,
+        })
+    }
+    let latencies = Vec::from_iter(results.iter().flat_map(|result| &result.latencies));
+    BenchmarkStats {
+        throughput: latencies.len() as f32 / duration.as_secs_f32(),
+        average_latency: latencies
+            .iter()
+            .copied()
+            .sum::<Duration>()
+            .checked_div(latencies.len() as u32),
+        offered_load: if open_loop {
+            let num_arrival = results
+                .iter()
+                .map(|result| result.num_arrival)
+                .sum::<usize>();
+            Some(num_arrival as f32 / duration.as_secs_f32())
+        } else {
+            None
+        },
+        num_failed: results.iter().map(|result| result.num_failed).sum(),
+        latency,
+        throughput_series,
+        groups,
+    }
+}
+
+async fn poll_benchmark(State(state): State<Arc<Mutex<AppState>>>) -> Json<Option<BenchmarkStats>> {
+    let state = state.lock().unwrap();
+    match &*state {
+        AppState::BenchmarkClientRunning
+        | AppState::BenchmarkClientReady { .. }
+        | AppState::Panicked => Json(None),
+        AppState::BenchmarkClientFinish { stats } => Json(Some(stats.clone())),
+        AppState::ReplicaRunning { .. } => Json(None),
+        _ => {
+            drop(state);
+            unimplemented!()
+        }
+    }
+}
+
+async fn poll_stats(State(state): State<Arc<Mutex<AppState>>>) -> Json<Option<ReplicaStats>> {
+    let state = state.lock().unwrap();
+    let AppState::ReplicaRunning {
+        multiplex,
+        num_signed,
+        num_verified,
+        ..
+    } = &*state
+    else {
+        return Json(None);
+    };
+    let Stats {
+        messages_in,
+        messages_out,
+        queue_depth,
+        max_queue_depth,
+        counters,
+    } = multiplex.stats();
+    let messages = |messages: std::collections::BTreeMap<&str, (u64, u64)>| {
+        messages
+            .into_iter()
+            .map(|(kind, (count, bytes))| (kind.into(), MessageStats { count, bytes }))
+            .collect()
+    };
+    Json(Some(ReplicaStats {
+        messages_in: messages(messages_in),
+        messages_out: messages(messages_out),
+        num_signed: crypto::num_signed() - num_signed,
+        num_verified: crypto::num_verified() - num_verified,
+        num_block_committed: counters.get(BLOCK_COMMITTED).copied().unwrap_or_default(),
+        num_view_change: counters.get(VIEW_CHANGE).copied().unwrap_or_default(),
+        num_state_diverged: counters.get(STATE_DIVERGED).copied().unwrap_or_default(),
+        first_diverged_block: counters
+            .get(FIRST_DIVERGED_BLOCK)
+            .map(|&height| height as _),
+        queue_depth,
+        max_queue_depth,
+    }))
+}
+
+async fn poll_ready(State(state): State<Arc<Mutex<AppState>>>) -> Json<bool> {
+    Json(matches!(
+        *state.lock().unwrap(),
+        AppState::BenchmarkClientReady { .. }
+    ))
+}
+
+async fn start(State(state): State<Arc<Mutex<AppState>>>) {
+    let mut state = state.lock().unwrap();
+    let AppState::BenchmarkClientReady { start } =
+        replace(&mut *state, AppState::BenchmarkClientRunning)
+    else {
+        drop(state);
+        unimplemented!()
+    };
+    start.send(()).unwrap()
+}
+
+async fn poll_panic(State(state): State<Arc<Mutex<AppState>>>) -> Json<bool> {
+    Json(matches!(*state.lock().unwrap(), AppState::Panicked))
+}
+
+async fn reset(State(state): State<Arc<Mutex<AppState>>>) {
+    let state = {
+        let mut state = state.lock().unwrap();
+        replace(&mut *state, AppState::Idle)
+    };
+    match state {
+        AppState::BenchmarkClientFinish { .. } => {}
+        AppState::ReplicaRunning { cancel, task, .. } => {
+            cancel.cancel();
+            task.await.unwrap()
+        }
+        _ => unimplemented!(),
+    }
+}
+
+pub fn serve(registry: Registry) {
+    let state = Arc::new(Mutex::new(AppState::Idle));
+    let hook = std::panic::take_hook();
+    std::panic::set_hook({
+        let state = state.clone();
+        Box::new(move |info| {
+            if let Ok(mut state) = state.try_lock() {
+                *state = AppState::Panicked
+            } else {
+                println!("fail to panicking app state")
+            }
+            hook(info)
+        })
+    });
+
+    let app = Router::new()
+        .route("/panic", get(poll_panic))
+        .route("/task", post(set_task))
+        .route("/reset", post(reset))
+        .route("/benchmark", get(poll_benchmark))
+        .route("/stats", get(poll_stats))
+        .route("/ready", get(poll_ready))
+        .route("/start", post(start))
+        .with_state(ServerState {
+            app: state,
+            registry: Arc::new(registry),
+        });
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime
+        .block_on(async move {
+            Server::bind(&"0.0.0.0:9999".parse().unwrap())
+                .serve(app.into_make_service())
+                .with_graceful_shutdown(async move { tokio::signal::ctrl_c().await.unwrap() })
+                .await
+        })
+        .unwrap();
+    runtime.shutdown_background()
+}
@@ -0,0 +1,212 @@
+// write-ahead log of replicas
+//
+// the log is a sequence of records, each one is
+//   length (u32, little endian) | checksum (first 4 bytes of sha256) | entry
+// a torn record at the end, i.e. the process crashed in the middle of an
+// append, is discarded on recovery. any other malformed record is an error
+
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use bincode::Options;
+use k256::sha2::{Digest, Sha256};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::common::Block;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Entry {
+    // executed blocks in execution order, i.e. the ledger
+    Block(Block),
+    // safety-critical protocol state e.g. view number, locks and votes sent
+    // opaque to the storage, interpreted by the protocol on recovery
+    State(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    // leave it to the OS, survive process crash but not machine crash
+    Never,
+    Always,
+    // fsync every this number of appends
+    Every(usize),
+}
+
+impl From<control_messages::SyncPolicy> for SyncPolicy {
+    fn from(value: control_messages::SyncPolicy) -> Self {
+        match value {
+            control_messages::SyncPolicy::Never => Self::Never,
+            control_messages::SyncPolicy::Always => Self::Always,
+            control_messages::SyncPolicy::Every(n) => Self::Every(n),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Storage {
+    Null,
+    File(FileStorage),
+}
+
+#[derive(Debug)]
+pub struct FileStorage {
+    file: File,
+    sync: SyncPolicy,
+    num_unsynced: usize,
+}
+
+impl Storage {
+    // open the log for appending, and return the entries that are already in it
+    pub fn open(path: impl AsRef<Path>, sync: SyncPolicy) -> io::Result<(Self, Vec<Entry>)> {
+        let mut file = File::options()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let records = read_records(&mut file)?;
+        // drop the torn tail, if any, so later appends are readable
+        file.set_len(records.last().map_or(0, |(_, len)| *len))?;
+        file.seek(SeekFrom::End(0))?;
+        let storage = FileStorage {
+            file,
+            sync,
+            num_unsynced: 0,
+        };
+        let entries = records.into_iter().map(|(entry, _)| entry).collect();
+        Ok((Self::File(storage), entries))
+    }
+
+    pub fn append(&mut self, entry: Entry) {
+        let Self::File(storage) = self else {
+            return;
+        };
+        append_record(&mut storage.file, &entry);
+        storage.num_unsynced += 1;
+        match storage.sync {
+            SyncPolicy::Never => {}
+            SyncPolicy::Always => storage.do_sync(),
+            SyncPolicy::Every(n) => {
+                if storage.num_unsynced >= n {
+                    storage.do_sync()
+                }
+            }
+        }
+    }
+
+    pub fn append_state(&mut self, state: &impl Serialize) {
+        if let Self::Null = self {
+            return;
+        }
+        self.append(Entry::State(bincode::options().serialize(state).unwrap()))
+    }
+}
+
+impl FileStorage {
+    fn do_sync(&mut self) {
+        self.file.sync_data().unwrap();
+        self.num_unsynced = 0
+    }
+}
+
+pub fn deserialize_state<T: DeserializeOwned>(state: &[u8]) -> T {
+    bincode::options().deserialize(state).unwrap()
+}
+
+// read all complete entries of a log
+pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<Entry>> {
+    let records = read_records(&mut File::open(path)?)?;
+    Ok(records.into_iter().map(|(entry, _)| entry).collect())
+}
+
+pub(crate) fn append_record(file: &mut File, record: &impl Serialize) {
+    let buf = bincode::options().serialize(record).unwrap();
+    let mut framed = Vec::with_capacity(buf.len() + 8);
+    framed.extend((buf.len() as u32).to_le_bytes());
+    framed.extend(&Sha256::digest(&buf)[..4]);
+    framed.extend(buf);
+    // one write per record, so the record is handed to the OS as a whole
+    file.write_all(&framed).unwrap()
+}
+
+// no entry comes close to this, a longer record is corrupted
+const MAX_RECORD_LEN: u64 = 1 << 30;
+
+// return complete records, each with the length of file that it ends at
+pub(crate) fn read_records<T: DeserializeOwned>(file: &mut File) -> io::Result<Vec<(T, u64)>> {
+    let file_len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    let mut records = Vec::new();
+    let mut len = 0;
+    loop {
+        let mut header = [0; 8];
+        match file.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+        let record_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+        if record_len > MAX_RECORD_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("record of {record_len} bytes at offset {len}"),
+            ));
+        }
+        if len + 8 + record_len > file_len {
+            break;
+        }
+        let mut buf = vec![0; record_len as usize];
+        file.read_exact(&mut buf)?;
+        if Sha256::digest(&buf)[..4] != header[4..] {
+            break;
+        }
+        let record = bincode::options().deserialize(&buf).map_err(|err| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{err} decoding record at offset {len}"),
+            )
+        })?;
+        len += 8 + record_len;
+        records.push((record, len))
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn discard_torn_tail() {
+        let path = std::env::temp_dir().join(format!("wal-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (mut storage, entries) = Storage::open(&path, SyncPolicy::Always).unwrap();
+        assert!(entries.is_empty());
+        storage.append(Entry::Block(Block::default()));
+        storage.append_state(&42u32);
+        drop(storage);
+        // half written record
+        File::options()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[100, 0, 0, 0, 1, 2])
+            .unwrap();
+
+        let (mut storage, entries) = Storage::open(&path, SyncPolicy::Never).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(matches!(&entries[0], Entry::Block(block) if *block == Block::default()));
+        let Entry::State(state) = &entries[1] else {
+            unreachable!()
+        };
+        assert_eq!(deserialize_state::<u32>(state), 42);
+        storage.append_state(&43u32);
+        drop(storage);
+        assert_eq!(read(&path).unwrap().len(), 3);
+        std::fs::remove_file(&path).unwrap()
+    }
+}
@@ -1,10 +1,17 @@
-use std::{collections::HashMap, sync::Mutex, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
     client::BoxedConsume,
-    common::{Block, BlockDigest, Chain, Request, Timer},
+    common::{
+        BatchConfig, Batcher, Block, BlockDigest, Chain, ClientTable, Request, RequestStatus,
+        Timer, BLOCK_COMMITTED,
+    },
     context::{Addr, MultiplexReceive},
     crypto::{Sign, Signed, Verify},
     App, ClientIndex, Context, ReplicaIndex, To,
@@ -32,8 +39,7 @@ pub struct Client {
 struct ClientShared {
     context: Context<Message>,
     request_num: u32,
-    op: Vec<u8>,
-    consume: Option<BoxedConsume>,
+    invokes: BTreeMap<u32, BoxedConsume>,
     resend_timer: Timer,
 }
 
@@ -44,8 +50,7 @@ impl Client {
             shared: Mutex::new(ClientShared {
                 context,
                 request_num: 0,
-                op: Default::default(),
-                consume: None,
+                invokes: Default::default(),
                 resend_timer: Timer::new(Duration::from_millis(100)),
             }),
         }
@@ -55,20 +60,37 @@ impl Client {
 impl crate::Client for Client {
     type Message = Message;
 
-    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) {
+    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
         let shared = &mut *self.shared.lock().unwrap();
-        assert!(shared.consume.is_none());
         shared.request_num += 1;
-        shared.op = op.clone();
-        shared.consume = Some(consume.into());
-        shared.resend_timer.set(&mut shared.context);
+        let ack_num = shared
+            .invokes
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or(shared.request_num);
+        shared.invokes.insert(shared.request_num, consume.into());
+        if shared.resend_timer.id.is_none() {
+            shared.resend_timer.set(&mut shared.context)
+        }
 
         let request = Request {
             client_index: self.index,
             request_num: shared.request_num,
+            ack_num,
             op,
         };
-        shared.context.send(To::Replica(0), request)
+        shared.context.send(To::Replica(0), request);
+        shared.request_num
+    }
+
+    fn abort(&self, invoke_num: u32) -> Option<BoxedConsume> {
+        let shared = &mut *self.shared.lock().unwrap();
+        let consume = shared.invokes.remove(&invoke_num)?;
+        if shared.invokes.is_empty() {
+            shared.resend_timer.unset(&mut shared.context)
+        }
+        Some(consume)
     }
 
     fn handle(&self, message: Self::Message) {
@@ -76,16 +98,18 @@ impl crate::Client for Client {
             unimplemented!()
         };
         let mut shared = self.shared.lock().unwrap();
-        if reply.request_num != shared.request_num {
+        // the invocation may be aborted
+        let Some(consume) = shared.invokes.remove(&reply.request_num) else {
             return;
-        }
-
+        };
         {
             let shared = &mut *shared;
-            shared.resend_timer.unset(&mut shared.context);
+            if shared.invokes.is_empty() {
+                shared.resend_timer.unset(&mut shared.context)
+            } else {
+                shared.resend_timer.reset(&mut shared.context)
+            }
         }
-        shared.op.clear();
-        let consume = shared.consume.take().unwrap();
         drop(shared);
 
         consume.apply(reply.inner.result);
@@ -98,13 +122,14 @@ pub struct Replica {
     blocks: HashMap<BlockDigest, Block>,
     chain: Chain,
     requests: Vec<Request>,
-    replies: HashMap<ClientIndex, Reply>,
+    replies: ClientTable<Reply>,
     app: App,
+    batcher: Batcher,
     pub make_blocks: bool,
 }
 
 impl Replica {
-    pub fn new(context: Context<Message>, app: App) -> Self {
+    pub fn new(context: Context<Message>, app: App, batching: BatchConfig) -> Self {
         Self {
             context,
             // probably need to reserve if `make_blocks` is set
@@ -114,6 +139,7 @@ impl Replica {
             requests: Default::default(),
             replies: Default::default(),
             app,
+            batcher: Batcher::new(batching),
             make_blocks: false,
         }
     }
@@ -127,13 +153,13 @@ impl MultiplexReceive for Replica {
         let Message::Request(request) = message else {
             unimplemented!()
         };
-        match self.replies.get(&request.client_index) {
-            Some(reply) if reply.request_num > request.request_num => return,
-            Some(reply) if reply.request_num == request.request_num => {
+        match self.replies.insert_request(&request) {
+            RequestStatus::New => {}
+            RequestStatus::Pending => return,
+            RequestStatus::Replied(reply) => {
                 self.context.send(To::Addr(remote), reply.clone());
                 return;
             }
-            _ => {}
         }
 
         self.requests.push(request.inner);
@@ -143,36 +169,42 @@ impl MultiplexReceive for Replica {
                 request_num: request.request_num,
                 result: self.app.execute(&request.op),
             };
-            let evicted = self.replies.insert(request.client_index, reply.clone());
-            if let Some(evicted) = evicted {
-                assert_eq!(evicted.request_num, request.request_num - 1)
-            }
+            self.replies.insert_reply(request, reply.clone());
             self.context.send(To::Client(request.client_index), reply)
         }
     }
 
-    fn on_timer(&mut self, _: Addr, _: crate::context::TimerId) {
-        unreachable!()
+    fn on_timer(&mut self, _: Addr, id: crate::context::TimerId) {
+        if self.batcher.on_timer(id, &mut self.context) {
+            self.on_pace()
+        }
     }
 
     fn on_pace(&mut self) {
-        if self.make_blocks && !self.requests.is_empty() {
-            let block = self.chain.propose(&mut self.requests);
+        let num_in_flight = self.chain.num_in_flight();
+        if self.make_blocks
+            && self
+                .batcher
+                .ready(&self.requests, num_in_flight, &mut self.context)
+        {
+            let block = self.chain.propose_batch(self.batcher.take(
+                &mut self.requests,
+                num_in_flight,
+                &mut self.context,
+            ));
             assert!(block.digest() != Chain::genesis().digest());
             let evicted = self.blocks.insert(block.digest(), block.clone());
             assert!(evicted.is_none());
 
             let execute = self.chain.commit(&block);
             assert!(execute);
+            self.context.count(BLOCK_COMMITTED, 1);
             for request in &block.requests {
                 let reply = Reply {
                     request_num: request.request_num,
                     result: self.app.execute(&request.op),
                 };
-                let evicted = self.replies.insert(request.client_index, reply.clone());
-                if let Some(evicted) = evicted {
-                    assert_eq!(evicted.request_num, request.request_num - 1)
-                }
+                self.replies.insert_reply(request, reply.clone());
                 self.context.send(To::Client(request.client_index), reply)
             }
             assert!(self.chain.next_execute().is_none())
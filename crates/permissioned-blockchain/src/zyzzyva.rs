@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     sync::{Arc, Mutex},
     time::Duration,
 };
@@ -7,8 +7,9 @@ use std::{
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    app::Speculative,
     client::BoxedConsume,
-    common::{Block, BlockDigest, Chain, Request, Timer},
+    common::{BatchConfig, Batcher, Block, BlockDigest, Chain, Request, Timer, BLOCK_COMMITTED},
     context::{Addr, MultiplexReceive},
     crypto::{Sign, Signed, Verify},
     App, ClientIndex, Context, ReplicaIndex, To,
@@ -21,6 +22,7 @@ pub enum Message {
     SpecResponse(Signed<SpecResponse>),
     Commit(Signed<Commit>),
     LocalCommit(Signed<LocalCommit>),
+    Checkpoint(Signed<Checkpoint>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -49,6 +51,13 @@ pub struct LocalCommit {
     replica_index: ReplicaIndex,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Checkpoint {
+    height: u32,
+    state_digest: [u8; 32],
+    replica_index: ReplicaIndex,
+}
+
 #[derive(Debug)]
 pub struct Client {
     index: ClientIndex,
@@ -60,7 +69,7 @@ pub struct Client {
 pub struct ClientShared {
     context: Context<Message>,
     request_num: u32,
-    invoke: Option<ClientInvoke>,
+    invokes: BTreeMap<u32, ClientInvoke>,
     resend_timer: Timer,
 }
 
@@ -82,106 +91,137 @@ impl Client {
             shared: Arc::new(Mutex::new(ClientShared {
                 context,
                 request_num: 0,
-                invoke: None,
+                invokes: Default::default(),
                 resend_timer: Timer::new(Duration::from_millis(100)),
             })),
         }
     }
 }
 
+impl ClientShared {
+    fn complete(&mut self, request_num: u32) -> ClientInvoke {
+        let invoke = self.invokes.remove(&request_num).unwrap();
+        if self.invokes.is_empty() {
+            self.resend_timer.unset(&mut self.context)
+        } else {
+            self.resend_timer.reset(&mut self.context)
+        }
+        invoke
+    }
+}
+
 impl crate::Client for Client {
     type Message = Message;
 
-    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) {
+    fn invoke(&self, op: Vec<u8>, consume: impl Into<BoxedConsume>) -> u32 {
         let shared = &mut *self.shared.lock().unwrap();
-        assert!(shared.invoke.is_none());
         shared.request_num += 1;
-        shared.invoke = Some(ClientInvoke {
-            op: op.clone(),
-            responses: Default::default(),
-            commit_digest: None,
-            commit_result: None,
-            local_commits: Default::default(),
-            consume: consume.into(),
-        });
         let request = Request {
             client_index: self.index,
             request_num: shared.request_num,
-            op,
+            ack_num: shared
+                .invokes
+                .keys()
+                .next()
+                .copied()
+                .unwrap_or(shared.request_num),
+            op: op.clone(),
         };
+        shared.invokes.insert(
+            shared.request_num,
+            ClientInvoke {
+                op,
+                responses: Default::default(),
+                commit_digest: None,
+                commit_result: None,
+                local_commits: Default::default(),
+                consume: consume.into(),
+            },
+        );
         shared.context.send(To::AllReplica, request);
-        shared.resend_timer.set(&mut shared.context)
+        if shared.resend_timer.id.is_none() {
+            shared.resend_timer.set(&mut shared.context)
+        }
+        shared.request_num
+    }
+
+    fn abort(&self, invoke_num: u32) -> Option<BoxedConsume> {
+        let shared = &mut *self.shared.lock().unwrap();
+        let invoke = shared.invokes.remove(&invoke_num)?;
+        if shared.invokes.is_empty() {
+            shared.resend_timer.unset(&mut shared.context)
+        }
+        Some(invoke.consume)
     }
 
     fn handle(&self, message: Self::Message) {
         let mut shared_guard = self.shared.lock().unwrap();
         let shared = &mut *shared_guard;
+        let mut completed = Vec::new();
         match message {
             Message::SpecResponse(message) => {
                 // println!("{message:?}");
-                let Some(invoke) = &mut shared.invoke else {
-                    return;
-                };
-                let Some(result) = message
-                    .block
-                    .requests
-                    .iter()
-                    .position(|request| {
-                        request.client_index == self.index
-                            && request.request_num == shared.request_num
-                    })
-                    .map(|i| &message.results[i])
-                else {
-                    return;
-                };
-                invoke
-                    .responses
-                    .insert(message.replica_index, message.clone());
-                let matched_responses = invoke.responses.values().filter(|response| {
-                    (response.block.digest(), &response.results)
-                        == (message.block.digest(), &message.results)
-                });
-                let num_match = matched_responses.clone().count();
-                if num_match == shared.context.num_replica() {
-                    shared.resend_timer.unset(&mut shared.context);
-                    let invoke = shared.invoke.take().unwrap();
-                    drop(shared_guard);
-
-                    let _op = invoke.op;
-                    invoke.consume.apply(result.clone())
-                } else if self.byzantine
-                    && num_match == shared.context.num_replica() - shared.context.num_faulty()
-                {
-                    invoke.commit_digest = Some(message.block.digest());
-                    invoke.commit_result = Some(result.clone());
-                    let commit = Commit {
-                        client_index: self.index,
-                        block_digest: message.block.digest(),
-                        responses: matched_responses.cloned().collect(),
+                let block_digest = message.block.digest();
+                // the block may include several outstanding requests
+                for (request, result) in message.block.requests.iter().zip(&message.results) {
+                    if request.client_index != self.index {
+                        continue;
+                    }
+                    let Some(invoke) = shared.invokes.get_mut(&request.request_num) else {
+                        continue;
                     };
-                    shared.context.send(To::AllReplica, commit)
+                    invoke
+                        .responses
+                        .insert(message.replica_index, message.clone());
+                    let matched_responses = invoke.responses.values().filter(|response| {
+                        (response.block.digest(), &response.results)
+                            == (block_digest, &message.results)
+                    });
+                    let num_match = matched_responses.clone().count();
+                    if num_match == shared.context.num_replica() {
+                        let invoke = shared.complete(request.request_num);
+                        let _op = &invoke.op;
+                        completed.push((invoke, result.clone()))
+                    } else if self.byzantine
+                        && num_match == shared.context.num_replica() - shared.context.num_faulty()
+                    {
+                        let commit = Commit {
+                            client_index: self.index,
+                            block_digest,
+                            responses: matched_responses.cloned().collect(),
+                        };
+                        invoke.commit_digest = Some(block_digest);
+                        invoke.commit_result = Some(result.clone());
+                        shared.context.send(To::AllReplica, commit)
+                    }
                 }
             }
             Message::LocalCommit(message) => {
-                let Some(invoke) = &mut shared.invoke else {
-                    return;
-                };
-                if Some(message.block_digest) != invoke.commit_digest {
-                    return;
-                }
-                invoke.local_commits.insert(message.replica_index);
-                if invoke.local_commits.len()
-                    == shared.context.num_replica() - shared.context.num_faulty()
-                {
-                    shared.resend_timer.unset(&mut shared.context);
-                    let invoke = shared.invoke.take().unwrap();
-                    drop(shared_guard);
-
-                    invoke.consume.apply(invoke.commit_result.unwrap())
+                let request_nums = Vec::from_iter(
+                    shared
+                        .invokes
+                        .iter()
+                        .filter(|(_, invoke)| invoke.commit_digest == Some(message.block_digest))
+                        .map(|(request_num, _)| *request_num),
+                );
+                for request_num in request_nums {
+                    let invoke = shared.invokes.get_mut(&request_num).unwrap();
+                    invoke.local_commits.insert(message.replica_index);
+                    if invoke.local_commits.len()
+                        == shared.context.num_replica() - shared.context.num_faulty()
+                    {
+                        let mut invoke = shared.complete(request_num);
+                        let result = invoke.commit_result.take().unwrap();
+                        completed.push((invoke, result))
+                    }
                 }
             }
             _ => unimplemented!(),
         }
+        drop(shared_guard);
+        for (invoke, result) in completed {
+            invoke.consume.apply(result)
+        }
     }
 }
 
@@ -195,23 +235,40 @@ pub struct Replica {
 
     view_num: u32,
     requests: Vec<Request>,
-    order_requests: HashMap<BlockDigest, Signed<OrderRequest>>,
+    // the ordered blocks, and the certified ones that replace speculatively
+    // executed blocks
+    blocks: HashMap<BlockDigest, Block>,
     commits: HashMap<BlockDigest, Signed<Commit>>,
     chain: Chain,
-    app: App,
+    // speculatively executed blocks are committed by either a commit
+    // certificate or a stable checkpoint, and rolled back if a commit
+    // certificate conflicts with them
+    app: Speculative,
+    // the digests of executed blocks since the committed height
+    executed: BTreeMap<u32, BlockDigest>,
+    checkpoints: HashMap<(u32, [u8; 32]), HashSet<ReplicaIndex>>,
+    batcher: Batcher,
 }
 
 impl Replica {
-    pub fn new(context: Context<Message>, index: ReplicaIndex, app: App) -> Self {
+    pub fn new(
+        context: Context<Message>,
+        index: ReplicaIndex,
+        app: App,
+        batching: BatchConfig,
+    ) -> Self {
         Self {
             context,
             index,
             view_num: 0,
             requests: Default::default(),
-            order_requests: Default::default(),
+            blocks: Default::default(),
             commits: Default::default(),
             chain: Default::default(),
-            app,
+            app: Speculative::new(app),
+            executed: Default::default(),
+            checkpoints: Default::default(),
+            batcher: Batcher::new(batching),
         }
     }
 }
@@ -226,32 +283,47 @@ impl MultiplexReceive for Replica {
             Message::Request(message) => self.handle_request(remote, message),
             Message::OrderRequest(message) => self.handle_order_request(remote, message),
             Message::Commit(message) => self.handle_commit(remote, message),
+            Message::Checkpoint(message) => self.handle_checkpoint(remote, message),
             _ => unimplemented!(),
         }
     }
 
-    fn on_timer(&mut self, receiver: Addr, _: crate::context::TimerId) {
+    fn on_timer(&mut self, receiver: Addr, id: crate::context::TimerId) {
         assert_eq!(receiver, self.context.addr());
-        todo!()
+        // the alarm may be delivered after the timer get unset
+        if self.batcher.on_timer(id, &mut self.context) {
+            self.on_pace()
+        }
     }
 
     fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
         assert_eq!(receiver, self.context.addr());
-        let Message::OrderRequest(message) = message else {
-            unimplemented!()
-        };
-        // is this ok?
-        self.handle_order_request(self.context.addr(), message)
+        match message {
+            // is this ok?
+            Message::OrderRequest(message) => {
+                self.handle_order_request(self.context.addr(), message)
+            }
+            Message::Checkpoint(message) => self.insert_checkpoint(message),
+            _ => unimplemented!(),
+        }
     }
 
     fn on_pace(&mut self) {
-        if self.index == self.primary_index() && !self.requests.is_empty() {
+        if self.index == self.primary_index()
+            && self.batcher.ready(
+                &self.requests,
+                self.chain.num_in_flight(),
+                &mut self.context,
+            )
+        {
             self.do_propose()
         }
     }
 }
 
 impl Replica {
+    const CHECKPOINT_INTERVAL: u32 = 100;
+
     fn primary_index(&self) -> ReplicaIndex {
         (self.view_num as usize % self.context.num_replica()) as _
     }
@@ -278,7 +350,7 @@ impl Replica {
         }
 
         let digest = order_request.block.digest();
-        self.order_requests.insert(digest, order_request);
+        self.blocks.insert(digest, order_request.inner.block);
         self.do_execute(digest);
     }
 
@@ -292,10 +364,42 @@ impl Replica {
             return;
         }
 
-        let Some(_order_request) = self.order_requests.get(&commit.block_digest) else {
-            // TODO
+        // the signatures are checked in `verify`
+        let num_certified = commit
+            .responses
+            .iter()
+            .filter(|response| response.block.digest() == commit.block_digest)
+            .map(|response| response.replica_index)
+            .collect::<HashSet<_>>()
+            .len();
+        if num_certified < self.context.num_replica() - self.context.num_faulty() {
             return;
-        };
+        }
+        if !self.blocks.contains_key(&commit.block_digest) {
+            let block = commit
+                .responses
+                .iter()
+                .map(|response| &response.block)
+                .find(|block| block.digest() == commit.block_digest)
+                .unwrap();
+            if self
+                .executed
+                .get(&block.height)
+                .is_none_or(|digest| *digest == commit.block_digest)
+            {
+                // TODO
+                return;
+            }
+            // the primary has ordered a conflicting block
+            let block = block.clone();
+            if !self.rollback(&block) {
+                // TODO state transfer
+                return;
+            }
+            self.blocks.insert(commit.block_digest, block);
+            self.do_execute(commit.block_digest)
+        }
+        self.commit(self.blocks[&commit.block_digest].height);
 
         let local_commit = LocalCommit {
             block_digest: commit.block_digest,
@@ -305,21 +409,81 @@ impl Replica {
         self.context.send(To::Addr(remote), local_commit)
     }
 
+    fn handle_checkpoint(&mut self, _remote: Addr, checkpoint: Signed<Checkpoint>) {
+        self.insert_checkpoint(checkpoint)
+    }
+
+    fn insert_checkpoint(&mut self, checkpoint: Signed<Checkpoint>) {
+        if checkpoint.height <= self.app.committed_height() {
+            return;
+        }
+        let replica_indexes = self
+            .checkpoints
+            .entry((checkpoint.height, checkpoint.state_digest))
+            .or_default();
+        replica_indexes.insert(checkpoint.replica_index);
+        // the checkpoint is stable once it includes the state of at least one
+        // correct replica
+        if replica_indexes.len() > self.context.num_faulty() {
+            self.commit(checkpoint.height);
+            let committed_height = self.app.committed_height();
+            self.checkpoints
+                .retain(|(height, _), _| *height > committed_height)
+        }
+    }
+
+    fn commit(&mut self, height: u32) {
+        self.app.commit(height);
+        // keep the committed block as the parent of the rolled back ones
+        self.executed = self.executed.split_off(&self.app.committed_height())
+    }
+
+    // revert the executed blocks from the height of the certified block, so it
+    // can be executed in place of them. false if the certified block does not
+    // extend the executed ones
+    fn rollback(&mut self, block: &Block) -> bool {
+        if block.height <= self.app.committed_height() {
+            return false;
+        }
+        let parent_digest = if block.height == 1 {
+            Chain::genesis().digest()
+        } else {
+            let Some(digest) = self.executed.get(&(block.height - 1)) else {
+                return false;
+            };
+            *digest
+        };
+        if block.parent_digest != parent_digest {
+            return false;
+        }
+        self.app.rollback(block.height - 1);
+        self.executed.split_off(&block.height);
+        self.chain.rollback(parent_digest, block.height - 1);
+        true
+    }
+
     fn do_propose(&mut self) {
         assert_eq!(self.index, self.primary_index());
         let order_request = OrderRequest {
             view_num: self.view_num,
-            block: self.chain.propose(&mut self.requests),
+            block: self.chain.propose_batch(self.batcher.take(
+                &mut self.requests,
+                self.chain.num_in_flight(),
+                &mut self.context,
+            )),
         };
         self.context.send(To::AllReplicaWithLoopback, order_request)
     }
 
     fn do_execute(&mut self, block_digest: BlockDigest) {
-        let mut block = &self.order_requests[&block_digest].block;
+        let mut block = &self.blocks[&block_digest];
         if !self.chain.commit(block) {
             return;
         }
         while let Some(block_digest) = {
+            self.context.count(BLOCK_COMMITTED, 1);
+            self.app.begin(block.height);
+            self.executed.insert(block.height, block.digest());
             let results = Vec::from_iter(
                 block
                     .requests
@@ -337,9 +501,17 @@ impl Replica {
                 .map(|request| request.client_index)
                 .collect();
             self.context.send(To::Clients(indexes), spec_response);
+            if block.height.is_multiple_of(Self::CHECKPOINT_INTERVAL) {
+                let checkpoint = Checkpoint {
+                    height: block.height,
+                    state_digest: self.app.app().snapshot_digest(),
+                    replica_index: self.index,
+                };
+                self.context.send(To::AllReplicaWithLoopback, checkpoint)
+            }
             self.chain.next_execute()
         } {
-            block = &self.order_requests[&block_digest].block
+            block = &self.blocks[&block_digest]
         }
     }
 }
@@ -368,6 +540,12 @@ impl Sign<Commit> for Message {
     }
 }
 
+impl Sign<Checkpoint> for Message {
+    fn sign(message: Checkpoint, signer: &crate::crypto::Signer) -> Self {
+        Self::Checkpoint(signer.sign_public(message))
+    }
+}
+
 impl Sign<LocalCommit> for Message {
     fn sign(message: LocalCommit, signer: &crate::crypto::Signer) -> Self {
         Self::LocalCommit(signer.sign_private(message))
@@ -395,6 +573,7 @@ impl Verify<ReplicaIndex> for Message {
                 Ok(())
             }
             Self::LocalCommit(message) => verifier.verify(message, message.replica_index),
+            Self::Checkpoint(message) => verifier.verify(message, message.replica_index),
         }
     }
 }
@@ -1,24 +1,144 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
 
+use nix::{
+    sched::{sched_getaffinity, sched_setaffinity, CpuSet},
+    unistd::Pid,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Task {
     pub mode: String,
     pub app: App,
+    // the addresses of replicas and clients are laid out one shard after
+    // another, each shard is a replica group over a partition of the keys
+    pub num_shard: usize,
     pub client_addrs: Vec<SocketAddr>,
     pub replica_addrs: Vec<SocketAddr>,
     pub multicast_addr: SocketAddr,
     pub num_faulty: usize,
     pub drop_rate: f64,
+    // uniformly delay every incoming message by up to this
+    pub network_max_delay: Duration,
+    pub batching: Batching,
+    // PBFT and MinBFT only. the number of proposed but not executed blocks the
+    // primary may have outstanding, which is also how far beyond its executed
+    // height a backup accepts proposals. zero for unbounded
+    pub pipeline_window: u32,
+    // PBFT only. replicas certify the roots of each executed block in an extra
+    // round, so a client accepts a single reply instead of f + 1 matching ones
+    pub certify_execution: bool,
+    pub storage: Option<Storage>,
+    // directory to export the committed blocks into, see `ledger-audit`
+    pub ledger_dir: Option<String>,
+    // compare application states across replicas every this number of blocks,
+    // zero to disable. PBFT, HotStuff and MinBFT only
+    pub checkpoint_interval: u32,
+    // the message counts and the max queue depth of `ReplicaStats`, which cost
+    // a lock per message. the other replica stats are always collected
+    pub collect_stats: bool,
     pub seed: u64,
+    pub affinity: Affinity,
     pub role: Role,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+// the cores to pin the threads of a task to. a benchmark client runs one
+// thread of each kind per group and takes the cores of a kind round robin by
+// the group index, while a replica runs as group 0. the threads of a kind with
+// no core are left unpinned
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Affinity {
+    pub runtime: Vec<usize>,
+    pub dispatch: Vec<usize>,
+    pub benchmark: Vec<usize>,
+}
+
+impl Default for Affinity {
+    fn default() -> Self {
+        Self::paired(1)
+    }
+}
+
+impl Affinity {
+    // each group on a pair of cores, the runtime on the first one and the
+    // other threads on the second one
+    pub fn paired(num_group: usize) -> Self {
+        let first = Vec::from_iter((0..num_group).map(|index| index * 2));
+        let second = Vec::from_iter((0..num_group).map(|index| index * 2 + 1));
+        Self {
+            runtime: first,
+            dispatch: second.clone(),
+            benchmark: second,
+        }
+    }
+
+    // fail before spawning anything if the plan is made for another machine
+    pub fn validate(&self) {
+        let available = available_cores();
+        for core in [&self.runtime, &self.dispatch, &self.benchmark]
+            .into_iter()
+            .flatten()
+        {
+            assert!(
+                available.contains(core),
+                "core {core} is not available, available cores {available:?}"
+            )
+        }
+    }
+
+    pub fn set_runtime(&self, index: usize) {
+        Self::set(&self.runtime, index)
+    }
+
+    pub fn set_dispatch(&self, index: usize) {
+        Self::set(&self.dispatch, index)
+    }
+
+    pub fn set_benchmark(&self, index: usize) {
+        Self::set(&self.benchmark, index)
+    }
+
+    fn set(cores: &[usize], index: usize) {
+        if !cores.is_empty() {
+            set_affinity(cores[index % cores.len()])
+        }
+    }
+}
+
+pub fn set_affinity(index: usize) {
+    let mut cpu_set = CpuSet::new();
+    cpu_set.set(index).unwrap();
+    sched_setaffinity(Pid::from_raw(0), &cpu_set).unwrap()
+}
+
+// the cores this process may run on, which are not necessarily the first
+// `available_parallelism` ones e.g. in a container
+pub fn available_cores() -> Vec<usize> {
+    let cpu_set = sched_getaffinity(Pid::from_raw(0)).unwrap();
+    Vec::from_iter((0..CpuSet::count()).filter(|&index| cpu_set.is_set(index).unwrap()))
+}
+
+// comma separated cores or ranges of cores, e.g. "0,2-5"
+pub fn parse_cores(spec: &str) -> Vec<usize> {
+    let mut cores = Vec::new();
+    for part in spec.split(',').filter(|part| !part.is_empty()) {
+        if let Some((start, end)) = part.split_once('-') {
+            cores.extend(start.parse::<usize>().unwrap()..=end.parse().unwrap())
+        } else {
+            cores.push(part.parse().unwrap())
+        }
+    }
+    cores
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum App {
     Null,
     Ycsb(YcsbConfig),
+    SmallBank(SmallBankConfig),
+    // registered with `permissioned_blockchain::app::Registry`, the config is
+    // opaque and interpreted by the registered application
+    Custom { name: String, config: Vec<u8> },
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -30,6 +150,105 @@ pub struct YcsbConfig {
     pub read_portion: u32,
     pub update_portion: u32,
     pub rmw_portion: u32,
+    pub scan_portion: u32,
+    pub insert_portion: u32,
+    pub delete_portion: u32,
+    pub max_scan_len: usize,
+    pub distribution: Distribution,
+    // issue reads through the read-only fast path if the protocol supports
+    pub read_only: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Distribution {
+    Uniform,
+    Zipfian,
+    Latest,
+}
+
+impl YcsbConfig {
+    // the core workloads A to F of YCSB, keeping the record settings. none for
+    // other letters
+    pub fn core_workload(self, workload: char) -> Option<Self> {
+        let (read_portion, update_portion, rmw_portion, scan_portion, insert_portion) =
+            match workload {
+                // update heavy
+                'A' => (50, 50, 0, 0, 0),
+                // read mostly
+                'B' => (95, 5, 0, 0, 0),
+                // read only
+                'C' => (100, 0, 0, 0, 0),
+                // read latest
+                'D' => (95, 0, 0, 0, 5),
+                // short ranges
+                'E' => (0, 0, 0, 95, 5),
+                // read-modify-write
+                'F' => (50, 0, 50, 0, 0),
+                _ => return None,
+            };
+        Some(Self {
+            read_portion,
+            update_portion,
+            rmw_portion,
+            scan_portion,
+            insert_portion,
+            delete_portion: 0,
+            max_scan_len: 100,
+            distribution: if workload == 'D' {
+                Distribution::Latest
+            } else {
+                Distribution::Zipfian
+            },
+            ..self
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SmallBankConfig {
+    pub num_account: usize,
+    pub num_hot_account: usize,
+    // in percent
+    pub hot_portion: u32,
+    // issue balance queries through the read-only fast path if the protocol
+    // supports
+    pub read_only: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Batching {
+    pub max_size: usize,
+    pub max_bytes: usize,
+    // hold a proposal that is not full for at most this long, zero to propose
+    // whenever there are pending requests
+    pub max_delay: Duration,
+    // grow batches while blocks are in flight and shrink them when idle
+    pub adaptive: bool,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Self {
+            max_size: 100,
+            max_bytes: usize::MAX,
+            max_delay: Duration::ZERO,
+            adaptive: false,
+        }
+    }
+}
+
+// replica write-ahead log, recovered on start if the log exists
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Storage {
+    pub dir: String,
+    pub sync: SyncPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SyncPolicy {
+    Never,
+    Always,
+    Every(usize),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,17 +262,220 @@ pub struct BenchmarkClient {
     pub num_group: usize,
     pub num_client: usize, // per group
     pub offset: usize,
+    // of the measurement, which happens between warming up and cooling down
     pub duration: Duration,
+    pub warmup: Duration,
+    pub cooldown: Duration,
+    // open-loop arrivals instead of invoking back to back, none for closed loop
+    pub arrival: Option<Arrival>,
+    // the transactions that each client may have outstanding, at least one
+    pub num_outstanding: usize,
+}
+
+// the arrival process of an open-loop benchmark client, the rates are in
+// transactions per second of the whole client host, split evenly among the
+// groups. each client still has at most `num_outstanding` outstanding
+// transactions, so the arrivals queue up when all clients are busy
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Arrival {
+    Constant(f64),
+    Poisson(f64),
+    // Poisson arrivals of bursts with on average `burst_size` back-to-back
+    // transactions
+    Bursty { rate: f64, burst_size: usize },
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Replica {
-    //
+    // across all shards
     pub index: u8,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+// counted since the replica starts, until the stats are polled
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReplicaStats {
+    // by message type, a message sent to multiple addresses is counted once for
+    // each of them
+    pub messages_in: BTreeMap<String, MessageStats>,
+    pub messages_out: BTreeMap<String, MessageStats>,
+    pub num_signed: u64,
+    pub num_verified: u64,
+    pub num_block_committed: u64,
+    pub num_view_change: u64,
+    // the comparisons with other replicas that find the application state
+    // diverged, see `Task::checkpoint_interval`
+    pub num_state_diverged: u64,
+    pub first_diverged_block: Option<u32>,
+    // of the messages waiting for the replica, when polled and at most
+    pub queue_depth: usize,
+    pub max_queue_depth: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct MessageStats {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BenchmarkStats {
     pub throughput: f32,
     pub average_latency: Option<Duration>,
+    // the arrival rate of an open-loop benchmark, which exceeds the throughput
+    // past saturation
+    pub offered_load: Option<f32>,
+    // the transactions that fail during the measurement e.g. on timeout, which
+    // are not in the throughput and the latencies
+    pub num_failed: usize,
+    pub latency: Histogram,
+    // the number of finished transactions in each second
+    pub throughput_series: Vec<u32>,
+    pub groups: Vec<GroupStats>,
+}
+
+impl BenchmarkStats {
+    // combine with the stats of a benchmark that runs side by side, e.g. on
+    // another client host
+    pub fn merge(&mut self, other: &Self) {
+        let (len, other_len) = (self.latency.len(), other.latency.len());
+        self.average_latency = match (self.average_latency, other.average_latency) {
+            (Some(latency), Some(other_latency)) => Some(Duration::from_secs_f64(
+                (latency.as_secs_f64() * len as f64
+                    + other_latency.as_secs_f64() * other_len as f64)
+                    / (len + other_len) as f64,
+            )),
+            (latency, other_latency) => latency.or(other_latency),
+        };
+        self.throughput += other.throughput;
+        self.offered_load = match (self.offered_load, other.offered_load) {
+            (Some(load), Some(other_load)) => Some(load + other_load),
+            (load, other_load) => load.or(other_load),
+        };
+        self.num_failed += other.num_failed;
+        self.latency.merge(&other.latency);
+        if self.throughput_series.len() < other.throughput_series.len() {
+            self.throughput_series
+                .resize(other.throughput_series.len(), 0)
+        }
+        for (count, other_count) in self
+            .throughput_series
+            .iter_mut()
+            .zip(&other.throughput_series)
+        {
+            *count += other_count
+        }
+        self.groups.extend(other.groups.iter().cloned())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupStats {
+    pub throughput: f32,
+    pub latency: Histogram,
+}
+
+// latency histogram in the style of HDR histogram, recording nanoseconds in
+// buckets with relative error under 1 / 2^SUB_BUCKET_BITS
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    counts: BTreeMap<u32, u64>,
+    len: u64,
+}
+
+impl Histogram {
+    const SUB_BUCKET_BITS: u32 = 7;
+
+    fn bucket(value: u64) -> u32 {
+        if value < 1 << Self::SUB_BUCKET_BITS {
+            return value as _;
+        }
+        let shift = u64::BITS - 1 - value.leading_zeros() - Self::SUB_BUCKET_BITS;
+        ((shift + 1) << Self::SUB_BUCKET_BITS) + (value >> shift) as u32
+            - (1 << Self::SUB_BUCKET_BITS)
+    }
+
+    // the lowest value of the bucket
+    fn value(bucket: u32) -> u64 {
+        if bucket < 1 << Self::SUB_BUCKET_BITS {
+            return bucket as _;
+        }
+        let shift = (bucket >> Self::SUB_BUCKET_BITS) - 1;
+        let sub_bucket = (bucket & ((1 << Self::SUB_BUCKET_BITS) - 1)) | 1 << Self::SUB_BUCKET_BITS;
+        (sub_bucket as u64) << shift
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        *self
+            .counts
+            .entry(Self::bucket(latency.as_nanos() as _))
+            .or_default() += 1;
+        self.len += 1
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (&bucket, &count) in &other.counts {
+            *self.counts.entry(bucket).or_default() += count
+        }
+        self.len += other.len
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // e.g. 0.99 for p99
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let rank = ((quantile * self.len as f64).ceil() as u64).max(1);
+        let mut count = 0;
+        for (&bucket, &bucket_count) in &self.counts {
+            count += bucket_count;
+            if count >= rank {
+                return Some(Duration::from_nanos(Self::value(bucket)));
+            }
+        }
+        None
+    }
+
+    // the cumulative distribution, as latencies and the portions of the
+    // recorded ones that are no higher
+    pub fn cdf(&self) -> impl Iterator<Item = (Duration, f64)> + '_ {
+        self.counts
+            .iter()
+            .scan(0, move |count, (&bucket, &bucket_count)| {
+                *count += bucket_count;
+                Some((
+                    Duration::from_nanos(Self::value(bucket)),
+                    *count as f64 / self.len as f64,
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_quantile() {
+        let mut histogram = Histogram::default();
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros))
+        }
+        for (quantile, expected) in [(0.5, 500.), (0.99, 990.), (0.999, 999.), (1., 1000.)] {
+            let value = histogram.quantile(quantile).unwrap().as_nanos() as f64 / 1000.;
+            assert!(
+                value <= expected && value > expected * 0.99,
+                "{quantile} {value}"
+            )
+        }
+        let mut merged = Histogram::default();
+        merged.merge(&histogram);
+        merged.merge(&histogram);
+        assert_eq!(merged.quantile(0.5), histogram.quantile(0.5));
+        assert_eq!(merged.cdf().last().unwrap().1, 1.)
+    }
 }
@@ -1,6 +1,7 @@
 use std::{
+    collections::HashSet,
     fmt::Write,
-    net::SocketAddr,
+    net::{Ipv4Addr, SocketAddr},
     sync::{
         atomic::{AtomicBool, Ordering::SeqCst},
         Arc,
@@ -8,285 +9,119 @@ use std::{
     time::Duration,
 };
 
-use control_messages::{App, BenchmarkClient, BenchmarkStats, Replica, Role, Task};
+use control_messages::{
+    Affinity, BenchmarkClient, BenchmarkStats, MessageStats, Replica, ReplicaStats, Role, Task,
+};
 use reqwest::Client;
 use tokio::{select, spawn, time::sleep};
 use tokio_util::sync::CancellationToken;
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    let ycsb_app = App::Ycsb(control_messages::YcsbConfig {
-        num_key: 10 * 1000,
-        num_value: 100 * 1000,
-        key_len: 64,
-        value_len: 128,
-        read_portion: 50,
-        update_portion: 40,
-        rmw_portion: 10,
-    });
-    match std::env::args().nth(1).as_deref() {
-        Some("test") => {
-            run(
-                5,
-                200,
-                1,
-                "hotstuff",
-                App::Null,
-                0.,
-                1,
-                &[],
-                &mut std::io::empty(),
-            )
-            .await
-        }
-        Some("fpga") => {
-            let saved = std::fs::read_to_string("saved-fpga.csv").unwrap_or_default();
-            let saved_lines = Vec::from_iter(saved.lines());
-            let mut out = std::fs::File::options()
-                .create(true)
-                .append(true)
-                .open("saved-fpga.csv")
-                .unwrap();
-            run_clients(
-                "unreplicated",
-                [1].into_iter()
-                    .chain((2..=20).step_by(2))
-                    .chain((20..=100).step_by(10))
-                    .chain((100..=200).step_by(20)),
-                &saved_lines,
-                &mut out,
-            )
-            .await;
-            run_clients(
-                "neo-pk",
-                [1].into_iter()
-                    .chain((2..=40).step_by(2))
-                    .chain((40..=100).step_by(10))
-                    .chain((100..=200).step_by(20)),
-                &saved_lines,
-                &mut out,
-            )
-            .await;
-            run_clients(
-                "neo-bn",
-                [1].into_iter()
-                    .chain((2..=60).step_by(2))
-                    .chain((60..=100).step_by(10))
-                    .chain((100..=300).step_by(20)),
-                &saved_lines,
-                &mut out,
-            )
-            .await;
-            run_clients(
-                "pbft",
-                [1].into_iter()
-                    .chain((2..=60).step_by(2))
-                    .chain((60..=100).step_by(10)),
-                &saved_lines,
-                &mut out,
-            )
-            .await;
-            run_clients(
-                "zyzzyva",
-                [1].into_iter().chain((2..=20).step_by(2)),
-                &saved_lines,
-                &mut out,
-            )
-            .await;
-            run_clients(
-                "zyzzyva-f",
-                [1].into_iter().chain((2..=20).step_by(2)),
-                &saved_lines,
-                &mut out,
-            )
-            .await;
-            run_clients(
-                "hotstuff",
-                [1].into_iter()
-                    .chain((2..=60).step_by(2))
-                    .chain((60..=100).step_by(10))
-                    .chain((100..=200).step_by(20)),
-                &saved_lines,
-                &mut out,
-            )
-            .await;
-            run_clients(
-                "minbft",
-                [1].into_iter()
-                    .chain((2..=60).step_by(2))
-                    .chain((60..=100).step_by(10))
-                    .chain((100..=300).step_by(20)),
-                &saved_lines,
-                &mut out,
-            )
-            .await;
+use crate::spec::{ClientHost, Run, Spec};
 
-            for mode in [
-                "unreplicated",
-                "neo-pk",
-                "neo-bn",
-                "pbft",
-                // "zyzzyva",
-                // "zyzzyva-f",
-                "hotstuff",
-                "minbft",
-            ] {
-                run_full_throughput(mode, ycsb_app, 0., &saved_lines, &mut out).await
-            }
-            run(5, 10, 1, "zyzzyva", ycsb_app, 0., 1, &saved_lines, &mut out).await;
-            run(
-                5,
-                6,
-                1,
-                "zyzzyva-f",
-                ycsb_app,
-                0.,
-                1,
-                &saved_lines,
-                &mut out,
-            )
-            .await;
+mod spec;
 
-            for drop_rate in [1e-5, 5e-5, 1e-4, 5e-4, 1e-3] {
-                run_full_throughput("neo-pk", App::Null, drop_rate, &saved_lines, &mut out).await
-            }
-        }
-        Some("hmac") => {
-            let saved = std::fs::read_to_string("saved-hmac.csv").unwrap_or_default();
-            let saved_lines = Vec::from_iter(saved.lines());
-            let mut out = std::fs::File::options()
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let spec = std::env::args()
+        .nth(1)
+        .expect("usage: control <spec name or path> [list]");
+    let spec = Spec::load(&spec);
+    if spec.aws && !cfg!(feature = "aws") {
+        panic!("require enable aws feature")
+    }
+    let saved = spec
+        .output
+        .as_ref()
+        .map(|output| std::fs::read_to_string(output).unwrap_or_default())
+        .unwrap_or_default();
+    let saved_lines = Vec::from_iter(saved.lines());
+    let mut out: Box<dyn std::io::Write> = match &spec.output {
+        Some(output) => Box::new(
+            std::fs::File::options()
                 .create(true)
                 .append(true)
-                .open("saved-hmac.csv")
-                .unwrap();
-
-            run_clients(
-                "neo-hm",
-                [1].into_iter()
-                    .chain((2..=40).step_by(2))
-                    .chain((40..=100).step_by(10))
-                    .chain((100..=200).step_by(20)),
-                &saved_lines,
-                &mut out,
-            )
-            .await;
-
-            run_full_throughput("neo-hm", ycsb_app, 0., &saved_lines, &mut out).await;
+                .open(output)
+                .unwrap(),
+        ),
+        None => Box::new(std::io::empty()),
+    };
 
-            for drop_rate in [1e-5, 5e-5, 1e-4, 5e-4, 1e-3] {
-                run_full_throughput("neo-hm", App::Null, drop_rate, &saved_lines, &mut out).await
-            }
+    // overlapping ranges may expand into the same run more than once
+    let mut ids = HashSet::new();
+    let runs = Vec::from_iter(
+        spec.runs()
+            .into_iter()
+            .map(|run| (run.id(), run))
+            .filter(|(id, _)| ids.insert(id.clone())),
+    );
+    let num_saved = runs
+        .iter()
+        .filter(|(id, run)| is_saved(&saved_lines, id, run))
+        .count();
+    println!("* {} runs, {num_saved} saved", runs.len());
+    if std::env::args().nth(2).as_deref() == Some("list") {
+        for (id, run) in &runs {
+            let saved = if is_saved(&saved_lines, id, run) {
+                " (saved)"
+            } else {
+                ""
+            };
+            println!("{id}{saved}")
         }
-        #[cfg(not(feature = "aws"))]
-        Some("aws") => panic!("require enable aws feature"),
-        #[cfg(feature = "aws")]
-        Some("aws") => {
-            let saved = std::fs::read_to_string("saved-aws.csv").unwrap_or_default();
-            let saved_lines = Vec::from_iter(saved.lines());
-            let mut out = std::fs::File::options()
-                .create(true)
-                .append(true)
-                .open("saved-aws.csv")
-                .unwrap();
-
-            for num_faulty in 2..=33 {
-                run(
-                    1,
-                    1,
-                    match num_faulty {
-                        n if n > 32 => 6,
-                        n if n > 29 => 7,
-                        n if n > 26 => 8,
-                        n if n > 24 => 9,
-                        n if n > 21 => 10,
-                        n if n > 19 => 11,
-                        n if n > 17 => 12,
-                        n if n > 14 => 14,
-                        n if n > 11 => 15,
-                        n if n > 9 => 20,
-                        n if n > 7 => 30,
-                        n if n > 5 => 44,
-                        n if n > 3 => 72,
-                        3 => 80,
-                        2 => 100,
-                        _ => unreachable!(),
-                    },
-                    "neo-hm",
-                    App::Null,
-                    0.,
-                    num_faulty,
-                    &saved_lines,
-                    &mut out,
-                )
-                .await
-            }
-            for num_faulty in 2..=33 {
-                run(
-                    1,
-                    1,
-                    100,
-                    "neo-pk",
-                    App::Null,
-                    0.,
-                    num_faulty,
-                    &saved_lines,
-                    &mut out,
-                )
-                .await
-            }
+        return;
+    }
+    for (index, (id, run)) in runs.iter().enumerate() {
+        println!("* work on {id} ({}/{})", index + 1, runs.len());
+        if is_saved(&saved_lines, id, run) {
+            println!("* skip because exist record found");
+            continue;
         }
-
-        _ => unimplemented!(),
+        self::run(run, id, &spec.client_hosts, &mut out).await
     }
 }
 
-async fn run_full_throughput(
-    mode: &str,
-    app: App,
-    drop_rate: f64,
-    saved_lines: &[&str],
-    out: impl std::io::Write,
-) {
-    run(5, 200, 1, mode, app, drop_rate, 1, saved_lines, out).await
-}
-
-async fn run_clients(
-    mode: &str,
-    num_clients_in_5_groups: impl Iterator<Item = usize>,
-    saved_lines: &[&str],
-    mut out: impl std::io::Write,
-) {
-    run(1, 1, 1, mode, App::Null, 0., 1, saved_lines, &mut out).await;
-    for num_client in num_clients_in_5_groups {
-        run(
-            5,
-            num_client,
-            1,
-            mode,
-            App::Null,
-            0.,
-            1,
-            saved_lines,
-            &mut out,
-        )
-        .await
-    }
+// a saved line is the id followed by exactly the result fields, so e.g. a run
+// of 5 clients does not take the line of 50 clients, or the line of a run that
+// has more id fields
+fn is_saved(saved_lines: &[&str], id: &str, run: &Run) -> bool {
+    // throughput, average latency and 4 percentiles, plus the offered load of
+    // open loop
+    let num_result = if run.arrival.is_some() { 7 } else { 6 };
+    saved_lines.iter().any(|line| {
+        line.strip_prefix(id)
+            .and_then(|line| line.strip_prefix(','))
+            .is_some_and(|results| results.split(',').count() == num_result)
+    })
 }
 
-#[allow(clippy::too_many_arguments)]
 async fn run(
-    num_group: usize,
-    num_client: usize,
-    num_client_host: usize,
-    mode: &str,
-    app: App,
-    drop_rate: f64,
-    num_faulty: usize,
-    saved_lines: &[&str],
+    run: &Run,
+    id: &str,
+    #[cfg_attr(feature = "aws", allow(unused_variables))] lab_client_hosts: &[ClientHost],
     mut out: impl std::io::Write,
 ) {
-    let client_addrs;
-    let replica_addrs;
+    let Run {
+        num_group,
+        num_client,
+        num_client_host,
+        drop_rate,
+        num_faulty,
+        num_shard,
+        batching,
+        pipeline_window,
+        certify_execution,
+        arrival,
+        num_outstanding,
+        network_max_delay,
+        checkpoint_interval,
+        duration,
+        warmup,
+        cooldown,
+        ..
+    } = *run;
+    let mode = &*run.mode;
+    let client_ips;
+    let replica_ips;
     let multicast_addr;
     let client_hosts;
     let replica_hosts;
@@ -294,39 +129,24 @@ async fn run(
     #[cfg(not(feature = "aws"))]
     {
         assert!(num_faulty <= 1);
-        client_addrs = (20000..).map(|port| SocketAddr::from(([10, 0, 0, 10], port)));
-        replica_addrs = vec![
-            SocketAddr::from(([10, 0, 0, 1], 10000)),
-            SocketAddr::from(([10, 0, 0, 2], 10000)),
-            SocketAddr::from(([10, 0, 0, 3], 10000)),
-            SocketAddr::from(([10, 0, 0, 4], 10000)),
-        ];
+        client_ips = Vec::from_iter(lab_client_hosts.iter().map(|host| host.ip));
+        replica_ips = Vec::from_iter((1..=4).map(|i| Ipv4Addr::new(10, 0, 0, i)));
         multicast_addr = SocketAddr::from(([10, 0, 0, 255], 60004));
 
-        client_hosts = ["nsl-node10.d2"];
-        assert_eq!(num_client_host, 1);
-        replica_hosts = [
-            "nsl-node1.d2",
-            "nsl-node2.d2",
-            "nsl-node3.d2",
-            "nsl-node4.d2",
-        ];
+        client_hosts = Vec::from_iter(lab_client_hosts.iter().map(|host| host.host.clone()));
+        replica_hosts = Vec::from_iter((1..=4).map(|i| format!("nsl-node{i}.d2")));
     }
 
     #[cfg(feature = "aws")]
     let output = neo_aws::Output::new_terraform();
     #[cfg(feature = "aws")]
     {
-        use std::net::Ipv4Addr;
-        client_addrs = output
-            .client_ips
-            .into_iter()
-            .map(|ip| ip.parse::<Ipv4Addr>().unwrap())
-            .flat_map(|ip| {
-                (20000..)
-                    .take(num_group * num_client)
-                    .map(move |port| SocketAddr::from((ip, port)))
-            });
+        client_ips = Vec::from_iter(
+            output
+                .client_ips
+                .into_iter()
+                .map(|ip| ip.parse::<Ipv4Addr>().unwrap()),
+        );
         #[allow(clippy::int_plus_one)]
         {
             assert!(
@@ -335,14 +155,11 @@ async fn run(
                 output.replica_ips.len()
             )
         }
-        replica_addrs = Vec::from_iter(
+        replica_ips = Vec::from_iter(
             output
                 .replica_ips
                 .into_iter()
-                .map(|ip| SocketAddr::from((ip.parse::<Ipv4Addr>().unwrap(), 10000)))
-                // TODO clarify this and avoid pitfall
-                .chain((30000..).map(|port| SocketAddr::from(([127, 0, 0, 1], port))))
-                .take(3 * num_faulty + 1),
+                .map(|ip| ip.parse::<Ipv4Addr>().unwrap()),
         );
         multicast_addr =
             SocketAddr::from((output.sequencer_ip.parse::<Ipv4Addr>().unwrap(), 60004));
@@ -350,22 +167,34 @@ async fn run(
         replica_hosts = output.replica_hosts
     }
 
-    assert!(client_hosts.len() >= num_client_host);
-    let client_addrs = Vec::from_iter(client_addrs.take(num_group * num_client * num_client_host));
-    let id = format!(
-        "{mode},{},{drop_rate},{},{num_faulty}",
-        match app {
-            App::Null => "null",
-            App::Ycsb(_) => "ycsb",
-        },
-        client_addrs.len(),
+    // the addresses of all shards are laid out one shard after another, see
+    // `Config::shard`. each shard has 3f + 1 replica addresses, and clients
+    // bind one port per shard
+    assert!(
+        client_hosts.len() >= num_client_host,
+        "there are only {} client hosts",
+        client_hosts.len()
+    );
+    let num_host_client = num_group * num_client;
+    let client_addrs = Vec::from_iter((0..num_shard).flat_map(|shard_index| {
+        client_ips
+            .iter()
+            .take(num_client_host)
+            .flat_map(move |&ip| {
+                (20000 + (shard_index * num_host_client) as u16..)
+                    .take(num_host_client)
+                    .map(move |port| SocketAddr::from((ip, port)))
+            })
+    }));
+    let num_shard_replica = 3 * num_faulty + 1;
+    let replica_addrs = Vec::from_iter(
+        replica_ips
+            .iter()
+            .map(|&ip| SocketAddr::from((ip, 10000)))
+            // TODO clarify this and avoid pitfall
+            .chain((30000..).map(|port| SocketAddr::from(([127, 0, 0, 1], port))))
+            .take(num_shard * num_shard_replica),
     );
-    println!("* work on {id}");
-    if saved_lines.iter().any(|line| line.starts_with(&id)) {
-        println!("* skip because exist record found");
-        return;
-    }
-
     #[cfg(feature = "aws")]
     {
         std::process::Command::new("ssh")
@@ -398,13 +227,26 @@ async fn run(
 
     let task = |role| Task {
         mode: String::from(mode),
-        app,
+        app: run.app.clone(),
+        num_shard,
         client_addrs: client_addrs.clone(),
         replica_addrs: replica_addrs.clone(),
         multicast_addr,
         num_faulty,
         drop_rate,
+        network_max_delay,
+        batching,
+        pipeline_window,
+        certify_execution,
+        storage: run.storage.clone(),
+        ledger_dir: run.ledger_dir.clone(),
+        checkpoint_interval,
+        collect_stats: run.collect_stats,
         seed: 3603269_3604874,
+        affinity: match &role {
+            Role::BenchmarkClient(config) => Affinity::paired(config.num_group),
+            Role::Replica(_) => Affinity::default(),
+        },
         role,
     };
 
@@ -421,26 +263,32 @@ async fn run(
     let http_client = Arc::new(Client::new());
     let panic = Arc::new(AtomicBool::new(false));
     println!("* start replicas");
-    let mut sessions = Vec::from_iter(
-        replica_hosts
-            .into_iter()
-            .enumerate()
-            .take(match mode {
-                "unreplicated" => 1,
-                "minbft" => num_faulty + 1,
-                "zyzzyva" => 3 * num_faulty + 1,
-                _ => 2 * num_faulty + 1,
-            })
-            .map(|(index, host)| {
-                spawn(host_session(
-                    host,
-                    task(Role::Replica(Replica { index: index as _ })),
-                    http_client.clone(),
-                    cancel.clone(),
-                    panic.clone(),
-                ))
-            }),
+    // the leading replicas of each shard, with their global indexes
+    let num_running = match mode {
+        "unreplicated" => 1,
+        "minbft" => num_faulty + 1,
+        "zyzzyva" | "honey-badger" => 3 * num_faulty + 1,
+        _ => 2 * num_faulty + 1,
+    };
+    assert!(
+        replica_hosts.len() >= (num_shard - 1) * num_shard_replica + num_running,
+        "there are only {} replica hosts",
+        replica_hosts.len()
     );
+    let replica_hosts = Vec::from_iter((0..num_shard).flat_map(|shard_index| {
+        (shard_index * num_shard_replica..)
+            .take(num_running)
+            .map(|index| (index, replica_hosts[index].to_string()))
+    }));
+    let mut sessions = Vec::from_iter(replica_hosts.iter().cloned().map(|(index, host)| {
+        spawn(host_session(
+            host,
+            task(Role::Replica(Replica { index: index as _ })),
+            http_client.clone(),
+            cancel.clone(),
+            panic.clone(),
+        ))
+    }));
 
     sleep(Duration::from_secs(1)).await;
     println!("* start clients");
@@ -448,7 +296,11 @@ async fn run(
         num_group,
         num_client,
         offset: 0,
-        duration: Duration::from_secs(10),
+        duration,
+        warmup,
+        cooldown,
+        arrival,
+        num_outstanding,
     };
     let mut delay = Duration::from_millis(100);
     for client_host in client_hosts.iter().take(num_client_host) {
@@ -464,13 +316,50 @@ async fn run(
         delay = Duration::ZERO;
     }
 
-    let mut throughput = 0.;
-    let mut result = String::new();
-    for (index, client_host) in client_hosts.into_iter().enumerate().take(num_client_host) {
-        if index == 0 {
-            sleep(Duration::from_secs(1)).await
+    // start all client hosts together once every one of them is set up, so
+    // that their measurements overlap
+    let client_hosts = Vec::from_iter(client_hosts.into_iter().take(num_client_host));
+    loop {
+        let mut num_ready = 0;
+        for client_host in &client_hosts {
+            let response = http_client
+                .get(format!("http://{client_host}:9999/ready"))
+                .send()
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap();
+            if response.json::<bool>().await.unwrap() {
+                num_ready += 1
+            }
+        }
+        if num_ready == client_hosts.len() {
+            break;
         }
+        select! {
+            _ = sleep(Duration::from_millis(100)) => {}
+            _ = cancel.cancelled() => break,
+        }
+    }
+    if !cancel.is_cancelled() {
+        for client_host in &client_hosts {
+            http_client
+                .post(format!("http://{client_host}:9999/start"))
+                .send()
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap();
+        }
+    }
+
+    let mut merged_stats = None::<BenchmarkStats>;
+    for client_host in &client_hosts {
         loop {
+            select! {
+                _ = sleep(Duration::from_secs(1)) => {}
+                _ = cancel.cancelled() => break,
+            }
             let response = http_client
                 .get(format!("http://{client_host}:9999/benchmark"))
                 .send()
@@ -479,22 +368,64 @@ async fn run(
                 .error_for_status()
                 .unwrap();
             if let Some(stats) = response.json::<Option<BenchmarkStats>>().await.unwrap() {
-                println!("* {stats:?}");
+                println!(
+                    "* {client_host} throughput {} latency {:?}",
+                    stats.throughput, stats.average_latency
+                );
                 assert_ne!(stats.throughput, 0.);
-                writeln!(
-                    &mut result,
-                    "{id},{index},{},{}",
-                    stats.throughput,
-                    stats.average_latency.unwrap().as_nanos() as f64 / 1000.,
-                )
-                .unwrap();
-                throughput += stats.throughput;
+                if let Some(merged_stats) = &mut merged_stats {
+                    merged_stats.merge(&stats)
+                } else {
+                    merged_stats = Some(stats)
+                }
                 break;
             }
-            select! {
-                _ = sleep(Duration::from_secs(1)) => {}
-                _ = cancel.cancelled() => break,
+        }
+    }
+
+    // collected before the sessions reset the replicas
+    let mut replica_stats = Vec::new();
+    if !cancel.is_cancelled() {
+        for (_, replica_host) in &replica_hosts {
+            let response = http_client
+                .get(format!("http://{replica_host}:9999/stats"))
+                .send()
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap();
+            let stats = response.json::<Option<ReplicaStats>>().await.unwrap();
+            if let Some(stats) = &stats {
+                let total = |messages: &std::collections::BTreeMap<_, _>| {
+                    messages
+                        .values()
+                        .fold((0, 0), |(count, bytes), message: &MessageStats| {
+                            (count + message.count, bytes + message.bytes)
+                        })
+                };
+                println!(
+                    "* {replica_host} signed {} verified {} blocks {} view changes {}",
+                    stats.num_signed,
+                    stats.num_verified,
+                    stats.num_block_committed,
+                    stats.num_view_change,
+                );
+                if run.collect_stats {
+                    println!(
+                        "* {replica_host} in {:?} out {:?} max queue {}",
+                        total(&stats.messages_in),
+                        total(&stats.messages_out),
+                        stats.max_queue_depth
+                    )
+                }
+                if let Some(height) = stats.first_diverged_block {
+                    println!(
+                        "! {replica_host} state diverged {} times, first at block {height}",
+                        stats.num_state_diverged
+                    )
+                }
             }
+            replica_stats.push(stats)
         }
     }
 
@@ -503,10 +434,41 @@ async fn run(
         session.await.unwrap()
     }
     assert!(!panic.load(SeqCst));
-    if num_client_host > 1 {
-        println!("{throughput}");
-        out.write_all(result.as_bytes()).unwrap()
+    let stats = merged_stats.unwrap();
+    let micros = |latency: Duration| latency.as_nanos() as f64 / 1000.;
+    let percentiles = [0.5, 0.9, 0.99, 0.999].map(|q| micros(stats.latency.quantile(q).unwrap()));
+    println!(
+        "* throughput {} latency {:?} p50/p90/p99/p99.9 {percentiles:?}us",
+        stats.throughput, stats.average_latency
+    );
+    if stats.num_failed != 0 {
+        println!("* {} transactions failed", stats.num_failed)
+    }
+    let mut result = format!(
+        "{id},{},{}",
+        stats.throughput,
+        micros(stats.average_latency.unwrap()),
+    );
+    for percentile in percentiles {
+        write!(&mut result, ",{percentile}").unwrap()
+    }
+    if let Some(offered_load) = stats.offered_load {
+        write!(&mut result, ",{offered_load}").unwrap()
     }
+    writeln!(&mut result).unwrap();
+    out.write_all(result.as_bytes()).unwrap();
+    // the histograms and the time series, for e.g. plotting latency CDF
+    std::fs::create_dir_all("stats").unwrap();
+    std::fs::write(
+        format!("stats/{id}.json"),
+        serde_json::to_vec(&stats).unwrap(),
+    )
+    .unwrap();
+    std::fs::write(
+        format!("stats/{id},replicas.json"),
+        serde_json::to_vec(&replica_stats).unwrap(),
+    )
+    .unwrap()
 }
 
 async fn host_session(
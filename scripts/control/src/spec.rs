@@ -0,0 +1,566 @@
+// an experiment spec is a list of experiments, each is a matrix of run
+// parameters that expands into the cartesian product of them
+//
+// every matrix entry takes either a single value or a list of values, and the
+// numbers in a list may be inclusive ranges i.e. `{ from = 2, to = 20, step = 2 }`
+
+use std::{collections::BTreeMap, fmt::Write, net::Ipv4Addr, path::Path, time::Duration};
+
+use control_messages::{App, Arrival, Batching, Storage, SyncPolicy};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Spec {
+    // the CSV file that results are appended to. a run that has a result in it
+    // is skipped, so an interrupted spec resumes by running it again. without
+    // an output the results are only printed
+    pub output: Option<String>,
+    // the runs only work with the aws deployment
+    #[serde(default)]
+    pub aws: bool,
+    // the applications that experiments refer to by name, besides `null`
+    #[serde(default)]
+    pub apps: BTreeMap<String, App>,
+    // the lab hosts that run clients, the first `num_client_host` of them are
+    // taken. ignored by the aws deployment
+    #[serde(rename = "client_host", default = "lab_client_hosts")]
+    pub client_hosts: Vec<ClientHost>,
+    #[serde(rename = "experiment")]
+    pub experiments: Vec<Experiment>,
+}
+
+// a host binds one port per client starting from 20000 on its address in the
+// experiment network
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(feature = "aws", allow(dead_code))]
+pub struct ClientHost {
+    pub host: String,
+    pub ip: Ipv4Addr,
+}
+
+fn lab_client_hosts() -> Vec<ClientHost> {
+    vec![ClientHost {
+        host: String::from("nsl-node10.d2"),
+        ip: Ipv4Addr::new(10, 0, 0, 10),
+    }]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Experiment {
+    pub mode: Values<String>,
+    #[serde(default = "one")]
+    pub num_group: Values<Count>,
+    // per group
+    pub num_client: Values<Count>,
+    #[serde(default = "one")]
+    pub num_client_host: Values<Count>,
+    #[serde(default = "null")]
+    pub app: Values<String>,
+    #[serde(default = "zero")]
+    pub drop_rate: Values<f64>,
+    #[serde(default = "one")]
+    pub num_faulty: Values<Count>,
+    // each shard is a replica group of 3f + 1 replica hosts
+    #[serde(default = "one")]
+    pub num_shard: Values<Count>,
+    #[serde(default)]
+    pub batching: Values<BatchingSpec>,
+    // PBFT and MinBFT only, zero for unbounded
+    #[serde(default = "unbounded")]
+    pub pipeline_window: Values<Count>,
+    // PBFT only, reply with execution certificates
+    #[serde(default)]
+    pub certify_execution: bool,
+    #[serde(default)]
+    pub arrival: Values<ArrivalSpec>,
+    // per client
+    #[serde(default = "one")]
+    pub num_outstanding: Values<Count>,
+    // uniformly delay every message a replica receives by up to this, zero
+    // for no delay
+    #[serde(default = "disabled")]
+    pub network_max_delay_us: Values<Count>,
+    // PBFT, HotStuff and MinBFT only, zero to disable
+    #[serde(default = "disabled")]
+    pub checkpoint_interval: Values<Count>,
+    // the replica logs are kept across runs, so a run recovers the logs of the
+    // previous run of the same mode unless they are removed in between
+    pub storage: Option<Storage>,
+    pub ledger_dir: Option<String>,
+    // of the measurement, which happens between warming up and cooling down
+    #[serde(default = "ten_seconds")]
+    pub duration_ms: u64,
+    #[serde(default = "one_second")]
+    pub warmup_ms: u64,
+    #[serde(default)]
+    pub cooldown_ms: u64,
+    // the number of runs of each combination
+    #[serde(default = "once")]
+    pub repeat: usize,
+    // also count the messages of the replicas by type, which slows them down
+    #[serde(default)]
+    pub collect_stats: bool,
+}
+
+fn one() -> Values<Count> {
+    Values::One(Count::Value(1))
+}
+
+fn null() -> Values<String> {
+    Values::One(String::from("null"))
+}
+
+fn zero() -> Values<f64> {
+    Values::One(0.)
+}
+
+fn unbounded() -> Values<Count> {
+    Values::One(Count::Value(0))
+}
+
+fn disabled() -> Values<Count> {
+    Values::One(Count::Value(0))
+}
+
+fn ten_seconds() -> u64 {
+    10_000
+}
+
+fn one_second() -> u64 {
+    1_000
+}
+
+fn once() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Values<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T: Default> Default for Values<T> {
+    fn default() -> Self {
+        Self::One(T::default())
+    }
+}
+
+impl<T: Clone> Values<T> {
+    fn to_vec(&self) -> Vec<T> {
+        match self {
+            Self::One(value) => vec![value.clone()],
+            Self::Many(values) => values.clone(),
+        }
+    }
+}
+
+impl Values<Count> {
+    fn expand(&self) -> Vec<usize> {
+        self.to_vec().into_iter().flat_map(Count::expand).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum Count {
+    Value(usize),
+    Range {
+        from: usize,
+        to: usize,
+        #[serde(default = "once")]
+        step: usize,
+    },
+}
+
+impl Count {
+    fn expand(self) -> Vec<usize> {
+        match self {
+            Self::Value(value) => vec![value],
+            Self::Range { from, to, step } => (from..=to).step_by(step).collect(),
+        }
+    }
+}
+
+// the unspecified fields are the ones of `Batching::default()`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchingSpec {
+    pub max_size: usize,
+    pub max_bytes: usize,
+    pub max_delay_us: u64,
+    pub adaptive: bool,
+}
+
+impl Default for BatchingSpec {
+    fn default() -> Self {
+        Batching::default().into()
+    }
+}
+
+impl From<Batching> for BatchingSpec {
+    fn from(value: Batching) -> Self {
+        Self {
+            max_size: value.max_size,
+            max_bytes: value.max_bytes,
+            max_delay_us: value.max_delay.as_micros() as _,
+            adaptive: value.adaptive,
+        }
+    }
+}
+
+impl From<BatchingSpec> for Batching {
+    fn from(value: BatchingSpec) -> Self {
+        Self {
+            max_size: value.max_size,
+            max_bytes: value.max_bytes,
+            max_delay: Duration::from_micros(value.max_delay_us),
+            adaptive: value.adaptive,
+        }
+    }
+}
+
+// rates are in transactions per second
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum ArrivalSpec {
+    #[default]
+    Closed,
+    Constant {
+        rate: Values<Count>,
+    },
+    Poisson {
+        rate: Values<Count>,
+    },
+    Bursty {
+        rate: Values<Count>,
+        burst_size: usize,
+    },
+}
+
+impl ArrivalSpec {
+    fn expand(&self) -> Vec<Option<Arrival>> {
+        match self {
+            Self::Closed => vec![None],
+            Self::Constant { rate } => Vec::from_iter(
+                rate.expand()
+                    .into_iter()
+                    .map(|rate| Some(Arrival::Constant(rate as _))),
+            ),
+            Self::Poisson { rate } => Vec::from_iter(
+                rate.expand()
+                    .into_iter()
+                    .map(|rate| Some(Arrival::Poisson(rate as _))),
+            ),
+            Self::Bursty { rate, burst_size } => {
+                Vec::from_iter(rate.expand().into_iter().map(|rate| {
+                    Some(Arrival::Bursty {
+                        rate: rate as _,
+                        burst_size: *burst_size,
+                    })
+                }))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Run {
+    pub mode: String,
+    pub num_group: usize,
+    pub num_client: usize,
+    pub num_client_host: usize,
+    pub app: App,
+    pub drop_rate: f64,
+    pub num_faulty: usize,
+    pub num_shard: usize,
+    pub batching: Batching,
+    pub pipeline_window: u32,
+    pub certify_execution: bool,
+    pub arrival: Option<Arrival>,
+    pub num_outstanding: usize,
+    pub network_max_delay: Duration,
+    pub checkpoint_interval: u32,
+    pub storage: Option<Storage>,
+    pub ledger_dir: Option<String>,
+    pub duration: Duration,
+    pub warmup: Duration,
+    pub cooldown: Duration,
+    pub collect_stats: bool,
+    // present if the experiment repeats
+    pub repetition: Option<usize>,
+}
+
+impl Run {
+    // identify the run in the results, which is the leading fields of its line
+    pub fn id(&self) -> String {
+        let mut id = format!(
+            "{},{},{},{},{}",
+            self.mode,
+            match &self.app {
+                App::Null => "null",
+                App::Ycsb(_) => "ycsb",
+                App::SmallBank(_) => "smallbank",
+                App::Custom { name, .. } => name,
+            },
+            self.drop_rate,
+            self.num_group * self.num_client * self.num_client_host,
+            self.num_faulty
+        );
+        let batching = self.batching;
+        if batching != Batching::default() {
+            write!(
+                &mut id,
+                ",{},{},{},{}",
+                batching.max_size,
+                batching.max_bytes,
+                batching.max_delay.as_micros(),
+                batching.adaptive
+            )
+            .unwrap()
+        }
+        if self.pipeline_window != 0 {
+            write!(&mut id, ",window{}", self.pipeline_window).unwrap()
+        }
+        if self.certify_execution {
+            write!(&mut id, ",certified").unwrap()
+        }
+        if self.num_shard != 1 {
+            write!(&mut id, ",shard{}", self.num_shard).unwrap()
+        }
+        if self.num_outstanding != 1 {
+            write!(&mut id, ",outstanding{}", self.num_outstanding).unwrap()
+        }
+        if !self.network_max_delay.is_zero() {
+            write!(&mut id, ",delay{}", self.network_max_delay.as_micros()).unwrap()
+        }
+        if self.checkpoint_interval != 0 {
+            write!(&mut id, ",checkpoint{}", self.checkpoint_interval).unwrap()
+        }
+        match self.storage.as_ref().map(|storage| storage.sync) {
+            None => {}
+            Some(SyncPolicy::Never) => write!(&mut id, ",wal").unwrap(),
+            Some(SyncPolicy::Always) => write!(&mut id, ",wal-sync").unwrap(),
+            Some(SyncPolicy::Every(n)) => write!(&mut id, ",wal-sync{n}").unwrap(),
+        }
+        match self.arrival {
+            None => {}
+            Some(Arrival::Constant(rate)) => write!(&mut id, ",constant,{rate}").unwrap(),
+            Some(Arrival::Poisson(rate)) => write!(&mut id, ",poisson,{rate}").unwrap(),
+            Some(Arrival::Bursty { rate, burst_size }) => {
+                write!(&mut id, ",bursty,{rate},{burst_size}").unwrap()
+            }
+        }
+        if let Some(repetition) = self.repetition {
+            write!(&mut id, ",rep{repetition}").unwrap()
+        }
+        id
+    }
+}
+
+impl Spec {
+    // a path to a TOML or JSON spec, or the name of a spec shipped in `specs/`
+    pub fn load(spec: &str) -> Self {
+        let path = if Path::new(spec).exists() {
+            spec.into()
+        } else {
+            format!("{}/specs/{spec}.toml", env!("CARGO_MANIFEST_DIR"))
+        };
+        let content =
+            std::fs::read_to_string(&path).unwrap_or_else(|err| panic!("{err} reading {path}"));
+        if path.ends_with(".json") {
+            serde_json::from_str(&content).unwrap_or_else(|err| panic!("{err} parsing {path}"))
+        } else {
+            toml::from_str(&content).unwrap_or_else(|err| panic!("{err} parsing {path}"))
+        }
+    }
+
+    // in the order of the experiments, and for each experiment the later
+    // entries vary faster, with the repetitions innermost
+    pub fn runs(&self) -> Vec<Run> {
+        let mut runs = Vec::new();
+        for experiment in &self.experiments {
+            let apps = Vec::from_iter(experiment.app.to_vec().into_iter().map(|name| {
+                match &*name {
+                    "null" => App::Null,
+                    name => self
+                        .apps
+                        .get(name)
+                        .unwrap_or_else(|| panic!("unknown app {name}"))
+                        .clone(),
+                }
+            }));
+            let arrivals = Vec::from_iter(
+                experiment
+                    .arrival
+                    .to_vec()
+                    .iter()
+                    .flat_map(ArrivalSpec::expand),
+            );
+            let mut experiment_runs = vec![Run {
+                mode: Default::default(),
+                num_group: 1,
+                num_client: 1,
+                num_client_host: 1,
+                app: App::Null,
+                drop_rate: 0.,
+                num_faulty: 1,
+                num_shard: 1,
+                batching: Default::default(),
+                pipeline_window: 0,
+                certify_execution: experiment.certify_execution,
+                arrival: None,
+                num_outstanding: 1,
+                network_max_delay: Duration::ZERO,
+                checkpoint_interval: 0,
+                storage: experiment.storage.clone(),
+                ledger_dir: experiment.ledger_dir.clone(),
+                duration: Duration::from_millis(experiment.duration_ms),
+                warmup: Duration::from_millis(experiment.warmup_ms),
+                cooldown: Duration::from_millis(experiment.cooldown_ms),
+                collect_stats: experiment.collect_stats,
+                repetition: None,
+            }];
+            vary(
+                &mut experiment_runs,
+                experiment.mode.to_vec(),
+                |run, mode| run.mode = mode,
+            );
+            vary(&mut experiment_runs, apps, |run, app| run.app = app);
+            vary(
+                &mut experiment_runs,
+                experiment.num_faulty.expand(),
+                |run, n| run.num_faulty = n,
+            );
+            vary(
+                &mut experiment_runs,
+                experiment.num_shard.expand(),
+                |run, n| run.num_shard = n,
+            );
+            vary(
+                &mut experiment_runs,
+                experiment.drop_rate.to_vec(),
+                |run, rate| run.drop_rate = rate,
+            );
+            vary(
+                &mut experiment_runs,
+                experiment.batching.to_vec(),
+                |run, batching| run.batching = batching.into(),
+            );
+            vary(
+                &mut experiment_runs,
+                experiment.pipeline_window.expand(),
+                |run, window| run.pipeline_window = window as _,
+            );
+            vary(&mut experiment_runs, arrivals, |run, arrival| {
+                run.arrival = arrival
+            });
+            vary(
+                &mut experiment_runs,
+                experiment.num_outstanding.expand(),
+                |run, n| run.num_outstanding = n,
+            );
+            vary(
+                &mut experiment_runs,
+                experiment.network_max_delay_us.expand(),
+                |run, delay| run.network_max_delay = Duration::from_micros(delay as _),
+            );
+            vary(
+                &mut experiment_runs,
+                experiment.checkpoint_interval.expand(),
+                |run, interval| run.checkpoint_interval = interval as _,
+            );
+            vary(
+                &mut experiment_runs,
+                experiment.num_group.expand(),
+                |run, n| run.num_group = n,
+            );
+            vary(
+                &mut experiment_runs,
+                experiment.num_client_host.expand(),
+                |run, n| run.num_client_host = n,
+            );
+            vary(
+                &mut experiment_runs,
+                experiment.num_client.expand(),
+                |run, n| run.num_client = n,
+            );
+            if experiment.repeat > 1 {
+                vary(
+                    &mut experiment_runs,
+                    Vec::from_iter(0..experiment.repeat),
+                    |run, i| run.repetition = Some(i),
+                )
+            }
+            runs.extend(experiment_runs)
+        }
+        runs
+    }
+}
+
+// replace each run with one copy per value
+fn vary<T: Clone>(runs: &mut Vec<Run>, values: Vec<T>, set: impl Fn(&mut Run, T)) {
+    *runs = Vec::from_iter(runs.drain(..).flat_map(|run| {
+        values
+            .iter()
+            .map(|value| {
+                let mut run = run.clone();
+                set(&mut run, value.clone());
+                run
+            })
+            .collect::<Vec<_>>()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_specs() {
+        for name in [
+            "fpga",
+            "hmac",
+            "batching",
+            "pipelining",
+            "open-loop",
+            "aws",
+            "test",
+        ] {
+            assert!(!Spec::load(name).runs().is_empty(), "{name}")
+        }
+        let runs = Spec::load("pipelining").runs();
+        assert_eq!(runs.len(), 2 * 6 * 3);
+        assert_eq!(runs[1].id(), "pbft,null,0,200,1,window1");
+    }
+
+    #[test]
+    fn replica_settings() {
+        let spec = toml::from_str::<Spec>(
+            r#"
+            [[experiment]]
+            mode = "pbft"
+            num_client = 10
+            network_max_delay_us = [0, 500]
+            checkpoint_interval = 100
+            certify_execution = true
+            storage = { dir = "/tmp", sync = { Every = 8 } }
+            warmup_ms = 500
+            "#,
+        )
+        .unwrap();
+        let runs = spec.runs();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(
+            runs[0].id(),
+            "pbft,null,0,10,1,certified,checkpoint100,wal-sync8"
+        );
+        assert_eq!(runs[1].network_max_delay, Duration::from_micros(500));
+        assert_eq!(runs[1].warmup, Duration::from_millis(500));
+        assert_eq!(runs[1].duration, Duration::from_secs(10));
+    }
+}
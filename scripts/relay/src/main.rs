@@ -1,34 +1,33 @@
 use std::{
     env::args,
-    iter::repeat,
+    iter::{repeat, repeat_n},
     net::{Ipv4Addr, UdpSocket},
     sync::Arc,
-    thread::{available_parallelism, spawn},
+    thread::spawn,
 };
 
-use nix::{
-    sched::{sched_setaffinity, CpuSet},
-    unistd::Pid,
-};
-
-pub fn set_affinity(index: usize) {
-    let mut cpu_set = CpuSet::new();
-    cpu_set.set(index).unwrap();
-    sched_setaffinity(Pid::from_raw(0), &cpu_set).unwrap()
-}
+use control_messages::{available_cores, parse_cores, set_affinity};
 
 fn main() {
     let ips = Vec::from_iter(args().skip(1).map(|ip| ip.parse::<Ipv4Addr>().unwrap()));
+    // receive on the first core and send on each of the others, e.g. AFFINITY=0,2-7
+    let cores = std::env::var("AFFINITY")
+        .map(|spec| parse_cores(&spec))
+        .unwrap_or_else(|_| available_cores());
+    let available = available_cores();
+    assert!(
+        cores.len() >= 2 && cores.iter().all(|core| available.contains(core)),
+        "cores {cores:?} are not enough or not all available, available cores {available:?}"
+    );
     let socket = Arc::new(UdpSocket::bind("0.0.0.0:60004").unwrap());
     // let messages = flume::bounded::<Vec<_>>(1024);
     let messages = flume::unbounded::<Vec<_>>();
-    for ((index, messages), (socket, ips)) in repeat(messages.1)
-        .take(usize::from(available_parallelism().unwrap()) - 1)
-        .enumerate()
+    for ((messages, &core), (socket, ips)) in repeat_n(messages.1, cores.len() - 1)
+        .zip(&cores[1..])
         .zip(repeat((socket.clone(), ips)))
     {
         spawn(move || {
-            set_affinity(index + 1);
+            set_affinity(core);
             loop {
                 let buf = messages.recv().unwrap();
                 for &ip in &ips {
@@ -50,7 +49,7 @@ fn main() {
     //     }
     // });
 
-    set_affinity(0);
+    set_affinity(cores[0]);
     let mut buf = vec![0; 65536];
     loop {
         let (len, _) = socket.recv_from(&mut buf).unwrap();
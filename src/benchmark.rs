@@ -1,77 +1,579 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Barrier},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
-use serde::{Deserialize, Serialize};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use tokio_util::sync::CancellationToken;
 
-use crate::context::{Addr, MultiplexReceive};
+use crate::context::{
+    simulated,
+    tokio::{Multiplex, MultiplexHandle},
+    Addr, MultiplexReceive, TimerId,
+};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Phases {
+    // the sessions run but nothing is recorded
+    pub warmup: Duration,
+    pub measure: Duration,
+    // keep the load on after measuring, so the last measured invocations are
+    // not sped up by a draining system
+    pub cooldown: Duration,
+}
+
+impl Default for Phases {
+    fn default() -> Self {
+        Self {
+            warmup: Duration::from_secs(1),
+            measure: Duration::from_secs(10),
+            cooldown: Duration::ZERO,
+        }
+    }
+}
+
+impl Phases {
+    fn total(&self) -> Duration {
+        self.warmup + self.measure + self.cooldown
+    }
+
+    fn is_measuring(&self, time: Duration) -> bool {
+        time >= self.warmup && time < self.warmup + self.measure
+    }
+}
+
+// the arrival process of an open loop benchmark
+pub trait Arrival: Debug + Send + Sync {
+    // the time until the next arrival
+    fn interval(&self, rng: &mut dyn RngCore) -> Duration;
+}
+
+// the measurement of a client group
 #[derive(Debug)]
-pub struct CloseLoop<W> {
-    workloads: HashMap<Addr, W>,
+pub struct GroupResult {
     pub latencies: Vec<Duration>,
+    // since the measurement starts
+    pub finish_times: Vec<Duration>,
+    // zero if running closed loop
+    pub num_arrival: usize,
+    // finished during the measurement without succeeding, which are not in
+    // the latencies
+    pub num_failed: usize,
+}
+
+// when to invoke on which session of a group, independent of how the sessions
+// are driven and how the time is told. all times are since the schedule starts
+#[derive(Debug)]
+pub struct Schedule<K> {
+    phases: Phases,
+    arrival: Option<Arc<dyn Arrival>>,
+    rng: StdRng,
+    // the start times of the outstanding invocations
+    busy: HashMap<K, Duration>,
+    // open loop only, the sessions without outstanding invocation and the
+    // arrival times of the invocations that wait for one
+    idle: Vec<K>,
+    queue: VecDeque<Duration>,
+    next_arrival: Duration,
+    num_arrival: usize,
+    latencies: Vec<Duration>,
+    finish_times: Vec<Duration>,
+    num_failed: usize,
+}
+
+impl<K: Copy + Eq + Hash> Schedule<K> {
+    pub fn new(phases: Phases, arrival: Option<Arc<dyn Arrival>>) -> Self {
+        Self {
+            phases,
+            arrival,
+            rng: StdRng::from_entropy(),
+            busy: Default::default(),
+            idle: Default::default(),
+            queue: Default::default(),
+            next_arrival: Duration::ZERO,
+            num_arrival: 0,
+            latencies: Default::default(),
+            finish_times: Default::default(),
+            num_failed: 0,
+        }
+    }
+
+    // return the sessions to invoke at time zero
+    pub fn start(&mut self, sessions: impl IntoIterator<Item = K>) -> Vec<K> {
+        if let Some(arrival) = &self.arrival {
+            self.idle.extend(sessions);
+            self.next_arrival = arrival.interval(&mut self.rng);
+            Vec::new()
+        } else {
+            let sessions = Vec::from_iter(sessions);
+            for &session in &sessions {
+                self.busy.insert(session, Duration::ZERO);
+            }
+            sessions
+        }
+    }
+
+    // the outstanding invocation of `session` finishes, return the session to
+    // invoke next if any. in open loop the latencies are measured from the
+    // arrivals so they include the queuing delay
+    pub fn finish(&mut self, now: Duration, session: K, succeeded: bool) -> Option<K> {
+        let start = self.busy.remove(&session).unwrap();
+        if self.phases.is_measuring(now) {
+            if succeeded {
+                self.latencies.push(now - start);
+                self.finish_times.push(now - self.phases.warmup)
+            } else {
+                self.num_failed += 1
+            }
+        }
+        if self.is_finished(now) {
+            return None;
+        }
+        let start = if self.arrival.is_some() {
+            let Some(start) = self.queue.pop_front() else {
+                self.idle.push(session);
+                return None;
+            };
+            start
+        } else {
+            now
+        };
+        self.busy.insert(session, start);
+        Some(session)
+    }
+
+    // return the sessions to invoke for the arrivals up to `now`
+    pub fn tick(&mut self, now: Duration) -> Vec<K> {
+        let mut sessions = Vec::new();
+        let Some(arrival) = &self.arrival else {
+            return sessions;
+        };
+        while self.next_arrival <= now && !self.is_finished(self.next_arrival) {
+            self.queue.push_back(self.next_arrival);
+            if self.phases.is_measuring(self.next_arrival) {
+                self.num_arrival += 1
+            }
+            self.next_arrival += arrival.interval(&mut self.rng)
+        }
+        while !self.queue.is_empty() && !self.idle.is_empty() {
+            let session = self.idle.pop().unwrap();
+            self.busy.insert(session, self.queue.pop_front().unwrap());
+            sessions.push(session)
+        }
+        sessions
+    }
+
+    // the time to call `tick` again
+    pub fn next_tick(&self) -> Duration {
+        if self.arrival.is_some() {
+            self.next_arrival.min(self.phases.total())
+        } else {
+            self.phases.total()
+        }
+    }
+
+    pub fn is_finished(&self, now: Duration) -> bool {
+        now >= self.phases.total()
+    }
+
+    pub fn into_result(self) -> GroupResult {
+        GroupResult {
+            latencies: self.latencies,
+            finish_times: self.finish_times,
+            num_arrival: self.num_arrival,
+            num_failed: self.num_failed,
+        }
+    }
 }
 
-pub trait Workload {
-    fn submit(&mut self);
+#[derive(Debug, Clone, Default)]
+pub struct RunConfig {
+    pub num_group: usize,
+    pub phases: Phases,
+    // of each group, closed loop if none
+    pub arrival: Option<Arc<dyn Arrival>>,
+    // the invocations that each session may have outstanding, at least one.
+    // tokio only, a simulated session has one at a time
+    pub num_outstanding: usize,
+    // tokio only, see `Ready`
+    pub ready: Option<Ready>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub enum Message<M> {
-    Workload(M),
-    Complete(Duration),
+// called on one of the groups once all of them are set up, and the groups
+// start after it returns, e.g. to start together with other hosts
+#[derive(Clone)]
+pub struct Ready(pub Arc<dyn Fn() + Send + Sync>);
+
+impl Debug for Ready {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Ready").field(&"..").finish()
+    }
 }
 
-impl<W> CloseLoop<W> {
-    pub fn bootstrap(&mut self)
-    where
-        W: Workload,
-    {
-        for workload in self.workloads.values_mut() {
-            workload.submit()
+// the invocations that a benchmark keeps making on the sessions of type `S`
+pub trait Workload<S>: Send + Sync {
+    // e.g. one transaction, resolves to whether it succeeds
+    fn invoke(&self, session: &S) -> impl Future<Output = bool> + Send + 'static;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Thread {
+    Runtime,
+    Dispatch,
+    Benchmark,
+}
+
+// run the groups on tokio contexts concurrently, each with its own runtime and
+// multiplex. `new_group` registers the sessions of a group to the multiplex and
+// returns them along with how to dispatch their messages. `on_thread` is
+// called on each spawned thread first, e.g. to pin it to a core. the schedule
+// works on slots, each session takes `num_outstanding` of them
+pub fn run<S, W, D>(
+    config: RunConfig,
+    workload: W,
+    on_thread: impl Fn(usize, Thread) + Send + Sync + 'static,
+    mut new_group: impl FnMut(usize, &mut Multiplex) -> (Vec<S>, D),
+) -> Vec<GroupResult>
+where
+    S: Send + Sync + 'static,
+    W: Workload<S> + 'static,
+    D: FnOnce(&mut Multiplex) + Send + 'static,
+{
+    struct Group {
+        benchmark_thread: JoinHandle<GroupResult>,
+        runtime_thread: JoinHandle<()>,
+        dispatch_thread: JoinHandle<()>,
+        dispatch_handle: MultiplexHandle,
+    }
+
+    let barrier = Arc::new(Barrier::new(config.num_group));
+    let workload = Arc::new(workload);
+    let on_thread = Arc::new(on_thread);
+    let groups = Vec::from_iter((0..config.num_group).map(|group_index| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let handle = runtime.handle().clone();
+        let mut multiplex = Multiplex::new(
+            handle.clone(),
+            crate::context::ordered_multicast::Receiver::Unreachable,
+        );
+        let (sessions, dispatch) = new_group(group_index, &mut multiplex);
+
+        let cancel = CancellationToken::new();
+        let runtime_thread = std::thread::spawn({
+            let on_thread = on_thread.clone();
+            let cancel = cancel.clone();
+            move || {
+                on_thread(group_index, Thread::Runtime);
+                runtime.block_on(cancel.cancelled())
+            }
+        });
+
+        let dispatch_handle = multiplex.handle();
+        let dispatch_thread = std::thread::spawn({
+            let on_thread = on_thread.clone();
+            move || {
+                on_thread(group_index, Thread::Dispatch);
+                dispatch(&mut multiplex);
+                cancel.cancel()
+            }
+        });
+
+        let benchmark_thread = std::thread::spawn({
+            let on_thread = on_thread.clone();
+            let barrier = barrier.clone();
+            let workload = workload.clone();
+            let mut schedule = Schedule::new(config.phases, config.arrival.clone());
+            let ready = config.ready.clone();
+            let num_slot = sessions.len() * config.num_outstanding.max(1);
+            move || {
+                on_thread(group_index, Thread::Benchmark);
+                let (finish_sender, finish_receiver) = flume::unbounded();
+                let invoke = |index: usize| {
+                    let invocation = workload.invoke(&sessions[index % sessions.len()]);
+                    let finish_sender = finish_sender.clone();
+                    handle.spawn(async move {
+                        let succeeded = invocation.await;
+                        // the invocations that are outstanding when the benchmark finishes
+                        // have nowhere to report
+                        let _ = finish_sender.send((index, succeeded));
+                    });
+                };
+
+                // synchronously finish the first invocation, to avoid first-packet reordering
+                if let Some(session) = sessions.first() {
+                    handle.block_on(workload.invoke(session));
+                }
+                if barrier.wait().is_leader() {
+                    if let Some(Ready(ready)) = &ready {
+                        ready()
+                    }
+                }
+                barrier.wait();
+                let start = Instant::now();
+                for index in schedule.start(0..num_slot) {
+                    invoke(index)
+                }
+                loop {
+                    let now = start.elapsed();
+                    for index in schedule.tick(now) {
+                        invoke(index)
+                    }
+                    if schedule.is_finished(now) {
+                        break;
+                    }
+                    // the sender is kept alive by `invoke` so this only times out
+                    if let Ok((index, succeeded)) =
+                        finish_receiver.recv_deadline(start + schedule.next_tick())
+                    {
+                        if let Some(index) = schedule.finish(start.elapsed(), index, succeeded) {
+                            invoke(index)
+                        }
+                    }
+                }
+                schedule.into_result()
+            }
+        });
+
+        Group {
+            benchmark_thread,
+            runtime_thread,
+            dispatch_thread,
+            dispatch_handle,
         }
+    }));
+
+    let mut results = Vec::new();
+    for group in groups {
+        results.push(group.benchmark_thread.join().unwrap());
+        group.dispatch_handle.stop();
+        group.dispatch_thread.join().unwrap();
+        group.runtime_thread.join().unwrap();
     }
+    results
 }
 
-impl<W> MultiplexReceive for CloseLoop<W>
+// a session that is driven by the events of a simulated dispatch
+pub trait Session: MultiplexReceive {
+    fn invoke(&mut self);
+
+    // polled after each event delivered to the session, whether the
+    // outstanding invocation has finished since the last poll
+    fn take_finished(&mut self) -> bool;
+}
+
+// run the groups on the simulated contexts of `dispatch` in virtual time. the
+// events of the addresses other than the sessions are delivered to `others`,
+// e.g. the replicas
+pub fn run_simulated<S>(
+    dispatch: &simulated::Dispatch<S::Message>,
+    config: RunConfig,
+    groups: Vec<HashMap<Addr, S>>,
+    others: &mut impl MultiplexReceive<Message = S::Message>,
+) -> Vec<GroupResult>
 where
-    W: MultiplexReceive + Workload,
+    S: Session,
 {
-    type Message = Message<W::Message>;
-
-    fn handle(&mut self, receiver: Addr, remote: Addr, message: Self::Message) {
-        match message {
-            Message::Workload(message) => self
-                .workloads
-                .get_mut(&receiver)
-                .unwrap()
-                .handle(receiver, remote, message),
-            Message::Complete(_) => unimplemented!(),
-        }
-    }
-
-    fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
-        match message {
-            Message::Workload(message) => self
-                .workloads
-                .get_mut(&receiver)
-                .unwrap()
-                .handle_loopback(receiver, message),
-            Message::Complete(latency) => {
-                self.latencies.push(latency);
-                self.workloads.get_mut(&receiver).unwrap().submit()
+    struct R<'a, S: Session, O> {
+        dispatch: &'a simulated::Dispatch<S::Message>,
+        start: Duration,
+        sessions: HashMap<Addr, (usize, S)>,
+        schedules: Vec<Schedule<Addr>>,
+        others: &'a mut O,
+    }
+
+    impl<S: Session, O> R<'_, S, O> {
+        fn poll(&mut self, receiver: Addr) {
+            let (group_index, session) = self.sessions.get_mut(&receiver).unwrap();
+            if session.take_finished() {
+                let now = self.dispatch.now() - self.start;
+                if self.schedules[*group_index]
+                    .finish(now, receiver, true)
+                    .is_some()
+                {
+                    session.invoke()
+                }
+            }
+        }
+    }
+
+    impl<S, O> MultiplexReceive for R<'_, S, O>
+    where
+        S: Session,
+        O: MultiplexReceive<Message = S::Message>,
+    {
+        type Message = S::Message;
+
+        fn handle(&mut self, receiver: Addr, remote: Addr, message: Self::Message) {
+            if let Some((_, session)) = self.sessions.get_mut(&receiver) {
+                session.handle(receiver, remote, message);
+                self.poll(receiver)
+            } else {
+                self.others.handle(receiver, remote, message)
+            }
+        }
+
+        fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
+            if let Some((_, session)) = self.sessions.get_mut(&receiver) {
+                session.handle_loopback(receiver, message);
+                self.poll(receiver)
+            } else {
+                self.others.handle_loopback(receiver, message)
+            }
+        }
+
+        fn on_timer(&mut self, receiver: Addr, id: TimerId) {
+            if let Some((_, session)) = self.sessions.get_mut(&receiver) {
+                session.on_timer(receiver, id);
+                self.poll(receiver)
+            } else {
+                self.others.on_timer(receiver, id)
             }
         }
     }
 
-    fn on_timer(&mut self, receiver: Addr, id: crate::context::TimerId) {
-        self.workloads
-            .get_mut(&receiver)
-            .unwrap()
-            .on_timer(receiver, id)
+    let mut receivers = R {
+        dispatch,
+        start: dispatch.now(),
+        sessions: Default::default(),
+        schedules: Default::default(),
+        others,
+    };
+    for (group_index, sessions) in groups.into_iter().enumerate() {
+        let mut schedule = Schedule::new(config.phases, config.arrival.clone());
+        let addrs = schedule.start(sessions.keys().copied());
+        receivers.schedules.push(schedule);
+        receivers.sessions.extend(
+            sessions
+                .into_iter()
+                .map(|(addr, session)| (addr, (group_index, session))),
+        );
+        for addr in addrs {
+            receivers.sessions.get_mut(&addr).unwrap().1.invoke()
+        }
+    }
+    loop {
+        let now = dispatch.now() - receivers.start;
+        for schedule_index in 0..receivers.schedules.len() {
+            for addr in receivers.schedules[schedule_index].tick(now) {
+                receivers.sessions.get_mut(&addr).unwrap().1.invoke()
+            }
+        }
+        if receivers
+            .schedules
+            .iter()
+            .all(|schedule| schedule.is_finished(now))
+        {
+            break;
+        }
+        let next_tick = receivers
+            .schedules
+            .iter()
+            .map(Schedule::next_tick)
+            .min()
+            .unwrap();
+        dispatch.deliver_event_before(receivers.start + next_tick, &mut receivers);
+    }
+    Vec::from_iter(receivers.schedules.into_iter().map(Schedule::into_result))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::context::{Context, To};
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    enum Message {
+        Ping,
+        Pong,
+    }
+
+    // replies all pings every millisecond
+    struct Server(Context<Message>, Vec<Addr>);
+
+    impl MultiplexReceive for Server {
+        type Message = Message;
+
+        fn handle(&mut self, _: Addr, remote: Addr, _: Self::Message) {
+            self.1.push(remote)
+        }
+
+        fn on_timer(&mut self, _: Addr, _: TimerId) {
+            for remote in self.1.drain(..) {
+                self.0.send(To::Addr(remote), Message::Pong)
+            }
+        }
+    }
+
+    struct Pinger(Context<Message>, bool);
+
+    impl MultiplexReceive for Pinger {
+        type Message = Message;
+
+        fn handle(&mut self, _: Addr, _: Addr, message: Self::Message) {
+            assert!(matches!(message, Message::Pong));
+            self.1 = true
+        }
+
+        fn on_timer(&mut self, _: Addr, _: TimerId) {
+            unreachable!()
+        }
+    }
+
+    impl Session for Pinger {
+        fn invoke(&mut self) {
+            self.0.send(
+                To::Addr(Addr::Simulated(simulated::Addr::Replica(0))),
+                Message::Ping,
+            )
+        }
+
+        fn take_finished(&mut self) -> bool {
+            std::mem::take(&mut self.1)
+        }
     }
 
-    fn on_pace(&mut self) {
-        for workload in self.workloads.values_mut() {
-            workload.on_pace()
+    #[test]
+    fn simulated_close_loop() {
+        let dispatch = simulated::Dispatch::new();
+        let mut server = Server(dispatch.register(simulated::Addr::Replica(0)), Vec::new());
+        server.0.set(Duration::from_millis(1));
+        let groups = Vec::from_iter((0..2).map(|group_index| {
+            HashMap::from_iter((0..5).map(|i| {
+                let addr = simulated::Addr::Client(group_index * 5 + i);
+                (
+                    Addr::Simulated(addr),
+                    Pinger(dispatch.register(addr), false),
+                )
+            }))
+        }));
+        let config = RunConfig {
+            num_group: 2,
+            phases: Phases {
+                warmup: Duration::from_millis(10),
+                measure: Duration::from_millis(100),
+                cooldown: Duration::from_millis(10),
+            },
+            arrival: None,
+            num_outstanding: 1,
+            ready: None,
+        };
+        let results = run_simulated(&dispatch, config, groups, &mut server);
+        assert_eq!(results.len(), 2);
+        for result in results {
+            assert_eq!(result.latencies.len(), 500);
+            assert!(result
+                .latencies
+                .iter()
+                .all(|&latency| latency == Duration::from_millis(1)))
         }
     }
 }
@@ -0,0 +1,20 @@
+//! Broadcast primitives among replicas, as subnode components.
+//!
+//! A component is registered with `Multiplex::register_subnode` on a parent
+//! replica context, so the parent message type must be convertible from the
+//! component's one. The parent forwards component messages received from
+//! network to `handle` and the ones received from loopback to
+//! `handle_loopback`, and starts a broadcast by passing a `Broadcast` message
+//! from `Addr::Upcall`. The component upcalls `Deliver` messages, which show up
+//! in parent's `handle_loopback`.
+//!
+//! Quorum sizes are derived from the `replication::Config` of the component
+//! context.
+
+pub mod consistent;
+pub mod reliable;
+
+use crate::context::replication::ReplicaIndex;
+
+// (sender, sequence number chosen by sender)
+pub type Session = (ReplicaIndex, u32);
@@ -0,0 +1,388 @@
+//! Signed echo (consistent) broadcast of Reiter.
+//!
+//! Sender collects a quorum of signed `Echo`s for its value and sends them as
+//! a certificate along with the value in `Final`. Correct replicas never
+//! deliver different values for a session, but some of them may not deliver
+//! at all if sender is faulty. The certificate in `Deliver` can be forwarded as
+//! a transferable proof.
+
+use std::collections::HashMap;
+
+use k256::sha2::Digest;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    context::{
+        replication::{Context, ReplicaIndex, To},
+        Addr, MultiplexReceive,
+    },
+    crypto::{Hasher, Sign, Signed, Verifier, Verify},
+};
+
+use super::Session;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Message<T> {
+    Broadcast(Broadcast<T>),
+    Initial(Signed<Initial<T>>),
+    Echo(Signed<Echo>),
+    // authenticated by the certificate
+    Final(Final<T>),
+    Deliver(Deliver<T>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Broadcast<T> {
+    pub sequence: u32,
+    pub value: T,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Initial<T> {
+    session: Session,
+    value: T,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Echo {
+    pub session: Session,
+    pub digest: [u8; 32],
+    pub replica_index: ReplicaIndex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Final<T> {
+    session: Session,
+    value: T,
+    certificate: Vec<Signed<Echo>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Deliver<T> {
+    pub session: Session,
+    pub value: T,
+    pub certificate: Vec<Signed<Echo>>,
+}
+
+#[derive(Debug)]
+pub struct ConsistentBroadcast<T> {
+    context: Context<Message<T>>,
+    index: ReplicaIndex,
+    // sessions of this replica that are collecting echoes
+    values: HashMap<u32, T>,
+    echoes: HashMap<u32, HashMap<ReplicaIndex, Signed<Echo>>>,
+    // echoed digests, for not echoing a faulty sender twice
+    echoed: HashMap<Session, [u8; 32]>,
+    delivered: HashMap<Session, [u8; 32]>,
+}
+
+impl<T> ConsistentBroadcast<T> {
+    pub fn new(context: Context<Message<T>>, index: ReplicaIndex) -> Self {
+        Self {
+            context,
+            index,
+            values: Default::default(),
+            echoes: Default::default(),
+            echoed: Default::default(),
+            delivered: Default::default(),
+        }
+    }
+
+    fn quorum_size(&self) -> usize {
+        (self.context.num_replica() + self.context.num_faulty()) / 2 + 1
+    }
+}
+
+impl<T> MultiplexReceive for ConsistentBroadcast<T>
+where
+    T: Clone + std::hash::Hash + Serialize + DeserializeOwned,
+{
+    type Message = Message<T>;
+
+    fn handle(&mut self, receiver: Addr, remote: Addr, message: Self::Message) {
+        assert_eq!(receiver, self.context.addr());
+        match message {
+            Message::Broadcast(message) => {
+                assert_eq!(remote, Addr::Upcall);
+                self.handle_broadcast(message)
+            }
+            Message::Initial(message) => self.handle_initial(message.inner),
+            Message::Echo(message) => self.handle_echo(message),
+            Message::Final(message) => self.handle_final(message),
+            Message::Deliver(_) => unimplemented!(),
+        }
+    }
+
+    fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
+        assert_eq!(receiver, self.context.addr());
+        match message {
+            Message::Initial(message) => self.handle_initial(message.inner),
+            Message::Echo(message) => self.handle_echo(message),
+            Message::Final(message) => self.handle_final(message),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn on_timer(&mut self, _: Addr, _: crate::context::TimerId) {
+        unreachable!()
+    }
+}
+
+impl<T> ConsistentBroadcast<T>
+where
+    T: Clone + std::hash::Hash + Serialize + DeserializeOwned,
+{
+    fn handle_broadcast(&mut self, message: Broadcast<T>) {
+        let evicted = self.values.insert(message.sequence, message.value.clone());
+        assert!(evicted.is_none());
+        let initial = Initial {
+            session: (self.index, message.sequence),
+            value: message.value,
+        };
+        self.context.send(To::AllReplicaWithLoopback, initial)
+    }
+
+    fn handle_initial(&mut self, message: Initial<T>) {
+        let digest = Hasher::sha256(&message.value).finalize().into();
+        if *self.echoed.entry(message.session).or_insert(digest) != digest {
+            return;
+        }
+        let echo = Echo {
+            session: message.session,
+            digest,
+            replica_index: self.index,
+        };
+        let to = if message.session.0 == self.index {
+            To::Loopback
+        } else {
+            To::Replica(message.session.0)
+        };
+        self.context.send(to, echo)
+    }
+
+    fn handle_echo(&mut self, message: Signed<Echo>) {
+        let (sender, sequence) = message.session;
+        // a faulty replica may echo to a replica that is not the sender
+        if sender != self.index {
+            return;
+        }
+        let Some(value) = self.values.get(&sequence) else {
+            return; // already finalized
+        };
+        if message.digest != <[u8; 32]>::from(Hasher::sha256(value).finalize()) {
+            return;
+        }
+        let echoes = self.echoes.entry(sequence).or_default();
+        echoes.insert(message.replica_index, message);
+        if echoes.len() < self.quorum_size() {
+            return;
+        }
+        let final_message = Final {
+            session: (sender, sequence),
+            value: self.values.remove(&sequence).unwrap(),
+            certificate: self
+                .echoes
+                .remove(&sequence)
+                .unwrap()
+                .into_values()
+                .collect(),
+        };
+        self.context
+            .send(To::AllReplicaWithLoopback, Message::Final(final_message))
+    }
+
+    fn handle_final(&mut self, message: Final<T>) {
+        if self.delivered.contains_key(&message.session) {
+            return;
+        }
+        let digest = Hasher::sha256(&message.value).finalize().into();
+        let mut replica_indexes = Vec::new();
+        for echo in &message.certificate {
+            if echo.session != message.session
+                || echo.digest != digest
+                || replica_indexes.contains(&echo.replica_index)
+            {
+                return;
+            }
+            replica_indexes.push(echo.replica_index)
+        }
+        if replica_indexes.len() < self.quorum_size() {
+            return;
+        }
+        self.delivered.insert(message.session, digest);
+        let deliver = Deliver {
+            session: message.session,
+            value: message.value,
+            certificate: message.certificate,
+        };
+        self.context
+            .send(To::Addr(Addr::Upcall), Message::Deliver(deliver))
+    }
+}
+
+impl<T: std::hash::Hash> Sign<Initial<T>> for Message<T> {
+    fn sign(message: Initial<T>, signer: &crate::crypto::Signer) -> Self {
+        Self::Initial(signer.sign_public(message))
+    }
+}
+
+impl<T> Sign<Echo> for Message<T> {
+    fn sign(message: Echo, signer: &crate::crypto::Signer) -> Self {
+        Self::Echo(signer.sign_public_for_batch(message))
+    }
+}
+
+impl<T: std::hash::Hash> Verify<ReplicaIndex> for Message<T> {
+    fn verify(&self, verifier: &Verifier<ReplicaIndex>) -> Result<(), crate::crypto::Invalid> {
+        match self {
+            Self::Initial(message) => verifier.verify(message, message.session.0),
+            Self::Echo(message) => verifier.verify(message, message.replica_index),
+            // certificate size and content are checked by `handle`
+            Self::Final(message) => verifier.verify_batch(
+                &message.certificate,
+                &message
+                    .certificate
+                    .iter()
+                    .map(|echo| echo.replica_index)
+                    .collect::<Vec<_>>(),
+            ),
+            // upcall only, rejected by `handle`
+            Self::Broadcast(_) | Self::Deliver(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::context::{
+        replication::Config,
+        simulated::{self, Dispatch},
+    };
+
+    use super::*;
+
+    // replica 3 is faulty, the echoes sent to it are collected instead
+    struct Group {
+        nodes: Vec<ConsistentBroadcast<u32>>,
+        delivered: Vec<(ReplicaIndex, Deliver<u32>)>,
+        faulty_echoes: Vec<Signed<Echo>>,
+    }
+
+    fn index(addr: Addr) -> ReplicaIndex {
+        let Addr::Simulated(simulated::Addr::Replica(index)) = addr else {
+            unreachable!()
+        };
+        index
+    }
+
+    fn addr(index: ReplicaIndex) -> Addr {
+        Addr::Simulated(simulated::Addr::Replica(index))
+    }
+
+    impl MultiplexReceive for Group {
+        type Message = Message<u32>;
+
+        fn handle(&mut self, receiver: Addr, remote: Addr, message: Self::Message) {
+            if index(receiver) != 3 {
+                self.nodes[index(receiver) as usize].handle(receiver, remote, message)
+            } else if let Message::Echo(echo) = message {
+                self.faulty_echoes.push(echo)
+            }
+        }
+
+        fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
+            if let Message::Deliver(deliver) = message {
+                self.delivered.push((index(receiver), deliver))
+            } else {
+                self.nodes[index(receiver) as usize].handle_loopback(receiver, message)
+            }
+        }
+
+        fn on_timer(&mut self, _: Addr, _: crate::context::TimerId) {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn deliver_agree() {
+        let dispatch = Dispatch::new();
+        let config = Arc::new(Config {
+            num_faulty: 1,
+            client_addrs: Default::default(),
+            replica_addrs: Vec::from_iter((0..4).map(addr)),
+            multicast_addr: None,
+        });
+        let mut group = Group {
+            nodes: Vec::from_iter((0..4).map(|index| {
+                let context = dispatch.register(simulated::Addr::Replica(index));
+                ConsistentBroadcast::new(context.into_replication(config.clone()), index)
+            })),
+            delivered: Default::default(),
+            faulty_echoes: Default::default(),
+        };
+        let broadcast = Broadcast {
+            sequence: 0,
+            value: 42,
+        };
+        group.handle(addr(0), Addr::Upcall, Message::Broadcast(broadcast));
+        // replica 3 equivocates by sending different values as sender
+        for (receiver, value) in [(0, 1), (1, 1), (2, 2)] {
+            let initial = Initial {
+                session: (3, 0),
+                value,
+            };
+            let initial = Message::Initial(crate::crypto::Signer::Simulated.sign_public(initial));
+            group.handle(addr(receiver), addr(3), initial)
+        }
+        while dispatch.deliver_event(&mut group) {}
+        // and tries to finalize both of them with its own echo
+        for value in [1, 2] {
+            let digest = Hasher::sha256(&value).finalize().into();
+            let echo = Echo {
+                session: (3, 0),
+                digest,
+                replica_index: 3,
+            };
+            let mut certificate = Vec::from_iter(
+                group
+                    .faulty_echoes
+                    .iter()
+                    .filter(|echo| echo.digest == digest)
+                    .cloned(),
+            );
+            certificate.push(crate::crypto::Signer::Simulated.sign_public_for_batch(echo));
+            let final_message = Final {
+                session: (3, 0),
+                value,
+                certificate,
+            };
+            for receiver in 0..3 {
+                group.handle(
+                    addr(receiver),
+                    addr(3),
+                    Message::Final(final_message.clone()),
+                )
+            }
+        }
+        while dispatch.deliver_event(&mut group) {}
+
+        let delivered = |session| {
+            Vec::from_iter(
+                group
+                    .delivered
+                    .iter()
+                    .filter(|(_, deliver)| deliver.session == session)
+                    .map(|(index, deliver)| (*index, deliver.value)),
+            )
+        };
+        let mut correct = delivered((0, 0));
+        correct.sort();
+        // the faulty replica 3 does not deliver
+        assert_eq!(correct, [(0, 42), (1, 42), (2, 42)]);
+        let mut equivocated = delivered((3, 0));
+        equivocated.sort();
+        assert_eq!(equivocated, [(0, 1), (1, 1), (2, 1)]);
+    }
+}
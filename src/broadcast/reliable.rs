@@ -0,0 +1,396 @@
+//! Bracha's reliable broadcast.
+//!
+//! The initial message is sender's `Echo`, so there is no separated step for
+//! it. Every replica delivers the same value for a session, and either all
+//! correct replicas deliver or none of them does, even if sender is faulty.
+
+use std::collections::HashMap;
+
+use k256::sha2::Digest;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    context::{
+        replication::{Context, ReplicaIndex, To},
+        Addr, MultiplexReceive,
+    },
+    crypto::{Hasher, Sign, Signed, Verifier, Verify},
+};
+
+use super::Session;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Message<T> {
+    Broadcast(Broadcast<T>),
+    Echo(Signed<Echo<T>>),
+    Ready(Signed<Ready>),
+    Deliver(Deliver<T>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Broadcast<T> {
+    pub sequence: u32,
+    pub value: T,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Echo<T> {
+    session: Session,
+    value: T,
+    replica_index: ReplicaIndex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Ready {
+    session: Session,
+    digest: [u8; 32],
+    replica_index: ReplicaIndex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Deliver<T> {
+    pub session: Session,
+    pub value: T,
+}
+
+#[derive(Debug)]
+pub struct ReliableBroadcast<T> {
+    context: Context<Message<T>>,
+    index: ReplicaIndex,
+    instances: HashMap<Session, Instance<T>>,
+}
+
+#[derive(Debug)]
+struct Instance<T> {
+    echoes: HashMap<ReplicaIndex, [u8; 32]>,
+    readies: HashMap<ReplicaIndex, [u8; 32]>,
+    values: HashMap<[u8; 32], T>,
+    echo_sent: bool,
+    ready_sent: bool,
+    delivered: bool,
+}
+
+impl<T> Default for Instance<T> {
+    fn default() -> Self {
+        Self {
+            echoes: Default::default(),
+            readies: Default::default(),
+            values: Default::default(),
+            echo_sent: false,
+            ready_sent: false,
+            delivered: false,
+        }
+    }
+}
+
+impl<T> ReliableBroadcast<T> {
+    pub fn new(context: Context<Message<T>>, index: ReplicaIndex) -> Self {
+        Self {
+            context,
+            index,
+            instances: Default::default(),
+        }
+    }
+
+    // forget sessions of `sender` that are prior to `sequence`. messages of
+    // them that arrive later start new instances that never deliver
+    pub fn truncate(&mut self, sender: ReplicaIndex, sequence: u32) {
+        self.instances.retain(|&(other_sender, other_sequence), _| {
+            other_sender != sender || other_sequence >= sequence
+        })
+    }
+}
+
+impl<T> MultiplexReceive for ReliableBroadcast<T>
+where
+    T: Clone + std::hash::Hash + Serialize + DeserializeOwned,
+{
+    type Message = Message<T>;
+
+    fn handle(&mut self, receiver: Addr, remote: Addr, message: Self::Message) {
+        assert_eq!(receiver, self.context.addr());
+        match message {
+            Message::Broadcast(message) => {
+                assert_eq!(remote, Addr::Upcall);
+                self.handle_broadcast(message)
+            }
+            Message::Echo(message) => self.handle_echo(message.inner),
+            Message::Ready(message) => self.handle_ready(message.inner),
+            Message::Deliver(_) => unimplemented!(),
+        }
+    }
+
+    fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
+        assert_eq!(receiver, self.context.addr());
+        match message {
+            Message::Echo(message) => self.handle_echo(message.inner),
+            Message::Ready(message) => self.handle_ready(message.inner),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn on_timer(&mut self, _: Addr, _: crate::context::TimerId) {
+        unreachable!()
+    }
+}
+
+impl<T> ReliableBroadcast<T>
+where
+    T: Clone + std::hash::Hash + Serialize + DeserializeOwned,
+{
+    fn handle_broadcast(&mut self, message: Broadcast<T>) {
+        let session = (self.index, message.sequence);
+        let instance = self.instances.entry(session).or_default();
+        assert!(!instance.echo_sent);
+        instance.echo_sent = true;
+        let echo = Echo {
+            session,
+            value: message.value,
+            replica_index: self.index,
+        };
+        self.context.send(To::AllReplicaWithLoopback, echo)
+    }
+
+    fn handle_echo(&mut self, message: Echo<T>) {
+        let instance = self.instances.entry(message.session).or_default();
+        if message.replica_index == message.session.0 && !instance.echo_sent {
+            instance.echo_sent = true;
+            let echo = Echo {
+                session: message.session,
+                value: message.value.clone(),
+                replica_index: self.index,
+            };
+            self.context.send(To::AllReplicaWithLoopback, echo)
+        }
+        let instance = self.instances.get_mut(&message.session).unwrap();
+        if instance.echoes.contains_key(&message.replica_index) {
+            return;
+        }
+        let digest = Hasher::sha256(&message.value).finalize().into();
+        instance.echoes.insert(message.replica_index, digest);
+        instance.values.entry(digest).or_insert(message.value);
+        self.do_progress(message.session)
+    }
+
+    fn handle_ready(&mut self, message: Ready) {
+        self.instances
+            .entry(message.session)
+            .or_default()
+            .readies
+            .entry(message.replica_index)
+            .or_insert(message.digest);
+        self.do_progress(message.session)
+    }
+
+    fn do_progress(&mut self, session: Session) {
+        let num_replica = self.context.num_replica();
+        let num_faulty = self.context.num_faulty();
+        let instance = self.instances.get_mut(&session).unwrap();
+        if !instance.ready_sent {
+            let digest = quorum(&instance.echoes, (num_replica + num_faulty) / 2 + 1)
+                .or_else(|| quorum(&instance.readies, num_faulty + 1));
+            if let Some(digest) = digest {
+                instance.ready_sent = true;
+                let ready = Ready {
+                    session,
+                    digest,
+                    replica_index: self.index,
+                };
+                self.context.send(To::AllReplicaWithLoopback, ready)
+            }
+        }
+        let instance = self.instances.get_mut(&session).unwrap();
+        if instance.delivered {
+            return;
+        }
+        let Some(digest) = quorum(&instance.readies, 2 * num_faulty + 1) else {
+            return;
+        };
+        // the value is guaranteed to arrive with some correct replica's echo
+        let Some(value) = instance.values.get(&digest) else {
+            return;
+        };
+        instance.delivered = true;
+        let deliver = Deliver {
+            session,
+            value: value.clone(),
+        };
+        self.context
+            .send(To::Addr(Addr::Upcall), Message::Deliver(deliver))
+    }
+}
+
+fn quorum(digests: &HashMap<ReplicaIndex, [u8; 32]>, threshold: usize) -> Option<[u8; 32]> {
+    let mut counts = HashMap::<_, usize>::new();
+    for digest in digests.values() {
+        *counts.entry(digest).or_default() += 1
+    }
+    counts
+        .into_iter()
+        .find(|(_, count)| *count >= threshold)
+        .map(|(digest, _)| *digest)
+}
+
+impl<T: std::hash::Hash> Sign<Echo<T>> for Message<T> {
+    fn sign(message: Echo<T>, signer: &crate::crypto::Signer) -> Self {
+        Self::Echo(signer.sign_public(message))
+    }
+}
+
+impl<T> Sign<Ready> for Message<T> {
+    fn sign(message: Ready, signer: &crate::crypto::Signer) -> Self {
+        Self::Ready(signer.sign_public(message))
+    }
+}
+
+impl<T: std::hash::Hash> Verify<ReplicaIndex> for Message<T> {
+    fn verify(&self, verifier: &Verifier<ReplicaIndex>) -> Result<(), crate::crypto::Invalid> {
+        match self {
+            Self::Echo(message) => verifier.verify(message, message.replica_index),
+            Self::Ready(message) => verifier.verify(message, message.replica_index),
+            // upcall only, rejected by `handle`
+            Self::Broadcast(_) | Self::Deliver(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::context::{
+        replication::Config,
+        simulated::{self, Dispatch},
+    };
+
+    use super::*;
+
+    struct Group {
+        nodes: Vec<ReliableBroadcast<u32>>,
+        delivered: Vec<(ReplicaIndex, Deliver<u32>)>,
+        crashed: Option<ReplicaIndex>,
+    }
+
+    fn index(addr: Addr) -> ReplicaIndex {
+        let Addr::Simulated(simulated::Addr::Replica(index)) = addr else {
+            unreachable!()
+        };
+        index
+    }
+
+    impl MultiplexReceive for Group {
+        type Message = Message<u32>;
+
+        fn handle(&mut self, receiver: Addr, remote: Addr, message: Self::Message) {
+            if self.crashed != Some(index(receiver)) {
+                self.nodes[index(receiver) as usize].handle(receiver, remote, message)
+            }
+        }
+
+        fn handle_loopback(&mut self, receiver: Addr, message: Self::Message) {
+            if self.crashed == Some(index(receiver)) {
+                return;
+            }
+            if let Message::Deliver(deliver) = message {
+                self.delivered.push((index(receiver), deliver))
+            } else {
+                self.nodes[index(receiver) as usize].handle_loopback(receiver, message)
+            }
+        }
+
+        fn on_timer(&mut self, _: Addr, _: crate::context::TimerId) {
+            unreachable!()
+        }
+    }
+
+    fn addr(index: ReplicaIndex) -> Addr {
+        Addr::Simulated(simulated::Addr::Replica(index))
+    }
+
+    fn group(dispatch: &Dispatch<Message<u32>>, crashed: Option<ReplicaIndex>) -> Group {
+        let config = Arc::new(Config {
+            num_faulty: 1,
+            client_addrs: Default::default(),
+            replica_addrs: Vec::from_iter((0..4).map(addr)),
+            multicast_addr: None,
+        });
+        Group {
+            nodes: Vec::from_iter((0..4).map(|index| {
+                let context = dispatch.register(simulated::Addr::Replica(index));
+                ReliableBroadcast::new(context.into_replication(config.clone()), index)
+            })),
+            delivered: Default::default(),
+            crashed,
+        }
+    }
+
+    #[test]
+    fn deliver_agree() {
+        let dispatch = Dispatch::new();
+        let mut group = group(&dispatch, None);
+        let broadcast = Broadcast {
+            sequence: 0,
+            value: 42,
+        };
+        group.handle(addr(0), Addr::Upcall, Message::Broadcast(broadcast));
+        // replica 3 equivocates by echoing different values as sender
+        for (receiver, value) in [(0, 1), (1, 1), (2, 2)] {
+            let echo = Echo {
+                session: (3, 0),
+                value,
+                replica_index: 3,
+            };
+            let echo = Message::Echo(crate::crypto::Signer::Simulated.sign_public(echo));
+            group.handle(addr(receiver), addr(3), echo)
+        }
+        while dispatch.deliver_event(&mut group) {}
+
+        let delivered = |session| {
+            Vec::from_iter(
+                group
+                    .delivered
+                    .iter()
+                    .filter(|(_, deliver)| deliver.session == session)
+                    .map(|(index, deliver)| (*index, deliver.value)),
+            )
+        };
+        let mut correct = delivered((0, 0));
+        correct.sort();
+        assert_eq!(correct, [(0, 42), (1, 42), (2, 42), (3, 42)]);
+        let equivocated = delivered((3, 0));
+        assert!(equivocated
+            .iter()
+            .all(|(_, value)| *value == equivocated[0].1));
+    }
+
+    // messages are reordered and one of the receivers never responds
+    #[test]
+    fn deliver_delayed() {
+        let dispatch = Dispatch::new();
+        dispatch.set_max_delay(std::time::Duration::from_millis(100));
+        let mut group = group(&dispatch, Some(3));
+        for sender in 0..3 {
+            let broadcast = Broadcast {
+                sequence: 0,
+                value: sender as _,
+            };
+            group.handle(addr(sender), Addr::Upcall, Message::Broadcast(broadcast))
+        }
+        while dispatch.deliver_event(&mut group) {}
+
+        let mut delivered = Vec::from_iter(
+            group
+                .delivered
+                .iter()
+                .map(|(index, deliver)| (deliver.session, *index, deliver.value)),
+        );
+        delivered.sort();
+        let mut expected = Vec::new();
+        for sender in 0..3 {
+            for index in 0..3 {
+                expected.push(((sender, 0), index, sender as u32))
+            }
+        }
+        assert_eq!(delivered, expected);
+    }
+}
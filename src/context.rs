@@ -55,6 +55,15 @@ impl<M> Context<M> {
             Self::Simulated(_) => todo!(),
         }
     }
+
+    // bump a receiver defined counter that is reported along with the
+    // multiplex statistics
+    pub fn count(&self, name: &'static str, n: u64) {
+        match self {
+            Self::Tokio(context) => context.count(name, n),
+            Self::Simulated(_) => {}
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
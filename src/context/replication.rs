@@ -42,6 +42,23 @@ impl Config {
             // also reduce client-side overhead a little bit by only need to sign once for broadcast
         }
     }
+
+    // the replica group of a shard, when the addresses of all shards are laid
+    // out one shard after another
+    pub fn shard(&self, num_shard: usize, shard_index: usize) -> Self {
+        assert!(shard_index < num_shard);
+        let chunk = |addrs: &[Addr]| {
+            assert_eq!(addrs.len() % num_shard, 0);
+            let len = addrs.len() / num_shard;
+            addrs[shard_index * len..(shard_index + 1) * len].to_vec()
+        };
+        Self {
+            num_faulty: self.num_faulty,
+            client_addrs: chunk(&self.client_addrs),
+            replica_addrs: chunk(&self.replica_addrs),
+            multicast_addr: self.multicast_addr,
+        }
+    }
 }
 
 #[derive(Debug)]
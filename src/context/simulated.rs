@@ -4,6 +4,7 @@ use std::{
     time::Duration,
 };
 
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 use crate::crypto::{Sign, Signer};
@@ -24,21 +25,41 @@ pub enum Addr {
 enum Event<M> {
     Message(Addr, Addr, M),
     LoopbackMessage(Addr, M),
-    Timer(Addr),
+    Timer(Addr, TimerId),
 }
 
 pub type TimerId = u32;
 
-#[derive(Debug)]
 pub struct Context<M> {
     pub source: Addr,
-    timeline: Arc<Mutex<Timeline<M>>>,
+    // the timeline of the dispatch, which converts the messages of a subnode
+    // into the dispatch ones
+    timeline: Arc<dyn Schedule<M> + Send + Sync>,
+}
+
+impl<M> std::fmt::Debug for Context<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Context")
+            .field("source", &self.source)
+            .finish_non_exhaustive()
+    }
+}
+
+trait Schedule<M> {
+    fn add_events(&self, events: Vec<(Duration, Event<M>)>);
+
+    fn set(&self, receiver: Addr, duration: Duration) -> TimerId;
+
+    fn unset(&self, id: TimerId);
 }
 
 #[derive(Debug)]
 struct Timeline<M> {
     now: Duration,
     id: u32,
+    // adversarial network: every message is delayed by a uniformly random
+    // duration up to this, so messages may be reordered
+    max_delay: Duration,
     events: BTreeMap<(Duration, u32), Event<M>>,
     timers: HashMap<TimerId, Timer>,
 }
@@ -56,16 +77,54 @@ impl<M> Timeline<M> {
         assert!(evicted.is_none())
     }
 
-    fn add_timer_event(&mut self, offset: Duration, event: Event<M>) {
-        self.add_event(offset, event);
+    fn add_timer_event(&mut self, receiver: Addr, offset: Duration) -> TimerId {
+        // the timer is identified by its first event
+        let id = self.id + 1;
+        self.add_event(offset, Event::Timer(receiver, id));
         let evicted = self.timers.insert(
-            self.id,
+            id,
             Timer {
                 duration: offset,
-                key: (self.now + offset, self.id),
+                key: (self.now + offset, id),
             },
         );
-        assert!(evicted.is_none())
+        assert!(evicted.is_none());
+        id
+    }
+}
+
+impl<M, N: Into<M>> Schedule<N> for Mutex<Timeline<M>> {
+    fn add_events(&self, events: Vec<(Duration, Event<N>)>) {
+        let mut timeline = self.try_lock().unwrap();
+        for (offset, event) in events {
+            let event = match event {
+                Event::Message(receiver, remote, message) => {
+                    let delay = timeline.max_delay.mul_f64(rand::thread_rng().gen());
+                    timeline.add_event(
+                        offset + delay,
+                        Event::Message(receiver, remote, message.into()),
+                    );
+                    continue;
+                }
+                Event::LoopbackMessage(receiver, message) => {
+                    Event::LoopbackMessage(receiver, message.into())
+                }
+                // timers are scheduled by `set`
+                Event::Timer(..) => unreachable!(),
+            };
+            timeline.add_event(offset, event)
+        }
+    }
+
+    fn set(&self, receiver: Addr, duration: Duration) -> TimerId {
+        let mut timeline = self.try_lock().unwrap();
+        timeline.add_timer_event(receiver, duration)
+    }
+
+    fn unset(&self, id: TimerId) {
+        let mut timeline = self.try_lock().unwrap();
+        let timer = timeline.timers.remove(&id).unwrap();
+        timeline.events.remove(&timer.key).unwrap();
     }
 }
 
@@ -75,19 +134,24 @@ impl<M> Context<M> {
         M: Sign<N> + Clone,
     {
         let message = M::sign(message, &Signer::Simulated);
-        let mut timeline = self.timeline.try_lock().unwrap();
+        let mut events = Vec::new();
         if matches!(to, To::Loopback | To::AddrsWithLoopback(_)) {
-            timeline.add_event(
+            events.push((
                 Duration::ZERO,
                 Event::LoopbackMessage(self.source, message.clone()),
-            )
+            ))
         }
         match to {
+            // there is no parent in simulation, the upcall goes back to the
+            // sender's own loopback
+            To::Addr(crate::context::Addr::Upcall) => {
+                events.push((Duration::ZERO, Event::LoopbackMessage(self.source, message)))
+            }
             To::Addr(addr) => {
                 let crate::context::Addr::Simulated(addr) = addr else {
                     unimplemented!()
                 };
-                timeline.add_event(Duration::ZERO, Event::Message(addr, self.source, message))
+                events.push((Duration::ZERO, Event::Message(addr, self.source, message)))
             }
             To::Addrs(addrs) | To::AddrsWithLoopback(addrs) => {
                 for addr in addrs {
@@ -95,26 +159,23 @@ impl<M> Context<M> {
                         unimplemented!()
                     };
                     assert_ne!(addr, self.source);
-                    timeline.add_event(
+                    events.push((
                         Duration::ZERO,
                         Event::Message(addr, self.source, message.clone()),
-                    )
+                    ))
                 }
             }
             To::Loopback => {}
         }
+        self.timeline.add_events(events)
     }
 
     pub fn set(&self, duration: Duration) -> TimerId {
-        let mut timeline = self.timeline.try_lock().unwrap();
-        timeline.add_timer_event(duration, Event::Timer(self.source));
-        timeline.id
+        self.timeline.set(self.source, duration)
     }
 
     pub fn unset(&self, id: TimerId) {
-        let mut timeline = self.timeline.try_lock().unwrap();
-        let timer = timeline.timers.remove(&id).unwrap();
-        timeline.events.remove(&timer.key).unwrap();
+        self.timeline.unset(id)
     }
 }
 
@@ -123,21 +184,94 @@ pub struct Dispatch<M> {
     timeline: Arc<Mutex<Timeline<M>>>,
 }
 
+impl<M> Default for Dispatch<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<M> Dispatch<M> {
-    pub fn register(&self, receiver: Addr) -> super::Context<M> {
+    pub fn new() -> Self {
+        Self {
+            timeline: Arc::new(Mutex::new(Timeline {
+                now: Duration::ZERO,
+                id: 0,
+                max_delay: Duration::ZERO,
+                events: Default::default(),
+                timers: Default::default(),
+            })),
+        }
+    }
+
+    pub fn set_max_delay(&self, max_delay: Duration) {
+        self.timeline.lock().unwrap().max_delay = max_delay
+    }
+
+    pub fn register(&self, receiver: Addr) -> super::Context<M>
+    where
+        M: Send + 'static,
+    {
         super::Context::Simulated(Context {
             source: receiver,
             timeline: self.timeline.clone(),
         })
     }
 
+    // the counterpart of `tokio::Multiplex::register_subnode`. the subnode
+    // shares the address of its parent, and its messages and timers are
+    // delivered to the parent's receiver as well
+    pub fn register_subnode<N, P>(&self, context: &super::Context<P>) -> super::Context<N>
+    where
+        N: Into<M>,
+        M: Send + 'static,
+    {
+        let super::Context::Simulated(context) = context else {
+            unimplemented!()
+        };
+        super::Context::Simulated(Context {
+            source: context.source,
+            timeline: self.timeline.clone(),
+        })
+    }
+
+    // the virtual time since the dispatch is created
+    pub fn now(&self) -> Duration {
+        self.timeline.lock().unwrap().now
+    }
+
     pub fn deliver_event(&self, receivers: &mut impl MultiplexReceive<Message = M>) -> bool {
+        self.deliver_event_before(Duration::MAX, receivers)
+    }
+
+    // deliver the next event if it happens before `deadline`, otherwise advance
+    // the virtual time to `deadline` and return false
+    pub fn deliver_event_before(
+        &self,
+        deadline: Duration,
+        receivers: &mut impl MultiplexReceive<Message = M>,
+    ) -> bool {
         let mut timeline = self.timeline.lock().unwrap();
-        let Some(((now, id), event)) = timeline.events.pop_first() else {
-            return false;
-        };
+        match timeline.events.first_key_value() {
+            Some(((at, _), _)) if *at < deadline => {}
+            _ => {
+                if deadline != Duration::MAX {
+                    timeline.now = deadline
+                }
+                return false;
+            }
+        }
+        let ((now, _), event) = timeline.events.pop_first().unwrap();
         assert!(now >= timeline.now);
         timeline.now = now;
+        if let Event::Timer(receiver, id) = &event {
+            let duration = timeline.timers[id].duration;
+            timeline.id += 1;
+            let key = (now + duration, timeline.id);
+            timeline.timers.get_mut(id).unwrap().key = key;
+            timeline.events.insert(key, Event::Timer(*receiver, *id));
+        }
+        // release the timeline so the receivers can send messages and set timers
+        drop(timeline);
         use crate::context::Addr::Simulated;
         match event {
             Event::Message(receiver, remote, message) => {
@@ -146,9 +280,7 @@ impl<M> Dispatch<M> {
             Event::LoopbackMessage(receiver, message) => {
                 receivers.handle_loopback(Simulated(receiver), message)
             }
-            Event::Timer(receiver) => {
-                let offset = timeline.timers[&id].duration;
-                timeline.add_timer_event(offset, Event::Timer(receiver));
+            Event::Timer(receiver, id) => {
                 receivers.on_timer(Simulated(receiver), crate::context::TimerId::Simulated(id))
             }
         }
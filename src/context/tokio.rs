@@ -3,7 +3,16 @@
 //! Although supported by an asynchronous reactor, protocol code, i.e.,
 //! `impl Receivers` is still synchronous and running in a separated thread.
 
-use std::{borrow::Borrow, collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::SeqCst},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
 
 use bincode::Options;
 use rand::Rng;
@@ -21,9 +30,11 @@ use super::{
 #[derive(Debug, Clone)]
 enum Event {
     Message(SocketAddr, SocketAddr, Vec<u8>),
+    DelayedMessage(SocketAddr, SocketAddr, Vec<u8>),
     LocalMessage(SocketAddr, Bytes),
     OrderedMulticastMessage(SocketAddr, Vec<u8>),
-    Timer(SocketAddr, TimerId),
+    // delivered only if the timer is still set
+    Timer(SocketAddr, TimerId, Arc<AtomicBool>),
     TimerNotification,
     Stop,
 }
@@ -34,32 +45,13 @@ pub struct Context<M> {
     pub source: SocketAddr,
     signer: Arc<Signer>,
     timer_id: TimerId,
-    timer_tasks: HashMap<TimerId, JoinHandle<()>>,
+    timer_tasks: HashMap<TimerId, (JoinHandle<()>, Arc<AtomicBool>)>,
     timer_lock: Arc<Mutex<Vec<Event>>>,
     event: flume::Sender<Event>,
     rdv_event: flume::Sender<Event>,
-    get_buf: Box<dyn Fn(M) -> Vec<u8> + Send + Sync>,
-}
-
-trait GetBuf<M> {
-    fn get_buf(&self, message: M) -> Vec<u8>
-    where
-        M: Serialize;
-}
-
-struct Bincode<M, N>(std::marker::PhantomData<(M, N)>);
-
-impl<M, N> GetBuf<N> for Bincode<M, N>
-where
-    N: Into<M>,
-    M: Serialize + 'static,
-{
-    fn get_buf(&self, message: N) -> Vec<u8>
-    where
-        N: Serialize,
-    {
-        bincode::options().serialize(&message.into()).unwrap()
-    }
+    get_buf: GetBuf<M>,
+    stats: Arc<std::sync::Mutex<Stats>>,
+    counters: Arc<Counters>,
 }
 
 impl<M> std::fmt::Debug for Context<M> {
@@ -75,7 +67,8 @@ impl<M> Context<M> {
     {
         // println!("{to:?}");
         let message = M::sign(message, &self.signer);
-        let buf = Bytes::from((self.get_buf)(message));
+        let (kind, buf) = (self.get_buf)(message);
+        let buf = Bytes::from(buf);
         // println!("{buf:02x?}");
         if matches!(
             to,
@@ -86,16 +79,44 @@ impl<M> Context<M> {
                 .send(Event::LocalMessage(self.source, buf.clone()))
                 .unwrap()
         }
-        match to {
-            To::Addr(Addr::Upcall) => {}
-            To::Addr(addr) => self.send_buf(addr, buf.clone()),
+        let num_sent = match to {
+            To::Addr(Addr::Upcall) => 0,
+            To::Addr(addr) => {
+                self.send_buf(addr, buf.clone());
+                1
+            }
             To::Addrs(addrs) | To::AddrsWithLoopback(addrs) => {
+                let num_sent = addrs.len();
                 for addr in addrs {
                     self.send_buf(addr, buf.clone())
                 }
+                num_sent
             }
-            To::Loopback => {}
+            To::Loopback => 0,
+        };
+        if let Some(kind) = kind {
+            if num_sent != 0 {
+                self.stats
+                    .lock()
+                    .unwrap()
+                    .record_out(kind, num_sent, buf.len())
+            }
+        }
+    }
+
+    // bump a receiver defined counter, e.g. the number of committed blocks.
+    // always collected, the write lock is only taken for a new name
+    pub fn count(&self, name: &'static str, n: u64) {
+        if let Some(counter) = self.counters.read().unwrap().get(name) {
+            counter.fetch_add(n, SeqCst);
+            return;
         }
+        self.counters
+            .write()
+            .unwrap()
+            .entry(name)
+            .or_default()
+            .fetch_add(n, SeqCst);
     }
 
     pub fn send_buf(&self, addr: Addr, buf: impl AsRef<[u8]> + Send + Sync + 'static) {
@@ -125,6 +146,10 @@ pub type TimerId = (u32, u32); // (subnode id, local sequence number)
 // channel instead passes a temporary `Event::TimerNotification` which is
 // allowed to be spurious
 // i believe the original solution should also work for multithreading runtime
+//
+// unsetting does not wait for the timer task, which may be blocked behind the
+// caller e.g. on a lock that the caller holds. instead the dispatch skips the
+// events of the unset timers
 
 impl<M> Context<M> {
     pub fn set(&mut self, duration: Duration) -> TimerId {
@@ -133,22 +158,62 @@ impl<M> Context<M> {
         let event = self.rdv_event.clone();
         let source = self.source;
         let timer_lock = self.timer_lock.clone();
-        let task = self.runtime.spawn(async move {
-            loop {
-                tokio::time::sleep(duration).await;
-                timer_lock.lock().await.push(Event::Timer(source, id));
-                event.send_async(Event::TimerNotification).await.unwrap()
+        let is_set = Arc::new(AtomicBool::new(true));
+        let task = self.runtime.spawn({
+            let is_set = is_set.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(duration).await;
+                    timer_lock
+                        .lock()
+                        .await
+                        .push(Event::Timer(source, id, is_set.clone()));
+                    event.send_async(Event::TimerNotification).await.unwrap()
+                }
             }
         });
-        self.timer_tasks.insert(id, task);
+        self.timer_tasks.insert(id, (task, is_set));
         id
     }
 
     pub fn unset(&mut self, id: TimerId) {
-        let task = self.timer_tasks.remove(&id).unwrap();
-        task.abort();
-        let result = self.runtime.block_on(task);
-        assert!(result.is_err())
+        let (task, is_set) = self.timer_tasks.remove(&id).unwrap();
+        is_set.store(false, SeqCst);
+        task.abort()
+    }
+}
+
+// the message kind is only taken if the multiplex collects stats
+type GetBuf<M> = Box<dyn Fn(M) -> (Option<&'static str>, Vec<u8>) + Send + Sync>;
+
+type Counters = RwLock<BTreeMap<&'static str, AtomicU64>>;
+
+// counters of a multiplex and the contexts registered to it. the message
+// counts and the max queue depth are only collected if
+// `Multiplex::collect_stats`
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    // (number of messages, total bytes) by message type. a message sent to
+    // multiple addresses is counted once for each of them
+    pub messages_in: BTreeMap<&'static str, (u64, u64)>,
+    pub messages_out: BTreeMap<&'static str, (u64, u64)>,
+    // events waiting for the receivers, when taking the snapshot and at most
+    pub queue_depth: usize,
+    pub max_queue_depth: usize,
+    pub counters: BTreeMap<&'static str, u64>,
+}
+
+impl Stats {
+    fn record_in(&mut self, kind: &'static str, len: usize) {
+        let (count, bytes) = self.messages_in.entry(kind).or_default();
+        *count += 1;
+        *bytes += len as u64
+    }
+
+    fn record_out(&mut self, kind: &'static str, num_sent: usize, len: usize) {
+        let (count, bytes) = self.messages_out.entry(kind).or_default();
+        *count += num_sent as u64;
+        *bytes += (num_sent * len) as u64
     }
 }
 
@@ -160,7 +225,14 @@ pub struct Multiplex {
     rdv_event: (flume::Sender<Event>, flume::Receiver<Event>),
     timer_lock: Arc<Mutex<Vec<Event>>>,
     subnode_id: u32,
+    stats: Arc<std::sync::Mutex<Stats>>,
+    counters: Arc<Counters>,
+    // take a lock per message for `Stats`, so off by default
+    pub collect_stats: bool,
     pub drop_rate: f64,
+    // adversarial network: every incoming message is delayed by a uniformly
+    // random duration up to this before processing, which also reorders them
+    pub max_delay: Duration,
 }
 
 impl Multiplex {
@@ -175,7 +247,11 @@ impl Multiplex {
             rdv_event: flume::bounded(0),
             timer_lock: Default::default(),
             subnode_id: Default::default(),
+            stats: Default::default(),
+            counters: Default::default(),
+            collect_stats: false,
             drop_rate: 0.,
+            max_delay: Duration::ZERO,
         }
     }
 
@@ -192,6 +268,7 @@ impl Multiplex {
                 .unwrap_or_else(|_| panic!("binding {addr:?}")),
         );
         socket.set_broadcast(true).unwrap();
+        let collect_stats = self.collect_stats;
         let context = Context {
             socket: socket.clone(),
             runtime: self.runtime.clone(),
@@ -202,7 +279,14 @@ impl Multiplex {
             timer_lock: self.timer_lock.clone(),
             event: self.event.0.clone(),
             rdv_event: self.rdv_event.0.clone(),
-            get_buf: Box::new(|message| bincode::options().serialize(&message).unwrap()),
+            get_buf: Box::new(move |message| {
+                (
+                    collect_stats.then(|| message_kind(&message)),
+                    bincode::options().serialize(&message).unwrap(),
+                )
+            }),
+            stats: self.stats.clone(),
+            counters: self.counters.clone(),
         };
         let event = self.event.0.clone();
         self.runtime.spawn(async move {
@@ -228,6 +312,7 @@ impl Multiplex {
             unimplemented!()
         };
         self.subnode_id += 1;
+        let collect_stats = self.collect_stats;
         super::Context::Tokio(Context {
             socket: context.socket.clone(),
             runtime: self.runtime.clone(),
@@ -238,7 +323,15 @@ impl Multiplex {
             timer_lock: self.timer_lock.clone(),
             event: self.event.0.clone(),
             rdv_event: self.rdv_event.0.clone(),
-            get_buf: Box::new(|message| bincode::options().serialize(&message.into()).unwrap()),
+            get_buf: Box::new(move |message| {
+                let message = message.into();
+                (
+                    collect_stats.then(|| message_kind(&message)),
+                    bincode::options().serialize(&message).unwrap(),
+                )
+            }),
+            stats: self.stats.clone(),
+            counters: self.counters.clone(),
         })
     }
 }
@@ -251,7 +344,7 @@ impl Multiplex {
         verifier: &Verifier<I>,
     ) where
         R: MultiplexReceive<Message = M>,
-        M: DeserializeOwned + Verify<I>,
+        M: Serialize + DeserializeOwned + Verify<I>,
         N: DeserializeOwned + DigestHash,
     {
         let deserialize = |buf: &_| {
@@ -275,7 +368,12 @@ impl Multiplex {
                 // println!("* pace count {pace_count}");
             }
 
-            assert!(self.event.1.len() < 4096, "receivers overwhelmed");
+            let queue_depth = self.event.1.len();
+            assert!(queue_depth < 4096, "receivers overwhelmed");
+            if self.collect_stats {
+                let mut stats = self.stats.lock().unwrap();
+                stats.max_queue_depth = stats.max_queue_depth.max(queue_depth)
+            }
             let event = flume::Selector::new()
                 .recv(&self.event.1, Result::unwrap)
                 .recv(&self.rdv_event.1, Result::unwrap)
@@ -283,21 +381,43 @@ impl Multiplex {
             // println!("{event:?}");
             let mut timer_lock = self.timer_lock.blocking_lock();
             for event in timer_lock.drain(..) {
-                let Event::Timer(receiver, id) = event else {
+                let Event::Timer(receiver, id, is_set) = event else {
                     unreachable!()
                 };
-                receive.on_timer(Socket(receiver), super::TimerId::Tokio(id))
+                if is_set.load(SeqCst) {
+                    receive.on_timer(Socket(receiver), super::TimerId::Tokio(id))
+                }
             }
 
             use crate::context::Addr::Socket;
             match event {
                 Event::Stop => break,
-                Event::Message(receiver, remote, message) => {
+                Event::Message(receiver, remote, message) if !self.max_delay.is_zero() => {
+                    pace_count -= 1;
+                    let delay = self.max_delay.mul_f64(rand::thread_rng().gen());
+                    let event = self.event.0.clone();
+                    self.runtime.spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        event
+                            .send_async(Event::DelayedMessage(receiver, remote, message))
+                            .await
+                            .unwrap()
+                    });
+                }
+                Event::Message(receiver, remote, message)
+                | Event::DelayedMessage(receiver, remote, message) => {
                     pace_count -= 1;
                     if self.drop_rate != 0. && rand::thread_rng().gen_bool(self.drop_rate) {
                         continue;
                     }
+                    let len = message.len();
                     let message = deserialize(&message);
+                    if self.collect_stats {
+                        self.stats
+                            .lock()
+                            .unwrap()
+                            .record_in(message_kind(&message), len)
+                    }
                     message.verify(verifier).unwrap();
                     receive.handle(Socket(receiver), Socket(remote), message)
                 }
@@ -310,6 +430,12 @@ impl Multiplex {
                     if self.drop_rate != 0. && rand::thread_rng().gen_bool(self.drop_rate) {
                         continue;
                     }
+                    if self.collect_stats {
+                        self.stats
+                            .lock()
+                            .unwrap()
+                            .record_in("OrderedMulticast", message.len())
+                    }
                     delegate.handle(
                         Socket(remote),
                         self.ordered_multicast_receiver.deserialize(message),
@@ -319,7 +445,7 @@ impl Multiplex {
                     )
                 }
                 Event::TimerNotification => {} // handled above
-                Event::Timer(_, _) => unreachable!(),
+                Event::Timer(..) => unreachable!(),
             }
         }
     }
@@ -329,7 +455,7 @@ impl Multiplex {
         receive: &mut impl MultiplexReceive<Message = M>,
         verifier: impl Borrow<Verifier<I>>,
     ) where
-        M: DeserializeOwned + Verify<I>,
+        M: Serialize + DeserializeOwned + Verify<I>,
     {
         #[derive(Deserialize)]
         enum O {}
@@ -383,7 +509,7 @@ impl OrderedMulticastMultiplex {
         receivers: &mut (impl MultiplexReceive<Message = M> + OrderedMulticastReceive<Message = N>),
         verifier: impl Borrow<Verifier<I>>,
     ) where
-        M: DeserializeOwned + Verify<I>,
+        M: Serialize + DeserializeOwned + Verify<I>,
         N: DeserializeOwned + DigestHash,
         OrderedMulticast<N>: Into<M>,
     {
@@ -392,14 +518,26 @@ impl OrderedMulticastMultiplex {
 }
 
 pub struct MultiplexHandle {
+    event: flume::Sender<Event>,
+    stats: Arc<std::sync::Mutex<Stats>>,
+    counters: Arc<Counters>,
     stop: Box<dyn Fn() + Send + Sync>,
     stop_async:
         Box<dyn Fn() -> std::pin::Pin<Box<dyn std::future::Future<Output = ()>>> + Send + Sync>,
 }
 
+impl std::fmt::Debug for MultiplexHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("MultiplexHandle").field(&"..").finish()
+    }
+}
+
 impl Multiplex {
     pub fn handle(&self) -> MultiplexHandle {
         MultiplexHandle {
+            event: self.event.0.clone(),
+            stats: self.stats.clone(),
+            counters: self.counters.clone(),
             stop: Box::new({
                 let rdv_event = self.rdv_event.0.clone();
                 move || rdv_event.send(Event::Stop).unwrap()
@@ -423,6 +561,175 @@ impl MultiplexHandle {
     pub async fn stop_async(&self) {
         (self.stop_async)().await
     }
+
+    pub fn stats(&self) -> Stats {
+        let counters = self.counters.read().unwrap();
+        Stats {
+            queue_depth: self.event.len(),
+            counters: BTreeMap::from_iter(
+                counters
+                    .iter()
+                    .map(|(&name, counter)| (name, counter.load(SeqCst))),
+            ),
+            ..self.stats.lock().unwrap().clone()
+        }
+    }
+}
+
+// the variant name if the message is an enum (through newtype wrappers), or
+// the type name otherwise
+fn message_kind<M: Serialize>(message: &M) -> &'static str {
+    use serde::ser::{Error, Impossible, Serializer};
+
+    #[derive(Debug)]
+    struct Kind(Option<&'static str>);
+    impl std::fmt::Display for Kind {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+    impl std::error::Error for Kind {}
+    impl Error for Kind {
+        fn custom<T: std::fmt::Display>(_: T) -> Self {
+            Self(None)
+        }
+    }
+
+    // short-circuit with the variant name as error, so the content is never
+    // visited
+    struct S;
+    macro_rules! not_enum {
+        ($($method:ident: $t:ty),*) => {
+            $(fn $method(self, _: $t) -> Result<Self::Ok, Self::Error> {
+                Err(Kind(None))
+            })*
+        };
+    }
+    impl Serializer for S {
+        type Ok = ();
+        type Error = Kind;
+        type SerializeSeq = Impossible<(), Kind>;
+        type SerializeTuple = Impossible<(), Kind>;
+        type SerializeTupleStruct = Impossible<(), Kind>;
+        type SerializeTupleVariant = Impossible<(), Kind>;
+        type SerializeMap = Impossible<(), Kind>;
+        type SerializeStruct = Impossible<(), Kind>;
+        type SerializeStructVariant = Impossible<(), Kind>;
+
+        not_enum!(
+            serialize_bool: bool,
+            serialize_i8: i8,
+            serialize_i16: i16,
+            serialize_i32: i32,
+            serialize_i64: i64,
+            serialize_u8: u8,
+            serialize_u16: u16,
+            serialize_u32: u32,
+            serialize_u64: u64,
+            serialize_f32: f32,
+            serialize_f64: f64,
+            serialize_char: char,
+            serialize_str: &str,
+            serialize_bytes: &[u8],
+            serialize_unit_struct: &'static str
+        );
+
+        fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+            Err(Kind(None))
+        }
+
+        fn serialize_some<T: ?Sized + Serialize>(self, _: &T) -> Result<Self::Ok, Self::Error> {
+            Err(Kind(None))
+        }
+
+        fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+            Err(Kind(None))
+        }
+
+        fn serialize_unit_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            variant: &'static str,
+        ) -> Result<Self::Ok, Self::Error> {
+            Err(Kind(Some(variant)))
+        }
+
+        fn serialize_newtype_struct<T: ?Sized + Serialize>(
+            self,
+            _: &'static str,
+            value: &T,
+        ) -> Result<Self::Ok, Self::Error> {
+            value.serialize(self)
+        }
+
+        fn serialize_newtype_variant<T: ?Sized + Serialize>(
+            self,
+            _: &'static str,
+            _: u32,
+            variant: &'static str,
+            _: &T,
+        ) -> Result<Self::Ok, Self::Error> {
+            Err(Kind(Some(variant)))
+        }
+
+        fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+            Err(Kind(None))
+        }
+
+        fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Self::Error> {
+            Err(Kind(None))
+        }
+
+        fn serialize_tuple_struct(
+            self,
+            _: &'static str,
+            _: usize,
+        ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+            Err(Kind(None))
+        }
+
+        fn serialize_tuple_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            variant: &'static str,
+            _: usize,
+        ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+            Err(Kind(Some(variant)))
+        }
+
+        fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+            Err(Kind(None))
+        }
+
+        fn serialize_struct(
+            self,
+            _: &'static str,
+            _: usize,
+        ) -> Result<Self::SerializeStruct, Self::Error> {
+            Err(Kind(None))
+        }
+
+        fn serialize_struct_variant(
+            self,
+            _: &'static str,
+            _: u32,
+            variant: &'static str,
+            _: usize,
+        ) -> Result<Self::SerializeStructVariant, Self::Error> {
+            Err(Kind(Some(variant)))
+        }
+    }
+
+    match message.serialize(S) {
+        Err(Kind(Some(variant))) => variant,
+        _ => {
+            let name = std::any::type_name::<M>();
+            let name = name.split('<').next().unwrap();
+            name.rsplit("::").next().unwrap()
+        }
+    }
 }
 
 #[cfg(test)]
@@ -504,6 +811,22 @@ mod tests {
         multiplex.run(&mut R(false, context, id), Verifier::<()>::Nop);
     }
 
+    #[test]
+    fn message_kind_of_enum() {
+        #[derive(Serialize)]
+        struct Wrapper<T>(T);
+        #[derive(Serialize)]
+        enum M {
+            A(u32),
+            B(u32, u32),
+            C,
+        }
+        assert_eq!(message_kind(&M::A(1)), "A");
+        assert_eq!(message_kind(&Wrapper(M::B(1, 2))), "B");
+        assert_eq!(message_kind(&M::C), "C");
+        assert_eq!(message_kind(&Wrapper(1u32)), "Wrapper");
+    }
+
     #[test]
     fn false_alarm_100() {
         for _ in 0..100 {
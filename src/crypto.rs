@@ -1,4 +1,13 @@
-use std::{collections::HashMap, hash::Hash, mem::take, ops::Deref, sync::Arc};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    mem::take,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+};
 
 use ed25519_dalek::{Sha512, Signer as _, Verifier as _};
 use hmac::{Hmac, Mac};
@@ -187,14 +196,14 @@ pub enum SigningKey {
 pub fn hardcoded_k256(index: usize) -> SigningKey {
     let k = format!("hardcoded-{index}");
     let mut buf = [0; 32];
-    buf[..k.as_bytes().len()].copy_from_slice(k.as_bytes());
+    buf[..k.len()].copy_from_slice(k.as_bytes());
     SigningKey::K256(k256::ecdsa::SigningKey::from_slice(&buf).unwrap())
 }
 
 pub fn hardcoded_ed25519(index: usize) -> SigningKey {
     let k = format!("hardcoded-{index}");
     let mut buf = [0; 32];
-    buf[..k.as_bytes().len()].copy_from_slice(k.as_bytes());
+    buf[..k.len()].copy_from_slice(k.as_bytes());
     SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&buf))
 }
 
@@ -202,6 +211,19 @@ pub fn hardcoded_hmac() -> Hmac<Sha256> {
     Hmac::new_from_slice("hardcoded".as_bytes()).unwrap()
 }
 
+// process-wide numbers of signatures (including hmac) that are actually
+// computed, i.e., simulated and nop ones are not counted
+static NUM_SIGNED: AtomicU64 = AtomicU64::new(0);
+static NUM_VERIFIED: AtomicU64 = AtomicU64::new(0);
+
+pub fn num_signed() -> u64 {
+    NUM_SIGNED.load(Relaxed)
+}
+
+pub fn num_verified() -> u64 {
+    NUM_VERIFIED.load(Relaxed)
+}
+
 impl Signer {
     pub fn new_standard(signing_key: impl Into<Option<SigningKey>>) -> Self {
         Self::Standard(Box::new(StandardSigner {
@@ -255,6 +277,7 @@ impl StandardSigner {
     where
         M: DigestHash,
     {
+        NUM_SIGNED.fetch_add(1, Relaxed);
         let signature = match self.signing_key.as_ref().unwrap() {
             SigningKey::K256(signing_key) => {
                 Signature::K256(signing_key.sign_digest(Hasher::sha256(&message)))
@@ -274,6 +297,7 @@ impl StandardSigner {
         M: DigestHash,
     {
         if let SigningKey::Ed25519(signing_key) = self.signing_key.as_ref().unwrap() {
+            NUM_SIGNED.fetch_add(1, Relaxed);
             Signed {
                 signature: Signature::Ed25519Batched(signing_key.sign(&Hasher::bytes(&message))),
                 inner: message,
@@ -288,6 +312,7 @@ impl StandardSigner {
         M: DigestHash,
     {
         // println!("{:02x?}", Hasher::bytes(&message));
+        NUM_SIGNED.fetch_add(1, Relaxed);
         let mut hmac = self.hmac.clone();
         Hasher::hmac_update(&message, &mut hmac);
         Signed {
@@ -389,15 +414,19 @@ impl<I> Verifier<I> {
             (Self::Simulated, _) => unimplemented!(),
             (Self::Standard(verifier), Signature::Hmac(code)) => {
                 // println!("{:02x?}", Hasher::bytes(&message));
+                NUM_VERIFIED.fetch_add(1, Relaxed);
                 let mut hmac = verifier.hmac.clone();
                 Hasher::hmac_update(&message.inner, &mut hmac);
                 hmac.verify(code.into()).map_err(|_| Invalid::Private)
             }
             (Self::Standard(verifier), signature) => {
-                match (
-                    &verifier.verifying_keys[&identity.into().unwrap()],
-                    signature,
-                ) {
+                NUM_VERIFIED.fetch_add(1, Relaxed);
+                // e.g. a replica index out of range in a forged message
+                let verifying_key = identity
+                    .into()
+                    .and_then(|identity| verifier.verifying_keys.get(&identity))
+                    .ok_or(Invalid::Public)?;
+                match (verifying_key, signature) {
                     (VerifyingKey::K256(verifying_key), Signature::K256(signature)) => {
                         verifying_key
                             .verify_digest(Hasher::sha256(&message.inner), signature)
@@ -434,8 +463,12 @@ impl<I> Verifier<I> {
         let mut signatures = Vec::new();
         let mut verifying_keys = Vec::new();
         for (message, identity) in messages.iter().zip(identities) {
+            let verifying_key = verifier
+                .verifying_keys
+                .get(identity)
+                .ok_or(Invalid::Public)?;
             let (&Signature::Ed25519Batched(signature), &VerifyingKey::Ed25519(verifying_key)) =
-                (&message.signature, &verifier.verifying_keys[identity])
+                (&message.signature, verifying_key)
             else {
                 for (message, identity) in messages.iter().zip(identities) {
                     self.verify(message, identity.clone())?
@@ -447,6 +480,7 @@ impl<I> Verifier<I> {
             signatures.push(signature);
             verifying_keys.push(verifying_key)
         }
+        NUM_VERIFIED.fetch_add(messages.len() as _, Relaxed);
         ed25519_dalek::verify_batch(
             &bytes.iter().map(AsRef::as_ref).collect::<Vec<_>>(),
             &signatures,
@@ -462,7 +496,10 @@ impl<I> Verifier<I> {
         match self {
             Self::Nop => Ok(()),
             Self::Simulated => unimplemented!(),
-            Self::Standard(verifier) => verifier.variant.verify(message),
+            Self::Standard(verifier) => {
+                NUM_VERIFIED.fetch_add(1, Relaxed);
+                verifier.variant.verify(message)
+            }
         }
     }
 }
@@ -480,3 +517,195 @@ impl<M, N: Into<M>> Sign<N> for M {
 pub trait Verify<I> {
     fn verify(&self, verifier: &Verifier<I>) -> Result<(), Invalid>;
 }
+
+// threshold common coin, i.e. a unique threshold signature on the coin session
+// the secret `x` is shamir shared with degree `threshold - 1`, a share is
+// `x_i * H(session)` and the coin is derived from `x * H(session)` that is
+// interpolated from any `threshold` shares
+// every share carries a chaum-pedersen proof that it has the same discrete log
+// as the public share `x_i * G`, which is derived from the (feldman)
+// commitments of the polynomial coefficients, so a malformed share is rejected
+// before combining
+#[derive(Debug, Clone)]
+pub struct ThresholdCoin {
+    index: usize,
+    secret_share: k256::Scalar,
+    commitments: Vec<k256::ProjectivePoint>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CoinShare {
+    share: Vec<u8>,
+    challenge: [u8; 32],
+    response: [u8; 32],
+}
+
+pub fn hardcoded_threshold_coin(index: usize, threshold: usize) -> ThresholdCoin {
+    use k256::{elliptic_curve::ops::Reduce, ProjectivePoint, Scalar, U256};
+    let coefficients = Vec::from_iter((0..threshold).map(|k| {
+        <Scalar as Reduce<U256>>::reduce_bytes(&Sha256::digest(format!("hardcoded-coin-{k}")))
+    }));
+    ThresholdCoin {
+        index,
+        secret_share: ThresholdCoin::evaluate(&coefficients, index),
+        commitments: Vec::from_iter(
+            coefficients
+                .iter()
+                .map(|coefficient| ProjectivePoint::GENERATOR * coefficient),
+        ),
+    }
+}
+
+impl ThresholdCoin {
+    fn evaluate<T: Copy + std::ops::Mul<k256::Scalar, Output = T> + std::ops::Add<Output = T>>(
+        coefficients: &[T],
+        index: usize,
+    ) -> T {
+        let x = k256::Scalar::from(index as u64 + 1);
+        let (&last, rest) = coefficients.split_last().unwrap();
+        rest.iter()
+            .rev()
+            .fold(last, |value, &coefficient| value * x + coefficient)
+    }
+
+    fn session_point(session: &impl DigestHash) -> k256::ProjectivePoint {
+        use k256::elliptic_curve::hash2curve::{ExpandMsgXmd, GroupDigest};
+        k256::Secp256k1::hash_from_bytes::<ExpandMsgXmd<Sha256>>(
+            &[&Hasher::bytes(session)],
+            &[b"neat-threshold-coin"],
+        )
+        .unwrap()
+    }
+
+    fn challenge(points: [&k256::ProjectivePoint; 5]) -> k256::Scalar {
+        use k256::{
+            elliptic_curve::{ops::Reduce, sec1::ToEncodedPoint},
+            Scalar, U256,
+        };
+        let mut hasher = Sha256::new();
+        for point in points {
+            hasher.update(point.to_affine().to_encoded_point(true).as_bytes())
+        }
+        <Scalar as Reduce<U256>>::reduce_bytes(&hasher.finalize())
+    }
+
+    pub fn share(&self, session: &impl DigestHash) -> CoinShare {
+        use k256::{
+            elliptic_curve::{ops::Reduce, sec1::ToEncodedPoint},
+            ProjectivePoint, Scalar, U256,
+        };
+        let base = Self::session_point(session);
+        let share = base * self.secret_share;
+        // deterministic nonce, so there is no randomness to get wrong
+        let nonce = <Scalar as Reduce<U256>>::reduce_bytes(&Sha256::digest(
+            [
+                &self.secret_share.to_bytes()[..],
+                share.to_affine().to_encoded_point(true).as_bytes(),
+            ]
+            .concat(),
+        ));
+        let public_share = ProjectivePoint::GENERATOR * self.secret_share;
+        let challenge = Self::challenge([
+            &public_share,
+            &base,
+            &share,
+            &(ProjectivePoint::GENERATOR * nonce),
+            &(base * nonce),
+        ]);
+        CoinShare {
+            share: share.to_affine().to_encoded_point(true).as_bytes().to_vec(),
+            challenge: challenge.to_bytes().into(),
+            response: (nonce + challenge * self.secret_share).to_bytes().into(),
+        }
+    }
+
+    pub fn verify_share(
+        &self,
+        index: usize,
+        session: &impl DigestHash,
+        share: &CoinShare,
+    ) -> Result<(), Invalid> {
+        use k256::{elliptic_curve::PrimeField, ProjectivePoint, Scalar};
+        let point = Self::decode(&share.share)?;
+        let challenge = Option::<Scalar>::from(Scalar::from_repr(share.challenge.into()))
+            .ok_or(Invalid::Public)?;
+        let response = Option::<Scalar>::from(Scalar::from_repr(share.response.into()))
+            .ok_or(Invalid::Public)?;
+        let base = Self::session_point(session);
+        let public_share = Self::evaluate(&self.commitments, index);
+        let expected = Self::challenge([
+            &public_share,
+            &base,
+            &point,
+            &(ProjectivePoint::GENERATOR * response - public_share * challenge),
+            &(base * response - point * challenge),
+        ]);
+        if expected != challenge {
+            return Err(Invalid::Public);
+        }
+        Ok(())
+    }
+
+    fn decode(share: &[u8]) -> Result<k256::ProjectivePoint, Invalid> {
+        use k256::{elliptic_curve::sec1::FromEncodedPoint, AffinePoint, EncodedPoint};
+        let share = EncodedPoint::from_bytes(share).map_err(|_| Invalid::Public)?;
+        let share = Option::<AffinePoint>::from(AffinePoint::from_encoded_point(&share))
+            .ok_or(Invalid::Public)?;
+        Ok(share.into())
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    // caller is responsible to pass exactly `threshold` shares from distinct
+    // indexes
+    pub fn combine(shares: &[(usize, CoinShare)]) -> Result<bool, Invalid> {
+        use k256::{elliptic_curve::sec1::ToEncodedPoint, ProjectivePoint, Scalar};
+        let mut coin = ProjectivePoint::IDENTITY;
+        for (index, share) in shares {
+            let share = Self::decode(&share.share)?;
+            // lagrange coefficient at zero
+            let x = Scalar::from(*index as u64 + 1);
+            let mut coefficient = Scalar::ONE;
+            for (other_index, _) in shares {
+                if other_index == index {
+                    continue;
+                }
+                let other_x = Scalar::from(*other_index as u64 + 1);
+                coefficient *= other_x
+                    * Option::<Scalar>::from((other_x - x).invert()).ok_or(Invalid::Public)?
+            }
+            coin += share * coefficient
+        }
+        let digest = Sha256::digest(coin.to_affine().to_encoded_point(true).as_bytes());
+        Ok(digest[0] & 1 == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold_coin_agree() {
+        let coins = Vec::from_iter((0..4).map(|index| hardcoded_threshold_coin(index, 2)));
+        for session in 0..10u32 {
+            let shares = Vec::from_iter(
+                coins
+                    .iter()
+                    .map(|coin| (coin.index(), coin.share(&session))),
+            );
+            for (index, share) in &shares {
+                coins[0].verify_share(*index, &session, share).unwrap();
+                assert!(coins[0].verify_share(*index + 1, &session, share).is_err())
+            }
+            let value = ThresholdCoin::combine(&shares[..2]).unwrap();
+            assert_eq!(ThresholdCoin::combine(&shares[2..]).unwrap(), value);
+            assert_eq!(
+                ThresholdCoin::combine(&[shares[3].clone(), shares[0].clone()]).unwrap(),
+                value
+            );
+        }
+    }
+}
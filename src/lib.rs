@@ -1,4 +1,5 @@
 pub mod benchmark;
+pub mod broadcast;
 pub mod context;
 pub mod crypto;
 